@@ -0,0 +1,52 @@
+//! Generates the gRPC service of `proto/dns_updater.proto` with the grpc
+//! feature. Its messages are written by hand in `src/grpc.rs`, so building
+//! doesn't need protoc.
+
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{input}"))
+            .output_type(format!("crate::grpc::{output}"))
+            .codec_path("tonic_prost::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Control")
+        .package("dns_updater.v1")
+        .method(method("status", "Status", "StatusRequest", "StatusReply").build())
+        .method(
+            method(
+                "watch_status",
+                "WatchStatus",
+                "StatusRequest",
+                "StatusReply",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .method(
+            method(
+                "trigger_update",
+                "TriggerUpdate",
+                "TriggerUpdateRequest",
+                "TriggerUpdateReply",
+            )
+            .build(),
+        )
+        .method(method("reload", "Reload", "ReloadRequest", "ReloadReply").build())
+        .build();
+    Builder::new()
+        .build_client(false)
+        .build_transport(false)
+        .compile(&[service]);
+}
@@ -45,7 +45,8 @@
           };
 
           dnsTuples = lib.mkOption {
-            type = lib.types.path;
+            # A string keeps the file out of the Nix store, where it'd be world readable
+            type = lib.types.either lib.types.path lib.types.str;
             description = "Path to a file, where each line represents a DNS tuple (e.g., 'DD;token;ipv4;interval;domains' or '(DD;token;ipv4;interval;domains)').";
             example = "./dns_tuples.txt";
           };
@@ -61,12 +62,15 @@
             Service = {
               ExecStart = "${cfg.package}/bin/dns_updater";
               Restart = "always";
-              
+
+              # The tuples hold secrets, hand them over as a credential instead of
+              # an environment variable. dns_updater reads $CREDENTIALS_DIRECTORY/DNS_TUPLES
+              LoadCredential = [ "DNS_TUPLES:${toString cfg.dnsTuples}" ];
+
               # Map Nix options to the Environment Variables your Rust code expects
               Environment = [
                 "RUST_LOG=debug"
                 "INTERFACE=${cfg.interface}"
-                "DNS_TUPLES=${builtins.readFile cfg.dnsTuples}"
               ];
             };
 
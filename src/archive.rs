@@ -0,0 +1,183 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    SimpleName,
+    config::{self, ConfigFormat},
+    dyn_dns::DynDns,
+    history::HistoryEntry,
+    persistence::{self, StateStore},
+};
+
+/// Version of the archive layout, bumped on incompatible changes
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid archive: {0}")]
+    Parse(serde_json::Error),
+    #[error("archive version {0} is newer than the supported {VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("{0}")]
+    Config(config::Error),
+    #[error("{0}")]
+    Persistence(persistence::Error),
+    /// The configuration doesn't list as many providers as the archived state
+    #[error(
+        "the archive holds the state of {archived} providers but the configuration lists {configured}"
+    )]
+    ProviderMismatch { archived: usize, configured: usize },
+}
+
+/// A deployment in one file, to back it up or move it to another machine:
+/// the configuration file and the state of every provider.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Archive {
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    /// Format of `config`, see [`ConfigFormat`]
+    pub config_format: String,
+    /// The configuration file as written, `${VAR}` references included
+    pub config: String,
+    /// Whether credentials were replaced by variable references, see
+    /// [`crate::config::Config::strip_secrets`]
+    pub secrets_stripped: bool,
+    /// Last published IP of every provider, in configuration order
+    pub ips: Vec<Option<IpAddr>>,
+    pub history: Vec<HistoryEntry>,
+}
+
+impl Archive {
+    /// Captures the configuration `config` and the state of its providers
+    /// `dyn_dnss`, as kept in `store`.
+    pub async fn capture(
+        config: String,
+        config_format: ConfigFormat,
+        secrets_stripped: bool,
+        dyn_dnss: &[Box<dyn DynDns>],
+        store: &dyn StateStore,
+    ) -> Result<Self, Error> {
+        let mut ips = Vec::with_capacity(dyn_dnss.len());
+        for dd in dyn_dnss {
+            ips.push(store.load_ip(dd.file_name()).await.unwrap_or(None));
+        }
+        Ok(Self {
+            version: VERSION,
+            created: Utc::now(),
+            config_format: config_format.simple_name().to_string(),
+            config,
+            secrets_stripped,
+            ips,
+            history: store.history().await.map_err(Error::Persistence)?,
+        })
+    }
+
+    /// Parses an archive, refusing the ones written by a newer version.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let archive: Self = serde_json::from_str(content).map_err(Error::Parse)?;
+        if archive.version > VERSION {
+            return Err(Error::UnsupportedVersion(archive.version));
+        }
+        Ok(archive)
+    }
+
+    pub fn config_format(&self) -> Result<ConfigFormat, Error> {
+        ConfigFormat::try_from(self.config_format.as_str()).map_err(Error::Config)
+    }
+
+    /// Writes the archived state to `store` for `dyn_dnss`, the providers of
+    /// the restored configuration.
+    pub async fn restore_state(
+        &self,
+        dyn_dnss: &[Box<dyn DynDns>],
+        store: &dyn StateStore,
+    ) -> Result<(), Error> {
+        if dyn_dnss.len() != self.ips.len() {
+            return Err(Error::ProviderMismatch {
+                archived: self.ips.len(),
+                configured: dyn_dnss.len(),
+            });
+        }
+        for (dd, ip) in dyn_dnss.iter().zip(&self.ips) {
+            if let Some(ip) = ip {
+                let mut state = store
+                    .load_state(dd.file_name())
+                    .await
+                    .map_err(Error::Persistence)?;
+                state.ip = Some(*ip);
+                store
+                    .save_state(dd.file_name(), &state)
+                    .await
+                    .map_err(Error::Persistence)?;
+            }
+        }
+        store
+            .replace_history(&self.history)
+            .await
+            .map_err(Error::Persistence)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use chrono::Utc;
+
+    use super::Archive;
+    use crate::{
+        config::ConfigFormat,
+        history::HistoryEntry,
+        persistence::{FileStore, StateStore},
+    };
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_archive_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = FileStore::new(&dir);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        store
+            .append_history(&HistoryEntry {
+                timestamp: Utc::now(),
+                provider: "DuckDNS".to_string(),
+                hostname: Some("a".to_string()),
+                old_ip: None,
+                new_ip: ip,
+                error: None,
+                latency_ms: None,
+                networks: None,
+            })
+            .await
+            .unwrap();
+
+        let archive = Archive::capture(
+            "interface = \"eth0\"".to_string(),
+            ConfigFormat::Toml,
+            true,
+            &[],
+            &store,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        let written = serde_json::to_string(&archive).unwrap();
+        let read = Archive::parse(&written).unwrap();
+        assert_eq!(read.config_format().unwrap(), ConfigFormat::Toml);
+        assert!(read.secrets_stripped);
+        read.restore_state(&[], &store).await.unwrap();
+        assert_eq!(store.history().await.unwrap(), archive.history);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mismatch = Archive {
+            ips: vec![Some(ip)],
+            ..read
+        };
+        assert!(mismatch.restore_state(&[], &store).await.is_err());
+        let newer = written.replace("\"version\":1", "\"version\":99");
+        assert!(Archive::parse(&newer).is_err());
+    }
+}
@@ -0,0 +1,450 @@
+use std::{env, fs, path::PathBuf};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::secrets::{Secret, block_on, http_client};
+
+/// Endpoint of the EC2 instance metadata service
+const IMDS: &str = "http://169.254.169.254";
+/// Endpoint of the ECS task metadata service for relative credential URIs
+const ECS_CREDENTIALS: &str = "http://169.254.170.2";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+    pub session_token: Option<Secret>,
+}
+
+impl Credentials {
+    /// Loads credentials like the AWS SDKs do, trying in order the
+    /// `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY` / `AWS_SESSION_TOKEN`
+    /// variables, the shared credentials file, the ECS task role and the EC2
+    /// instance profile.
+    pub async fn load(client: &Client) -> Result<Self, String> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Self {
+                access_key_id,
+                secret_access_key: secret_access_key.into(),
+                session_token: env::var("AWS_SESSION_TOKEN").ok().map(Secret::from),
+            });
+        }
+        if let Some(credentials) = Self::from_shared_file() {
+            return Ok(credentials);
+        }
+        if let Some(credentials) = Self::from_ecs(client).await? {
+            return Ok(credentials);
+        }
+        Self::from_imds(client)
+            .await
+            .map_err(|e| format!("No AWS credentials found: {e}"))
+    }
+
+    fn from_shared_file() -> Option<Self> {
+        let path = env::var_os("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws/credentials"))
+            })?;
+        let profile = ini_section(&fs::read_to_string(path).ok()?, &profile_name());
+        Some(Self {
+            access_key_id: profile.get("aws_access_key_id")?.clone(),
+            secret_access_key: profile.get("aws_secret_access_key")?.as_str().into(),
+            session_token: profile.get("aws_session_token").map(|t| t.as_str().into()),
+        })
+    }
+
+    async fn from_ecs(client: &Client) -> Result<Option<Self>, String> {
+        let url = match (
+            env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+        ) {
+            (Ok(relative), _) => format!("{ECS_CREDENTIALS}{relative}"),
+            (_, Ok(full)) => full,
+            _ => return Ok(None),
+        };
+        let mut request = client.get(url);
+        if let Ok(token) = env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            request = request.header("Authorization", token);
+        }
+        let body = send(request).await?;
+        Self::from_metadata(&body).map(Some)
+    }
+
+    async fn from_imds(client: &Client) -> Result<Self, String> {
+        let token = imds_token(client).await?;
+        let role = send(
+            client
+                .get(format!("{IMDS}/latest/meta-data/iam/security-credentials/"))
+                .header("X-aws-ec2-metadata-token", &token),
+        )
+        .await?;
+        let role = role.lines().next().ok_or("No instance profile")?;
+        let body = send(
+            client
+                .get(format!(
+                    "{IMDS}/latest/meta-data/iam/security-credentials/{role}"
+                ))
+                .header("X-aws-ec2-metadata-token", &token),
+        )
+        .await?;
+        Self::from_metadata(&body)
+    }
+
+    /// Parses the credentials document served by ECS and EC2
+    fn from_metadata(body: &str) -> Result<Self, String> {
+        let document: Value =
+            serde_json::from_str(body).map_err(|e| format!("Invalid AWS credentials: {e}"))?;
+        let field = |name: &str| document[name].as_str().map(str::to_string);
+        Ok(Self {
+            access_key_id: field("AccessKeyId").ok_or("AWS credentials without AccessKeyId")?,
+            secret_access_key: field("SecretAccessKey")
+                .ok_or("AWS credentials without SecretAccessKey")?
+                .into(),
+            session_token: field("Token").map(Secret::from),
+        })
+    }
+}
+
+/// Region of the request: `AWS_REGION`, `AWS_DEFAULT_REGION`, the shared
+/// config file or the region the EC2 instance runs in.
+async fn region(client: &Client) -> Result<String, String> {
+    if let Ok(region) = env::var("AWS_REGION").or(env::var("AWS_DEFAULT_REGION")) {
+        return Ok(region);
+    }
+    let config = env::var_os("AWS_CONFIG_FILE")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws/config")));
+    let profile = match profile_name().as_str() {
+        "default" => "default".to_string(),
+        name => format!("profile {name}"),
+    };
+    if let Some(region) = config
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| ini_section(&content, &profile).remove("region"))
+    {
+        return Ok(region);
+    }
+    let token = imds_token(client)
+        .await
+        .map_err(|e| format!("No AWS region configured, set AWS_REGION: {e}"))?;
+    send(
+        client
+            .get(format!("{IMDS}/latest/meta-data/placement/region"))
+            .header("X-aws-ec2-metadata-token", token),
+    )
+    .await
+}
+
+fn profile_name() -> String {
+    env::var("AWS_PROFILE").unwrap_or("default".to_string())
+}
+
+async fn imds_token(client: &Client) -> Result<String, String> {
+    send(
+        client
+            .put(format!("{IMDS}/latest/api/token"))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", "21600"),
+    )
+    .await
+}
+
+/// Key/values of `[section]` in an INI file such as `~/.aws/credentials`
+fn ini_section(content: &str, section: &str) -> std::collections::HashMap<String, String> {
+    let mut current = None;
+    let mut values = std::collections::HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = Some(name.trim());
+        } else if current == Some(section)
+            && let Some((key, value)) = line.split_once('=')
+        {
+            values.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    values
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<String, String> {
+    let response = request.send().await.map_err(|e| format!("AWS: {e}"))?;
+    let status = response.status();
+    let body = response.text().await.map_err(|e| format!("AWS: {e}"))?;
+    match status.is_success() {
+        true => Ok(body),
+        false => Err(format!("AWS returned {status}: {body}")),
+    }
+}
+
+/// Computes the Signature Version 4 `Authorization` header of a request
+/// without query string. `headers` must include `host` and `x-amz-date`.
+pub fn authorization(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> String {
+    let mut headers: Vec<_> = headers
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value.trim()))
+        .collect();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{}",
+        hex::encode(Sha256::digest(body))
+    );
+
+    let amz_date = headers
+        .iter()
+        .find(|(name, _)| name == "x-amz-date")
+        .map(|(_, value)| *value)
+        .unwrap_or_default();
+    let date = amz_date.get(..8).unwrap_or_default();
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex::encode(Sha256::digest(canonical_request))
+    );
+
+    let key = [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_access_key.expose()).into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={}",
+        credentials.access_key_id,
+        hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Calls a JSON 1.1 protocol action, such as `secretsmanager.GetSecretValue`
+async fn call(
+    client: &Client,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    target: &str,
+    payload: Value,
+) -> Result<Value, String> {
+    let host = format!("{service}.{region}.amazonaws.com");
+    let body = payload.to_string();
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1"),
+        ("host", host.as_str()),
+        ("x-amz-date", amz_date.as_str()),
+        ("x-amz-target", target),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.expose()));
+    }
+    let authorization = authorization(
+        credentials,
+        region,
+        service,
+        "POST",
+        "/",
+        &headers,
+        body.as_bytes(),
+    );
+
+    let mut request = client
+        .post(format!("https://{host}/"))
+        .header("Authorization", authorization)
+        .body(body);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.header(*name, *value);
+    }
+    let response = send(request).await?;
+    serde_json::from_str(&response).map_err(|e| format!("Invalid AWS response: {e}"))
+}
+
+/// A secret store reference: `aws-sm://SECRET[#KEY]` or `ssm://PARAMETER`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reference<'a> {
+    /// Secrets Manager secret by name or ARN, optionally picking a key of a
+    /// JSON secret
+    SecretsManager { id: &'a str, key: Option<&'a str> },
+    /// SSM Parameter Store parameter, decrypted if it's a `SecureString`
+    Parameter(&'a str),
+}
+
+impl<'a> Reference<'a> {
+    pub fn parse(reference: &'a str) -> Option<Self> {
+        if let Some(secret) = reference.strip_prefix("aws-sm://") {
+            let (id, key) = match secret.rsplit_once('#') {
+                Some((id, key)) => (id, Some(key)),
+                None => (secret, None),
+            };
+            return Some(Reference::SecretsManager { id, key });
+        }
+        reference.strip_prefix("ssm://").map(Reference::Parameter)
+    }
+
+    /// Region in the ARN, when referenced by ARN
+    fn region(&self) -> Option<&'a str> {
+        let id = match self {
+            Reference::SecretsManager { id, .. } => id,
+            Reference::Parameter(name) => name,
+        };
+        id.strip_prefix("arn:")
+            .and_then(|arn| arn.split(':').nth(2))
+            .filter(|region| !region.is_empty())
+    }
+
+    async fn fetch(&self) -> Result<String, String> {
+        let client = http_client().map_err(|e| format!("AWS: {e}"))?;
+        let credentials = Credentials::load(&client).await?;
+        let region = match self.region() {
+            Some(region) => region.to_string(),
+            None => region(&client).await?,
+        };
+
+        match self {
+            Reference::SecretsManager { id, key } => {
+                let response = call(
+                    &client,
+                    &credentials,
+                    &region,
+                    "secretsmanager",
+                    "secretsmanager.GetSecretValue",
+                    json!({ "SecretId": id }),
+                )
+                .await?;
+                let secret = response["SecretString"]
+                    .as_str()
+                    .ok_or(format!("Secret {id} has no string value"))?;
+                let Some(key) = key else {
+                    return Ok(secret.to_string());
+                };
+                let fields: Value = serde_json::from_str(secret)
+                    .map_err(|e| format!("Secret {id} is not JSON, can't read {key}: {e}"))?;
+                match &fields[key] {
+                    Value::String(value) => Ok(value.clone()),
+                    Value::Null => Err(format!("Secret {id} has no key {key}")),
+                    other => Ok(other.to_string()),
+                }
+            }
+            Reference::Parameter(name) => {
+                let response = call(
+                    &client,
+                    &credentials,
+                    &region,
+                    "ssm",
+                    "AmazonSSM.GetParameter",
+                    json!({ "Name": name, "WithDecryption": true }),
+                )
+                .await?;
+                response["Parameter"]["Value"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or(format!("Parameter {name} has no value"))
+            }
+        }
+    }
+}
+
+/// Resolves an `aws-sm://` or `ssm://` reference, `None` for anything else.
+pub fn resolve(reference: &str) -> Option<Result<String, String>> {
+    let reference = Reference::parse(reference)?;
+    Some(block_on(reference.fetch()).and_then(|value| value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Credentials, Reference, authorization, ini_section};
+
+    #[test]
+    fn test_signature() {
+        // "get-vanilla" from the AWS Signature Version 4 test suite
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+        };
+        assert_eq!(
+            authorization(
+                &credentials,
+                "us-east-1",
+                "service",
+                "GET",
+                "/",
+                &[
+                    ("Host", "example.amazonaws.com"),
+                    ("X-Amz-Date", "20150830T123600Z")
+                ],
+                b"",
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        assert!(!format!("{credentials:?}").contains("EXAMPLEKEY"));
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(
+            Reference::parse("aws-sm://dns-updater/duckdns"),
+            Some(Reference::SecretsManager {
+                id: "dns-updater/duckdns",
+                key: None
+            })
+        );
+        let arn = Reference::parse(
+            "aws-sm://arn:aws:secretsmanager:eu-west-1:123456789012:secret:ddns-AbCdEf#token",
+        )
+        .unwrap();
+        assert_eq!(arn.region(), Some("eu-west-1"));
+        assert!(matches!(
+            arn,
+            Reference::SecretsManager {
+                key: Some("token"),
+                ..
+            }
+        ));
+        assert_eq!(
+            Reference::parse("ssm:///dns-updater/token"),
+            Some(Reference::Parameter("/dns-updater/token"))
+        );
+        assert_eq!(
+            Reference::parse("ssm:///dns-updater/token")
+                .unwrap()
+                .region(),
+            None
+        );
+        assert_eq!(Reference::parse("TOKEN"), None);
+    }
+
+    #[test]
+    fn test_ini_section() {
+        let content = "[default]\naws_access_key_id = A\n\n[profile ci]\nregion=eu-west-1\n";
+        assert_eq!(ini_section(content, "default")["aws_access_key_id"], "A");
+        assert_eq!(ini_section(content, "profile ci")["region"], "eu-west-1");
+        assert!(ini_section(content, "other").is_empty());
+    }
+}
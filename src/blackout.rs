@@ -0,0 +1,123 @@
+//! Maintenance windows during which a provider publishes nothing, e.g. while
+//! an ISP forcibly reconnects at a known hour: the addresses detected then
+//! are often transient, so only the latest one is published once the window
+//! ends.
+
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use chrono::{NaiveTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+/// A daily window in local time, written `HH:MM-HH:MM`. It wraps around
+/// midnight when it ends before it starts, e.g. `23:30-00:30`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    /// How long until the window ends, `None` when `now` is outside of it
+    fn left(&self, now: NaiveTime) -> Option<Duration> {
+        let inside = if self.start < self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        };
+        if !inside {
+            return None;
+        }
+        let mut left = self.end.signed_duration_since(now);
+        if left <= TimeDelta::zero() {
+            left += TimeDelta::days(1);
+        }
+        left.to_std().ok()
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: &dyn Display| format!("Invalid window '{s}', expected HH:MM-HH:MM: {e}");
+        let (start, end) = s.trim().split_once('-').ok_or_else(|| invalid(&"no '-'"))?;
+        let time = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|e| invalid(&e));
+        let window = Window {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if window.start == window.end {
+            Err(invalid(&"it starts when it ends"))?
+        }
+        Ok(window)
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> Self {
+        format!(
+            "{}-{}",
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M")
+        )
+    }
+}
+
+/// How long until `now` is out of every window, `None` when it already is.
+/// Windows overlapping or following each other make up a single one.
+pub fn remaining(windows: &[Window], now: NaiveTime) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut at = now;
+    // Windows covering the whole day never end, waiting a day is enough
+    while total < Duration::from_secs(24 * 3600)
+        && let Some(left) = windows.iter().filter_map(|w| w.left(at)).max()
+    {
+        total += left;
+        at += TimeDelta::from_std(left).unwrap_or_default();
+    }
+    (!total.is_zero()).then_some(total)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::{Window, remaining};
+
+    #[test]
+    fn test_windows() {
+        let at = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+        let mins = |m: u64| Some(Duration::from_secs(m * 60));
+        let windows: Vec<Window> = ["04:00-04:30", "04:30-05:00", "23:50-00:10"]
+            .iter()
+            .map(|w| w.parse().unwrap())
+            .collect();
+
+        assert_eq!(remaining(&windows, at("03:59")), None);
+        // Both morning windows make up one
+        assert_eq!(remaining(&windows, at("04:15")), mins(45));
+        assert_eq!(remaining(&windows, at("05:00")), None);
+        assert_eq!(remaining(&windows, at("23:55")), mins(15));
+        assert_eq!(remaining(&windows, at("00:05")), mins(5));
+        assert_eq!(remaining(&windows, at("00:10")), None);
+        let all_day: Window = "00:00-23:59".parse().unwrap();
+        let all_day = [all_day, "23:59-00:00".parse().unwrap()];
+        assert!(remaining(&all_day, at("12:00")) >= mins(24 * 60));
+
+        assert_eq!(String::from(windows[2]), "23:50-00:10");
+        assert!("04:00".parse::<Window>().is_err());
+        assert!("04:00-25:00".parse::<Window>().is_err());
+        assert!("04:00-04:00".parse::<Window>().is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    num::NonZeroU32,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Caps how many updates a provider may send per 24 hour window.
+///
+/// The window starts with the first update attempt and every attempt counts,
+/// successful or not, since failed calls still hit the provider's API.
+#[derive(Debug, Clone)]
+pub struct UpdateBudget {
+    max_per_day: NonZeroU32,
+    window_start: Option<Instant>,
+    used: u32,
+}
+
+impl UpdateBudget {
+    pub fn new(max_per_day: NonZeroU32) -> Self {
+        Self {
+            max_per_day,
+            window_start: None,
+            used: 0,
+        }
+    }
+
+    /// Takes one update from the budget, or returns how long to wait until
+    /// the window resets.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let start = match self.window_start {
+            Some(start) if now.duration_since(start) < WINDOW => start,
+            _ => {
+                self.window_start = Some(now);
+                self.used = 0;
+                now
+            }
+        };
+
+        if self.used < self.max_per_day.get() {
+            self.used += 1;
+            Ok(())
+        } else {
+            Err(WINDOW - now.duration_since(start))
+        }
+    }
+
+    /// Waits until an update is allowed, warning when it has to defer.
+    pub async fn acquire(&mut self, provider: &str) {
+        while let Err(wait) = self.try_acquire(Instant::now()) {
+            tracing::warn!(
+                provider,
+                max_per_day = self.max_per_day,
+                defer_secs = wait.as_secs(),
+                "Reached the limit of updates per day, deferring the update"
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        num::NonZeroU32,
+        time::{Duration, Instant},
+    };
+
+    use super::UpdateBudget;
+
+    #[test]
+    fn test_budget_window() {
+        let start = Instant::now();
+        let mut budget = UpdateBudget::new(NonZeroU32::new(2).unwrap());
+
+        assert!(budget.try_acquire(start).is_ok());
+        assert!(budget.try_acquire(start + Duration::from_secs(10)).is_ok());
+        let wait = budget
+            .try_acquire(start + Duration::from_secs(60 * 60))
+            .unwrap_err();
+        assert_eq!(wait, Duration::from_secs(23 * 60 * 60));
+
+        // The window resets a day after its first update
+        assert!(
+            budget
+                .try_acquire(start + Duration::from_secs(24 * 60 * 60))
+                .is_ok()
+        );
+    }
+}
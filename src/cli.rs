@@ -0,0 +1,622 @@
+use std::{
+    env, fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use dns_updater::{
+    config::{self, Config, ConfigFormat},
+    history, kubernetes,
+    persistence::StateBackend,
+    secrets,
+};
+
+use crate::{
+    logfile::{self, Rotation},
+    logging::LogFormat,
+};
+
+/// Keeps dynamic DNS records pointing at this machine's current IP.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options shared by every subcommand, each flag overrides its environment variable.
+#[derive(Debug, Clone, Args)]
+pub struct GlobalArgs {
+    /// Configuration file (TOML, YAML or JSON)
+    #[arg(long, short, global = true, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Format of the configuration file, guessed from its extension by default
+    #[arg(long, global = true, env = "CONFIG_FORMAT", value_parser = parse_format)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Network interface to monitor, overrides the configuration file
+    #[arg(long, short, global = true, env = "INTERFACE")]
+    pub interface: Option<String>,
+
+    /// Providers in the tuple syntax, used when no configuration file is given
+    #[arg(long, global = true, env = "DNS_TUPLES", hide_env_values = true)]
+    pub dns_tuples: Option<String>,
+
+    /// Directories whose files list more providers in the tuple syntax, such
+    /// as mounted Kubernetes ConfigMaps and Secrets, reloaded when they change
+    #[arg(
+        long = "providers-dir",
+        global = true,
+        env = "PROVIDERS_DIRS",
+        value_delimiter = ','
+    )]
+    pub providers_dirs: Vec<PathBuf>,
+
+    /// Label selector of the ConfigMaps and Secrets listing more providers,
+    /// e.g. app=dns-updater, read through the Kubernetes API from inside the
+    /// cluster and watched for changes
+    #[arg(long, global = true, env = "KUBE_SELECTOR")]
+    pub kube_selector: Option<String>,
+
+    /// Namespace of the --kube-selector ConfigMaps and Secrets, the pod's own
+    /// by default
+    #[arg(
+        long,
+        global = true,
+        env = "KUBE_NAMESPACE",
+        requires = "kube_selector"
+    )]
+    pub kube_namespace: Option<String>,
+
+    /// Profile of the configuration file to use, keeping its state in a
+    /// subdirectory named after it
+    #[arg(long, global = true, env = "PROFILE")]
+    pub profile: Option<String>,
+
+    /// Directory of the state files and history. Defaults to $STATE_DIRECTORY
+    /// under systemd, /var/lib/dns-updater for root and
+    /// $XDG_STATE_HOME/dns-updater otherwise
+    #[arg(long, global = true, env = "STATE_DIR")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Before run and once, wait this long at most for a route to the
+    /// internet, e.g. 30s, or 0 to start right away. Stays below the 90s
+    /// systemd gives a service to start by default
+    #[arg(
+        long,
+        global = true,
+        env = "WAIT_ONLINE",
+        default_value = "60s",
+        value_parser = parse_wait_online
+    )]
+    pub wait_online: Duration,
+
+    /// OTLP/HTTP collector receiving a trace of every update, e.g. http://localhost:4318
+    #[cfg(feature = "otel")]
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Format of the logs: text, json for one object per line or journald
+    /// (Linux only). Journald when started by systemd, text otherwise
+    #[arg(long, global = true, env = "LOG_FORMAT", value_parser = parse_log_format)]
+    pub log_format: Option<LogFormat>,
+
+    /// Append the logs to this file instead of stderr
+    #[arg(long, global = true, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Write the HTTP requests of providers and IP sources and their answers
+    /// to this JSON file, with the credentials redacted, to report the quirks
+    /// of a provider
+    #[arg(long, global = true, env = "RECORD_HTTP")]
+    pub record_http: Option<PathBuf>,
+
+    /// Start a new log file once it reaches this size, e.g. 10M
+    #[arg(long, global = true, env = "LOG_MAX_SIZE", value_parser = logfile::parse_size)]
+    pub log_max_size: Option<u64>,
+
+    /// Start a new log file once it's this old: 12h, 1d, 1w, ...
+    #[arg(long, global = true, env = "LOG_MAX_AGE", value_parser = parse_log_age)]
+    pub log_max_age: Option<Duration>,
+
+    /// Number of rotated log files to keep
+    #[arg(long, global = true, env = "LOG_KEEP", default_value_t = 5)]
+    pub log_keep: usize,
+
+    /// Syslog server of --log-format syslog: udp://HOST[:PORT],
+    /// tcp://HOST[:PORT] or a socket path, the local daemon by default
+    #[cfg(unix)]
+    #[arg(long, global = true, env = "SYSLOG_SERVER", value_parser = parse_syslog_server)]
+    pub syslog_server: Option<crate::syslog::Server>,
+
+    /// Syslog facility of --log-format syslog, e.g. daemon, user or local0
+    #[cfg(unix)]
+    #[arg(long, global = true, env = "SYSLOG_FACILITY", default_value = "daemon", value_parser = crate::syslog::parse_facility)]
+    pub syslog_facility: syslog::Facility,
+
+    /// Working directory before [`Self::enter_state_dir`], where older
+    /// versions kept their state files
+    #[arg(skip)]
+    pub launch_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Keep every record up to date (the default)
+    Run(Box<RunArgs>),
+    /// Detect and publish the IP of every provider a single time, then exit
+    Once,
+    /// Run every detection source once and show what would be published, without updating
+    Check,
+    /// Push an IP to one or all providers right away
+    Update {
+        /// Provider type (e.g. "DuckDNS") or hostname to update, all providers when omitted
+        #[arg(long, short)]
+        provider: Option<String>,
+        /// IP to publish instead of the detected one
+        #[arg(long)]
+        ip: Option<IpAddr>,
+    },
+    /// Show past IP changes
+    History {
+        /// Only changes of this provider type or hostname
+        #[arg(long, short)]
+        provider: Option<String>,
+        /// Only changes after this time: 2024-05-01, an RFC 3339 timestamp or 12h, 7d, ...
+        #[arg(long)]
+        since: Option<String>,
+        /// Print one JSON object per line
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check provider credentials and record names without changing any record
+    Test {
+        /// Provider type (e.g. "DuckDNS") or hostname to test, all providers when omitted
+        provider: Option<String>,
+    },
+    /// Write the current (environment based) configuration to a configuration file
+    MigrateConfig {
+        /// File to write, printed to stdout when omitted
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Format to write, guessed from the output extension and TOML by default
+        #[arg(long, value_parser = parse_format)]
+        format: Option<ConfigFormat>,
+        /// Overwrite the output file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write the configuration file and the state to a single archive
+    Export {
+        /// Archive to write, printed to stdout when omitted
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Replace credentials with ${VAR} references to set on the new machine
+        #[arg(long)]
+        strip_secrets: bool,
+        /// Overwrite the archive if it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Restore an archive written by export to the --config file and the state directory
+    Import {
+        /// Archive to read
+        archive: PathBuf,
+        /// Overwrite the configuration file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write a hardened systemd unit running the current configuration file
+    InstallService {
+        /// Name of the service, also used for its state directory
+        #[arg(long, default_value = "dns-updater")]
+        name: String,
+        /// Unit file to write, /etc/systemd/system/<NAME>.service by default
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Print the unit instead of writing it
+        #[arg(long, conflicts_with_all = ["output", "enable"])]
+        dry_run: bool,
+        /// Overwrite the unit file if it exists
+        #[arg(long)]
+        force: bool,
+        /// Enable and start the service right away
+        #[arg(long)]
+        enable: bool,
+    },
+    /// Write a macOS LaunchDaemon plist running the current configuration file
+    Launchd {
+        /// Label of the daemon, also used for its state directory and log
+        #[arg(long, default_value = "dns-updater")]
+        label: String,
+        /// Plist to write, /Library/LaunchDaemons/<LABEL>.plist by default
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Run `once` whenever the network changes and every SECS seconds,
+        /// instead of keeping `run` alive
+        #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "900")]
+        on_demand: Option<u64>,
+        /// Print the plist instead of writing it
+        #[arg(long, conflicts_with_all = ["output", "load"])]
+        dry_run: bool,
+        /// Overwrite the plist if it exists
+        #[arg(long)]
+        force: bool,
+        /// Load the daemon right away with launchctl
+        #[arg(long)]
+        load: bool,
+    },
+    /// Query or control the updater running with the same state directory
+    #[cfg(unix)]
+    Ctl {
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+    /// Install or remove the Windows service
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// List the provider types and the parts of their batches
+    ListProviders {
+        /// Print a JSON array, for tooling
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the configuration without contacting any provider
+    Validate,
+    /// Show the last IP published for every provider
+    Status,
+    /// Show the error rate and latencies of every provider, flakiest first
+    Stats {
+        /// Print a JSON array, for tooling
+        #[arg(long)]
+        json: bool,
+    },
+    /// Store a secret read from stdin in the OS keyring, for use as ${keyring:NAME}
+    #[cfg(feature = "keyring")]
+    StoreSecret {
+        /// Name of the keyring entry
+        name: String,
+    },
+}
+
+impl Command {
+    /// Whether the command reads or writes the state files, kept in the
+    /// directory of the profile
+    pub fn uses_state(&self) -> bool {
+        match self {
+            Command::Run(_)
+            | Command::Once
+            | Command::Update { .. }
+            | Command::Check
+            | Command::Status
+            | Command::Stats { .. }
+            | Command::History { .. }
+            | Command::Export { .. }
+            | Command::Import { .. } => true,
+            // Where the control socket is
+            #[cfg(unix)]
+            Command::Ctl { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the command writes the state files, which only one instance
+    /// may do at a time
+    pub fn writes_state(&self) -> bool {
+        matches!(
+            self,
+            Command::Run(_) | Command::Once | Command::Update { .. } | Command::Import { .. }
+        )
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    /// Detach from the terminal, logging to dns-updater.log in the state directory
+    #[cfg(unix)]
+    #[arg(long)]
+    pub daemon: bool,
+    /// Write the PID to this file, removed on shutdown
+    #[arg(long)]
+    pub pid_file: Option<PathBuf>,
+    /// Serve /healthz and /readyz on this address, e.g. 127.0.0.1:8080
+    #[arg(long, env = "HEALTH_LISTEN")]
+    pub health_listen: Option<SocketAddr>,
+    /// Token the health and metrics endpoints ask for as `Authorization:
+    /// Bearer TOKEN`, none by default
+    #[arg(long, env = "HEALTH_TOKEN", hide_env_values = true)]
+    pub health_token: Option<String>,
+    /// Serve a web dashboard of the IPs, providers and history on this
+    /// address, e.g. 127.0.0.1:8081
+    #[cfg(feature = "dashboard")]
+    #[arg(long, env = "DASHBOARD_LISTEN", requires = "dashboard_token")]
+    pub dashboard_listen: Option<SocketAddr>,
+    /// Token the dashboard asks for
+    #[cfg(feature = "dashboard")]
+    #[arg(long, env = "DASHBOARD_TOKEN", hide_env_values = true)]
+    pub dashboard_token: Option<String>,
+    /// Let the dashboard edit the --config file, reloaded once saved
+    #[cfg(feature = "dashboard")]
+    #[arg(long, env = "DASHBOARD_EDIT_CONFIG", requires = "dashboard_listen")]
+    pub dashboard_edit_config: bool,
+    /// Accept DynDNS2 updates from a router on this address, e.g.
+    /// 0.0.0.0:8245, publishing the IP it pushes instead of detecting it
+    #[arg(long, env = "DYNDNS_LISTEN", requires = "dyndns_password")]
+    pub dyndns_listen: Option<SocketAddr>,
+    /// User name the router updates with
+    #[arg(long, env = "DYNDNS_USERNAME", default_value = "dns-updater")]
+    pub dyndns_username: String,
+    /// Password the router updates with
+    #[arg(long, env = "DYNDNS_PASSWORD", hide_env_values = true)]
+    pub dyndns_password: Option<String>,
+    /// Serve the health, metrics, dashboard and DynDNS2 endpoints over HTTPS
+    /// with this PEM certificate chain
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Serve them over HTTPS with a self-signed certificate, generated in the
+    /// state directory on first use
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "TLS_SELF_SIGNED", conflicts_with = "tls_cert")]
+    pub tls_self_signed: bool,
+    /// Serve the gRPC control plane of proto/dns_updater.proto on this
+    /// address, e.g. 0.0.0.0:50051
+    #[cfg(feature = "grpc")]
+    #[arg(long, env = "GRPC_LISTEN", requires = "grpc_token")]
+    pub grpc_listen: Option<SocketAddr>,
+    /// Token the gRPC calls must carry as `authorization: Bearer TOKEN`
+    #[cfg(feature = "grpc")]
+    #[arg(long, env = "GRPC_TOKEN", hide_env_values = true)]
+    pub grpc_token: Option<String>,
+    /// Answer DNS queries for --dns-zone on this address, over UDP and TCP,
+    /// e.g. 0.0.0.0:53
+    #[cfg(feature = "dns-server")]
+    #[arg(long, env = "DNS_LISTEN", requires = "dns_zone")]
+    pub dns_listen: Option<SocketAddr>,
+    /// Zone whose apex resolves to the detected IPs, e.g. home.lan
+    #[cfg(feature = "dns-server")]
+    #[arg(long, env = "DNS_ZONE", requires = "dns_listen")]
+    pub dns_zone: Option<String>,
+    /// Ping this Healthchecks.io (or compatible) URL while every provider is
+    /// up, and its /fail endpoint once one keeps failing
+    #[arg(long, env = "HEALTHCHECKS_URL", hide_env_values = true, value_parser = parse_url)]
+    pub healthchecks_url: Option<String>,
+    /// Push the status to this Uptime Kuma push monitor URL, up while every
+    /// provider is, down with the errors once one keeps failing
+    #[arg(long, env = "UPTIME_KUMA_URL", hide_env_values = true, value_parser = parse_url)]
+    pub uptime_kuma_url: Option<String>,
+    /// Publish the IPs and health to this MQTT broker,
+    /// mqtt://[USER:PASSWORD@]HOST[:PORT] or mqtts:// over TLS
+    #[cfg(feature = "mqtt")]
+    #[arg(long, env = "MQTT_URL", hide_env_values = true, value_parser = parse_url)]
+    pub mqtt_url: Option<String>,
+    /// Prefix of the MQTT topics, TOPIC/state and TOPIC/availability
+    #[cfg(feature = "mqtt")]
+    #[arg(long, env = "MQTT_TOPIC", default_value = "dns-updater")]
+    pub mqtt_topic: String,
+    /// Prefix of the Home Assistant discovery topics
+    #[cfg(feature = "mqtt")]
+    #[arg(long, env = "MQTT_DISCOVERY_PREFIX", default_value = "homeassistant")]
+    pub mqtt_discovery_prefix: String,
+    /// Don't announce the sensors to Home Assistant
+    #[cfg(feature = "mqtt")]
+    #[arg(long, env = "MQTT_NO_DISCOVERY")]
+    pub mqtt_no_discovery: bool,
+    /// Export the state on this D-Bus bus, system or session, and signal
+    /// every published IP
+    #[cfg(all(feature = "dbus", target_os = "linux"))]
+    #[arg(long, env = "DBUS", value_parser = parse_bus)]
+    pub dbus: Option<dns_updater::dbus::Bus>,
+    /// Run under the Windows service control manager, as set up by `service install`
+    #[cfg(windows)]
+    #[arg(long)]
+    pub service: bool,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Subcommand)]
+pub enum CtlAction {
+    /// Show the detected and published IPs and the errors of every provider
+    Status {
+        /// Print the answer as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Publish the latest detected IPs again, even if they didn't change
+    ForceUpdate {
+        /// Only the provider with this id, as listed by `ctl status`
+        #[arg(long, short)]
+        provider: Option<u64>,
+    },
+    /// Reload the configuration, like SIGHUP
+    Reload,
+}
+
+#[cfg(windows)]
+#[derive(Debug, Subcommand)]
+pub enum ServiceAction {
+    /// Register a service starting at boot with the current configuration file, and start it
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+}
+
+fn parse_format(s: &str) -> Result<ConfigFormat, String> {
+    ConfigFormat::try_from(s).map_err(|e| e.to_string())
+}
+
+fn parse_url(s: &str) -> Result<String, String> {
+    reqwest::Url::parse(s)
+        .map(|_| s.to_string())
+        .map_err(|e| format!("Invalid URL: {e}"))
+}
+
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+fn parse_bus(s: &str) -> Result<dns_updater::dbus::Bus, String> {
+    dns_updater::dbus::Bus::try_from(s)
+}
+
+fn parse_log_format(s: &str) -> Result<LogFormat, String> {
+    LogFormat::try_from(s)
+}
+
+fn parse_log_age(s: &str) -> Result<Duration, String> {
+    history::parse_duration(s)?
+        .to_std()
+        .map_err(|_| format!("Invalid duration '{s}', it must be positive"))
+}
+
+/// Seconds such as 30s, 0, or the units of [`parse_log_age`]
+fn parse_wait_online(s: &str) -> Result<Duration, String> {
+    if s == "0" {
+        return Ok(Duration::ZERO);
+    }
+    match s.strip_suffix('s').map(str::parse) {
+        Some(Ok(seconds)) => Ok(Duration::from_secs(seconds)),
+        _ => parse_log_age(s),
+    }
+}
+
+#[cfg(unix)]
+fn parse_syslog_server(s: &str) -> Result<crate::syslog::Server, String> {
+    crate::syslog::Server::try_from(s)
+}
+
+impl GlobalArgs {
+    pub fn log_rotation(&self) -> Rotation {
+        Rotation {
+            max_size: self.log_max_size,
+            max_age: self.log_max_age,
+            keep: self.log_keep,
+        }
+    }
+
+    /// Loads the configuration file, or builds one from the flags and environment.
+    pub fn load_config(&self) -> Result<Config, config::Error> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path, self.config_format)?,
+            None => {
+                // Also accepts DNS_TUPLES_FILE or a Docker secret, keeping tokens out of the environment
+                let dns_tuples = match &self.dns_tuples {
+                    Some(tuples) => tuples.clone(),
+                    None => secrets::env_or_file("DNS_TUPLES")
+                        .map_err(config::Error::Secret)?
+                        // Every provider may come from the directories
+                        .or_else(|| (!self.providers_dirs.is_empty()).then(String::new))
+                        .ok_or(config::Error::MissingEnv("DNS_TUPLES"))?,
+                };
+                Config::from_tuples(
+                    self.interface
+                        .clone()
+                        .ok_or(config::Error::MissingEnv("INTERFACE"))?,
+                    &dns_tuples,
+                )?
+            }
+        };
+        if let Some(profile) = &self.profile {
+            config = config.profile(profile)?;
+        }
+        for dir in &self.providers_dirs {
+            let tuples = kubernetes::read_providers_dir(dir).map_err(|e| {
+                config::Error::Providers(format!("Couldn't read {}: {e}", dir.display()))
+            })?;
+            config.add_tuples(&tuples);
+        }
+        if let Some(interface) = &self.interface {
+            config.interface = interface.clone();
+        }
+        Ok(config)
+    }
+
+    /// Moves to the state directory, or its subdirectory for the profile if
+    /// any, creating it. The configuration and providers paths are made
+    /// absolute first so they can still be (re)loaded.
+    pub fn enter_state_dir(&mut self) -> io::Result<PathBuf> {
+        let mut dir = match &self.state_dir {
+            Some(dir) => dir.clone(),
+            None => default_state_dir()?,
+        };
+        if let Some(profile) = &self.profile {
+            dir.push(profile);
+        }
+        for path in self.config.iter_mut().chain(&mut self.providers_dirs) {
+            *path = std::path::absolute(&path)?;
+        }
+        create_private_dir(&dir)?;
+        self.launch_dir = env::current_dir().ok();
+        env::set_current_dir(&dir)?;
+        Ok(dir)
+    }
+
+    /// Directories where state files of older versions may be left to
+    /// import into a store kept by `backend`: the launch directory, and the
+    /// state directory itself unless the store is made of its files.
+    pub fn legacy_state_dirs(&self, backend: &StateBackend) -> Vec<PathBuf> {
+        // Importing into memory would only lose the files
+        if *backend == StateBackend::Memory {
+            return Vec::new();
+        }
+        let state_dir = env::current_dir().and_then(fs::canonicalize).ok();
+        let mut dirs = Vec::new();
+        if let Some(launch_dir) = &self.launch_dir
+            && fs::canonicalize(launch_dir).ok() != state_dir
+        {
+            dirs.push(launch_dir.clone());
+        }
+        if !backend.is_default() {
+            dirs.extend(state_dir);
+        }
+        dirs
+    }
+}
+
+/// Where the state is kept when `--state-dir` isn't given
+fn default_state_dir() -> io::Result<PathBuf> {
+    // Set up by systemd for units with StateDirectory=
+    if let Some(dir) = env::var_os("STATE_DIRECTORY") {
+        return Ok(PathBuf::from(dir));
+    }
+    #[cfg(unix)]
+    {
+        // SAFETY: geteuid can't fail and has no side effect
+        if unsafe { libc::geteuid() } == 0 {
+            return Ok(PathBuf::from("/var/lib/dns-updater"));
+        }
+        let state_home = match env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => env::home_dir()
+                .ok_or(io::Error::other("Couldn't find the home directory"))?
+                .join(".local/state"),
+        };
+        Ok(state_home.join("dns-updater"))
+    }
+    #[cfg(not(unix))]
+    {
+        env::var_os("LOCALAPPDATA")
+            .map(|dir| PathBuf::from(dir).join("dns-updater"))
+            .ok_or(io::Error::other("LOCALAPPDATA isn't set"))
+    }
+}
+
+/// Creates `dir` and its missing parents, only accessible to the current
+/// user on Unix
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
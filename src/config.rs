@@ -0,0 +1,730 @@
+use std::{collections::BTreeMap, env, fs, io, path::Path};
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    SimpleName,
+    dyn_dns::{DynDns, KeyedDynDns, ParseError, split_batches},
+    geoip::{GeoIp, GeoIpConfig},
+    history,
+    hooks::Hooks,
+    http::{self, HttpConfig},
+    interpolate::interpolate,
+    notify::{Notifications, Notifiers},
+    persistence::StateBackend,
+    provider::ProviderEntry,
+    records::{Records, RecordsConfig},
+    reverse_dns::{ReverseDns, ReverseDnsConfig},
+    secrets::{self, env_or_file},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("couldn't read the configuration: {0}")]
+    Io(io::Error),
+    #[error("invalid TOML: {0}")]
+    Toml(toml::de::Error),
+    #[error("invalid YAML: {0}")]
+    Yaml(serde_yaml::Error),
+    #[error("invalid JSON: {0}")]
+    Json(serde_json::Error),
+    #[error("environment variable {0} is not set")]
+    MissingEnv(&'static str),
+    #[error("unknown format {0}, expected toml, yaml or json")]
+    InvalidFormat(String),
+    #[error("{0}")]
+    Providers(String),
+    #[error("{0}")]
+    Tuples(ParseError),
+    #[error("couldn't serialize: {0}")]
+    Serialize(String),
+    #[error("{0}")]
+    Interpolation(String),
+    #[error("{0}")]
+    Secret(String),
+    #[error("unknown profile {0}")]
+    UnknownProfile(String),
+    #[error("invalid history retention: {0}")]
+    HistoryRetention(String),
+    #[error("{0}")]
+    StateBackend(String),
+    #[error("invalid notifications: {0}")]
+    Notifications(String),
+    #[error("invalid HTTP settings: {0}")]
+    Http(String),
+    #[error("invalid hooks: {0}")]
+    Hooks(String),
+    #[error("invalid GeoIP settings: {0}")]
+    GeoIp(String),
+    #[error("invalid reverse DNS records: {0}")]
+    ReverseDns(String),
+    #[error("invalid DNS records: {0}")]
+    Records(String),
+}
+
+/// Serialization format of a configuration file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Guesses the format from the file extension, defaulting to TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+impl SimpleName for ConfigFormat {
+    fn simple_name(&self) -> &str {
+        match self {
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Json => "json",
+        }
+    }
+}
+
+impl TryFrom<&str> for ConfigFormat {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(Error::InvalidFormat(value.to_string())),
+        }
+    }
+}
+
+/// Everything needed to start a [`crate::runner::Runner`].
+///
+/// ```toml
+/// interface = "eth0"
+/// providers = [
+///     "DD;TOKEN;ipv4;300;my-domain",
+///     "OVH;USER;PASSWORD;home.example.com;ipv6;600;priority=10",
+///     { type = "freedns", token = "TOKEN", ip_version = "ipv4", poll_secs = 300 },
+/// ]
+///
+/// [hooks]
+/// on_change = "wg set wg0 peer ... endpoint $DNS_UPDATER_NEW_IP:51820"
+///
+/// [[notifications.webhooks]]
+/// url = "https://n8n.example.com/webhook/dns"
+/// secret = "${WEBHOOK_SECRET}"
+///
+/// [profiles.vpn]
+/// interface = "wg0"
+/// providers = ["DD;TOKEN;ipv4;60;my-vpn"]
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Network interface to monitor
+    pub interface: String,
+    /// Providers in the `DNS_TUPLES` syntax, one or more records per entry,
+    /// or tables, see [`crate::provider::ProviderConfig`]
+    #[serde(default)]
+    pub providers: Vec<ProviderEntry>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Where notifications are sent, shared by every profile
+    #[serde(default, skip_serializing_if = "Notifications::is_empty")]
+    pub notifications: Notifications,
+    /// Where the state is kept, relative paths being in the directory of the profile
+    #[serde(default, skip_serializing_if = "StateBackend::is_default")]
+    pub state: StateBackend,
+    /// How long IP changes are kept in the history, e.g. `90d`, forever when
+    /// omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_retention: Option<String>,
+    /// Timeouts of the HTTP requests, shared by every profile
+    #[serde(default, skip_serializing_if = "HttpConfig::is_default")]
+    pub http: HttpConfig,
+    /// Where the ASN, ISP and country of changed IPs are looked up, shared
+    /// by every profile. IP changes aren't annotated when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip: Option<GeoIpConfig>,
+    /// PTR records pointing the IPs of providers back at their hostname,
+    /// shared by every profile
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reverse_dns: Vec<ReverseDnsConfig>,
+    /// MX, SRV and TXT records following the hostnames or IPs of providers,
+    /// shared by every profile
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_records: Vec<RecordsConfig>,
+    /// Separate sets of providers, see [`Config::profile`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named set of providers run instead of the top level ones.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Network interface to monitor, the top level one when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    pub providers: Vec<ProviderEntry>,
+    /// Hooks of this profile, the top level ones when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<Hooks>,
+}
+
+impl Config {
+    /// Builds the configuration from the `INTERFACE`, `DNS_TUPLES`, `HOOK_*`,
+    /// `WEBHOOK_*`, `HISTORY_RETENTION`, `HTTP_*` and `STATE_BACKEND`
+    /// environment variables.
+    /// `DNS_TUPLES` may also come from `DNS_TUPLES_FILE` or a Docker secret.
+    pub fn from_env() -> Result<Self, Error> {
+        let dns_tuples = env_or_file("DNS_TUPLES")
+            .map_err(Error::Secret)?
+            .ok_or(Error::MissingEnv("DNS_TUPLES"))?;
+        Self::from_tuples(
+            env::var("INTERFACE").map_err(|_| Error::MissingEnv("INTERFACE"))?,
+            &dns_tuples,
+        )
+    }
+
+    /// Builds the configuration from a `DNS_TUPLES` value, one provider entry
+    /// per record, with hooks, the history retention and the state backend
+    /// from the environment. Records may be separated by newlines too, as in
+    /// credential files.
+    pub fn from_tuples(interface: String, dns_tuples: &str) -> Result<Self, Error> {
+        // Backends needing more settings are only available in a file
+        let state = match env::var("STATE_BACKEND").as_deref() {
+            Err(_) | Ok("files") => StateBackend::Files,
+            Ok("none") => StateBackend::Memory,
+            Ok(other) => Err(Error::StateBackend(format!(
+                "Unknown STATE_BACKEND {other}, expected files or none"
+            )))?,
+        };
+        let http = HttpConfig::from_env().map_err(Error::Http)?;
+        secrets::set_http(&http);
+        let config = Self {
+            interface,
+            providers: parse_tuples(dns_tuples),
+            hooks: Hooks::from_env().map_err(Error::Hooks)?,
+            notifications: Notifications::from_env().map_err(Error::Secret)?,
+            state,
+            history_retention: env::var("HISTORY_RETENTION").ok(),
+            http,
+            geoip: None,
+            reverse_dns: Vec::new(),
+            dns_records: Vec::new(),
+            profiles: BTreeMap::new(),
+        };
+        config.history_retention()?;
+        // Only the settings are checked here, nothing is sent
+        config.notifiers(&Client::new())?;
+        Ok(config)
+    }
+
+    /// Appends the providers of `dns_tuples`, e.g. those kept apart in
+    /// Kubernetes, see [`crate::kubernetes`]
+    pub fn add_tuples(&mut self, dns_tuples: &str) {
+        self.providers.extend(parse_tuples(dns_tuples));
+    }
+
+    /// Serializes the configuration, e.g. to migrate it to a file
+    pub fn to_string(&self, format: ConfigFormat) -> Result<String, Error> {
+        match format {
+            ConfigFormat::Toml => {
+                toml::to_string_pretty(self).map_err(|e| Error::Serialize(e.to_string()))
+            }
+            ConfigFormat::Yaml => {
+                serde_yaml::to_string(self).map_err(|e| Error::Serialize(e.to_string()))
+            }
+            ConfigFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(|e| Error::Serialize(e.to_string()))
+            }
+        }
+    }
+
+    /// Reads a configuration file, guessing its format from the extension
+    /// unless `format` is given.
+    pub fn from_file(path: impl AsRef<Path>, format: Option<ConfigFormat>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+        let content = fs::read_to_string(path).map_err(Error::Io)?;
+        Self::parse(&content, format)
+    }
+
+    /// Parses a configuration and expands `${VAR}` references in the interface,
+    /// providers, notifications, Redis URL, state key, ipinfo.io token and
+    /// PTR record credentials. Hooks are left alone since they are shell commands.
+    pub fn parse(content: &str, format: ConfigFormat) -> Result<Self, Error> {
+        let mut config = Self::parse_raw(content, format)?;
+        secrets::set_http(&config.http);
+        config.interface = interpolate(&config.interface).map_err(Error::Interpolation)?;
+        for provider in config.providers.iter_mut() {
+            provider.interpolate().map_err(Error::Interpolation)?;
+        }
+        match &mut config.state {
+            StateBackend::Redis { url, .. } => {
+                *url = interpolate(url).map_err(Error::Interpolation)?;
+            }
+            StateBackend::Encrypted { key: Some(key), .. } => {
+                *key = interpolate(key).map_err(Error::Interpolation)?;
+            }
+            _ => {}
+        }
+        if let Some(GeoIpConfig::Ipinfo { token: Some(token) }) = &mut config.geoip {
+            *token.expose_mut() = interpolate(token.expose()).map_err(Error::Interpolation)?;
+        }
+        for reverse_dns in config.reverse_dns.iter_mut() {
+            reverse_dns.interpolate().map_err(Error::Interpolation)?;
+        }
+        for records in config.dns_records.iter_mut() {
+            records.interpolate().map_err(Error::Interpolation)?;
+        }
+        config
+            .notifications
+            .interpolate()
+            .map_err(Error::Interpolation)?;
+        config.history_retention()?;
+        // Only the settings are checked here, nothing is sent
+        config.notifiers(&Client::new())?;
+        if config.providers.is_empty() && config.profiles.is_empty() {
+            Err(Error::Providers(
+                "No providers nor profiles configured".to_string(),
+            ))?
+        }
+        for profile in config.profiles.values_mut() {
+            if let Some(interface) = profile.interface.as_mut() {
+                *interface = interpolate(interface).map_err(Error::Interpolation)?;
+            }
+            for provider in profile.providers.iter_mut() {
+                provider.interpolate().map_err(Error::Interpolation)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Parses a configuration as written, leaving `${VAR}` references alone.
+    pub fn parse_raw(content: &str, format: ConfigFormat) -> Result<Self, Error> {
+        match format {
+            ConfigFormat::Toml => toml::from_str(content).map_err(Error::Toml),
+            ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(Error::Yaml),
+            ConfigFormat::Json => serde_json::from_str(content).map_err(Error::Json),
+        }
+    }
+
+    /// Replaces the credentials of every provider, profiles included, with
+    /// references to variables named after them: `${DD_TOKEN}`, then
+    /// `${DD_TOKEN_2}`, ... See [`crate::dyn_dns::strip_secrets`].
+    pub fn strip_secrets(&mut self) -> Result<(), Error> {
+        let mut used: BTreeMap<String, usize> = BTreeMap::new();
+        let mut placeholder = |kind: &str, field: &str| {
+            let name = format!("{kind}_{field}");
+            let count = used.entry(name.clone()).or_default();
+            *count += 1;
+            match count {
+                1 => format!("${{{name}}}"),
+                n => format!("${{{name}_{n}}}"),
+            }
+        };
+        let profiles = self.profiles.values_mut().map(|p| &mut p.providers);
+        for providers in std::iter::once(&mut self.providers).chain(profiles) {
+            for provider in providers.iter_mut() {
+                provider
+                    .strip_secrets(&mut placeholder)
+                    .map_err(Error::Tuples)?;
+            }
+        }
+        self.notifications.strip_secrets(&mut placeholder);
+        if let Some(GeoIpConfig::Ipinfo { token: Some(token) }) = &mut self.geoip {
+            *token = placeholder("IPINFO", "TOKEN").into();
+        }
+        for reverse_dns in self.reverse_dns.iter_mut() {
+            reverse_dns.strip_secrets(&mut placeholder);
+        }
+        for records in self.dns_records.iter_mut() {
+            records.strip_secrets(&mut placeholder);
+        }
+        Ok(())
+    }
+
+    /// The configuration of profile `name`: its providers, with its interface
+    /// and hooks or the top level ones.
+    pub fn profile(mut self, name: &str) -> Result<Self, Error> {
+        let profile = self
+            .profiles
+            .remove(name)
+            .ok_or(Error::UnknownProfile(name.to_string()))?;
+        Ok(Self {
+            interface: profile.interface.unwrap_or(self.interface),
+            providers: profile.providers,
+            hooks: profile.hooks.unwrap_or(self.hooks),
+            notifications: self.notifications,
+            state: self.state,
+            history_retention: self.history_retention,
+            http: self.http,
+            geoip: self.geoip,
+            reverse_dns: self.reverse_dns,
+            dns_records: self.dns_records,
+            profiles: BTreeMap::new(),
+        })
+    }
+
+    /// The parsed [`Config::history_retention`]
+    pub fn history_retention(&self) -> Result<Option<chrono::Duration>, Error> {
+        self.history_retention
+            .as_deref()
+            .map(history::parse_duration)
+            .transpose()
+            .map_err(Error::HistoryRetention)
+    }
+
+    /// The HTTP client of [`Config::http`], shared by the providers, IP
+    /// sources, notifiers and heartbeats
+    pub fn client(&self) -> Result<Client, Error> {
+        http::client(&self.http).map_err(|e| Error::Http(e.to_string()))
+    }
+
+    /// The notifiers of [`Config::notifications`], sending through `client`
+    pub fn notifiers(&self, client: &Client) -> Result<Notifiers, Error> {
+        self.notifications
+            .notifiers(client)
+            .map_err(Error::Notifications)
+    }
+
+    /// The opened [`Config::geoip`] databases
+    pub fn geoip(&self) -> Result<Option<GeoIp>, Error> {
+        self.geoip
+            .as_ref()
+            .map(GeoIpConfig::open)
+            .transpose()
+            .map_err(Error::GeoIp)
+    }
+
+    /// The checked [`Config::reverse_dns`] records
+    pub fn reverse_dns(&self) -> Result<ReverseDns, Error> {
+        ReverseDns::new(self.reverse_dns.clone()).map_err(Error::ReverseDns)
+    }
+
+    /// The checked [`Config::dns_records`]
+    pub fn dns_records(&self) -> Result<Records, Error> {
+        Records::new(self.dns_records.clone()).map_err(Error::Records)
+    }
+
+    /// Checks what [`Config::parse`] leaves for later: the history retention,
+    /// notifiers, GeoIP databases, PTR and other records, HTTP client and providers,
+    /// those of every profile included
+    pub fn validate(&self) -> Result<(), Error> {
+        self.history_retention()?;
+        self.notifiers(&self.client()?)?;
+        self.geoip()?;
+        self.reverse_dns()?;
+        self.dns_records()?;
+        self.dyn_dnss()?;
+        #[cfg(feature = "rhai")]
+        if let Some(script) = &self.hooks.script {
+            crate::script::compile(script).map_err(Error::Hooks)?;
+        }
+        for name in self.profiles.keys() {
+            self.clone()
+                .profile(name)?
+                .dyn_dnss()
+                .map_err(|e| match e {
+                    Error::Providers(e) => Error::Providers(format!("Profile {name}: {e}")),
+                    Error::Tuples(e) => Error::Providers(format!("Profile {name}: {e}")),
+                    e => e,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// The settings `other` changes that are only read at startup, so that
+    /// changing them takes a restart rather than a reload
+    pub fn restart_needed(&self, other: &Config) -> Vec<&'static str> {
+        [
+            ("state", self.state != other.state),
+            ("http", self.http != other.http),
+            ("geoip", self.geoip != other.geoip),
+            ("reverse_dns", self.reverse_dns != other.reverse_dns),
+            ("dns_records", self.dns_records != other.dns_records),
+            (
+                "history_retention",
+                self.history_retention != other.history_retention,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(setting, changed)| changed.then_some(setting))
+        .collect()
+    }
+
+    /// Parses every provider entry.
+    pub fn dyn_dnss(&self) -> Result<Vec<Box<dyn DynDns>>, Error> {
+        Ok(self
+            .keyed_dyn_dnss()?
+            .into_iter()
+            .map(|(_, dd)| dd)
+            .collect())
+    }
+
+    /// Parses every provider entry, keyed by the record it was parsed from so
+    /// a reload can tell which providers changed.
+    pub fn keyed_dyn_dnss(&self) -> Result<Vec<KeyedDynDns>, Error> {
+        let mut dyn_dnss: Vec<KeyedDynDns> = Vec::new();
+        for entry in &self.providers {
+            let entry_key = entry.key();
+            for (i, dd) in entry
+                .dyn_dnss()
+                .map_err(Error::Tuples)?
+                .into_iter()
+                .enumerate()
+            {
+                let mut key = format!("{entry_key}#{i}");
+                // The same entry may be listed twice
+                while dyn_dnss.iter().any(|(k, _)| *k == key) {
+                    key.push('\'');
+                }
+                dyn_dnss.push((key, dd));
+            }
+        }
+        Ok(dyn_dnss)
+    }
+}
+
+/// Entries of the `DNS_TUPLES` syntax: one or more records per line, blank
+/// lines being ignored
+fn parse_tuples(dns_tuples: &str) -> Vec<ProviderEntry> {
+    dns_tuples
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .flat_map(split_batches)
+        .map(|t| t.trim().to_string().into())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{Config, ConfigFormat, Error};
+    use crate::provider::{ProviderConfig, ProviderEntry};
+
+    #[test]
+    fn test_format_detection() {
+        let format = |p| ConfigFormat::from_path(Path::new(p));
+        assert_eq!(format("/etc/dns-updater/config.yml"), ConfigFormat::Yaml);
+        assert_eq!(format("config.yaml"), ConfigFormat::Yaml);
+        assert_eq!(format("config.json"), ConfigFormat::Json);
+        assert_eq!(format("config.toml"), ConfigFormat::Toml);
+        assert_eq!(format("config"), ConfigFormat::Toml);
+        assert!(ConfigFormat::try_from("xml").is_err());
+    }
+
+    #[test]
+    fn test_equivalent_formats() {
+        let toml = r#"
+            interface = "eth0"
+            providers = ["DD;tok;ipv4;300;name", "FD;tok;ipv6;0"]
+
+            [hooks]
+            on_change = "echo changed"
+        "#;
+        let yaml = r#"
+            interface: eth0
+            providers:
+              - DD;tok;ipv4;300;name
+              - FD;tok;ipv6;0
+            hooks:
+              on_change: echo changed
+        "#;
+        let json = r#"{
+            "interface": "eth0",
+            "providers": ["DD;tok;ipv4;300;name", "FD;tok;ipv6;0"],
+            "hooks": { "on_change": "echo changed" }
+        }"#;
+
+        for (content, format) in [
+            (toml, ConfigFormat::Toml),
+            (yaml, ConfigFormat::Yaml),
+            (json, ConfigFormat::Json),
+        ] {
+            let config = Config::parse(content, format).expect("Should parse");
+            assert_eq!(config.interface, "eth0");
+            assert_eq!(config.hooks.on_change.as_deref(), Some("echo changed"));
+            assert_eq!(config.dyn_dnss().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_migrate_tuples() {
+        let config = Config::from_tuples(
+            "eth0".to_string(),
+            "(DD;tok;ipv4;300;name), FD;tok;ipv6;0;priority=3",
+        )
+        .unwrap();
+        assert_eq!(
+            config.providers,
+            vec!["(DD;tok;ipv4;300;name)", "FD;tok;ipv6;0;priority=3"]
+        );
+        let from_file = Config::from_tuples(
+            "eth0".to_string(),
+            "(DD;tok;ipv4;300;name)\nFD;tok;ipv6;0;priority=3\n",
+        )
+        .unwrap();
+        assert_eq!(from_file.providers, config.providers);
+        let escaped = Config::from_tuples(
+            "eth0".to_string(),
+            r#"OVH;u;p\,w;h;ipv4;60,OVH;u;"p,w";h;ipv4;60"#,
+        )
+        .unwrap();
+        assert_eq!(escaped.providers.len(), 2);
+
+        for format in [ConfigFormat::Toml, ConfigFormat::Yaml, ConfigFormat::Json] {
+            let written = config.to_string(format).unwrap();
+            let read = Config::parse(&written, format).unwrap();
+            assert_eq!(read.providers, config.providers);
+            assert_eq!(read.dyn_dnss().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_hooks_not_interpolated() {
+        let toml = r#"
+            interface = "eth0"
+            providers = ["FD;tok;ipv4;0"]
+            hooks.on_change = "echo ${DNS_UPDATER_NEW_IP}"
+        "#;
+        let config = Config::parse(toml, ConfigFormat::Toml).unwrap();
+        assert_eq!(
+            config.hooks.on_change.as_deref(),
+            Some("echo ${DNS_UPDATER_NEW_IP}")
+        );
+
+        let missing = "interface = \"eth0\"\nproviders = [\"FD;${DNS_UPDATER_UNSET_VAR};ipv4;0\"]";
+        assert!(Config::parse(missing, ConfigFormat::Toml).is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let toml = r#"
+            interface = "eth0"
+            hooks.on_change = "echo changed"
+
+            [profiles.home]
+            providers = ["DD;tok;ipv4;300;home"]
+
+            [profiles.vpn]
+            interface = "wg0"
+            providers = ["FD;tok;ipv4;0", "FD;tok2;ipv6;0"]
+            hooks.on_change = "echo vpn"
+        "#;
+        let config = Config::parse(toml, ConfigFormat::Toml).unwrap();
+        assert!(config.providers.is_empty());
+
+        let home = config.clone().profile("home").unwrap();
+        assert_eq!(home.interface, "eth0");
+        assert_eq!(home.hooks.on_change.as_deref(), Some("echo changed"));
+        assert_eq!(home.dyn_dnss().unwrap().len(), 1);
+
+        let vpn = config.clone().profile("vpn").unwrap();
+        assert_eq!(vpn.interface, "wg0");
+        assert_eq!(vpn.hooks.on_change.as_deref(), Some("echo vpn"));
+        assert_eq!(vpn.dyn_dnss().unwrap().len(), 2);
+        assert!(vpn.profiles.is_empty());
+
+        assert!(config.profile("work").is_err());
+    }
+
+    #[test]
+    fn test_restart_needed() {
+        let running = "interface = \"eth0\"\nproviders = [\"FD;tok;ipv4;0\"]";
+        let running = Config::parse(running, ConfigFormat::Toml).unwrap();
+        let reloaded = "interface = \"eth1\"\nproviders = [\"FD;tok;ipv6;0\"]\n\
+                        history_retention = \"30d\"\n[http]\ntimeout_secs = 20";
+        let reloaded = Config::parse(reloaded, ConfigFormat::Toml).unwrap();
+        // The interface and providers are reloaded
+        assert_eq!(
+            running.restart_needed(&reloaded),
+            ["http", "history_retention"]
+        );
+        assert!(running.restart_needed(&running).is_empty());
+    }
+
+    #[test]
+    fn test_strip_secrets() {
+        let toml = r#"
+            interface = "eth0"
+            providers = ["DD;tok;ipv4;300;a,DD;tok2;ipv4;300;b", "FD;${FD_TOKEN};ipv4;0"]
+
+            [profiles.vpn]
+            providers = [
+                "DD;tok3;ipv6;60;c",
+                { type = "ovh", username = "u", password = "pw", hostname = "h.example.com", ip_version = "ipv4", poll_secs = 60 },
+            ]
+
+            [[notifications.webhooks]]
+            url = "https://example.com/hook/abc"
+            secret = "s3cret"
+        "#;
+        let mut config = Config::parse_raw(toml, ConfigFormat::Toml).unwrap();
+        config.strip_secrets().unwrap();
+        let webhook = &config.notifications.webhooks[0];
+        assert_eq!(webhook.url.expose(), "${WEBHOOK_URL}");
+        assert_eq!(
+            webhook.secret.as_ref().map(|s| s.expose().as_str()),
+            Some("${WEBHOOK_SECRET}")
+        );
+        assert_eq!(
+            config.providers,
+            vec![
+                "DD;${DD_TOKEN};ipv4;300;a,DD;${DD_TOKEN_2};ipv4;300;b",
+                "FD;${FD_TOKEN};ipv4;0"
+            ]
+        );
+        let vpn = &config.profiles["vpn"].providers;
+        assert_eq!(vpn[0], "DD;${DD_TOKEN_3};ipv6;60;c");
+        let ProviderEntry::Table(ProviderConfig::Ovh { password, .. }) = &vpn[1] else {
+            panic!("Expected an OVH table, got {:?}", vpn[1]);
+        };
+        assert_eq!(password.expose(), "${OVH_PASSWORD}");
+
+        for format in [ConfigFormat::Toml, ConfigFormat::Yaml, ConfigFormat::Json] {
+            let written = config.to_string(format).unwrap();
+            let read = Config::parse_raw(&written, format).unwrap();
+            assert_eq!(read.profiles["vpn"].providers, *vpn);
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Config::parse("interface = \"eth0\"", ConfigFormat::Toml).is_err());
+        let unknown = "interface = \"eth0\"\nproviders = []\ncolour = \"blue\"";
+        assert!(Config::parse(unknown, ConfigFormat::Toml).is_err());
+        let bad_provider = "interface = \"eth0\"\nproviders = [\"XX;tok\"]";
+        let config = Config::parse(bad_provider, ConfigFormat::Toml).unwrap();
+        assert!(config.dyn_dnss().is_err() && config.validate().is_err());
+        let no_budget =
+            "interface = \"eth0\"\nproviders = [\"FD;tok;ipv4;0;max_updates_per_day=0\"]";
+        let config = Config::parse(no_budget, ConfigFormat::Toml).unwrap();
+        assert!(config.validate().is_err());
+        let bad_profile = "interface = \"eth0\"\n[profiles.vpn]\nproviders = [\"XX;tok\"]";
+        let config = Config::parse(bad_profile, ConfigFormat::Toml).unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(matches!(&error, Error::Providers(e) if e.starts_with("Profile vpn: ")));
+        assert!(error.to_string().starts_with("Profile vpn: "));
+        let http =
+            "interface = \"eth0\"\nproviders = [\"FD;tok;ipv4;0\"]\n[http]\ntimeout_secs = 20";
+        let config = Config::parse(http, ConfigFormat::Toml).unwrap();
+        assert_eq!(
+            (config.http.timeout_secs, config.http.connect_timeout_secs),
+            (20, 10)
+        );
+        let typo = "interface = \"eth0\"\nproviders = [\"FD;tok;ipv4;0\"]\n[http]\ntimeout = 20";
+        assert!(Config::parse(typo, ConfigFormat::Toml).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Control of a running updater over a Unix socket in its state directory,
+//! for `ctl`: each connection sends one request and gets one answer, both
+//! JSON lines. Only the owner of the state directory can connect.
+
+use std::{fs, io, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+use dns_updater::health::Health;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::reload;
+
+/// Relative to the state directory, the working directory by then
+pub const SOCKET: &str = "control.sock";
+
+/// Longest a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: u64 = 8192;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// How every provider is doing
+    Status,
+    /// Publishes the latest detected IP of one or every provider again
+    ForceUpdate { provider: Option<u64> },
+    /// Reloads the configuration, like SIGHUP
+    Reload,
+}
+
+/// The control socket, removed on shutdown
+pub struct Socket(UnixListener);
+
+impl Socket {
+    /// Listens on [`SOCKET`], replacing the one of an instance that didn't
+    /// shut down cleanly: the instance lock is held by then
+    pub fn bind() -> io::Result<Self> {
+        match fs::remove_file(SOCKET) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(SOCKET)?;
+        fs::set_permissions(SOCKET, fs::Permissions::from_mode(0o600))?;
+        Ok(Self(listener))
+    }
+
+    /// Answers the requests until dropped
+    pub async fn serve(&self, health: Health, reload: reload::Trigger) {
+        loop {
+            let stream = match self.0.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Couldn't accept a control connection");
+                    continue;
+                }
+            };
+            let (health, reload) = (health.clone(), reload.clone());
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &health, &reload).await {
+                    tracing::debug!(error = %e, "Couldn't answer a control request");
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(SOCKET) {
+            tracing::warn!(path = SOCKET, error = %e, "Couldn't remove the control socket");
+        }
+    }
+}
+
+async fn respond(stream: UnixStream, health: &Health, reload: &reload::Trigger) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    let mut reader = BufReader::new(read.take(MAX_REQUEST));
+    let read = reader.read_line(&mut line);
+    if tokio::time::timeout(REQUEST_TIMEOUT, read).await.is_err() {
+        return Ok(());
+    }
+
+    let answer = match serde_json::from_str(&line) {
+        Ok(Request::Status) => json!({
+            "healthy": health.is_healthy(),
+            "ready": health.is_ready(),
+            "providers": health.providers(),
+        }),
+        Ok(Request::ForceUpdate { provider }) => match health.force_update(provider) {
+            0 if provider.is_some() => json!({ "error": "No running provider with this id" }),
+            forced => json!({ "forced": forced }),
+        },
+        Ok(Request::Reload) => match reload.send("control socket") {
+            Ok(()) => json!({ "reloading": true }),
+            Err(_) => json!({ "error": "The configuration can't be reloaded" }),
+        },
+        Err(e) => json!({ "error": format!("Invalid request: {e}") }),
+    };
+    write.write_all(format!("{answer}\n").as_bytes()).await?;
+    write.shutdown().await
+}
+
+/// Sends `request` to the updater running in the state directory, returning
+/// its answer or the error it answered with
+pub async fn send(request: &Request) -> Result<Value, String> {
+    let socket = Path::new(SOCKET);
+    let mut stream = UnixStream::connect(socket).await.map_err(|e| {
+        format!(
+            "Couldn't reach a running updater at {}: {e}",
+            std::path::absolute(socket).unwrap_or_default().display()
+        )
+    })?;
+    let request = serde_json::to_string(request).unwrap_or_default() + "\n";
+    let mut answer = String::new();
+    let exchange = async {
+        stream.write_all(request.as_bytes()).await?;
+        stream.read_to_string(&mut answer).await
+    };
+    exchange
+        .await
+        .map_err(|e| format!("Couldn't talk to the running updater: {e}"))?;
+    let answer: Value =
+        serde_json::from_str(&answer).map_err(|e| format!("Invalid answer: {e}"))?;
+    match answer["error"].as_str() {
+        Some(error) => Err(error.to_string()),
+        None => Ok(answer),
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs::{self, File, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::Path,
+};
+
+use dns_updater::persistence::private_file_options;
+
+/// Locked by the instance publishing from the current (state) directory
+pub const LOCK_FILE: &str = "dns-updater.lock";
+/// Where a daemon writes its output, in the state directory
+#[cfg(unix)]
+pub const LOG_FILE: &str = "dns-updater.log";
+
+/// Exclusive lock on the state directory, released when dropped or when the
+/// process dies. Keeps a second instance from publishing the same records and
+/// racing on the state files.
+pub struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire() -> Result<Self, String> {
+        let mut file = private_file_options()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(LOCK_FILE)
+            .map_err(|e| format!("Couldn't open {LOCK_FILE}: {e}"))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { file }),
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                let owner = match pid.trim() {
+                    "" => String::new(),
+                    pid => format!(" (PID {pid})"),
+                };
+                Err(format!(
+                    "Another instance{owner} is already running from this directory"
+                ))
+            }
+            Err(TryLockError::Error(e)) => Err(format!("Couldn't lock {LOCK_FILE}: {e}")),
+        }
+    }
+
+    /// Records the PID of the current process in the lock file, to name it
+    /// in the error of other instances.
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        write!(self.file, "{}", std::process::id())
+    }
+}
+
+/// Written by `run --pid-file`, removed on shutdown
+pub struct PidFile<'a>(&'a Path);
+
+impl<'a> PidFile<'a> {
+    pub fn create(path: &'a Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(self.0) {
+            tracing::warn!(path = %self.0.display(), error = %e, "Couldn't remove the PID file");
+        }
+    }
+}
+
+/// Detaches from the terminal: forks twice around `setsid`, the original
+/// process exiting, and sends the output to [`LOG_FILE`]. Must run before
+/// any thread is started, the runtime included.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let log = private_file_options().append(true).open(LOG_FILE)?;
+    let null = File::open("/dev/null")?;
+    // SAFETY: the process is still single threaded
+    unsafe {
+        for session_leader in [true, false] {
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {}
+                _ => libc::_exit(0),
+            }
+            // The second fork keeps the daemon from getting a terminal back
+            if session_leader && libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (from, to) in [(&null, 0), (&log, 1), (&log, 2)] {
+            if libc::dup2(from.as_raw_fd(), to) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on SIGTERM or Ctrl+C.
+pub async fn terminated() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!(error = %e, "Couldn't listen for Ctrl+C");
+            std::future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let sigterm = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Couldn't listen for SIGTERM");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let sigterm = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = sigterm => {}
+    }
+}
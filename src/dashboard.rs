@@ -0,0 +1,494 @@
+//! A web page showing the detected IPs, how every provider is doing and the
+//! recent history, with the JSON API it's drawn from, which also forces and
+//! pauses updates and, when enabled, edits the configuration file. The API
+//! answers only requests bearing the token, the page itself holds no data.
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+    SimpleName,
+    config::{Config, ConfigFormat},
+    health::Health,
+    persistence::{StateStore, write_private},
+    secrets::Secret,
+};
+
+const PAGE: &str = include_str!("dashboard.html");
+
+/// History entries returned unless the request asks for another number
+const DEFAULT_HISTORY: usize = 50;
+
+/// What the dashboard shows, and the token its API asks for
+#[derive(Clone)]
+pub struct Dashboard {
+    pub health: Health,
+    pub store: Arc<dyn StateStore>,
+    pub token: Secret,
+    /// `None` to keep the configuration out of reach
+    pub editor: Option<ConfigEditor>,
+    /// Serves HTTPS instead of HTTP when set
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsAcceptor>,
+}
+
+/// The configuration file edited through the dashboard
+#[derive(Clone)]
+pub struct ConfigEditor {
+    pub path: PathBuf,
+    pub format: ConfigFormat,
+    /// Asks for a reload with its cause once the file is saved, `None` when
+    /// it can't be reloaded
+    pub reload: Option<mpsc::UnboundedSender<&'static str>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigBody {
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateQuery {
+    /// [`crate::health::ProviderHealth::id`], every provider by default
+    provider: Option<u64>,
+}
+
+/// Serves the dashboard on `listener` until it fails
+pub async fn serve(listener: TcpListener, dashboard: Dashboard) -> io::Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = dashboard.tls.clone() {
+        let listener = crate::tls::TlsListener::new(listener, acceptor)?;
+        return axum::serve(listener, router(dashboard)).await;
+    }
+    axum::serve(listener, router(dashboard)).await
+}
+
+fn router(dashboard: Dashboard) -> Router {
+    Router::new()
+        .route("/", get(Html(PAGE)))
+        .route("/api/status", get(status))
+        .route("/api/history", get(history))
+        .route("/api/stats", get(stats))
+        .route("/api/update", post(update))
+        .route("/api/providers/{id}/pause", post(pause))
+        .route("/api/providers/{id}/resume", post(resume))
+        .route("/api/config", get(config).put(save_config))
+        .route("/api/config/validate", post(validate_config))
+        .with_state(Arc::new(dashboard))
+}
+
+/// Whether `headers` carry the token as `Authorization: Bearer TOKEN`
+fn authorized(dashboard: &Dashboard, headers: &HeaderMap) -> bool {
+    let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    dashboard.token.matches(token.trim())
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "Missing or wrong token",
+    )
+        .into_response()
+}
+
+/// Every provider with its detected and published IPs and latest errors
+async fn status(State(dashboard): State<Arc<Dashboard>>, headers: HeaderMap) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    Json(serde_json::json!({
+        "ready": dashboard.health.is_ready(),
+        "providers": dashboard.health.providers(),
+    }))
+    .into_response()
+}
+
+/// The update statistics and IP stability of every provider
+async fn stats(State(dashboard): State<Arc<Dashboard>>, headers: HeaderMap) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    let now = Utc::now();
+    let providers: Vec<_> = dashboard
+        .health
+        .providers()
+        .into_iter()
+        .map(|p| {
+            serde_json::json!({
+                "id": p.id,
+                "provider": p.provider,
+                "hostname": p.hostname,
+                "ip_version": p.ip_version,
+                "stats": p.stats,
+                "stability": p.stability(now),
+            })
+        })
+        .collect();
+    Json(providers).into_response()
+}
+
+/// The latest history entries, newest first
+async fn history(
+    State(dashboard): State<Arc<Dashboard>>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    match dashboard.store.history().await {
+        Ok(mut entries) => {
+            entries.reverse();
+            entries.truncate(query.limit.unwrap_or(DEFAULT_HISTORY));
+            Json(entries).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Publishes the latest detected IPs again, see [`Health::force_update`]
+async fn update(
+    State(dashboard): State<Arc<Dashboard>>,
+    Query(query): Query<UpdateQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    match dashboard.health.force_update(query.provider) {
+        0 if query.provider.is_some() => not_running(),
+        forced => (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "forced": forced })),
+        )
+            .into_response(),
+    }
+}
+
+async fn pause(
+    State(dashboard): State<Arc<Dashboard>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Response {
+    set_paused(&dashboard, &headers, id, true)
+}
+
+async fn resume(
+    State(dashboard): State<Arc<Dashboard>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Response {
+    set_paused(&dashboard, &headers, id, false)
+}
+
+fn set_paused(dashboard: &Dashboard, headers: &HeaderMap, id: u64, paused: bool) -> Response {
+    if !authorized(dashboard, headers) {
+        return unauthorized();
+    }
+    match dashboard.health.set_paused(id, paused) {
+        true => Json(serde_json::json!({ "id": id, "paused": paused })).into_response(),
+        false => not_running(),
+    }
+}
+
+/// The configuration file as written, with its format
+async fn config(State(dashboard): State<Arc<Dashboard>>, headers: HeaderMap) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    let Some(editor) = &dashboard.editor else {
+        return no_editor();
+    };
+    match tokio::fs::read_to_string(&editor.path).await {
+        Ok(content) => Json(serde_json::json!({
+            "format": editor.format.simple_name(),
+            "content": content,
+        }))
+        .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Checks a configuration like `validate`, without saving it
+async fn validate_config(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    Json(body): Json<ConfigBody>,
+) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    let Some(editor) = &dashboard.editor else {
+        return no_editor();
+    };
+    match validate(editor, &body.content) {
+        Ok(()) => Json(serde_json::json!({ "valid": true })).into_response(),
+        Err(error) => invalid_config(error),
+    }
+}
+
+/// Replaces the configuration file once the new one is checked like
+/// [`validate_config`] does, then reloads it
+async fn save_config(
+    State(dashboard): State<Arc<Dashboard>>,
+    headers: HeaderMap,
+    Json(body): Json<ConfigBody>,
+) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    let Some(editor) = &dashboard.editor else {
+        return no_editor();
+    };
+    if let Err(error) = validate(editor, &body.content) {
+        return invalid_config(error);
+    }
+    // Written to a temporary file first, so it's never read half written
+    if let Err(e) = write_private(&editor.path, body.content.as_bytes()).await {
+        tracing::warn!(path = %editor.path.display(), error = %e, "Couldn't save the configuration");
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    tracing::info!(path = %editor.path.display(), "Configuration saved from the dashboard");
+    let reloading = editor
+        .reload
+        .as_ref()
+        .is_some_and(|reload| reload.send("dashboard").is_ok());
+    Json(serde_json::json!({ "saved": true, "reloading": reloading })).into_response()
+}
+
+/// Checks `content` with the code of `validate`
+fn validate(editor: &ConfigEditor, content: &str) -> Result<(), String> {
+    Config::parse(content, editor.format)
+        .and_then(|config| config.validate())
+        .map_err(|e| e.to_string())
+}
+
+fn invalid_config(error: String) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!({ "error": error })),
+    )
+        .into_response()
+}
+
+fn no_editor() -> Response {
+    (StatusCode::NOT_FOUND, "The configuration can't be edited").into_response()
+}
+
+fn not_running() -> Response {
+    (StatusCode::NOT_FOUND, "No running provider with this id").into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use chrono::Utc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::{ConfigEditor, Dashboard, serve};
+    use crate::{
+        IpVersion,
+        config::ConfigFormat,
+        health::Health,
+        history::HistoryEntry,
+        persistence::{MemoryStore, StateStore},
+    };
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    async fn request(addr: std::net::SocketAddr, method: &str, path: &str, token: &str) -> String {
+        send(addr, method, path, token, "").await
+    }
+
+    async fn send(
+        addr: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        token: &str,
+        json: &str,
+    ) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{json}",
+            json.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str, token: &str) -> String {
+        request(addr, "GET", path, token).await
+    }
+
+    #[tokio::test]
+    async fn test_dashboard() {
+        let health = Health::default();
+        let reporter = health.register("DuckDNS", Some("home"), IpVersion::V4);
+        reporter.detected(IP);
+        let store = Arc::new(MemoryStore::default());
+        for minute in 0..3 {
+            let entry = HistoryEntry {
+                timestamp: Utc::now() + chrono::Duration::minutes(minute),
+                provider: "DuckDNS".to_string(),
+                hostname: Some("home".to_string()),
+                old_ip: None,
+                new_ip: IP,
+                error: None,
+                latency_ms: Some(minute as u64),
+                networks: None,
+            };
+            store.append_history(&entry).await.unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dashboard = Dashboard {
+            health: health.clone(),
+            store,
+            token: "s3cret".into(),
+            editor: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+        tokio::spawn(serve(listener, dashboard));
+
+        assert!(get(addr, "/", "").await.starts_with("HTTP/1.1 200"));
+        assert!(
+            get(addr, "/api/status", "wrong")
+                .await
+                .starts_with("HTTP/1.1 401")
+        );
+        let status = get(addr, "/api/status", "s3cret").await;
+        assert!(status.starts_with("HTTP/1.1 200"));
+        assert!(status.contains(r#""detected_ip":"192.0.2.1""#));
+        let history = get(addr, "/api/history?limit=2", "s3cret").await;
+        let (_, body) = history.split_once("\r\n\r\n").unwrap();
+        let entries: Vec<HistoryEntry> = serde_json::from_str(body).unwrap();
+        let latencies: Vec<_> = entries.iter().map(|e| e.latency_ms).collect();
+        assert_eq!(latencies, [Some(2), Some(1)]);
+        reporter.published_at(vec![Utc::now() - chrono::Duration::hours(1)]);
+        let stats = get(addr, "/api/stats", "s3cret").await;
+        assert!(
+            stats.contains(r#""changes":0,"changes_per_week":0.0"#),
+            "{stats}"
+        );
+
+        let id = health.providers()[0].id;
+        let paused = request(
+            addr,
+            "POST",
+            &format!("/api/providers/{id}/pause"),
+            "s3cret",
+        )
+        .await;
+        assert!(paused.starts_with("HTTP/1.1 200"));
+        assert!(reporter.is_paused() && health.providers()[0].paused);
+        let resumed = format!("/api/providers/{id}/resume");
+        assert!(
+            request(addr, "POST", &resumed, "s3cret")
+                .await
+                .starts_with("HTTP/1.1 200")
+        );
+        assert!(!reporter.is_paused());
+        assert!(
+            request(addr, "POST", "/api/providers/99/pause", "s3cret")
+                .await
+                .starts_with("HTTP/1.1 404")
+        );
+        let forced = request(addr, "POST", "/api/update", "s3cret").await;
+        assert!(forced.starts_with("HTTP/1.1 202") && forced.contains(r#"{"forced":1}"#));
+        tokio::time::timeout(std::time::Duration::from_secs(1), reporter.forced())
+            .await
+            .unwrap();
+        assert!(
+            get(addr, "/api/config", "s3cret")
+                .await
+                .starts_with("HTTP/1.1 404")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_editor() {
+        let path =
+            std::env::temp_dir().join(format!("dns_updater_editor_{}.toml", std::process::id()));
+        let original = "interface = \"eth0\"\nproviders = [\"DD;tok;ipv4;300;home\"]\n";
+        std::fs::write(&path, original).unwrap();
+        let (reload, mut reloads) = mpsc::unbounded_channel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dashboard = Dashboard {
+            health: Health::default(),
+            store: Arc::new(MemoryStore::default()),
+            token: "s3cret".into(),
+            editor: Some(ConfigEditor {
+                path: path.clone(),
+                format: ConfigFormat::Toml,
+                reload: Some(reload),
+            }),
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+        tokio::spawn(serve(listener, dashboard));
+
+        let config = get(addr, "/api/config", "s3cret").await;
+        let (_, body) = config.split_once("\r\n\r\n").unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            (&body["format"], &body["content"]),
+            (&"toml".into(), &original.into())
+        );
+        let wrong = send(addr, "PUT", "/api/config", "wrong", r#"{"content":""}"#).await;
+        assert!(wrong.starts_with("HTTP/1.1 401"));
+
+        let invalid =
+            serde_json::json!({ "content": "interface = \"eth0\"\nproviders = [\"XX;tok\"]" });
+        let invalid = invalid.to_string();
+        let checked = send(addr, "POST", "/api/config/validate", "s3cret", &invalid).await;
+        assert!(checked.starts_with("HTTP/1.1 422") && checked.contains("XX"));
+        let saved = send(addr, "PUT", "/api/config", "s3cret", &invalid).await;
+        assert!(saved.starts_with("HTTP/1.1 422"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+
+        let edited = original.replace("300", "600");
+        let valid = serde_json::json!({ "content": edited }).to_string();
+        let checked = send(addr, "POST", "/api/config/validate", "s3cret", &valid).await;
+        assert!(checked.starts_with("HTTP/1.1 200"));
+        assert!(reloads.try_recv().is_err());
+        let saved = send(addr, "PUT", "/api/config", "s3cret", &valid).await;
+        assert!(saved.contains(r#""reloading":true"#), "{saved}");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), edited);
+        assert_eq!(reloads.recv().await, Some("dashboard"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
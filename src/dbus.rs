@@ -0,0 +1,197 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use chrono::Utc;
+use zbus::{Connection, connection, interface, object_server::SignalEmitter};
+
+use crate::health::{Health, ProviderHealth};
+
+/// Well-known name owned on the bus
+pub const NAME: &str = "io.github.juancabe.DnsUpdater";
+/// Path of the object with the state
+pub const PATH: &str = "/io/github/juancabe/DnsUpdater";
+
+/// Bus the object is exported on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// For servers, which needs a policy allowing the updater to own
+    /// [`NAME`]
+    System,
+    /// For desktops, that of the user's session
+    Session,
+}
+
+impl TryFrom<&str> for Bus {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "system" => Ok(Bus::System),
+            "session" => Ok(Bus::Session),
+            _ => Err(format!("Unknown bus {value}, expected system or session")),
+        }
+    }
+}
+
+/// A provider as a property: provider, hostname, IP version, detected IP,
+/// published IP and whether it's healthy, with empty strings for what's
+/// unknown
+type Provider = (String, String, String, String, String, bool);
+
+fn provider(p: &ProviderHealth) -> Provider {
+    let ip = |ip: Option<IpAddr>| ip.map(|ip| ip.to_string()).unwrap_or_default();
+    (
+        p.provider.clone(),
+        p.hostname.clone().unwrap_or_default(),
+        p.ip_version.clone(),
+        ip(p.detected_ip),
+        ip(p.published_ip),
+        p.is_healthy(),
+    )
+}
+
+/// The object at [`PATH`]
+struct Updater {
+    health: Health,
+}
+
+#[interface(name = "io.github.juancabe.DnsUpdater1")]
+impl Updater {
+    #[zbus(property)]
+    fn providers(&self) -> Vec<Provider> {
+        self.health.providers().iter().map(provider).collect()
+    }
+
+    /// Whether the latest detection and update of every provider succeeded
+    #[zbus(property)]
+    fn healthy(&self) -> bool {
+        self.health.is_healthy()
+    }
+
+    /// A provider published a new IP, `old_ip` being empty for its first one
+    #[zbus(signal)]
+    async fn ip_changed(
+        emitter: &SignalEmitter<'_>,
+        provider: &str,
+        hostname: &str,
+        ip_version: &str,
+        old_ip: &str,
+        new_ip: &str,
+    ) -> zbus::Result<()>;
+}
+
+async fn connect(bus: Bus, health: Health) -> zbus::Result<Connection> {
+    let builder = match bus {
+        Bus::System => connection::Builder::system()?,
+        Bus::Session => connection::Builder::session()?,
+    };
+    builder
+        .name(NAME)?
+        .serve_at(PATH, Updater { health })?
+        .build()
+        .await
+}
+
+/// Exports the state of the providers on `bus` and emits `IpChanged` every
+/// time one of them publishes a new IP. Never returns.
+pub async fn run(bus: Bus, health: Health) {
+    let connection = match connect(bus, health.clone()).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::error!(?bus, error = %e, "Couldn't export the state on D-Bus");
+            return std::future::pending().await;
+        }
+    };
+    let updater = match connection
+        .object_server()
+        .interface::<_, Updater>(PATH)
+        .await
+    {
+        Ok(updater) => updater,
+        Err(e) => {
+            tracing::error!(error = %e, "Couldn't find the exported D-Bus object");
+            return std::future::pending().await;
+        }
+    };
+    let emitter = updater.signal_emitter();
+    // IPs loaded from the state before starting weren't published by us
+    let started = Utc::now();
+    let mut published: BTreeMap<(String, Option<String>, String), IpAddr> = BTreeMap::new();
+    let mut last: Option<(Vec<Provider>, bool)> = None;
+    loop {
+        let changed = health.changed();
+        let providers = health.providers();
+        for p in &providers {
+            let Some(ip) = p.published_ip else { continue };
+            let key = (p.provider.clone(), p.hostname.clone(), p.ip_version.clone());
+            let old_ip = published.insert(key, ip);
+            let updated = p.last_update.is_some_and(|at| at >= started);
+            if old_ip != Some(ip) && updated {
+                let old_ip = old_ip.map(|ip| ip.to_string()).unwrap_or_default();
+                let hostname = p.hostname.as_deref().unwrap_or_default();
+                let new_ip = ip.to_string();
+                let signal = Updater::ip_changed(
+                    emitter,
+                    &p.provider,
+                    hostname,
+                    &p.ip_version,
+                    &old_ip,
+                    &new_ip,
+                );
+                if let Err(e) = signal.await {
+                    tracing::warn!(error = %e, "Couldn't emit the IpChanged D-Bus signal");
+                }
+            }
+        }
+
+        let current = (
+            providers.iter().map(provider).collect(),
+            providers.iter().all(ProviderHealth::is_healthy),
+        );
+        if last.as_ref() != Some(&current) {
+            let updater = updater.get().await;
+            let mut signalled = updater.providers_changed(emitter).await;
+            if last
+                .as_ref()
+                .is_none_or(|(_, healthy)| *healthy != current.1)
+            {
+                signalled = signalled.and(updater.healthy_changed(emitter).await);
+            }
+            if let Err(e) = signalled {
+                tracing::debug!(error = %e, "Couldn't signal the D-Bus properties changed");
+            }
+            last = Some(current);
+        }
+        changed.await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bus, provider};
+    use crate::health::ProviderHealth;
+
+    #[test]
+    fn test_provider_property() {
+        assert_eq!(Bus::try_from("system"), Ok(Bus::System));
+        assert!(Bus::try_from("user").is_err());
+
+        let health = ProviderHealth {
+            provider: "DuckDNS".to_string(),
+            hostname: None,
+            ip_version: "ipv6".to_string(),
+            published_ip: Some("2001:db8::1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            provider(&health),
+            (
+                "DuckDNS".to_string(),
+                String::new(),
+                "ipv6".to_string(),
+                String::new(),
+                "2001:db8::1".to_string(),
+                true
+            )
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! A tiny authoritative DNS server for the LAN: the apex of its zone answers
+//! A and AAAA queries with the IPs detected last, so machines on the network
+//! resolve the current address, e.g. a new global IPv6 one, without waiting
+//! for the public records to propagate.
+
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
+
+use hickory_server::{
+    ServerFuture,
+    authority::{Catalog, ZoneType},
+    proto::{
+        error::ProtoError,
+        rr::{
+            LowerName, Name, RData, Record, RecordSet, RecordType, RrKey,
+            rdata::{A, AAAA, NS, SOA},
+        },
+    },
+    store::in_memory::InMemoryAuthority,
+};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::{IpVersion, health::Health};
+
+/// Of the A and AAAA records, short so clients follow IP changes soon
+const TTL: u32 = 60;
+/// Longest a TCP client may stay idle
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The zone served by [`DnsServer::serve`]
+pub struct DnsServer {
+    origin: Name,
+    authority: Arc<InMemoryAuthority>,
+}
+
+impl DnsServer {
+    /// A server for `zone`, e.g. `home.lan`, with no address until the
+    /// providers detect theirs
+    pub fn new(zone: &str) -> Result<Self, String> {
+        let mut origin = Name::from_ascii(zone).map_err(|e| format!("Invalid zone {zone}: {e}"))?;
+        origin.set_fqdn(true);
+        let mut records = BTreeMap::new();
+        for record_set in [soa(&origin, 0), ns(&origin)] {
+            let key = RrKey::new(LowerName::new(&origin), record_set.record_type());
+            records.insert(key, record_set);
+        }
+        let authority = InMemoryAuthority::new(origin.clone(), records, ZoneType::Primary, false)?;
+        Ok(Self {
+            origin,
+            authority: Arc::new(authority),
+        })
+    }
+
+    /// Answers on `socket` and `listener` until they fail, with the IPs the
+    /// providers of `health` detected last
+    pub async fn serve(
+        self,
+        socket: UdpSocket,
+        listener: TcpListener,
+        health: Health,
+    ) -> Result<(), ProtoError> {
+        let mut catalog = Catalog::new();
+        catalog.upsert(
+            LowerName::new(&self.origin),
+            Box::new(self.authority.clone()),
+        );
+        let mut server = ServerFuture::new(catalog);
+        server.register_socket(socket);
+        server.register_listener(listener, TCP_TIMEOUT);
+        tokio::spawn(async move {
+            let mut serial = 0;
+            loop {
+                // Created before reading the IPs, so no change is missed
+                let changed = health.changed();
+                serial = self.sync(&health, serial).await;
+                changed.await;
+            }
+        });
+        server.block_until_done().await
+    }
+
+    /// Points the apex at the IPs detected last, bumping the serial of the
+    /// zone when they changed. Returns the current serial.
+    async fn sync(&self, health: &Health, serial: u32) -> u32 {
+        let detected = |version| {
+            health
+                .providers()
+                .into_iter()
+                .filter_map(|p| p.detected_ip)
+                .find(|ip| IpVersion::from(ip) == version)
+        };
+        let key = |record_type| RrKey::new(LowerName::new(&self.origin), record_type);
+        let wanted = [
+            (RecordType::A, detected(IpVersion::V4)),
+            (RecordType::AAAA, detected(IpVersion::V6)),
+        ];
+
+        let mut records = self.authority.records_mut().await;
+        let current = |record_type| {
+            records
+                .get(&key(record_type))
+                .and_then(|set| set.records_without_rrsigs().next())
+                .and_then(Record::data)
+                .and_then(|data| data.ip_addr())
+        };
+        if wanted.iter().all(|(kind, ip)| current(*kind) == *ip) {
+            return serial;
+        }
+        let serial = serial.wrapping_add(1);
+        for (record_type, ip) in wanted {
+            match ip {
+                Some(ip) => {
+                    let mut set = RecordSet::with_ttl(self.origin.clone(), record_type, TTL);
+                    set.add_rdata(match ip {
+                        IpAddr::V4(ip) => RData::A(A(ip)),
+                        IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+                    });
+                    records.insert(key(record_type), Arc::new(set));
+                    tracing::info!(zone = %self.origin, %ip, "Serving the IP on the LAN");
+                }
+                None => {
+                    records.remove(&key(record_type));
+                }
+            }
+        }
+        records.insert(key(RecordType::SOA), Arc::new(soa(&self.origin, serial)));
+        serial
+    }
+}
+
+fn soa(origin: &Name, serial: u32) -> RecordSet {
+    let hostmaster = Name::from_ascii("hostmaster")
+        .and_then(|name| name.append_domain(origin))
+        .unwrap_or_else(|_| origin.clone());
+    let soa = SOA::new(origin.clone(), hostmaster, serial, 3600, 600, 86400, TTL);
+    let mut set = RecordSet::with_ttl(origin.clone(), RecordType::SOA, TTL);
+    set.add_rdata(RData::SOA(soa));
+    set
+}
+
+/// The server itself is the zone's only name server, named like its apex
+fn ns(origin: &Name) -> RecordSet {
+    let mut set = RecordSet::with_ttl(origin.clone(), RecordType::NS, TTL);
+    set.add_rdata(RData::NS(NS(origin.clone())));
+    set
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use hickory_server::proto::{
+        op::{Message, Query, ResponseCode},
+        rr::{Name, RData, RecordType},
+    };
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::DnsServer;
+    use crate::{IpVersion, health::Health};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const IPV6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    async fn query(server: std::net::SocketAddr, name: &str, kind: RecordType) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), kind));
+        socket
+            .send_to(&message.to_vec().unwrap(), server)
+            .await
+            .unwrap();
+        let mut answer = [0; 512];
+        let receive = socket.recv(&mut answer);
+        let n = tokio::time::timeout(Duration::from_secs(1), receive)
+            .await
+            .unwrap()
+            .unwrap();
+        Message::from_vec(&answer[..n]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dns_server() {
+        assert!(DnsServer::new("home..lan").is_err());
+        let health = Health::default();
+        let v4 = health.register("DuckDNS", Some("home"), IpVersion::V4);
+        let v6 = health.register("DuckDNS", Some("home"), IpVersion::V6);
+        v4.detected(IP);
+
+        let server = DnsServer::new("home.lan").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        tokio::spawn(server.serve(socket, listener, health.clone()));
+
+        let answer = query(addr, "home.lan.", RecordType::A).await;
+        assert!(answer.authoritative());
+        assert_eq!(
+            answer.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(192, 0, 2, 1).into()))
+        );
+        let answer = query(addr, "home.lan.", RecordType::AAAA).await;
+        assert_eq!(answer.response_code(), ResponseCode::NoError);
+        assert!(answer.answers().is_empty());
+        let answer = query(addr, "other.home.lan.", RecordType::A).await;
+        assert_eq!(answer.response_code(), ResponseCode::NXDomain);
+
+        v6.detected(IPV6);
+        // Applied once the server saw the change
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let answer = query(addr, "home.lan.", RecordType::AAAA).await;
+        assert_eq!(
+            answer.answers()[0].data().and_then(RData::ip_addr),
+            Some(IPV6)
+        );
+        let answer = query(addr, "home.lan.", RecordType::SOA).await;
+        let serial = answer.answers()[0]
+            .data()
+            .and_then(RData::as_soa)
+            .unwrap()
+            .serial();
+        assert_eq!(serial, 2);
+    }
+}
@@ -1,50 +1,283 @@
-use std::{fmt::Debug, net::IpAddr, time::Duration};
+use std::{
+    fmt::{Debug, Display},
+    net::IpAddr,
+    num::NonZeroU32,
+    str::FromStr,
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use reqwest::{Client, redirect::Policy};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
-use crate::{IpVersion, SimpleName};
+use crate::{
+    IpVersion, SimpleName,
+    blackout::Window,
+    ip_grabber::SourceSpec,
+    registry::{self, Field, ProviderSchema},
+    resolve,
+    scheduler::Schedule,
+    secrets::{Secret, redact_url},
+    vcr,
+};
 
 #[async_trait]
 pub trait DynDns: PersistsToFile + Send + Sync + Debug {
-    // ip is optional because for Ip4Addr APIs auto detect
-    async fn update(&mut self, ip: IpAddr) -> Result<(), String>;
+    /// Publishes `ip` with `client`, returning what the provider answered
+    async fn update(&mut self, client: &Client, ip: IpAddr) -> Result<String, UpdateError>;
+    /// Checks the credentials and record name without changing the record
+    async fn test(&mut self, client: &Client) -> Result<CredentialCheck, UpdateError>;
+    /// The IPs the record holds now, of the provider's IP version, so
+    /// [`update_if_changed`] skips publishing one it already holds. Empty
+    /// when the provider doesn't tell.
+    async fn current(&mut self, _client: &Client) -> Result<Vec<IpAddr>, CurrentError> {
+        Ok(Vec::new())
+    }
     fn get_ip_version(&self) -> IpVersion;
-    fn get_poll_secs(&self) -> u64;
+    fn get_schedule(&self) -> &Schedule;
+    fn options(&self) -> &ProviderOptions;
+    fn options_mut(&mut self) -> &mut ProviderOptions;
+    /// Short human readable name of the service, e.g. "DuckDNS"
+    fn provider_name(&self) -> &'static str;
+    /// The record being updated, when the provider configuration names it
+    fn hostname(&self) -> Option<&str>;
+}
+
+/// Outcome of [`DynDns::test`] when the provider didn't reject the check
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredentialCheck {
+    /// The provider accepted the credentials, with what was verified
+    Valid(String),
+    /// The record exists, but the provider has no way of checking the
+    /// credentials without changing it, with what the record holds
+    RecordFound(String),
+    /// The provider has no way of checking without changing the record
+    Untestable(&'static str),
+}
+
+/// Why a provider couldn't publish an IP or check its credentials
+#[derive(Debug, thiserror::Error)]
+pub enum UpdateError {
+    /// No answer: DNS, connection, TLS or timeout
+    #[error("couldn't reach {provider}: {source}")]
+    Network {
+        provider: &'static str,
+        #[source]
+        source: reqwest::Error,
+    },
+    /// The credentials were refused
+    #[error("{provider} refused the credentials: {response}")]
+    Auth {
+        provider: &'static str,
+        response: String,
+    },
+    /// The provider asks to slow down
+    #[error("{provider} is rate limiting the updates: {response}")]
+    RateLimited {
+        provider: &'static str,
+        response: String,
+    },
+    /// Any other refusal, e.g. of an unknown record
+    #[error("{provider} rejected the update: {response}")]
+    Rejected {
+        provider: &'static str,
+        response: String,
+    },
+    /// The record to check doesn't resolve on its zone's primary server
+    #[error("{host} doesn't resolve: {reason}")]
+    Resolve { host: String, reason: String },
+    #[error("{host} has no {ip_version} record to check against")]
+    NoRecord {
+        host: String,
+        ip_version: &'static str,
+    },
+}
+
+/// Why [`DynDns::current`] couldn't read the record
+#[derive(Debug, thiserror::Error)]
+pub enum CurrentError {
+    /// No server answered for the record, e.g. its zone's primary server
+    #[error("couldn't look {host} up: {reason}")]
+    Lookup { host: String, reason: String },
+    /// The provider answered with something else than IPs
+    #[error("{provider} answered an invalid record: {response}")]
+    Invalid {
+        provider: &'static str,
+        response: String,
+    },
+    /// The provider refused or couldn't be reached
+    #[error(transparent)]
+    Update(#[from] UpdateError),
+}
+
+impl CurrentError {
+    fn lookup(host: &str, reason: String) -> Self {
+        CurrentError::Lookup {
+            host: host.to_string(),
+            reason,
+        }
+    }
+}
+
+/// Why a `DNS_TUPLES` value couldn't be parsed, see [`parse_dns_tuples`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Empty batch found")]
+    EmptyBatch,
+    #[error("Invalid Dynamic Dns Type found: {0}")]
+    UnknownType(String),
+    #[error("Batch ends with an unfinished escape: {0}")]
+    UnfinishedEscape(String),
+    #[error("Unterminated quote in batch: {0}")]
+    UnterminatedQuote(String),
+    /// A part the provider's factory rejected, see [`registry::ProviderFactory`]
+    #[error("{0}")]
+    Part(String),
+    #[error("Expected KEY=VALUE option, found: {0}")]
+    NotAnOption(String),
+    #[error("Unknown option found: {0}")]
+    UnknownOption(String),
+    #[error("Couldn't parse {option}: {reason}")]
+    InvalidOption {
+        option: &'static str,
+        reason: String,
+    },
+}
+
+impl UpdateError {
+    fn network(provider: &'static str, source: reqwest::Error) -> Self {
+        // The URL may hold the token
+        let source = source.without_url();
+        UpdateError::Network { provider, source }
+    }
+
+    /// Classifies an HTTP error status
+    fn status(provider: &'static str, status: StatusCode) -> Self {
+        let response = format!("HTTP {status}");
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                UpdateError::Auth { provider, response }
+            }
+            StatusCode::TOO_MANY_REQUESTS => UpdateError::RateLimited { provider, response },
+            _ => UpdateError::Rejected { provider, response },
+        }
+    }
+}
+
+/// Reads the record `host` on the primary server of its zone, without
+/// writing anything: a stale or split-horizon answer must never end up
+/// published by a check
+async fn check_record<D: DynDns + ?Sized>(
+    dyn_dns: &D,
+    host: &str,
+) -> Result<CredentialCheck, UpdateError> {
+    let version = dyn_dns.get_ip_version();
+    let current = resolve::authoritative(host, version)
+        .await
+        .map_err(|reason| UpdateError::Resolve {
+            host: host.to_string(),
+            reason,
+        })?;
+    if current.is_empty() {
+        return Err(UpdateError::NoRecord {
+            host: host.to_string(),
+            ip_version: match version {
+                IpVersion::V4 => "ipv4",
+                IpVersion::V6 => "ipv6",
+            },
+        });
+    }
+    let current: Vec<_> = current.iter().map(IpAddr::to_string).collect();
+    Ok(CredentialCheck::RecordFound(format!(
+        "{host} points to {}",
+        current.join(", ")
+    )))
+}
+
+/// Publishes `ip` with [`DynDns::update`], unless [`DynDns::current`] shows
+/// the record already holds it alone, e.g. when the state was lost or
+/// another tool set it. A record that can't be read is updated anyway, and
+/// so is a `forced` one: forcing is how a record read wrong gets fixed.
+pub async fn update_if_changed(
+    dyn_dns: &mut dyn DynDns,
+    client: &Client,
+    ip: IpAddr,
+    forced: bool,
+) -> Result<String, UpdateError> {
+    if forced {
+        return dyn_dns.update(client, ip).await;
+    }
+    match dyn_dns.current(client).await {
+        Ok(current) if current == [ip] => {
+            tracing::info!(%ip, "The record already points to the IP, not updating it");
+            return Ok(format!("already {ip}"));
+        }
+        Ok(_) => {}
+        Err(e) => tracing::debug!(error = %e, "Couldn't read the record, updating it"),
+    }
+    dyn_dns.update(client, ip).await
 }
 
 pub trait PersistsToFile {
     fn file_name(&self) -> &str;
+    /// Name of the state file in older versions, which held credentials, see
+    /// [`crate::persistence::migrate_legacy_names`]
+    fn legacy_file_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Name of a provider's state file: its type and a hash of what identifies
+/// its record, so that tokens and account names don't end up in directory
+/// listings, logs and backups
+pub fn state_file_name(provider: &str, identity: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in identity {
+        hasher.update(part.as_bytes());
+        // Keeps ["ab", "c"] and ["a", "bc"] apart
+        hasher.update([0]);
+    }
+    format!("{provider}_{}", &hex::encode(hasher.finalize())[..16])
 }
 
 pub struct FreeDns {
-    token: String,
+    token: Secret,
     file_name: String,
+    /// Holds the token
+    legacy_file_name: Secret,
     ip_version: IpVersion,
-    poll_secs: u64,
+    schedule: Schedule,
+    options: ProviderOptions,
 }
 
 impl std::fmt::Debug for FreeDns {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("FreeDns")
-            .field("token", &"[REDACTED]")
+            .field("token", &self.token)
             .field("file_name", &self.file_name)
             .field("ip_version", &self.ip_version)
-            .field("poll_secs", &self.poll_secs)
+            .field("schedule", &self.schedule.to_string())
+            .field("options", &self.options)
             .finish()
     }
 }
 
 impl FreeDns {
-    pub fn new(token: String, ip_version: IpVersion, poll_secs: u64) -> Self {
-        let file_name = format!("FreeDNS_{}_{}", token, ip_version.simple_name());
+    pub fn new(token: String, ip_version: IpVersion, schedule: Schedule) -> Self {
+        // There's no hostname, the token is the only thing telling records apart
+        let file_name = state_file_name("FreeDNS", &[&token, ip_version.simple_name()]);
+        let legacy_file_name = format!("FreeDNS_{}_{}", token, ip_version.simple_name());
         let s = Self {
-            token,
+            token: token.into(),
             file_name,
+            legacy_file_name: legacy_file_name.into(),
             ip_version,
-            poll_secs,
+            schedule,
+            options: ProviderOptions::default(),
         };
-        log::info!("Created DynDns: {s:?}");
+        tracing::info!(dyn_dns = ?s, "Created DynDns");
         s
     }
 }
@@ -53,136 +286,237 @@ impl PersistsToFile for FreeDns {
     fn file_name(&self) -> &str {
         &self.file_name
     }
+
+    fn legacy_file_name(&self) -> Option<&str> {
+        Some(self.legacy_file_name.expose())
+    }
 }
 
 #[async_trait]
 impl DynDns for FreeDns {
-    async fn update(&mut self, ip: IpAddr) -> Result<(), String> {
-        let mut update_url = format!(
+    async fn update(&mut self, client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let mut update_url = Zeroizing::new(format!(
             "https://freedns.afraid.org/dynamic/update.php?{}",
-            self.token
-        );
-        if let IpAddr::V6(ip) = ip {
-            update_url.push_str("&address=");
-            update_url.push_str(&ip.to_string());
-        }
+            self.token.expose()
+        ));
+        // Always explicit, so the published IP is the requested one and not the caller's
+        update_url.push_str("&address=");
+        update_url.push_str(&ip.to_string());
 
-        log::info!("Calling HTTP: {update_url}");
-        match reqwest::get(&update_url).await {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    log::info!("FreeDNS update successful for {}", ip);
-                    Ok(())
-                } else {
-                    Err(format!("FreeDNS update failed: Status {}", resp.status()))
-                }
-            }
-            Err(e) => Err(format!("Failed to send request to FreeDNS: {:?}", e)),
+        tracing::info!(url = redact_url(&update_url), "Calling HTTP");
+        let request = self.options.with_timeout(client.get(update_url.as_str()));
+        let resp = vcr::send(request)
+            .await
+            .map_err(|e| UpdateError::network("FreeDNS", e))?;
+        if !resp.status().is_success() {
+            return Err(UpdateError::status("FreeDNS", resp.status()));
         }
+        let body = resp.text().await.unwrap_or_default();
+        let response = body.trim().to_string();
+        // Errors are reported with a 200 status, e.g. "ERROR: Unable to locate this record"
+        if response.contains("Invalid update URL") {
+            return Err(UpdateError::Auth {
+                provider: "FreeDNS",
+                response,
+            });
+        }
+        if response.contains("ERROR") {
+            return Err(UpdateError::Rejected {
+                provider: "FreeDNS",
+                response,
+            });
+        }
+        tracing::info!(%ip, "FreeDNS update successful");
+        Ok(response)
+    }
+
+    async fn test(&mut self, _client: &Client) -> Result<CredentialCheck, UpdateError> {
+        Ok(CredentialCheck::Untestable(
+            "FreeDNS tokens can only be checked by updating the record",
+        ))
     }
 
     fn get_ip_version(&self) -> IpVersion {
         self.ip_version
     }
 
-    fn get_poll_secs(&self) -> u64 {
-        self.poll_secs
+    fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut ProviderOptions {
+        &mut self.options
+    }
+
+    fn provider_name(&self) -> &'static str {
+        "FreeDNS"
+    }
+
+    fn hostname(&self) -> Option<&str> {
+        // The update token identifies the record, its name isn't known
+        None
     }
 }
 
 pub struct DuckDns {
-    token: String,
+    token: Secret,
     name: String,
     file_name: String,
+    /// Holds the token
+    legacy_file_name: Secret,
     ip_version: IpVersion,
-    poll_secs: u64,
+    schedule: Schedule,
+    options: ProviderOptions,
 }
 
 impl std::fmt::Debug for DuckDns {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DuckDns")
-            .field("token", &"[REDACTED]")
+            .field("token", &self.token)
             .field("name", &self.name)
             .field("file_name", &self.file_name)
             .field("ip_version", &self.ip_version)
-            .field("poll_secs", &self.poll_secs)
+            .field("schedule", &self.schedule.to_string())
+            .field("options", &self.options)
             .finish()
     }
 }
 
 impl DuckDns {
-    pub fn new(token: String, name: String, ip_version: IpVersion, poll_secs: u64) -> Self {
-        let file_name = format!("DuckDNS_{}_{}", token, name);
+    pub fn new(token: String, name: String, ip_version: IpVersion, schedule: Schedule) -> Self {
+        let file_name = state_file_name("DuckDNS", &[&name, ip_version.simple_name()]);
+        let legacy_file_name = format!("DuckDNS_{}_{}", token, name);
         let s = Self {
-            token,
+            token: token.into(),
             name,
             file_name,
+            legacy_file_name: legacy_file_name.into(),
             ip_version,
-            poll_secs,
+            schedule,
+            options: ProviderOptions::default(),
         };
-        log::info!("Created DynDns: {s:?}");
+        tracing::info!(dyn_dns = ?s, "Created DynDns");
         s
     }
+
+    /// The full name of the record, e.g. `home.duckdns.org`
+    fn host(&self) -> String {
+        match self.name.contains('.') {
+            true => self.name.clone(),
+            false => format!("{}.duckdns.org", self.name),
+        }
+    }
 }
 
 impl PersistsToFile for DuckDns {
     fn file_name(&self) -> &str {
         &self.file_name
     }
+
+    fn legacy_file_name(&self) -> Option<&str> {
+        Some(self.legacy_file_name.expose())
+    }
 }
 
 #[async_trait]
 impl DynDns for DuckDns {
-    async fn update(&mut self, ip: IpAddr) -> Result<(), String> {
-        let mut update_url = format!(
+    async fn update(&mut self, client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let mut update_url = Zeroizing::new(format!(
             "https://www.duckdns.org/update?domains={}&token={}",
-            self.name, self.token
-        );
-        if let IpAddr::V6(ip) = ip {
-            update_url.push_str("&ipv6=");
-            update_url.push_str(&ip.to_string());
+            self.name,
+            self.token.expose()
+        ));
+        // Always explicit, so the published IP is the requested one and not the caller's
+        match ip {
+            IpAddr::V4(ip) => update_url.push_str(&format!("&ip={ip}")),
+            IpAddr::V6(ip) => update_url.push_str(&format!("&ipv6={ip}")),
         }
-        log::info!("Calling HTTP: {update_url}");
-        match reqwest::get(&update_url).await {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    log::info!("DuckDNS update successful for {}", ip);
-                    Ok(())
-                } else {
-                    Err(format!("DuckDNS update failed: Status {}", resp.status()))
-                }
-            }
-            Err(e) => Err(format!("Failed to send request to DuckDNS: {:?}", e)),
+        tracing::info!(url = redact_url(&update_url), "Calling HTTP");
+        let request = self.options.with_timeout(client.get(update_url.as_str()));
+        let resp = vcr::send(request)
+            .await
+            .map_err(|e| UpdateError::network("DuckDNS", e))?;
+        if !resp.status().is_success() {
+            return Err(UpdateError::status("DuckDNS", resp.status()));
+        }
+        let body = resp.text().await.unwrap_or_default();
+        // A bad token or domain is reported as "KO" with a 200 status, with
+        // no telling which
+        if body.trim() != "OK" {
+            return Err(UpdateError::Rejected {
+                provider: "DuckDNS",
+                response: format!("{}, check the token and domain", body.trim()),
+            });
         }
+        tracing::info!(%ip, "DuckDNS update successful");
+        Ok(body.trim().to_string())
+    }
+
+    async fn test(&mut self, _client: &Client) -> Result<CredentialCheck, UpdateError> {
+        check_record(self, &self.host()).await
+    }
+
+    async fn current(&mut self, _client: &Client) -> Result<Vec<IpAddr>, CurrentError> {
+        // Several domains updated at once may hold different IPs
+        if self.name.contains(',') {
+            return Ok(Vec::new());
+        }
+        let host = self.host();
+        resolve::authoritative(&host, self.ip_version)
+            .await
+            .map_err(|e| CurrentError::lookup(&host, e))
     }
 
     fn get_ip_version(&self) -> IpVersion {
         self.ip_version
     }
 
-    fn get_poll_secs(&self) -> u64 {
-        self.poll_secs
+    fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut ProviderOptions {
+        &mut self.options
+    }
+
+    fn provider_name(&self) -> &'static str {
+        "DuckDNS"
+    }
+
+    fn hostname(&self) -> Option<&str> {
+        Some(&self.name)
     }
 }
 
 pub struct Ovh {
     username: String,
-    password: String,
+    password: Secret,
     subdomain: String,
     file_name: String,
+    legacy_file_name: String,
     ip_version: IpVersion,
-    poll_secs: u64,
+    schedule: Schedule,
+    options: ProviderOptions,
 }
 
 impl std::fmt::Debug for Ovh {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Ovh")
             .field("username", &self.username)
-            .field("password", &"[REDACTED]")
+            .field("password", &self.password)
             .field("subdomain", &self.subdomain)
             .field("file_name", &self.file_name)
             .field("ip_version", &self.ip_version)
-            .field("poll_secs", &self.poll_secs)
+            .field("schedule", &self.schedule.to_string())
+            .field("options", &self.options)
             .finish()
     }
 }
@@ -193,18 +527,21 @@ impl Ovh {
         password: String,
         subdomain: String,
         ip_version: IpVersion,
-        poll_secs: u64,
+        schedule: Schedule,
     ) -> Self {
-        let file_name = format!("OVH_{username}_{subdomain}_{}", ip_version.simple_name());
+        let file_name = state_file_name("OVH", &[&subdomain, ip_version.simple_name()]);
+        let legacy_file_name = format!("OVH_{username}_{subdomain}_{}", ip_version.simple_name());
         let s = Self {
             username,
-            password,
+            password: password.into(),
             subdomain,
             file_name,
+            legacy_file_name,
             ip_version,
-            poll_secs,
+            schedule,
+            options: ProviderOptions::default(),
         };
-        log::info!("Created DynDns: {s:?}");
+        tracing::info!(dyn_dns = ?s, "Created DynDns");
         s
     }
 }
@@ -213,57 +550,279 @@ impl PersistsToFile for Ovh {
     fn file_name(&self) -> &str {
         &self.file_name
     }
+
+    fn legacy_file_name(&self) -> Option<&str> {
+        Some(&self.legacy_file_name)
+    }
 }
 
 #[async_trait]
 impl DynDns for Ovh {
-    async fn update(&mut self, ip: IpAddr) -> Result<(), String> {
-        let client = Client::builder()
-            // Equivalent to `-m 5` (Timeout the entire request after 5 seconds)
-            .timeout(Duration::from_secs(5))
-            // Equivalent to `-L` (Follow redirects). reqwest follows up to 10 by default,
-            // but we are setting it explicitly here for clarity.
-            .redirect(Policy::limited(10))
-            .build()
-            .map_err(|e| format!("[Ovh::update] Error creating reqwest client: {e:?}"))?;
-
-        let fut = client
+    async fn update(&mut self, client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let request = client
             .get("https://www.ovh.com/nic/update")
             .query(&[
                 ("system", "dyndns"),
                 ("hostname", &self.subdomain),
                 ("myip", &ip.to_string()),
             ])
-            .basic_auth(&self.username, Some(&self.password))
-            .send();
+            .basic_auth(&self.username, Some(self.password.expose()));
+        let fut = vcr::send(self.options.with_timeout(request));
 
-        log::info!(
-            "Calling HTTP: {update_url}",
-            update_url = "https://www.ovh.com/nic/update"
-        );
-        match fut.await {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    log::info!("Ovh update successful for {}", ip);
-                    Ok(())
-                } else {
-                    Err(format!("Ovh update failed: Status {}", resp.status()))
-                }
-            }
-            Err(e) => Err(format!("Failed to send request to Ovh: {:?}", e)),
+        tracing::info!(url = "https://www.ovh.com/nic/update", "Calling HTTP");
+        let resp = fut.await.map_err(|e| UpdateError::network("OVH", e))?;
+        if !resp.status().is_success() {
+            return Err(UpdateError::status("OVH", resp.status()));
+        }
+        let body = resp.text().await.unwrap_or_default();
+        let response = body.trim().to_string();
+        // DynDNS2 answers "good <ip>" or "nochg <ip>", anything else is an error
+        if response.starts_with("good") || response.starts_with("nochg") {
+            tracing::info!(%ip, "Ovh update successful");
+            return Ok(response);
         }
+        let provider = "OVH";
+        Err(match response.as_str() {
+            "badauth" | "badagent" => UpdateError::Auth { provider, response },
+            "abuse" => UpdateError::RateLimited { provider, response },
+            _ => UpdateError::Rejected { provider, response },
+        })
+    }
+
+    async fn test(&mut self, _client: &Client) -> Result<CredentialCheck, UpdateError> {
+        check_record(self, &self.subdomain).await
+    }
+
+    async fn current(&mut self, _client: &Client) -> Result<Vec<IpAddr>, CurrentError> {
+        resolve::authoritative(&self.subdomain, self.ip_version)
+            .await
+            .map_err(|e| CurrentError::lookup(&self.subdomain, e))
     }
 
     fn get_ip_version(&self) -> IpVersion {
         self.ip_version
     }
 
-    fn get_poll_secs(&self) -> u64 {
-        self.poll_secs
+    fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut ProviderOptions {
+        &mut self.options
+    }
+
+    fn provider_name(&self) -> &'static str {
+        "OVH"
+    }
+
+    fn hostname(&self) -> Option<&str> {
+        Some(&self.subdomain)
+    }
+}
+
+/// Settings shared by every provider, set through trailing `KEY=VALUE`
+/// parts of a batch or the `options` of a [`crate::provider::ProviderConfig`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderOptions {
+    /// Maximum number of update attempts per 24 hours, unlimited when `None`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_updates_per_day: Option<NonZeroU32>,
+    /// Enables flap detection: more detected changes than this within
+    /// `flap_window_mins` dampens updates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flap_max_changes: Option<u32>,
+    pub flap_window_mins: u64,
+    /// How long a new IP must stay the same before being published while dampened
+    pub flap_debounce_secs: u64,
+    /// Providers with a higher priority publish a new IP first
+    pub priority: i32,
+    /// Disabled providers stay configured, keeping their state, but are never
+    /// updated by the runner
+    pub enabled: bool,
+    /// Seconds a request to the provider may take, the client's timeout when
+    /// `None`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Sources asked at once for the IP, the first valid answer winning,
+    /// instead of the default source
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ip_sources: Vec<SourceSpec>,
+    /// Seconds each of `ip_sources` may take to answer
+    pub ip_source_timeout_secs: u64,
+    /// Daily windows during which nothing is published, the latest detected
+    /// IP being published once they end
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub blackout: Vec<Window>,
+}
+
+impl Default for ProviderOptions {
+    fn default() -> Self {
+        Self {
+            max_updates_per_day: None,
+            flap_max_changes: None,
+            flap_window_mins: 60,
+            flap_debounce_secs: 600,
+            priority: 0,
+            enabled: true,
+            timeout_secs: None,
+            ip_sources: Vec::new(),
+            ip_source_timeout_secs: 10,
+            blackout: Vec::new(),
+        }
+    }
+}
+
+impl ProviderOptions {
+    /// The `KEY=VALUE` options any batch may end with
+    pub const FIELDS: &[Field] = &[
+        Field {
+            name: "max_updates_per_day",
+            description: "Maximum number of update attempts per 24 hours, at least 1",
+            default: None,
+            secret: false,
+        },
+        Field {
+            name: "flap_max_changes",
+            description: "Dampens updates after more detected changes than this within flap_window_mins",
+            default: None,
+            secret: false,
+        },
+        Field {
+            name: "flap_window_mins",
+            description: "Window of the flap detection, in minutes",
+            default: Some("60"),
+            secret: false,
+        },
+        Field {
+            name: "flap_debounce_secs",
+            description: "How long a new IP must stay the same before being published while dampened",
+            default: Some("600"),
+            secret: false,
+        },
+        Field {
+            name: "priority",
+            description: "Providers with a higher priority publish a new IP first",
+            default: Some("0"),
+            secret: false,
+        },
+        Field {
+            name: "enabled",
+            description: "Set to false to keep the provider configured without updating it",
+            default: Some("true"),
+            secret: false,
+        },
+        Field {
+            name: "timeout_secs",
+            description: "Seconds a request to the provider may take, overriding the global timeout",
+            default: None,
+            secret: false,
+        },
+        Field {
+            name: "ip_sources",
+            description: "Comma-separated sources raced for the IP: ipify, icanhazip, ident.me, if_inet6 or URLs",
+            default: None,
+            secret: false,
+        },
+        Field {
+            name: "ip_source_timeout_secs",
+            description: "Seconds each of ip_sources may take to answer",
+            default: Some("10"),
+            secret: false,
+        },
+        Field {
+            name: "blackout",
+            description: "Comma-separated HH:MM-HH:MM local time windows during which nothing is published",
+            default: None,
+            secret: false,
+        },
+    ];
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
     }
+
+    /// `request` with the provider's timeout, when it has one
+    pub fn with_timeout(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.timeout_secs {
+            Some(secs) => request.timeout(Duration::from_secs(secs)),
+            None => request,
+        }
+    }
+
+    fn parse<'a>(parts: impl Iterator<Item = &'a str>) -> Result<Self, ParseError> {
+        let mut options = Self::default();
+        for part in parts.map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once("=")
+                .ok_or(ParseError::NotAnOption(part.to_string()))?;
+            let value = value.trim();
+            match key.trim() {
+                "max_updates_per_day" => {
+                    options.max_updates_per_day = Some(option("max_updates_per_day", value)?)
+                }
+                "flap_max_changes" => {
+                    options.flap_max_changes = Some(option("flap_max_changes", value)?)
+                }
+                "flap_window_mins" => options.flap_window_mins = option("flap_window_mins", value)?,
+                "flap_debounce_secs" => {
+                    options.flap_debounce_secs = option("flap_debounce_secs", value)?
+                }
+                "priority" => options.priority = option("priority", value)?,
+                "enabled" => options.enabled = option("enabled", value)?,
+                "timeout_secs" => options.timeout_secs = Some(option("timeout_secs", value)?),
+                "ip_sources" => {
+                    options.ip_sources = value
+                        .split(',')
+                        .map(|spec| SourceSpec::try_from(spec.to_string()))
+                        .collect::<Result<_, _>>()
+                        .map_err(|reason| ParseError::InvalidOption {
+                            option: "ip_sources",
+                            reason,
+                        })?
+                }
+                "ip_source_timeout_secs" => {
+                    options.ip_source_timeout_secs = option("ip_source_timeout_secs", value)?
+                }
+                "blackout" => {
+                    options.blackout = value
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|reason| ParseError::InvalidOption {
+                            option: "blackout",
+                            reason,
+                        })?
+                }
+                k => Err(ParseError::UnknownOption(k.to_string()))?,
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Parses `value` of the option named `option`
+fn option<T: FromStr>(option: &'static str, value: &str) -> Result<T, ParseError>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e: T::Err| ParseError::InvalidOption {
+            option,
+            reason: e.to_string(),
+        })
 }
 
-pub fn parse_dns_tuples(to_parse: &str) -> Result<Vec<Box<dyn DynDns>>, String> {
+/// A provider with a key identifying its configuration, see
+/// [`crate::runner::Reload`]
+pub type KeyedDynDns = (String, Box<dyn DynDns>);
+
+pub fn parse_dns_tuples(to_parse: &str) -> Result<Vec<Box<dyn DynDns>>, ParseError> {
     // to_parse := BATCH,BATCH,...
 
     // let free_dns = FreeDns::new(token, ip_version);
@@ -276,91 +835,275 @@ pub fn parse_dns_tuples(to_parse: &str) -> Result<Vec<Box<dyn DynDns>>, String>
     // ("OVH";USERNAME;PASSWORD;SUBDOMAIN;VERSION;POLL_SECS) = BATCH
     //
     // Parenthesis are not mandatory
+    // POLL_SECS may also be a cron expression, e.g. "*/5 8-22 * * *"
+    // Any BATCH may end with ProviderOptions, e.g. ";max_updates_per_day=10"
+    // Other types come from the registry, see registry::register_provider
+    // `\` escapes the next character and "..." quotes a part, e.g. "pa;ss"
 
-    to_parse
-        .split(",")
-        .map(|s| {
-            s.trim()
-                .trim_start_matches("(")
-                .trim_end_matches(")")
-                .split(";")
+    split_batches(to_parse)
+        .into_iter()
+        .map(split_parts)
+        .map(|parts| {
+            let parts = parts?;
+            let mut parts = parts.iter().map(String::as_str);
+            let mut dyn_dns = match parts.next() {
+                None => Err(ParseError::EmptyBatch)?,
+                Some(t) => {
+                    registry::factory(t).ok_or(ParseError::UnknownType(t.to_string()))?(&mut parts)
+                        .map_err(ParseError::Part)?
+                }
+            };
+            *dyn_dns.options_mut() = ProviderOptions::parse(parts)?;
+            Ok(dyn_dns)
         })
-        .map(|mut parts| match parts.next() {
-            None => Err("Empty Batch found".to_string()),
-            Some("FD") => {
-                let token = parts
-                    .next()
-                    .ok_or("No TOKEN found in batch".to_string())?
-                    .to_string();
-                let version: IpVersion = parts
-                    .next()
-                    .ok_or("No VERSION found in batch".to_string())?
-                    .try_into()?;
-                let poll_secs: u64 = parts
-                    .next()
-                    .ok_or("No POLL_SECS found in batch".to_string())?
-                    .parse()
-                    .map_err(|e| format!("Couldn't parse POLL_SECS error: {e:?}"))?;
-
-                Ok(Box::new(FreeDns::new(token, version, poll_secs)) as Box<dyn DynDns>)
+        .collect()
+}
+
+/// Splits a `DNS_TUPLES` value into its batches at every `,` that is neither
+/// escaped nor quoted. Batches are returned as written, escapes included.
+pub fn split_batches(to_parse: &str) -> Vec<&str> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in to_parse.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                batches.push(&to_parse[start..i]);
+                start = i + 1;
             }
-            Some("DD") => {
-                let token = parts
-                    .next()
-                    .ok_or("No TOKEN found in batch".to_string())?
-                    .to_string();
-                let version: IpVersion = parts
-                    .next()
-                    .ok_or("No VERSION found in batch".to_string())?
-                    .try_into()?;
-                let poll_secs: u64 = parts
-                    .next()
-                    .ok_or("No POLL_SECS found in batch".to_string())?
-                    .parse()
-                    .map_err(|e| format!("Couldn't parse POLL_SECS error: {e:?}"))?;
-
-                let name = parts
-                    .next()
-                    .ok_or("No NAME found in batch".to_string())?
-                    .to_string();
-                Ok(Box::new(DuckDns::new(token, name, version, poll_secs)) as Box<dyn DynDns>)
+            _ => {}
+        }
+    }
+    batches.push(&to_parse[start..]);
+    batches
+}
+
+/// Splits a batch into its `;` separated parts, removing the optional
+/// parenthesis around it and resolving escapes and quotes.
+fn split_parts(batch: &str) -> Result<Vec<String>, ParseError> {
+    let mut batch = batch.trim().trim_start_matches("(");
+    while let Some(rest) = batch.strip_suffix(")") {
+        // An escaped parenthesis belongs to the last part
+        if (rest.len() - rest.trim_end_matches('\\').len()) % 2 == 1 {
+            break;
+        }
+        batch = rest;
+    }
+
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    let mut quoted = false;
+    for c in batch.chars() {
+        let part = parts.last_mut().expect("Never empty");
+        match c {
+            _ if escaped => {
+                part.push(c);
+                escaped = false;
             }
-            Some("OVH") => {
-                let username = parts
-                    .next()
-                    .ok_or("No USERNAME found in batch".to_string())?
-                    .to_string();
-                let password = parts
-                    .next()
-                    .ok_or("No PASSWORD found in batch".to_string())?
-                    .to_string();
-                let subdomain = parts
-                    .next()
-                    .ok_or("No SUBDOMAIN found in batch".to_string())?
-                    .to_string();
-
-                let version: IpVersion = parts
-                    .next()
-                    .ok_or("No VERSION found in batch".to_string())?
-                    .try_into()?;
-                let poll_secs: u64 = parts
-                    .next()
-                    .ok_or("No POLL_SECS found in batch".to_string())?
-                    .parse()
-                    .map_err(|e| format!("Couldn't parse POLL_SECS error: {e:?}"))?;
-                Ok(
-                    Box::new(Ovh::new(username, password, subdomain, version, poll_secs))
-                        as Box<dyn DynDns>,
-                )
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => parts.push(String::new()),
+            _ => part.push(c),
+        }
+    }
+    if escaped {
+        Err(ParseError::UnfinishedEscape(batch.to_string()))?
+    }
+    if quoted {
+        Err(ParseError::UnterminatedQuote(batch.to_string()))?
+    }
+    Ok(parts)
+}
+
+/// Rewrites every batch of `entry`, replacing the parts its schema flags as
+/// secret with `placeholder(kind, field)`. Parts referencing a variable,
+/// such as `${TOKEN}`, are no secret and kept.
+pub fn strip_secrets(
+    entry: &str,
+    placeholder: &mut dyn FnMut(&str, &str) -> String,
+) -> Result<String, ParseError> {
+    let batches: Result<Vec<_>, ParseError> = split_batches(entry)
+        .into_iter()
+        .map(|batch| {
+            let mut parts = split_parts(batch)?;
+            let kind = parts.first().cloned().unwrap_or_default();
+            let (_, schema) = registry::providers()
+                .into_iter()
+                .find(|(k, _)| *k == kind)
+                .ok_or(ParseError::UnknownType(kind.clone()))?;
+            for (part, field) in parts.iter_mut().skip(1).zip(schema.fields) {
+                if field.secret && !part.contains("${") {
+                    *part = placeholder(&kind, field.name);
+                }
             }
-            Some(t) => Err(format!("Invalid Dynamic Dns Type found: {t}")),
+            let parts: Vec<_> = parts.iter().map(|p| escape_part(p)).collect();
+            Ok(parts.join(";"))
         })
-        .collect()
+        .collect();
+    Ok(batches?.join(","))
+}
+
+/// Escapes the characters [`split_batches`] and [`split_parts`] give a
+/// meaning to
+fn escape_part(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        if matches!(c, '\\' | ';' | ',' | '"' | '(' | ')') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Takes the next part of a batch, named `name` in errors
+pub fn required_part<'a>(
+    parts: &mut dyn Iterator<Item = &'a str>,
+    name: &str,
+) -> Result<&'a str, String> {
+    parts.next().ok_or(format!("No {name} found in batch"))
+}
+
+/// Takes the `VERSION` part of a batch
+pub fn ip_version_part(parts: &mut dyn Iterator<Item = &str>) -> Result<IpVersion, String> {
+    required_part(parts, "VERSION")?.try_into()
+}
+
+/// Takes the `POLL_SECS` part of a batch, seconds or a cron expression
+pub fn schedule_part(parts: &mut dyn Iterator<Item = &str>) -> Result<Schedule, String> {
+    required_part(parts, "POLL_SECS")?
+        .parse()
+        .map_err(|e| format!("Couldn't parse POLL_SECS error: {e}"))
+}
+
+/// The `VERSION` part of a batch, see [`ip_version_part`]
+pub const VERSION_FIELD: Field = Field {
+    name: "VERSION",
+    description: "IP version of the record: ipv4 or ipv6",
+    default: None,
+    secret: false,
+};
+
+/// The `POLL_SECS` part of a batch, see [`schedule_part`]
+pub const POLL_SECS_FIELD: Field = Field {
+    name: "POLL_SECS",
+    description: "Seconds between IP checks, or a cron expression such as \"*/5 8-22 * * *\"",
+    default: None,
+    secret: false,
+};
+
+pub(crate) const FREE_DNS_SCHEMA: ProviderSchema = ProviderSchema {
+    name: "FreeDNS",
+    fields: &[
+        Field {
+            name: "TOKEN",
+            description: "Update token, the end of the record's direct update URL",
+            default: None,
+            secret: true,
+        },
+        VERSION_FIELD,
+        POLL_SECS_FIELD,
+    ],
+};
+
+pub(crate) const DUCK_DNS_SCHEMA: ProviderSchema = ProviderSchema {
+    name: "DuckDNS",
+    fields: &[
+        Field {
+            name: "TOKEN",
+            description: "Account token",
+            default: None,
+            secret: true,
+        },
+        VERSION_FIELD,
+        POLL_SECS_FIELD,
+        Field {
+            name: "NAME",
+            description: "Subdomain, without .duckdns.org",
+            default: None,
+            secret: false,
+        },
+    ],
+};
+
+pub(crate) const OVH_SCHEMA: ProviderSchema = ProviderSchema {
+    name: "OVH",
+    fields: &[
+        Field {
+            name: "USERNAME",
+            description: "DynHost login",
+            default: None,
+            secret: false,
+        },
+        Field {
+            name: "PASSWORD",
+            description: "DynHost password",
+            default: None,
+            secret: true,
+        },
+        Field {
+            name: "SUBDOMAIN",
+            description: "Full hostname of the DynHost record",
+            default: None,
+            secret: false,
+        },
+        VERSION_FIELD,
+        POLL_SECS_FIELD,
+    ],
+};
+
+/// `FD;TOKEN;VERSION;POLL_SECS`
+pub(crate) fn parse_free_dns(
+    parts: &mut dyn Iterator<Item = &str>,
+) -> Result<Box<dyn DynDns>, String> {
+    let token = required_part(parts, "TOKEN")?.to_string();
+    let version = ip_version_part(parts)?;
+    let schedule = schedule_part(parts)?;
+    Ok(Box::new(FreeDns::new(token, version, schedule)))
+}
+
+/// `DD;TOKEN;VERSION;POLL_SECS;NAME`
+pub(crate) fn parse_duck_dns(
+    parts: &mut dyn Iterator<Item = &str>,
+) -> Result<Box<dyn DynDns>, String> {
+    let token = required_part(parts, "TOKEN")?.to_string();
+    let version = ip_version_part(parts)?;
+    let schedule = schedule_part(parts)?;
+    let name = required_part(parts, "NAME")?.to_string();
+    Ok(Box::new(DuckDns::new(token, name, version, schedule)))
+}
+
+/// `OVH;USERNAME;PASSWORD;SUBDOMAIN;VERSION;POLL_SECS`
+pub(crate) fn parse_ovh(parts: &mut dyn Iterator<Item = &str>) -> Result<Box<dyn DynDns>, String> {
+    let username = required_part(parts, "USERNAME")?.to_string();
+    let password = required_part(parts, "PASSWORD")?.to_string();
+    let subdomain = required_part(parts, "SUBDOMAIN")?.to_string();
+    let version = ip_version_part(parts)?;
+    let schedule = schedule_part(parts)?;
+    Ok(Box::new(Ovh::new(
+        username, password, subdomain, version, schedule,
+    )))
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{SimpleName, dyn_dns::parse_dns_tuples};
+    use std::{num::NonZeroU32, time::Duration};
+
+    use reqwest::StatusCode;
+
+    use crate::{
+        SimpleName,
+        dyn_dns::{
+            ParseError, ProviderOptions, UpdateError, parse_dns_tuples, split_batches, split_parts,
+            strip_secrets,
+        },
+        ip_grabber::SourceSpec,
+        scheduler::Schedule,
+    };
 
     #[test]
     fn test_parse() {
@@ -390,6 +1133,74 @@ mod test {
         assert!(parse_dns_tuples(dd_fails).is_err());
     }
 
+    #[test]
+    fn test_escaping() {
+        assert_eq!(
+            split_parts(r"OVH;user;pa\;ss\,w\)rd;home.example.com;ipv4;60").unwrap(),
+            [
+                "OVH",
+                "user",
+                "pa;ss,w)rd",
+                "home.example.com",
+                "ipv4",
+                "60"
+            ]
+        );
+        assert_eq!(
+            split_parts(r#"(OVH;user;"pa;ss,w)rd";home.example.com;ipv4;60)"#).unwrap()[2],
+            "pa;ss,w)rd"
+        );
+        assert_eq!(
+            split_parts(r#"OVH;user;"say \"hi\"" "#).unwrap()[2],
+            r#"say "hi""#
+        );
+        // A trailing escaped parenthesis is part of the password
+        assert_eq!(split_parts(r"(OVH;user;pass\))").unwrap()[2], "pass)");
+        assert_eq!(split_parts(r"OVH;user;pass\\)").unwrap()[2], r"pass\");
+        assert!(split_parts(r#"OVH;user;"pass"#).is_err());
+        assert!(split_parts(r"OVH;user;pass\").is_err());
+
+        assert_eq!(
+            split_batches(r#"OVH;u;p\,w;h;ipv4;60,OVH;u;"p,w";h;ipv4;60,FD;t;ipv4;0"#),
+            [
+                r"OVH;u;p\,w;h;ipv4;60",
+                r#"OVH;u;"p,w";h;ipv4;60"#,
+                "FD;t;ipv4;0"
+            ]
+        );
+
+        let parsed =
+            parse_dns_tuples(r#"OVH;user;pa\;ss,OVH;user;"pa;ss,w)rd";home.example.com;ipv4;60"#);
+        assert!(parsed.is_err(), "The first batch is missing parts");
+        let parsed = parse_dns_tuples(
+            r#"OVH;user;pa\;s\,s;home.example.com;ipv4;60,(OVH;user;"pa;ss,w)rd";home.example.com;ipv6;60)"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].get_ip_version().simple_name(), "ipv6");
+    }
+
+    #[test]
+    fn test_strip_secrets() {
+        let mut placeholder = |kind: &str, field: &str| format!("${{{kind}_{field}}}");
+        let stripped = strip_secrets(
+            r#"(DD;tok;ipv4;60;name;priority=2),OVH;user;"pa;ss";host;ipv4;60,FD;${FD_TOKEN};ipv6;0"#,
+            &mut placeholder,
+        )
+        .unwrap();
+        assert_eq!(
+            stripped,
+            "DD;${DD_TOKEN};ipv4;60;name;priority=2,OVH;user;${OVH_PASSWORD};host;ipv4;60,FD;${FD_TOKEN};ipv6;0"
+        );
+        // Everything else survives the round trip, escapes included
+        let kept = strip_secrets(
+            r"FD;${T};ipv4;0,OVH;u\,s\\er;${P};h;ipv4;60",
+            &mut placeholder,
+        );
+        assert_eq!(kept.unwrap(), r"FD;${T};ipv4;0,OVH;u\,s\\er;${P};h;ipv4;60");
+        assert!(strip_secrets("XX;tok", &mut placeholder).is_err());
+    }
+
     #[test]
     fn test_ovh_parsing() {
         // Format: OVH;USERNAME;PASSWORD;SUBDOMAIN;VERSION;POLL_SECS
@@ -398,9 +1209,110 @@ mod test {
 
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].get_ip_version().simple_name(), "ipv4");
-        assert_eq!(results[0].get_poll_secs(), 60);
-        assert!(results[0].file_name().contains("user123"));
-        assert!(results[0].file_name().contains("home.example.com"));
+        assert_eq!(
+            results[0].get_schedule(),
+            &Schedule::Every(Duration::from_secs(60))
+        );
+        // Account names stay out of the state file name, but were in older ones
+        assert!(results[0].file_name().starts_with("OVH_"));
+        assert!(!results[0].file_name().contains("user123"));
+        assert_eq!(
+            results[0].legacy_file_name(),
+            Some("OVH_user123_home.example.com_ipv4")
+        );
+    }
+
+    #[test]
+    fn test_state_file_names() {
+        let dd =
+            parse_dns_tuples("DD;secret-token;ipv4;60;home,DD;other-token;ipv6;60;home").unwrap();
+        assert!(!dd[0].file_name().contains("secret-token"));
+        // Stable across tokens, apart for each IP version
+        let same = parse_dns_tuples("DD;new-token;ipv4;60;home").unwrap();
+        assert_eq!(dd[0].file_name(), same[0].file_name());
+        assert_ne!(dd[0].file_name(), dd[1].file_name());
+        assert_eq!(dd[0].legacy_file_name(), Some("DuckDNS_secret-token_home"));
+    }
+
+    #[test]
+    fn test_cron_schedule_parsing() {
+        let input = "DD;tok;ipv4;*/5 8-22 * * *;name";
+        let results = parse_dns_tuples(input).expect("Should parse a cron schedule");
+        assert!(matches!(results[0].get_schedule(), Schedule::Cron(_)));
+
+        let input = "FD;tok;ipv4;0";
+        let results = parse_dns_tuples(input).expect("Should parse a one shot schedule");
+        assert_eq!(results[0].get_schedule(), &Schedule::Once);
+
+        assert!(parse_dns_tuples("FD;tok;ipv4;sometimes").is_err());
+    }
+
+    #[test]
+    fn test_options_parsing() {
+        let input =
+            "(FD;tok;ipv4;60;max_updates_per_day=12;flap_max_changes=3),DD;tok;ipv6;60;name;";
+        let results = parse_dns_tuples(input).expect("Should parse options");
+        assert_eq!(
+            results[0].options().max_updates_per_day,
+            NonZeroU32::new(12)
+        );
+        assert_eq!(results[0].options().flap_max_changes, Some(3));
+        assert_eq!(results[0].options().flap_window_mins, 60);
+        assert_eq!(results[1].options().max_updates_per_day, None);
+        assert_eq!(results[1].options().priority, 0);
+
+        let results = parse_dns_tuples("FD;tok;ipv4;60;priority=-5").unwrap();
+        assert_eq!(results[0].options().priority, -5);
+        assert!(results[0].options().enabled);
+        let results = parse_dns_tuples("FD;tok;ipv4;60;enabled=false").unwrap();
+        assert!(!results[0].options().enabled);
+        let results = parse_dns_tuples("FD;tok;ipv4;60;timeout_secs=5").unwrap();
+        assert_eq!(results[0].options().timeout_secs, Some(5));
+        let results = parse_dns_tuples(
+            r#"FD;tok;ipv4;60;ip_sources="ipify,https://ip.example";ip_source_timeout_secs=3"#,
+        )
+        .unwrap();
+        assert_eq!(
+            results[0].options().ip_sources,
+            [
+                SourceSpec::Ipify,
+                SourceSpec::Url("https://ip.example".to_string())
+            ]
+        );
+        assert_eq!(results[0].options().ip_source_timeout_secs, 3);
+        assert!(parse_dns_tuples("FD;tok;ipv4;60;ip_sources=ipfy").is_err());
+        let results =
+            parse_dns_tuples(r#"FD;tok;ipv4;60;blackout="04:00-04:30, 23:50-00:10""#).unwrap();
+        let blackout: Vec<String> = results[0]
+            .options()
+            .blackout
+            .iter()
+            .map(|w| String::from(*w))
+            .collect();
+        assert_eq!(blackout, ["04:00-04:30", "23:50-00:10"]);
+        assert!(parse_dns_tuples("FD;tok;ipv4;60;blackout=4h").is_err());
+
+        let unknown = parse_dns_tuples("FD;tok;ipv4;60;colour=blue");
+        assert_eq!(
+            unknown.unwrap_err(),
+            ParseError::UnknownOption("colour".to_string())
+        );
+        assert!(parse_dns_tuples("FD;tok;ipv4;60;max_updates_per_day=many").is_err());
+        assert!(matches!(
+            parse_dns_tuples("FD;tok;ipv4;60;max_updates_per_day=0"),
+            Err(ParseError::InvalidOption {
+                option: "max_updates_per_day",
+                ..
+            })
+        ));
+
+        // The documented defaults are the ones used
+        let defaults: Vec<_> = ProviderOptions::FIELDS
+            .iter()
+            .filter_map(|f| Some(format!("{}={}", f.name, f.default?)))
+            .collect();
+        let options = ProviderOptions::parse(defaults.iter().map(String::as_str)).unwrap();
+        assert_eq!(options, ProviderOptions::default());
     }
 
     #[test]
@@ -418,7 +1330,7 @@ mod test {
         let input = "OVH;user123;pass456;home.example.com;ipv4";
         let result = parse_dns_tuples(input);
         assert!(result.is_err(), "Should fail when parts are missing");
-        assert!(result.unwrap_err().contains("No POLL_SECS"));
+        assert!(result.unwrap_err().to_string().contains("No POLL_SECS"));
     }
 
     #[test]
@@ -426,7 +1338,12 @@ mod test {
         let input = "UNKNOWN;data1;data2";
         let result = parse_dns_tuples(input);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Invalid Dynamic Dns Type"));
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid Dynamic Dns Type")
+        );
     }
 
     #[test]
@@ -437,4 +1354,27 @@ mod test {
         let result = parse_dns_tuples(input);
         assert!(result.is_err(), "Empty segment between commas should fail");
     }
+
+    #[test]
+    fn test_update_error_kinds() {
+        let kind = |status| UpdateError::status("DuckDNS", status);
+        assert!(matches!(
+            kind(StatusCode::UNAUTHORIZED),
+            UpdateError::Auth { .. }
+        ));
+        assert!(matches!(
+            kind(StatusCode::FORBIDDEN),
+            UpdateError::Auth { .. }
+        ));
+        assert!(matches!(
+            kind(StatusCode::TOO_MANY_REQUESTS),
+            UpdateError::RateLimited { .. }
+        ));
+        let error = kind(StatusCode::BAD_GATEWAY);
+        assert!(matches!(error, UpdateError::Rejected { .. }));
+        assert_eq!(
+            error.to_string(),
+            "DuckDNS rejected the update: HTTP 502 Bad Gateway"
+        );
+    }
 }
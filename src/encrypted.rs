@@ -0,0 +1,267 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng, Payload},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    history::HistoryEntry,
+    persistence::{Error, ProviderState, StateStore, private_file_options},
+};
+
+/// Start of every file, authenticated along with the content so that a
+/// later format can't be mistaken for this one
+const MAGIC: &[u8] = b"dns-updater-state-v1\n";
+const NONCE_LEN: usize = 12;
+
+/// Everything the file holds, encrypted as a whole
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Document {
+    providers: BTreeMap<String, ProviderState>,
+    history: Vec<HistoryEntry>,
+}
+
+/// Every provider and the history in a single file encrypted with
+/// ChaCha20-Poly1305, for machines where not even the record names and past
+/// IPs may be stored in clear. The file is rewritten with a fresh nonce on
+/// every change, through a temporary file so that it's never half written.
+pub struct EncryptedStore {
+    path: PathBuf,
+    cipher: ChaCha20Poly1305,
+    /// Serializes the read-modify-write of each change
+    lock: Mutex<()>,
+}
+
+impl std::fmt::Debug for EncryptedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedStore {
+    /// Uses the file at `path`, created on the first change, with `key`
+    /// given as 64 hexadecimal digits
+    pub fn open(path: impl Into<PathBuf>, key: &str) -> Result<Self, Error> {
+        let key = hex::decode(key.trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or(Error::Backend(
+                "The state key must be 64 hexadecimal digits, e.g. from `openssl rand -hex 32`"
+                    .to_string(),
+            ))?;
+        Ok(Self {
+            path: path.into(),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Reads the key from `key_file`, e.g. a systemd credential
+    pub fn open_with_key_file(path: impl Into<PathBuf>, key_file: &Path) -> Result<Self, Error> {
+        let key = fs::read_to_string(key_file)
+            .map_err(|e| Error::Backend(format!("Couldn't read {}: {e}", key_file.display())))?;
+        Self::open(path, &key)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The decrypted content, empty when the file doesn't exist yet
+    fn read(&self) -> Result<Document, Error> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Document::default()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let invalid = || Error::Backend(format!("{} isn't a state file", self.path.display()));
+        let rest = content.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        if rest.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, msg) = rest.split_at(NONCE_LEN);
+        let json = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg, aad: MAGIC })
+            .map_err(|_| {
+                Error::Backend(format!(
+                    "Couldn't decrypt {}, wrong key or corrupted file",
+                    self.path.display()
+                ))
+            })?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    fn write(&self, document: &Document) -> Result<(), Error> {
+        let json = serde_json::to_vec(document)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &json,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| Error::Backend("Couldn't encrypt the state".to_string()))?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = private_file_options()
+            .write(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&nonce)?;
+        file.write_all(&encrypted)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Applies `f`, rewriting the file unless it returns false
+    fn change(&self, f: impl FnOnce(&mut Document) -> bool) -> Result<(), Error> {
+        let _lock = self.lock();
+        let mut document = self.read()?;
+        if f(&mut document) {
+            self.write(&document)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StateStore for EncryptedStore {
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error> {
+        let _lock = self.lock();
+        Ok(self.read()?.providers.remove(key).unwrap_or_default())
+    }
+
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error> {
+        self.change(|document| {
+            document.providers.insert(key.to_string(), state.clone());
+            true
+        })
+    }
+
+    async fn remove_state(&self, key: &str) -> Result<(), Error> {
+        self.change(|document| document.providers.remove(key).is_some())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error> {
+        let _lock = self.lock();
+        Ok(self
+            .read()?
+            .providers
+            .into_iter()
+            .filter(|(_, state)| state.ip.is_some())
+            .collect())
+    }
+
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        self.change(|document| {
+            document.history.push(entry.clone());
+            true
+        })
+    }
+
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let _lock = self.lock();
+        Ok(self.read()?.history)
+    }
+
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        self.change(|document| {
+            document.history = entries.to_vec();
+            true
+        })
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        self.change(|document| {
+            let len = document.history.len();
+            document.history.retain(|entry| entry.timestamp >= before);
+            document.history.len() < len
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use chrono::Utc;
+
+    use super::EncryptedStore;
+    use crate::{
+        history::HistoryEntry,
+        persistence::{ProviderState, StateStore},
+    };
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[tokio::test]
+    async fn test_encrypted_store() {
+        let path =
+            std::env::temp_dir().join(format!("dns_updater_encrypted_{}", std::process::id()));
+        let store = EncryptedStore::open(&path, KEY).unwrap();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+
+        assert_eq!(
+            store.load_state("DuckDNS_tok_name").await.unwrap(),
+            ProviderState::default()
+        );
+        let mut state = ProviderState::default();
+        state.record_success(ip, "OK".to_string(), Utc::now(), Duration::from_millis(120));
+        store.save_state("DuckDNS_tok_name", &state).await.unwrap();
+        assert_eq!(store.load_state("DuckDNS_tok_name").await.unwrap(), state);
+        store
+            .save_state("FreeDNS_tok_ipv4", &ProviderState::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list().await.unwrap(),
+            vec![("DuckDNS_tok_name".to_string(), state)]
+        );
+
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            provider: "DuckDNS".to_string(),
+            hostname: Some("name".to_string()),
+            old_ip: None,
+            new_ip: ip,
+            error: None,
+            latency_ms: Some(80),
+            networks: None,
+        };
+        store.append_history(&entry).await.unwrap();
+        store.append_history(&entry).await.unwrap();
+        assert_eq!(store.history().await.unwrap().len(), 2);
+        store
+            .replace_history(std::slice::from_ref(&entry))
+            .await
+            .unwrap();
+        assert_eq!(store.history().await.unwrap(), vec![entry]);
+
+        // Nothing readable on disk, and another key can't read it
+        let content = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("name"));
+        let other = EncryptedStore::open(&path, &KEY.replace('0', "f")).unwrap();
+        assert!(other.history().await.is_err());
+        assert!(EncryptedStore::open(&path, "abcd").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
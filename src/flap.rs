@@ -0,0 +1,89 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Detects an IP address that changes too often.
+///
+/// Once more than `max_changes` changes are seen within `window` the detector
+/// is dampened: changes should only be published after the IP stayed the same
+/// for `debounce`. It leaves that state when the change rate drops again.
+#[derive(Debug, Clone)]
+pub struct FlapDetector {
+    max_changes: u32,
+    window: Duration,
+    debounce: Duration,
+    changes: VecDeque<Instant>,
+    dampened: bool,
+}
+
+impl FlapDetector {
+    pub fn new(max_changes: u32, window: Duration, debounce: Duration) -> Self {
+        Self {
+            max_changes,
+            window,
+            debounce,
+            changes: VecDeque::new(),
+            dampened: false,
+        }
+    }
+
+    pub fn debounce(&self) -> Duration {
+        self.debounce
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(first) = self.changes.front()
+            && now.duration_since(*first) > self.window
+        {
+            self.changes.pop_front();
+        }
+    }
+
+    /// Records a detected change, returns `true` when it makes the detector
+    /// enter the dampened state.
+    pub fn record(&mut self, now: Instant) -> bool {
+        self.prune(now);
+        self.changes.push_back(now);
+        if !self.dampened && self.changes.len() > self.max_changes as usize {
+            self.dampened = true;
+            return true;
+        }
+        false
+    }
+
+    /// Whether changes should currently be debounced.
+    pub fn is_dampened(&mut self, now: Instant) -> bool {
+        self.prune(now);
+        if self.dampened && self.changes.len() <= self.max_changes as usize {
+            tracing::info!("IP stopped flapping, leaving dampened state");
+            self.dampened = false;
+        }
+        self.dampened
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::FlapDetector;
+
+    #[test]
+    fn test_flap_detection() {
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut flap = FlapDetector::new(2, 10 * minute, minute);
+
+        assert!(!flap.record(start));
+        assert!(!flap.record(start + minute));
+        assert!(!flap.is_dampened(start + minute));
+        assert!(flap.record(start + 2 * minute));
+        assert!(flap.is_dampened(start + 2 * minute));
+        // Already dampened, no new transition
+        assert!(!flap.record(start + 3 * minute));
+
+        // Two changes fell out of the window, the rate is acceptable again
+        assert!(!flap.is_dampened(start + 12 * minute));
+    }
+}
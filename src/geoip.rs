@@ -0,0 +1,357 @@
+//! Who announces an IP: its autonomous system, ISP and country, looked up on
+//! every IP change so it tells whether the ISP changed or only the lease.
+
+use std::{fmt, net::IpAddr, path::PathBuf, sync::Arc};
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{secrets::Secret, vcr};
+
+const IPINFO_URL: &str = "https://ipinfo.io";
+
+/// The network an IP belongs to, each part being `None` when unknown
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Network {
+    /// Number of its autonomous system, e.g. 3352
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// Organization running the autonomous system, usually the ISP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isp: Option<String>,
+    /// ISO 3166 code of the country, e.g. `ES`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+impl Network {
+    pub fn is_empty(&self) -> bool {
+        self.asn.is_none() && self.isp.is_none() && self.country.is_none()
+    }
+
+    /// Whether both are announced by the same autonomous system, or named
+    /// after the same ISP when either number is unknown. `None` when
+    /// neither is known.
+    pub fn same_isp(&self, other: &Network) -> Option<bool> {
+        match (self.asn, other.asn, &self.isp, &other.isp) {
+            (Some(asn), Some(other), _, _) => Some(asn == other),
+            (_, _, Some(isp), Some(other)) => Some(isp.eq_ignore_ascii_case(other)),
+            _ => None,
+        }
+    }
+}
+
+/// e.g. `AS3352 Telefonica de Espana (ES)`
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(asn) = self.asn {
+            parts.push(format!("AS{asn}"));
+        }
+        if let Some(isp) = &self.isp {
+            parts.push(isp.clone());
+        }
+        if let Some(country) = &self.country {
+            parts.push(format!("({country})"));
+        }
+        match parts.is_empty() {
+            true => f.write_str("unknown network"),
+            false => f.write_str(&parts.join(" ")),
+        }
+    }
+}
+
+/// The networks of both IPs of a change
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Networks {
+    /// `None` for the first IP, or when it couldn't be looked up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old: Option<Network>,
+    pub new: Network,
+}
+
+impl Networks {
+    /// Whether the ISP changed along with the IP, `None` when unknown
+    pub fn isp_changed(&self) -> Option<bool> {
+        self.old
+            .as_ref()
+            .and_then(|old| old.same_isp(&self.new))
+            .map(|same| !same)
+    }
+
+    /// One line telling users what the change means, e.g. `Same ISP, new
+    /// lease: AS3352 Telefonica de Espana (ES)`
+    pub fn summary(&self) -> String {
+        match (self.isp_changed(), &self.old) {
+            (Some(true), Some(old)) => format!("ISP changed: {old} -> {}", self.new),
+            (Some(false), _) => format!("Same ISP, new lease: {}", self.new),
+            (_, Some(old)) => format!("Network: {old} -> {}", self.new),
+            (_, None) => format!("Network: {}", self.new),
+        }
+    }
+}
+
+/// Where the networks of IPs are looked up, the `geoip` table of a
+/// configuration file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "source", rename_all = "lowercase", deny_unknown_fields)]
+pub enum GeoIpConfig {
+    /// The ipinfo.io API, anonymously within its free quota or with a token
+    Ipinfo {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<Secret>,
+    },
+    /// Local MaxMind databases, with the `geoip` feature: an ASN (or ISP)
+    /// one such as `GeoLite2-ASN.mmdb`, a country (or city) one, or both
+    Mmdb {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        asn: Option<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        country: Option<PathBuf>,
+    },
+}
+
+impl GeoIpConfig {
+    /// Opens the databases to look IPs up in
+    pub fn open(&self) -> Result<GeoIp, String> {
+        let source = match self {
+            GeoIpConfig::Ipinfo { token } => Source::Ipinfo {
+                token: token.clone(),
+            },
+            #[cfg(feature = "geoip")]
+            GeoIpConfig::Mmdb { asn, country } => {
+                if asn.is_none() && country.is_none() {
+                    return Err("The mmdb GeoIP source needs an asn or country database".into());
+                }
+                let open = |path: &PathBuf| {
+                    maxminddb::Reader::open_readfile(path)
+                        .map(Box::new)
+                        .map_err(|e| format!("Couldn't open {}: {e}", path.display()))
+                };
+                Source::Mmdb {
+                    asn: asn.as_ref().map(open).transpose()?,
+                    country: country.as_ref().map(open).transpose()?,
+                }
+            }
+            #[cfg(not(feature = "geoip"))]
+            GeoIpConfig::Mmdb { .. } => {
+                return Err("dns_updater was built without the geoip feature".to_string());
+            }
+        };
+        Ok(GeoIp(Arc::new(source)))
+    }
+}
+
+/// Looks up the networks of IPs, see [`GeoIpConfig::open`]. Clones share
+/// the databases.
+#[derive(Debug, Clone)]
+pub struct GeoIp(Arc<Source>);
+
+#[derive(Debug)]
+enum Source {
+    Ipinfo {
+        token: Option<Secret>,
+    },
+    #[cfg(feature = "geoip")]
+    Mmdb {
+        asn: Option<Box<maxminddb::Reader<Vec<u8>>>>,
+        country: Option<Box<maxminddb::Reader<Vec<u8>>>>,
+    },
+}
+
+/// What ipinfo.io answers, `org` being e.g. `AS3352 Telefonica de Espana`
+#[derive(Deserialize)]
+struct IpinfoAnswer {
+    org: Option<String>,
+    country: Option<String>,
+}
+
+impl GeoIp {
+    pub async fn lookup(&self, client: &Client, ip: IpAddr) -> Result<Network, String> {
+        match &*self.0 {
+            Source::Ipinfo { token } => {
+                let mut request = client.get(format!("{IPINFO_URL}/{ip}/json"));
+                if let Some(token) = token {
+                    request = request.bearer_auth(token.expose());
+                }
+                let response = vcr::send(request)
+                    .await
+                    .map_err(|e| e.without_url().to_string())?;
+                if !response.status().is_success() {
+                    return Err(format!("ipinfo.io answered HTTP {}", response.status()));
+                }
+                let body = response
+                    .text()
+                    .await
+                    .map_err(|e| e.without_url().to_string())?;
+                let answer: IpinfoAnswer = serde_json::from_str(&body)
+                    .map_err(|e| format!("Invalid ipinfo.io answer: {e}"))?;
+                // Reserved IPs have no organization
+                let (asn, isp) = match answer.org.as_deref().map(|org| org.split_once(' ')) {
+                    Some(Some((asn, isp))) => match asn.strip_prefix("AS").map(str::parse) {
+                        Some(Ok(asn)) => (Some(asn), Some(isp.to_string())),
+                        _ => (None, answer.org.clone()),
+                    },
+                    _ => (None, answer.org.clone()),
+                };
+                Ok(Network {
+                    asn,
+                    isp,
+                    country: answer.country,
+                })
+            }
+            #[cfg(feature = "geoip")]
+            Source::Mmdb { asn, country } => {
+                use maxminddb::geoip2;
+
+                let mut network = Network::default();
+                if let Some(reader) = asn
+                    && let Some(asn) = found(reader.lookup::<geoip2::Asn>(ip))?
+                {
+                    network.asn = asn.autonomous_system_number;
+                    network.isp = asn.autonomous_system_organization.map(str::to_string);
+                }
+                if let Some(reader) = country
+                    && let Some(country) = found(reader.lookup::<geoip2::Country>(ip))?
+                {
+                    network.country = country.country.and_then(|c| c.iso_code).map(str::to_string);
+                }
+                Ok(network)
+            }
+        }
+    }
+
+    /// The networks of a change to `new_ip`, logging why they couldn't be
+    /// looked up. `None` when the new IP's network is unknown.
+    pub async fn networks(
+        &self,
+        client: &Client,
+        old_ip: Option<IpAddr>,
+        new_ip: IpAddr,
+    ) -> Option<Networks> {
+        let lookup = |ip| async move {
+            match self.lookup(client, ip).await {
+                Ok(network) if !network.is_empty() => Some(network),
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::warn!(%ip, error = %e, "Couldn't look up the network of the IP");
+                    None
+                }
+            }
+        };
+        let new = lookup(new_ip).await?;
+        let old = match old_ip {
+            Some(ip) => lookup(ip).await,
+            None => None,
+        };
+        Some(Networks { old, new })
+    }
+}
+
+/// The record of a database lookup, `None` for IPs it doesn't know, e.g.
+/// private ones
+#[cfg(feature = "geoip")]
+fn found<T>(record: Result<T, maxminddb::MaxMindDBError>) -> Result<Option<T>, String> {
+    match record {
+        Ok(record) => Ok(Some(record)),
+        Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use reqwest::Client;
+
+    use super::{GeoIpConfig, Network, Networks};
+    use crate::vcr::{Cassette, Interaction};
+
+    fn answer(ip: &str, body: &str) -> Interaction {
+        Interaction {
+            method: "GET".to_string(),
+            url: format!("https://ipinfo.io/{ip}/json"),
+            status: 200,
+            body: body.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ipinfo() {
+        let geoip = GeoIpConfig::Ipinfo { token: None }.open().unwrap();
+        let cassette = Cassette::replay(vec![
+            answer(
+                "192.0.2.1",
+                r#"{"ip": "192.0.2.1", "org": "AS3352 Telefonica de Espana", "country": "ES"}"#,
+            ),
+            answer(
+                "192.0.2.2",
+                r#"{"ip": "192.0.2.2", "org": "AS3352 Telefonica de Espana", "country": "ES"}"#,
+            ),
+            answer("10.0.0.1", r#"{"ip": "10.0.0.1", "bogon": true}"#),
+        ]);
+        let client = Client::new();
+        let (old, new): (IpAddr, IpAddr) =
+            ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let (networks, bogon) = cassette
+            .scope(async {
+                (
+                    geoip.networks(&client, Some(old), new).await,
+                    geoip
+                        .networks(&client, None, "10.0.0.1".parse().unwrap())
+                        .await,
+                )
+            })
+            .await;
+        let networks = networks.unwrap();
+        assert_eq!(networks.new.asn, Some(3352));
+        assert_eq!(networks.isp_changed(), Some(false));
+        assert_eq!(
+            networks.summary(),
+            "Same ISP, new lease: AS3352 Telefonica de Espana (ES)"
+        );
+        assert_eq!(bogon, None);
+    }
+
+    #[test]
+    fn test_isp_changed() {
+        let network = |asn, isp: &str| Network {
+            asn,
+            isp: Some(isp.to_string()),
+            country: None,
+        };
+        let networks = Networks {
+            old: Some(network(Some(3352), "Telefonica")),
+            new: network(Some(12479), "Orange"),
+        };
+        assert_eq!(
+            networks.summary(),
+            "ISP changed: AS3352 Telefonica -> AS12479 Orange"
+        );
+        let networks = Networks {
+            old: Some(network(None, "ORANGE")),
+            new: network(Some(12479), "Orange"),
+        };
+        assert_eq!(networks.isp_changed(), Some(false));
+        let networks = Networks {
+            old: None,
+            new: Network::default(),
+        };
+        assert_eq!(networks.summary(), "Network: unknown network");
+    }
+
+    #[test]
+    fn test_mmdb_errors() {
+        let config = GeoIpConfig::Mmdb {
+            asn: None,
+            country: None,
+        };
+        assert!(config.open().is_err());
+        let config = GeoIpConfig::Mmdb {
+            asn: Some("/nonexistent/GeoLite2-ASN.mmdb".into()),
+            country: None,
+        };
+        assert!(config.open().is_err());
+    }
+}
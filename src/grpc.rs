@@ -0,0 +1,290 @@
+//! The gRPC control plane of `proto/dns_updater.proto`, for orchestrators
+//! managing many updaters: the status, streamed after every change, forced
+//! updates and reloads. The messages below mirror those of the definitions,
+//! which are shipped with the crate for clients to generate theirs from.
+
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status, metadata::MetadataMap};
+
+use crate::{
+    health::{Health, ProviderHealth},
+    secrets::Secret,
+};
+
+mod service {
+    include!(concat!(env!("OUT_DIR"), "/dns_updater.v1.Control.rs"));
+}
+
+pub use service::control_server::{Control, ControlServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatusRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatusReply {
+    #[prost(bool, tag = "1")]
+    pub healthy: bool,
+    #[prost(bool, tag = "2")]
+    pub ready: bool,
+    #[prost(message, repeated, tag = "3")]
+    pub providers: Vec<ProviderStatus>,
+}
+
+/// A [`ProviderHealth`] with its IPs and times as strings
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProviderStatus {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub provider: String,
+    #[prost(string, optional, tag = "3")]
+    pub hostname: Option<String>,
+    #[prost(string, tag = "4")]
+    pub ip_version: String,
+    #[prost(string, optional, tag = "5")]
+    pub detected_ip: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub published_ip: Option<String>,
+    /// In RFC 3339
+    #[prost(string, optional, tag = "7")]
+    pub last_detection: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub detection_error: Option<String>,
+    /// In RFC 3339
+    #[prost(string, optional, tag = "9")]
+    pub last_update: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub update_error: Option<String>,
+    #[prost(uint32, tag = "11")]
+    pub consecutive_failures: u32,
+    #[prost(bool, tag = "12")]
+    pub paused: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TriggerUpdateRequest {
+    /// Every provider when `None`
+    #[prost(uint64, optional, tag = "1")]
+    pub provider: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TriggerUpdateReply {
+    #[prost(uint32, tag = "1")]
+    pub forced: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReloadRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReloadReply {}
+
+impl From<ProviderHealth> for ProviderStatus {
+    fn from(health: ProviderHealth) -> Self {
+        Self {
+            id: health.id,
+            provider: health.provider,
+            hostname: health.hostname,
+            ip_version: health.ip_version,
+            detected_ip: health.detected_ip.map(|ip| ip.to_string()),
+            published_ip: health.published_ip.map(|ip| ip.to_string()),
+            last_detection: health.last_detection.map(|time| time.to_rfc3339()),
+            detection_error: health.detection_error,
+            last_update: health.last_update.map(|time| time.to_rfc3339()),
+            update_error: health.update_error,
+            consecutive_failures: health.consecutive_failures,
+            paused: health.paused,
+        }
+    }
+}
+
+/// What the control plane manages, and the token it asks for
+#[derive(Clone)]
+pub struct ControlPlane {
+    pub health: Health,
+    /// Asks for a reload with its cause, `None` when the configuration can't
+    /// be reloaded
+    pub reload: Option<mpsc::UnboundedSender<&'static str>>,
+    /// `None` to answer every call
+    pub token: Option<Secret>,
+}
+
+impl ControlPlane {
+    fn snapshot(&self) -> StatusReply {
+        StatusReply {
+            healthy: self.health.is_healthy(),
+            ready: self.health.is_ready(),
+            providers: self
+                .health
+                .providers()
+                .into_iter()
+                .map(ProviderStatus::from)
+                .collect(),
+        }
+    }
+
+    /// Whether `metadata` carry the token as `authorization: Bearer TOKEN`
+    fn authorize(&self, metadata: &MetadataMap) -> Result<(), Status> {
+        let Some(expected) = &self.token else {
+            return Ok(());
+        };
+        let token = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if expected.matches(token.trim()) => Ok(()),
+            _ => Err(Status::unauthenticated("Missing or wrong token")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlPlane {
+    async fn status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<StatusReply>, Status> {
+        self.authorize(request.metadata())?;
+        Ok(Response::new(self.snapshot()))
+    }
+
+    type WatchStatusStream = ReceiverStream<Result<StatusReply, Status>>;
+
+    async fn watch_status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<Self::WatchStatusStream>, Status> {
+        self.authorize(request.metadata())?;
+        let (tx, rx) = mpsc::channel(1);
+        let plane = self.clone();
+        tokio::spawn(async move {
+            loop {
+                // Created before reading the status, so no change is missed
+                let changed = plane.health.changed();
+                if tx.send(Ok(plane.snapshot())).await.is_err() {
+                    return;
+                }
+                tokio::select! {
+                    _ = changed => {}
+                    _ = tx.closed() => return,
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn trigger_update(
+        &self,
+        request: Request<TriggerUpdateRequest>,
+    ) -> Result<Response<TriggerUpdateReply>, Status> {
+        self.authorize(request.metadata())?;
+        let provider = request.into_inner().provider;
+        match self.health.force_update(provider) {
+            0 if provider.is_some() => Err(Status::not_found("No running provider with this id")),
+            forced => Ok(Response::new(TriggerUpdateReply {
+                forced: forced as u32,
+            })),
+        }
+    }
+
+    async fn reload(
+        &self,
+        request: Request<ReloadRequest>,
+    ) -> Result<Response<ReloadReply>, Status> {
+        self.authorize(request.metadata())?;
+        match self.reload.as_ref().map(|reload| reload.send("gRPC")) {
+            Some(Ok(())) => Ok(Response::new(ReloadReply {})),
+            _ => Err(Status::failed_precondition(
+                "The configuration can't be reloaded",
+            )),
+        }
+    }
+}
+
+/// Serves the control plane on `listener` until it fails
+pub async fn serve(
+    listener: TcpListener,
+    plane: ControlPlane,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(ControlServer::new(plane))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+    use tokio_stream::StreamExt;
+    use tonic::{Code, Request};
+
+    use super::{Control, ControlPlane, ReloadRequest, StatusRequest, TriggerUpdateRequest};
+    use crate::{IpVersion, health::Health};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn authorized<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let token = "Bearer s3cret".parse().unwrap();
+        request.metadata_mut().insert("authorization", token);
+        request
+    }
+
+    #[tokio::test]
+    async fn test_control_plane() {
+        let health = Health::default();
+        let reporter = health.register("DuckDNS", Some("home"), IpVersion::V4);
+        let (reload, mut reloads) = mpsc::unbounded_channel();
+        let plane = ControlPlane {
+            health: health.clone(),
+            reload: Some(reload),
+            token: Some("s3cret".into()),
+        };
+
+        let error = plane.status(Request::new(StatusRequest {})).await;
+        assert_eq!(error.unwrap_err().code(), Code::Unauthenticated);
+        let status = plane.status(authorized(StatusRequest {})).await.unwrap();
+        let status = status.into_inner();
+        assert!(!status.ready);
+        assert_eq!(status.providers[0].hostname.as_deref(), Some("home"));
+
+        let mut watch = plane
+            .watch_status(authorized(StatusRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let first = watch.next().await.unwrap().unwrap();
+        assert_eq!(first.providers[0].detected_ip, None);
+        reporter.detected(IP);
+        let next = tokio::time::timeout(Duration::from_secs(1), watch.next());
+        let next = next.await.unwrap().unwrap().unwrap();
+        assert!(next.ready);
+        assert_eq!(next.providers[0].detected_ip.as_deref(), Some("192.0.2.1"));
+
+        let id = health.providers()[0].id;
+        let forced = plane
+            .trigger_update(authorized(TriggerUpdateRequest { provider: Some(id) }))
+            .await
+            .unwrap();
+        assert_eq!(forced.into_inner().forced, 1);
+        tokio::time::timeout(Duration::from_secs(1), reporter.forced())
+            .await
+            .unwrap();
+        let missing = TriggerUpdateRequest {
+            provider: Some(id + 1),
+        };
+        let error = plane.trigger_update(authorized(missing)).await.unwrap_err();
+        assert_eq!(error.code(), Code::NotFound);
+
+        plane.reload(authorized(ReloadRequest {})).await.unwrap();
+        assert_eq!(reloads.recv().await, Some("gRPC"));
+    }
+}
@@ -0,0 +1,698 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{Notify, futures::Notified, watch},
+};
+
+use crate::{
+    IpVersion, SimpleName,
+    geoip::Network,
+    persistence::{ProviderState, write_private},
+    secrets::Secret,
+    stats::{IpStability, UpdateStats},
+};
+
+/// How a provider is doing, as reported by the runner
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProviderHealth {
+    /// Identifies the provider while it runs, e.g. to [`Health::set_paused`]
+    pub id: u64,
+    pub provider: String,
+    pub hostname: Option<String>,
+    pub ip_version: String,
+    /// The IP detected last
+    pub detected_ip: Option<IpAddr>,
+    /// The IP published last, `None` when it never was
+    pub published_ip: Option<IpAddr>,
+    /// Last time its IP was detected
+    pub last_detection: Option<DateTime<Utc>>,
+    /// Why the latest detection failed, if it did
+    pub detection_error: Option<String>,
+    /// Detections that failed in a row
+    pub detection_failures: u32,
+    /// Last time an update was accepted
+    pub last_update: Option<DateTime<Utc>>,
+    /// Why the latest update failed, if it did
+    pub update_error: Option<String>,
+    /// Updates that failed in a row
+    pub consecutive_failures: u32,
+    /// `None` until it was updated at least once
+    pub stats: Option<UpdateStats>,
+    /// Whether its updates are held back, see [`Health::set_paused`]
+    pub paused: bool,
+    /// Who announces the IP it changed to last, when `geoip` is configured
+    pub network: Option<Network>,
+    /// When its IPs were published, oldest first, see [`ProviderHealth::stability`]
+    #[serde(skip)]
+    pub published_at: Vec<DateTime<Utc>>,
+}
+
+impl ProviderHealth {
+    /// Whether its latest detection and update succeeded
+    pub fn is_healthy(&self) -> bool {
+        self.detection_error.is_none() && self.update_error.is_none()
+    }
+
+    /// How long its IPs last, as of `now`
+    pub fn stability(&self, now: DateTime<Utc>) -> Option<IpStability> {
+        IpStability::of(&self.published_at, now)
+    }
+
+    /// Whether it tried to detect its IP at least once
+    pub fn tried_detection(&self) -> bool {
+        self.last_detection.is_some() || self.detection_error.is_some()
+    }
+}
+
+/// Health of every running provider, shared between the runner and the
+/// `/healthz` and `/readyz` endpoints of [`serve`]
+#[derive(Debug, Clone, Default)]
+pub struct Health(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    providers: Mutex<Providers>,
+    /// Woken on every report
+    changed: Notify,
+}
+
+/// Providers by the order they were registered in
+#[derive(Debug, Default)]
+struct Providers {
+    next: u64,
+    providers: BTreeMap<u64, ProviderHealth>,
+    controls: BTreeMap<u64, Arc<Control>>,
+}
+
+/// What a running provider is asked to do, through [`Health`]
+#[derive(Debug)]
+struct Control {
+    paused: watch::Sender<bool>,
+    forced: Notify,
+}
+
+impl Health {
+    fn lock(&self) -> MutexGuard<'_, Providers> {
+        self.0.providers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts tracking a provider until every clone of the returned reporter
+    /// is dropped, e.g. when it's stopped on reload
+    pub fn register(
+        &self,
+        provider: &str,
+        hostname: Option<&str>,
+        ip_version: IpVersion,
+    ) -> Reporter {
+        let mut providers = self.lock();
+        let id = providers.next;
+        providers.next += 1;
+        providers.providers.insert(
+            id,
+            ProviderHealth {
+                id,
+                provider: provider.to_string(),
+                hostname: hostname.map(str::to_string),
+                ip_version: ip_version.simple_name().to_string(),
+                ..Default::default()
+            },
+        );
+        let control = Arc::new(Control {
+            paused: watch::Sender::new(false),
+            forced: Notify::new(),
+        });
+        providers.controls.insert(id, control.clone());
+        Reporter(Arc::new(Registration {
+            health: self.clone(),
+            id,
+            control,
+        }))
+    }
+
+    pub fn providers(&self) -> Vec<ProviderHealth> {
+        self.lock().providers.values().cloned().collect()
+    }
+
+    /// Whether every provider detected its IP at least once
+    pub fn is_ready(&self) -> bool {
+        self.lock()
+            .providers
+            .values()
+            .all(|p| p.last_detection.is_some())
+    }
+
+    /// Waits until providers are running and each of them tried to detect
+    /// its IP at least once, successfully or not
+    pub async fn detection_tried(&self) {
+        loop {
+            // Created before checking, so no report is missed
+            let changed = self.changed();
+            let providers = self.providers();
+            if !providers.is_empty() && providers.iter().all(ProviderHealth::tried_detection) {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Completes on the next report, including one made after this is
+    /// called but before it's awaited
+    pub fn changed(&self) -> Notified<'_> {
+        self.0.changed.notified()
+    }
+
+    /// Whether the latest detection and update of every provider succeeded
+    pub fn is_healthy(&self) -> bool {
+        self.lock()
+            .providers
+            .values()
+            .all(ProviderHealth::is_healthy)
+    }
+
+    /// Holds back the updates of the provider `id` until resumed, its IP
+    /// still being detected. Returns whether it's running.
+    pub fn set_paused(&self, id: u64, paused: bool) -> bool {
+        let mut providers = self.lock();
+        let Some(control) = providers.controls.get(&id) else {
+            return false;
+        };
+        control.paused.send_replace(paused);
+        if let Some(health) = providers.providers.get_mut(&id) {
+            health.paused = paused;
+        }
+        drop(providers);
+        self.0.changed.notify_waiters();
+        true
+    }
+
+    /// Publishes the latest detected IP of the provider `id`, or of every
+    /// provider, again even if it didn't change, once resumed for paused
+    /// ones. Returns how many providers were asked to.
+    pub fn force_update(&self, id: Option<u64>) -> usize {
+        let providers = self.lock();
+        let mut forced = 0;
+        for (_, control) in providers
+            .controls
+            .iter()
+            .filter(|(running, _)| id.is_none_or(|id| **running == id))
+        {
+            control.forced.notify_one();
+            forced += 1;
+        }
+        forced
+    }
+}
+
+struct Registration {
+    health: Health,
+    id: u64,
+    control: Arc<Control>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut providers = self.health.lock();
+        providers.providers.remove(&self.id);
+        providers.controls.remove(&self.id);
+    }
+}
+
+/// Records the outcomes of one provider in its [`Health`]
+#[derive(Clone)]
+pub struct Reporter(Arc<Registration>);
+
+impl Reporter {
+    fn change(&self, f: impl FnOnce(&mut ProviderHealth)) {
+        if let Some(health) = self.0.health.lock().providers.get_mut(&self.0.id) {
+            f(health);
+        }
+        self.0.health.0.changed.notify_waiters();
+    }
+
+    /// Takes over how the updates went from the state of the provider, once
+    /// it's loaded and after every update
+    pub fn record(&self, state: &ProviderState) {
+        self.change(|health| {
+            health.published_ip = state.ip;
+            health.last_update = state.last_success;
+            health.consecutive_failures = state.consecutive_failures;
+            health.update_error = match state.consecutive_failures {
+                0 => None,
+                _ => state.last_error.clone(),
+            };
+            health.stats = UpdateStats::of(state);
+        });
+    }
+
+    pub fn detected(&self, ip: IpAddr) {
+        self.change(|health| {
+            health.detected_ip = Some(ip);
+            health.last_detection = Some(Utc::now());
+            health.detection_error = None;
+            health.detection_failures = 0;
+        });
+    }
+
+    /// Takes over when its IPs were published, from the history, once it's
+    /// loaded
+    pub fn published_at(&self, published_at: Vec<DateTime<Utc>>) {
+        self.change(|health| health.published_at = published_at);
+    }
+
+    /// Records that a new IP was published at `at`
+    pub fn published(&self, at: DateTime<Utc>) {
+        self.change(|health| health.published_at.push(at));
+    }
+
+    pub fn network(&self, network: Network) {
+        self.change(|health| health.network = Some(network));
+    }
+
+    pub fn detection_failed(&self, error: &str) {
+        self.change(|health| {
+            health.detection_error = Some(error.to_string());
+            health.detection_failures += 1;
+        });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.0.control.paused.borrow()
+    }
+
+    /// Completes once the provider isn't paused
+    pub async fn unpaused(&self) {
+        // The sender lives as long as the reporter
+        let _ = self
+            .0
+            .control
+            .paused
+            .subscribe()
+            .wait_for(|paused| !paused)
+            .await;
+    }
+
+    /// Completes when an update is forced, including one forced before this
+    /// is called
+    pub async fn forced(&self) {
+        self.0.control.forced.notified().await
+    }
+}
+
+/// Longest a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8192;
+
+/// Who may query the endpoints of [`serve`], and how
+#[derive(Clone, Default)]
+pub struct Access {
+    /// Asked for as `Authorization: Bearer TOKEN` when set
+    pub token: Option<Secret>,
+    /// Serves HTTPS instead of HTTP when set
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsAcceptor>,
+}
+
+/// Answers health probes on `listener` until dropped: `GET /healthz` is 200
+/// while every provider is healthy and 503 otherwise, `GET /readyz` is 200
+/// once every provider detected its IP. Both list the providers as JSON.
+pub async fn serve(listener: TcpListener, health: Health, access: Access) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!(error = %e, "Couldn't accept a health probe");
+                continue;
+            }
+        };
+        let (health, access) = (health.clone(), access.clone());
+        tokio::spawn(async move {
+            #[cfg(feature = "tls")]
+            let responded = match &access.tls {
+                Some(acceptor) => match crate::tls::accept(acceptor, stream).await {
+                    Ok(stream) => respond(stream, &health, &access).await,
+                    Err(e) => Err(e),
+                },
+                None => respond(stream, &health, &access).await,
+            };
+            #[cfg(not(feature = "tls"))]
+            let responded = respond(stream, &health, &access).await;
+            if let Err(e) = responded {
+                tracing::debug!(error = %e, "Couldn't answer a health probe");
+            }
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct Body {
+    status: &'static str,
+    time: DateTime<Utc>,
+    providers: Vec<ProviderHealth>,
+}
+
+impl Body {
+    fn new(health: &Health, ok: bool) -> Self {
+        Self {
+            status: if ok { "ok" } else { "failing" },
+            time: Utc::now(),
+            providers: health.providers(),
+        }
+    }
+}
+
+/// Rewrites `path` with the health of every provider after each report,
+/// like the body of `/healthz`, for scripts and dashboards reading it
+/// instead of probing. Never returns.
+pub async fn write_status(path: PathBuf, health: Health) {
+    let mut failing = false;
+    loop {
+        let changed = health.changed();
+        let json =
+            serde_json::to_vec_pretty(&Body::new(&health, health.is_healthy())).unwrap_or_default();
+        match write_private(&path, &json).await {
+            Ok(()) => failing = false,
+            // Only once until it works again, it's retried on every report
+            Err(e) if !failing => {
+                tracing::warn!(path = %path.display(), error = %e, "Couldn't write the status file");
+                failing = true;
+            }
+            Err(_) => {}
+        }
+        changed.await;
+    }
+}
+
+async fn respond(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    health: &Health,
+    access: &Access,
+) -> std::io::Result<()> {
+    let Some(request) = read_head(&mut stream).await? else {
+        return Ok(());
+    };
+    let mut words = request.split_whitespace();
+    let (method, path) = (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+    );
+    // Probes may add a query string
+    let path = path.split('?').next().unwrap_or_default();
+    if let Some(expected) = &access.token {
+        let token = request
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .and_then(|(_, value)| value.trim().strip_prefix("Bearer "));
+        if !token.is_some_and(|token| expected.matches(token.trim())) {
+            return write(
+                &mut stream,
+                "401 Unauthorized",
+                "text/plain",
+                "Missing or wrong token\n",
+            )
+            .await;
+        }
+    }
+    let ok = match path {
+        "/healthz" => health.is_healthy(),
+        "/readyz" => health.is_ready(),
+        "/metrics" => true,
+        _ => return write(&mut stream, "404 Not Found", "text/plain", "Not found\n").await,
+    };
+    if method != "GET" && method != "HEAD" {
+        return write(&mut stream, "405 Method Not Allowed", "text/plain", "").await;
+    }
+    if path == "/metrics" {
+        let metrics = metrics(&health.providers());
+        let metrics = if method == "HEAD" { "" } else { &metrics };
+        return write(&mut stream, "200 OK", METRICS_CONTENT_TYPE, metrics).await;
+    }
+    let json = serde_json::to_string(&Body::new(health, ok)).unwrap_or_default() + "\n";
+    let status = if ok {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let json = if method == "HEAD" { "" } else { &json };
+    write(&mut stream, status, "application/json", json).await
+}
+
+/// The request line and headers of an HTTP request, `None` when the client
+/// took too long to send them
+pub(crate) async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> std::io::Result<Option<String>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        std::io::Result::Ok(())
+    };
+    match tokio::time::timeout(REQUEST_TIMEOUT, read).await {
+        Ok(read) => read.map(|()| Some(String::from_utf8_lossy(&request).into_owned())),
+        Err(_) => Ok(None),
+    }
+}
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Name, help and value of a gauge of [`metrics`]
+type StabilityGauge = (&'static str, &'static str, fn(&IpStability) -> Option<f64>);
+
+/// The update counts, latencies and IP stability of every provider, in the
+/// Prometheus text format
+fn metrics(providers: &[ProviderHealth]) -> String {
+    let labels = |p: &ProviderHealth| {
+        let escape = |value: &str| {
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        };
+        format!(
+            "provider=\"{}\",hostname=\"{}\",ip_version=\"{}\"",
+            escape(&p.provider),
+            escape(p.hostname.as_deref().unwrap_or("-")),
+            p.ip_version
+        )
+    };
+    let mut out = String::new();
+    out.push_str("# HELP dns_updater_updates_total Updates attempted, by result\n");
+    out.push_str("# TYPE dns_updater_updates_total counter\n");
+    for p in providers {
+        let (successes, failures) = match &p.stats {
+            Some(stats) => (stats.attempts - stats.failures, stats.failures),
+            None => (0, 0),
+        };
+        let labels = labels(p);
+        out.push_str(&format!(
+            "dns_updater_updates_total{{{labels},result=\"success\"}} {successes}\n"
+        ));
+        out.push_str(&format!(
+            "dns_updater_updates_total{{{labels},result=\"failure\"}} {failures}\n"
+        ));
+    }
+    out.push_str(
+        "# HELP dns_updater_update_latency_milliseconds Time taken by the recent updates\n",
+    );
+    out.push_str("# TYPE dns_updater_update_latency_milliseconds gauge\n");
+    for p in providers {
+        let Some(stats) = &p.stats else { continue };
+        let quantiles = [
+            ("0.5", stats.p50_latency_ms),
+            ("0.9", stats.p90_latency_ms),
+            ("0.99", stats.p99_latency_ms),
+        ];
+        for (quantile, latency) in quantiles {
+            if let Some(latency) = latency {
+                out.push_str(&format!(
+                    "dns_updater_update_latency_milliseconds{{{},quantile=\"{quantile}\"}} {latency}\n",
+                    labels(p)
+                ));
+            }
+        }
+    }
+    out.push_str("# HELP dns_updater_consecutive_failures Updates that failed in a row\n");
+    out.push_str("# TYPE dns_updater_consecutive_failures gauge\n");
+    for p in providers {
+        out.push_str(&format!(
+            "dns_updater_consecutive_failures{{{}}} {}\n",
+            labels(p),
+            p.consecutive_failures
+        ));
+    }
+    let now = Utc::now();
+    let stabilities: Vec<_> = providers
+        .iter()
+        .filter_map(|p| Some((labels(p), p.stability(now)?)))
+        .collect();
+    let gauges: [StabilityGauge; 4] = [
+        (
+            "dns_updater_ip_age_seconds",
+            "Time since the current IP was published",
+            |s| Some(s.current_ip_age_secs as f64),
+        ),
+        (
+            "dns_updater_ip_average_lease_seconds",
+            "How long the replaced IPs lasted on average",
+            |s| s.average_lease_secs.map(|secs| secs as f64),
+        ),
+        (
+            "dns_updater_ip_longest_stable_seconds",
+            "Longest an IP lasted, the current one included",
+            |s| Some(s.longest_stable_secs as f64),
+        ),
+        (
+            "dns_updater_ip_changes_per_week",
+            "IP changes per week over the history",
+            |s| s.changes_per_week,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} gauge\n"));
+        for (labels, stability) in &stabilities {
+            if let Some(value) = value(stability) {
+                out.push_str(&format!("{name}{{{labels}}} {value}\n"));
+            }
+        }
+    }
+    out
+}
+
+async fn write(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use chrono::Utc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{Access, Health, serve};
+    use crate::{IpVersion, persistence::ProviderState};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        get_with(addr, path, "").await
+    }
+
+    async fn get_with(addr: std::net::SocketAddr, path: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n").as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let health = Health::default();
+        let reporter = health.register("DuckDNS", Some("name"), IpVersion::V4);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, health.clone(), Access::default()));
+
+        // Healthy but not ready before the first detection
+        assert!(get(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+        assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 503"));
+        reporter.detected(IP);
+        assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 200"));
+
+        let mut state = ProviderState::default();
+        state.record_failure("KO".to_string(), Utc::now(), Duration::from_millis(120));
+        reporter.record(&state);
+        let response = get(addr, "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains(r#""update_error":"KO""#));
+        state.record_success(IP, "OK".to_string(), Utc::now(), Duration::from_millis(80));
+        reporter.record(&state);
+        reporter.published_at(vec![Utc::now() - chrono::TimeDelta::days(7)]);
+        reporter.published(Utc::now());
+        assert!(
+            get(addr, "/healthz?verbose")
+                .await
+                .starts_with("HTTP/1.1 200")
+        );
+        let metrics = get(addr, "/metrics").await;
+        assert!(metrics.contains(
+            r#"dns_updater_updates_total{provider="DuckDNS",hostname="name",ip_version="ipv4",result="failure"} 1"#
+        ));
+        assert!(metrics.contains(r#"ip_version="ipv4",quantile="0.5"} 80"#));
+        assert!(metrics.contains(r#"dns_updater_ip_longest_stable_seconds{provider="DuckDNS",hostname="name",ip_version="ipv4"} 604800"#));
+        assert!(get(addr, "/status").await.starts_with("HTTP/1.1 404"));
+
+        let id = health.providers()[0].id;
+        assert!(health.set_paused(id, true) && reporter.is_paused());
+        assert!(!health.set_paused(id + 1, true));
+        assert_eq!(health.force_update(None), 1);
+        assert_eq!(health.force_update(Some(id + 1)), 0);
+        assert!(get(addr, "/healthz").await.contains(r#""paused":true"#));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let access = Access {
+            token: Some("s3cret".into()),
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+        tokio::spawn(serve(listener, health.clone(), access));
+        assert!(get(addr, "/metrics").await.starts_with("HTTP/1.1 401"));
+        let wrong = "Authorization: Bearer wrong\r\n";
+        assert!(
+            get_with(addr, "/healthz", wrong)
+                .await
+                .starts_with("HTTP/1.1 401")
+        );
+        let right = "authorization: Bearer s3cret\r\n";
+        assert!(
+            get_with(addr, "/healthz", right)
+                .await
+                .starts_with("HTTP/1.1 200")
+        );
+
+        // Stopped providers are forgotten
+        drop(reporter);
+        assert!(health.providers().is_empty());
+    }
+}
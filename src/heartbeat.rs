@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+use reqwest::{Client, Url};
+
+use crate::health::{Health, ProviderHealth};
+
+/// Failed detections or updates in a row after which a provider is reported
+/// down, so a single network hiccup doesn't page anyone
+pub const FAILURES_BEFORE_DOWN: u32 = 3;
+/// Shortest time between two pings telling the same status
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Push monitor told how the providers are doing, a dead man's switch
+/// alerting when the pings stop or report a failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Heartbeat {
+    /// Healthchecks.io or a compatible server: the check's ping URL, `/fail`
+    /// is appended on failures
+    Healthchecks(String),
+    /// Uptime Kuma push monitor: its push URL, whose `status` and `msg`
+    /// parameters are replaced with the ones of each ping
+    UptimeKuma(String),
+}
+
+/// What a ping tells
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Up,
+    /// Why, one line per failing provider
+    Down(String),
+}
+
+impl Status {
+    /// Up once every provider detected its IP and published it, down once
+    /// one of them failed [`FAILURES_BEFORE_DOWN`] times in a row. In
+    /// between, or until every provider tried, there's nothing to tell.
+    pub fn of(providers: &[ProviderHealth]) -> Option<Self> {
+        if providers.is_empty() || !providers.iter().all(ProviderHealth::tried_detection) {
+            return None;
+        }
+        let down: Vec<_> = providers
+            .iter()
+            .filter_map(|p| {
+                let name = format!("{} {}", p.provider, p.hostname.as_deref().unwrap_or("-"));
+                match (&p.detection_error, &p.update_error) {
+                    (Some(e), _) if p.detection_failures >= FAILURES_BEFORE_DOWN => {
+                        Some(format!("{name}: couldn't detect the IP: {e}"))
+                    }
+                    (_, Some(e)) if p.consecutive_failures >= FAILURES_BEFORE_DOWN => {
+                        Some(format!("{name}: couldn't update: {e}"))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        if !down.is_empty() {
+            Some(Status::Down(down.join("\n")))
+        } else if providers
+            .iter()
+            .all(|p| p.detection_error.is_none() && p.update_error.is_none())
+        {
+            Some(Status::Up)
+        } else {
+            None
+        }
+    }
+}
+
+impl Heartbeat {
+    async fn ping(&self, client: &Client, status: &Status) -> Result<(), String> {
+        let request = match (self, status) {
+            (Heartbeat::Healthchecks(url), Status::Up) => client.post(url),
+            // The body shows up in the check's log
+            (Heartbeat::Healthchecks(url), Status::Down(why)) => client
+                .post(format!("{}/fail", url.trim_end_matches('/')))
+                .body(why.clone()),
+            (Heartbeat::UptimeKuma(url), status) => {
+                let mut url = Url::parse(url).map_err(|e| format!("Invalid URL: {e}"))?;
+                // Pasted push URLs come with example parameters
+                url.set_query(None);
+                let (status, msg) = match status {
+                    Status::Up => ("up", "OK"),
+                    Status::Down(why) => ("down", why.as_str()),
+                };
+                url.query_pairs_mut()
+                    .append_pair("status", status)
+                    .append_pair("msg", msg);
+                client.get(url)
+            }
+        };
+        // The URLs hold the check's token
+        let response = request
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Pings `heartbeat` after every detection or update telling a status,
+/// right away when it changed and at most once a minute otherwise, through
+/// `client`. Never returns.
+pub async fn run(heartbeat: Heartbeat, health: Health, client: Client) {
+    let mut last: Option<(Status, Instant)> = None;
+    loop {
+        let changed = health.changed();
+        if let Some(status) = Status::of(&health.providers()) {
+            let due = match &last {
+                Some((sent, at)) => *sent != status || at.elapsed() >= MIN_INTERVAL,
+                None => true,
+            };
+            if due {
+                match heartbeat.ping(&client, &status).await {
+                    Ok(()) => last = Some((status, Instant::now())),
+                    Err(e) => tracing::warn!(?status, error = %e, "Couldn't ping the heartbeat"),
+                }
+            }
+        }
+        changed.await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::Status;
+    use crate::health::ProviderHealth;
+
+    #[test]
+    fn test_status() {
+        let mut provider = ProviderHealth {
+            provider: "DuckDNS".to_string(),
+            hostname: Some("name".to_string()),
+            ..Default::default()
+        };
+        // Nothing to tell before the first detection
+        assert_eq!(Status::of(&[provider.clone()]), None);
+        provider.last_detection = Some(Utc::now());
+        assert_eq!(Status::of(&[provider.clone()]), Some(Status::Up));
+
+        provider.update_error = Some("KO".to_string());
+        provider.consecutive_failures = 1;
+        assert_eq!(Status::of(&[provider.clone()]), None);
+        provider.consecutive_failures = 3;
+        assert_eq!(
+            Status::of(&[provider]),
+            Some(Status::Down(
+                "DuckDNS name: couldn't update: KO".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    geoip::Networks,
+    persistence::{private_file_options, write_private},
+};
+
+pub const DEFAULT_FILE_NAME: &str = "history.jsonl";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[source] io::Error),
+    #[error("invalid entry: {0}")]
+    Parse(#[source] serde_json::Error),
+}
+
+/// One published IP change.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    pub hostname: Option<String>,
+    pub old_ip: Option<IpAddr>,
+    pub new_ip: IpAddr,
+    /// Why the provider refused the change, `None` once it's published.
+    /// Missing from entries of older versions, which only recorded successes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// How long the provider took to answer, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Who announces the old and new IPs, when `geoip` is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub networks: Option<Networks>,
+}
+
+/// Append only log of IP changes, stored as JSON lines. Clones share a lock
+/// so that appends aren't lost when the file is rewritten at the same time.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl History {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Arc::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn append(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry).map_err(Error::Parse)?;
+        line.push('\n');
+        let mut options = private_file_options();
+        options.append(true);
+        let _lock = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::from(options)
+            .open(&self.path)
+            .await
+            .map_err(Error::Io)?;
+        file.write_all(line.as_bytes()).await.map_err(Error::Io)?;
+        // tokio's File finishes writes in the background, make sure it's done
+        file.flush().await.map_err(Error::Io)
+    }
+
+    /// Reads every entry, oldest first. A missing file is an empty history.
+    pub async fn read(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let _lock = self.lock.lock().await;
+        self.read_unlocked().await
+    }
+
+    async fn read_unlocked(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| serde_json::from_str(l).map_err(Error::Parse))
+            .collect()
+    }
+
+    /// Overwrites the history with `entries`, e.g. when restoring a backup
+    pub async fn replace(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        let _lock = self.lock.lock().await;
+        self.replace_unlocked(entries).await
+    }
+
+    async fn replace_unlocked(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        let mut content = String::new();
+        for entry in entries {
+            content.push_str(&serde_json::to_string(entry).map_err(Error::Parse)?);
+            content.push('\n');
+        }
+        write_private(&self.path, content.as_bytes())
+            .await
+            .map_err(Error::Io)
+    }
+
+    /// Drops the entries recorded before `before`
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        let _lock = self.lock.lock().await;
+        let entries = self.read_unlocked().await?;
+        let kept: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.timestamp >= before)
+            .cloned()
+            .collect();
+        if kept.len() < entries.len() {
+            self.replace_unlocked(&kept).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `--since` value: an RFC 3339 timestamp, a `YYYY-MM-DD` date or a
+/// duration back from `now` such as `30m`, `12h` or `7d`.
+pub fn parse_since(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let s = s.trim();
+    if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+        return Ok(ts.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_time(Default::default()).and_utc());
+    }
+    let ago = parse_duration(s)
+        .map_err(|_| format!("Invalid time '{s}', expected e.g. 2024-05-01, 12h or 7d"))?;
+    Ok(now - ago)
+}
+
+/// Parses a duration such as `30m`, `12h`, `7d` or `4w`, e.g. the
+/// retention of the history
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.char_indices().last().map(|(i, _)| i).unwrap_or_default();
+    let (amount, unit) = s.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid duration '{s}', expected e.g. 12h or 7d"))?;
+    match unit {
+        "m" => Ok(Duration::minutes(amount)),
+        "h" => Ok(Duration::hours(amount)),
+        "d" => Ok(Duration::days(amount)),
+        "w" => Ok(Duration::weeks(amount)),
+        _ => Err(format!("Invalid time unit in '{s}', expected m, h, d or w")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::{History, HistoryEntry, parse_since};
+
+    #[test]
+    fn test_parse_since() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 10, 12, 0, 0).unwrap();
+        assert_eq!(
+            parse_since("2024-05-01", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_since("2024-05-01T08:30:00+02:00", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 1, 6, 30, 0).unwrap()
+        );
+        assert_eq!(
+            parse_since("12h", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 10, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_since("7d", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 5, 3, 12, 0, 0).unwrap()
+        );
+        assert!(parse_since("yesterday", now).is_err());
+        assert!(parse_since("5y", now).is_err());
+        assert!(parse_since("5é", now).is_err());
+        assert!(parse_since("", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_append_and_read() {
+        let path = std::env::temp_dir().join(format!("dns_updater_history_{}", std::process::id()));
+        let history = History::new(&path);
+        assert!(history.read().await.unwrap().is_empty());
+
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            provider: "DuckDNS".to_string(),
+            hostname: Some("my-domain".to_string()),
+            old_ip: None,
+            new_ip: "203.0.113.7".parse().unwrap(),
+            error: None,
+            latency_ms: Some(120),
+            networks: None,
+        };
+        history.append(&entry).await.unwrap();
+        history.append(&entry).await.unwrap();
+        assert_eq!(history.read().await.unwrap(), vec![entry.clone(), entry]);
+        // Written by older versions
+        let old = r#"{"timestamp":"2024-05-01T00:00:00Z","provider":"DuckDNS","hostname":null,"old_ip":null,"new_ip":"203.0.113.7"}"#;
+        std::fs::write(&path, format!("{old}\n")).unwrap();
+        let entries = history.read().await.unwrap();
+        assert_eq!((&entries[0].error, entries[0].latency_ms), (&None, None));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prune_keeps_concurrent_appends() {
+        let path = std::env::temp_dir().join(format!("dns_updater_prune_{}", std::process::id()));
+        let history = History::new(&path);
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            provider: "DuckDNS".to_string(),
+            hostname: None,
+            old_ip: None,
+            new_ip: "203.0.113.7".parse().unwrap(),
+            error: None,
+            latency_ms: None,
+            networks: None,
+        };
+        let old = HistoryEntry {
+            timestamp: entry.timestamp - chrono::Duration::days(10),
+            ..entry.clone()
+        };
+        history.replace(&[old.clone(), old]).await.unwrap();
+
+        // They run while the prune waits on the file
+        let mut appends = tokio::task::JoinSet::new();
+        for _ in 0..10 {
+            let (history, entry) = (history.clone(), entry.clone());
+            appends.spawn(async move { history.append(&entry).await });
+        }
+        history
+            .prune(entry.timestamp - chrono::Duration::days(1))
+            .await
+            .unwrap();
+        while let Some(appended) = appends.join_next().await {
+            appended.unwrap().unwrap();
+        }
+        assert_eq!(history.read().await.unwrap(), vec![entry; 10]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+#[cfg(feature = "rhai")]
+use std::path::PathBuf;
+use std::{env, net::IpAddr, process::ExitStatus, time::Duration};
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{IpVersion, SimpleName};
+
+/// Moment in an update cycle at which a hook can be run.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HookEvent {
+    /// The detected IP differs from the last one published for the provider.
+    Change,
+    /// The provider accepted the update.
+    Success,
+    /// The provider update failed.
+    Failure,
+    /// The IP changes too often, updates are being dampened.
+    Flapping,
+}
+
+impl SimpleName for HookEvent {
+    fn simple_name(&self) -> &str {
+        match self {
+            HookEvent::Change => "change",
+            HookEvent::Success => "success",
+            HookEvent::Failure => "failure",
+            HookEvent::Flapping => "flapping",
+        }
+    }
+}
+
+/// Information handed to a hook through `DNS_UPDATER_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct HookContext<'a> {
+    pub provider: &'a str,
+    pub hostname: Option<&'a str>,
+    pub old_ip: Option<IpAddr>,
+    pub new_ip: IpAddr,
+    pub error: Option<&'a str>,
+}
+
+/// Shell commands configured by the user, run with `sh -c`, and with the
+/// `rhai` feature a script.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub on_change: Option<String>,
+    pub on_success: Option<String>,
+    pub on_failure: Option<String>,
+    pub on_flap: Option<String>,
+    /// Seconds a command may run before it's killed, so a hung one can't
+    /// hold up the updates of its provider. `0` never kills it.
+    pub timeout_secs: u64,
+    /// Rhai script whose `on_change(ctx)`, `on_success(ctx)`, ... functions
+    /// run along with the commands, see [`crate::script::run_hook`]
+    #[cfg(feature = "rhai")]
+    pub script: Option<PathBuf>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            on_change: None,
+            on_success: None,
+            on_failure: None,
+            on_flap: None,
+            timeout_secs: 60,
+            #[cfg(feature = "rhai")]
+            script: None,
+        }
+    }
+}
+
+/// Why a hook command didn't succeed
+#[derive(Debug, thiserror::Error)]
+enum HookError {
+    #[error("couldn't spawn the hook: {0}")]
+    Spawn(#[source] std::io::Error),
+    #[error("the hook failed: {0}")]
+    Failed(ExitStatus),
+    #[error("the hook didn't finish within {0:?}, killed it")]
+    TimedOut(Duration),
+}
+
+impl Hooks {
+    /// Reads `HOOK_ON_CHANGE`, `HOOK_ON_SUCCESS`, `HOOK_ON_FAILURE`,
+    /// `HOOK_ON_FLAP`, `HOOK_TIMEOUT` in seconds and, with the `rhai`
+    /// feature, `HOOK_SCRIPT`.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|v: &String| !v.trim().is_empty())
+        };
+        let mut hooks = Self {
+            on_change: var("HOOK_ON_CHANGE"),
+            on_success: var("HOOK_ON_SUCCESS"),
+            on_failure: var("HOOK_ON_FAILURE"),
+            on_flap: var("HOOK_ON_FLAP"),
+            #[cfg(feature = "rhai")]
+            script: var("HOOK_SCRIPT").map(PathBuf::from),
+            ..Self::default()
+        };
+        if let Some(secs) = var("HOOK_TIMEOUT") {
+            hooks.timeout_secs = secs
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid HOOK_TIMEOUT '{secs}', expected seconds"))?;
+        }
+        Ok(hooks)
+    }
+
+    fn command_for(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Change => self.on_change.as_deref(),
+            HookEvent::Success => self.on_success.as_deref(),
+            HookEvent::Failure => self.on_failure.as_deref(),
+            HookEvent::Flapping => self.on_flap.as_deref(),
+        }
+    }
+
+    /// Runs the command configured for `event`, if any, and waits for it.
+    /// The HTTP functions of the hook script use `client`. Hook failures are
+    /// logged and never abort the update cycle.
+    pub async fn run(&self, event: HookEvent, ctx: &HookContext<'_>, client: &Client) {
+        #[cfg(feature = "rhai")]
+        if let Some(script) = &self.script {
+            crate::script::run_hook(script, event, ctx, client).await;
+        }
+        #[cfg(not(feature = "rhai"))]
+        let _ = client;
+        let Some(command) = self.command_for(event) else {
+            return;
+        };
+
+        let name = event.simple_name();
+        tracing::debug!(
+            event = name,
+            provider = ctx.provider,
+            command,
+            "Running hook"
+        );
+        match self.run_command(command, event, ctx).await {
+            Ok(()) => {}
+            Err(HookError::Spawn(e)) => tracing::error!(
+                event = name,
+                provider = ctx.provider,
+                error = ?e,
+                "Couldn't spawn the hook"
+            ),
+            Err(HookError::Failed(status)) => {
+                tracing::warn!(event = name, provider = ctx.provider, %status, "Hook failed")
+            }
+            Err(e @ HookError::TimedOut(_)) => tracing::warn!(
+                event = name,
+                provider = ctx.provider,
+                error = %e,
+                "Hook timed out"
+            ),
+        }
+    }
+
+    /// Runs `command` with the context of `event` in its environment,
+    /// killing it once it ran longer than the timeout
+    async fn run_command(
+        &self,
+        command: &str,
+        event: HookEvent,
+        ctx: &HookContext<'_>,
+    ) -> Result<(), HookError> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(command)
+            .env("DNS_UPDATER_EVENT", event.simple_name())
+            .env("DNS_UPDATER_PROVIDER", ctx.provider)
+            .env("DNS_UPDATER_HOSTNAME", ctx.hostname.unwrap_or_default())
+            .env(
+                "DNS_UPDATER_IP_VERSION",
+                IpVersion::from(&ctx.new_ip).simple_name(),
+            )
+            .env("DNS_UPDATER_NEW_IP", ctx.new_ip.to_string())
+            .env(
+                "DNS_UPDATER_OLD_IP",
+                ctx.old_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            )
+            .env("DNS_UPDATER_ERROR", ctx.error.unwrap_or_default())
+            // Dropped on a timeout, or when the provider stops
+            .kill_on_drop(true);
+
+        let status = cmd.status();
+        let status = match self.timeout_secs {
+            0 => status.await,
+            secs => {
+                let timeout = Duration::from_secs(secs);
+                tokio::time::timeout(timeout, status)
+                    .await
+                    .map_err(|_| HookError::TimedOut(timeout))?
+            }
+        };
+        match status.map_err(HookError::Spawn)? {
+            status if status.success() => Ok(()),
+            status => Err(HookError::Failed(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{HookContext, HookError, HookEvent, Hooks};
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let hooks = Hooks {
+            timeout_secs: 1,
+            ..Hooks::default()
+        };
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("home.duckdns.org"),
+            old_ip: None,
+            new_ip: "2001:db8::1".parse().unwrap(),
+            error: Some("HTTP 500"),
+        };
+        let path = std::env::temp_dir().join(format!("dns_updater_hooks_{}", std::process::id()));
+        let command = format!("env | grep ^DNS_UPDATER_ | sort > {}", path.display());
+        hooks
+            .run_command(&command, HookEvent::Failure, &ctx)
+            .await
+            .unwrap();
+        let env = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            env.lines().collect::<Vec<_>>(),
+            [
+                "DNS_UPDATER_ERROR=HTTP 500",
+                "DNS_UPDATER_EVENT=failure",
+                "DNS_UPDATER_HOSTNAME=home.duckdns.org",
+                "DNS_UPDATER_IP_VERSION=ipv6",
+                "DNS_UPDATER_NEW_IP=2001:db8::1",
+                "DNS_UPDATER_OLD_IP=",
+                "DNS_UPDATER_PROVIDER=DuckDNS",
+            ]
+        );
+
+        let failed = hooks.run_command("exit 3", HookEvent::Change, &ctx).await;
+        assert!(
+            matches!(failed, Err(HookError::Failed(status)) if status.code() == Some(3)),
+            "{failed:?}"
+        );
+
+        let started = Instant::now();
+        let hung = hooks.run_command("sleep 30", HookEvent::Change, &ctx).await;
+        assert!(matches!(hung, Err(HookError::TimedOut(_))), "{hung:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, env, net::IpAddr, time::Duration};
+
+use reqwest::{Client, ClientBuilder, redirect::Policy};
+use serde::{Deserialize, Serialize};
+
+use crate::resolve::Resolver;
+
+/// Sent unless [`HttpConfig::user_agent`] is set, since some providers block
+/// generic clients and want a way to reach whoever runs them
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "dns-updater/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/juancabe/dns-updater)"
+);
+
+/// Why the HTTP client couldn't be built
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The DNS-over-HTTPS resolver couldn't be set up
+    #[error("{0}")]
+    Doh(String),
+    #[error("Couldn't create an HTTP client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+/// Settings of the HTTP client shared by every provider and IP source
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Seconds to wait for a connection to be established
+    pub connect_timeout_secs: u64,
+    /// Seconds to wait for each read of an answer
+    pub read_timeout_secs: u64,
+    /// Seconds a whole request may take, overridden by the `timeout_secs`
+    /// option of a provider
+    pub timeout_secs: u64,
+    /// Sent instead of [`DEFAULT_USER_AGENT`], e.g. with a contact address as
+    /// No-IP asks for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// DNS servers asked in turn when the system resolver fails
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bootstrap_resolvers: Vec<IpAddr>,
+    /// IPs of each host used when no resolver could resolve it, e.g. those
+    /// of the provider endpoints
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fallback_ips: BTreeMap<String, Vec<IpAddr>>,
+    /// DNS-over-HTTPS server resolving instead of the system resolver:
+    /// `cloudflare`, `quad9` or the URL of another one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doh: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            timeout_secs: 60,
+            user_agent: None,
+            bootstrap_resolvers: Vec::new(),
+            fallback_ips: BTreeMap::new(),
+            doh: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Reads `HTTP_CONNECT_TIMEOUT`, `HTTP_READ_TIMEOUT` and `HTTP_TIMEOUT`,
+    /// in seconds, `HTTP_USER_AGENT`, `HTTP_BOOTSTRAP_RESOLVERS` as
+    /// comma-separated IPs, `HTTP_FALLBACK_IPS` as comma-separated `HOST=IP`
+    /// and `HTTP_DOH`, keeping the defaults of those that aren't set
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self {
+            user_agent: env::var("HTTP_USER_AGENT").ok().filter(|ua| !ua.is_empty()),
+            doh: env::var("HTTP_DOH").ok().filter(|doh| !doh.is_empty()),
+            ..Self::default()
+        };
+        for (name, value) in [
+            ("HTTP_CONNECT_TIMEOUT", &mut config.connect_timeout_secs),
+            ("HTTP_READ_TIMEOUT", &mut config.read_timeout_secs),
+            ("HTTP_TIMEOUT", &mut config.timeout_secs),
+        ] {
+            if let Ok(secs) = env::var(name) {
+                *value = secs
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid {name} '{secs}', expected seconds"))?;
+            }
+        }
+        if let Ok(resolvers) = env::var("HTTP_BOOTSTRAP_RESOLVERS") {
+            for ip in resolvers
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+            {
+                let ip = ip
+                    .parse()
+                    .map_err(|_| format!("Invalid bootstrap resolver '{ip}', expected an IP"))?;
+                config.bootstrap_resolvers.push(ip);
+            }
+        }
+        if let Ok(fallbacks) = env::var("HTTP_FALLBACK_IPS") {
+            config.fallback_ips = parse_fallback_ips(&fallbacks)?;
+        }
+        Ok(config)
+    }
+
+    /// [`HttpConfig::user_agent`] or the default one
+    pub fn user_agent(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT)
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Parses comma-separated `HOST=IP`, a host repeated for each of its IPs
+fn parse_fallback_ips(value: &str) -> Result<BTreeMap<String, Vec<IpAddr>>, String> {
+    let mut fallbacks: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (host, ip) = pair
+            .split_once('=')
+            .and_then(|(host, ip)| Some((host.trim(), ip.trim().parse().ok()?)))
+            .ok_or_else(|| format!("Invalid fallback IP '{pair}', expected HOST=IP"))?;
+        fallbacks.entry(host.to_string()).or_default().push(ip);
+    }
+    Ok(fallbacks)
+}
+
+/// A client builder with the TLS backend chosen at build time and the
+/// default User-Agent, for every HTTP client of the crate
+pub fn builder() -> ClientBuilder {
+    let builder = Client::builder().user_agent(DEFAULT_USER_AGENT);
+    // rustls is a default feature, so enabling native-tls as well means
+    // preferring it
+    #[cfg(feature = "native-tls")]
+    let builder = builder.tls_backend_native();
+    builder
+}
+
+/// Builds the HTTP client shared by every provider and IP source, so that
+/// updates reuse its pooled connections and TLS sessions
+pub fn client(config: &HttpConfig) -> Result<Client, Error> {
+    let mut builder = builder();
+    if let Some(doh) = &config.doh {
+        builder = builder.dns_resolver(
+            Resolver::doh(
+                doh,
+                config.user_agent(),
+                &config.bootstrap_resolvers,
+                &config.fallback_ips,
+            )
+            .map_err(Error::Doh)?,
+        );
+    } else if !config.bootstrap_resolvers.is_empty() || !config.fallback_ips.is_empty() {
+        builder = builder.dns_resolver(Resolver::new(
+            &config.bootstrap_resolvers,
+            &config.fallback_ips,
+        ));
+    }
+    builder
+        .user_agent(config.user_agent())
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(config.read_timeout_secs))
+        .timeout(Duration::from_secs(config.timeout_secs))
+        // Like curl's `-L`, which the DynDNS2 examples use
+        .redirect(Policy::limited(10))
+        .build()
+        .map_err(Error::Client)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{DEFAULT_USER_AGENT, HttpConfig, parse_fallback_ips};
+
+    #[test]
+    fn test_user_agent() {
+        let config = HttpConfig::default();
+        assert_eq!(config.user_agent(), DEFAULT_USER_AGENT);
+        assert!(DEFAULT_USER_AGENT.starts_with(concat!("dns-updater/", env!("CARGO_PKG_VERSION"))));
+
+        let config = HttpConfig {
+            user_agent: Some("home-router admin@example.com".to_string()),
+            ..HttpConfig::default()
+        };
+        assert_eq!(config.user_agent(), "home-router admin@example.com");
+        assert!(!config.is_default());
+    }
+
+    #[test]
+    fn test_fallback_ips() {
+        let fallbacks =
+            parse_fallback_ips("freedns.afraid.org=192.0.2.1, freedns.afraid.org=2001:db8::1,")
+                .unwrap();
+        assert_eq!(
+            fallbacks["freedns.afraid.org"],
+            [
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ]
+        );
+        assert!(parse_fallback_ips("freedns.afraid.org").is_err());
+        assert!(parse_fallback_ips("freedns.afraid.org=nope").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+
+use crate::secrets::{self, env_or_file};
+
+/// Expands `${VAR}` references in a configuration value.
+///
+/// Variables are looked up with [`env_or_file`], so `VAR_FILE` and Docker
+/// secrets work too. `${VAR:file:/path}` falls back to the trimmed contents of `/path` when
+/// `VAR` is unset or empty, so a secret can come from either place. Secret
+/// stores are referenced by scheme, e.g. `${keyring:NAME}`, see
+/// [`secrets::from_backend`]. `$${` produces a literal `${`.
+pub fn interpolate(value: &str) -> Result<String, String> {
+    interpolate_with(value, env_or_file)
+}
+
+pub fn interpolate_with(
+    value: &str,
+    lookup: impl Fn(&str) -> Result<Option<String>, String>,
+) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            // Escaped, keep a single "${"
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or(format!("Unterminated ${{ in config value: {value}"))?;
+        out.push_str(&resolve(&after[..end], &lookup)?);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn resolve(
+    reference: &str,
+    lookup: &impl Fn(&str) -> Result<Option<String>, String>,
+) -> Result<String, String> {
+    if let Some(secret) = secrets::from_backend(reference) {
+        return secret;
+    }
+    let (name, fallback) = match reference.split_once(":file:") {
+        Some((name, path)) => (name, Some(path)),
+        None => (reference, None),
+    };
+    if name.is_empty() {
+        return Err("Empty variable name in ${}".to_string());
+    }
+
+    match (lookup(name)?.filter(|v| !v.is_empty()), fallback) {
+        (Some(value), _) => Ok(value),
+        (None, Some(path)) => fs::read_to_string(path)
+            .map(|content| content.trim().to_string())
+            .map_err(|e| format!("{name} is not set and {path} can't be read: {e}")),
+        (None, None) => Err(format!("Environment variable {name} is not set")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::interpolate_with;
+
+    fn lookup(name: &str) -> Result<Option<String>, String> {
+        match name {
+            "TOKEN" => Ok(Some("s3cr3t".to_string())),
+            "EMPTY" => Ok(Some(String::new())),
+            "UNREADABLE" => Err("Couldn't read secret file".to_string()),
+            _ => Ok(None),
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        assert_eq!(
+            interpolate_with("DD;${TOKEN};ipv4;300;name", lookup).unwrap(),
+            "DD;s3cr3t;ipv4;300;name"
+        );
+        assert_eq!(
+            interpolate_with("no references", lookup).unwrap(),
+            "no references"
+        );
+        assert_eq!(interpolate_with("$${TOKEN}", lookup).unwrap(), "${TOKEN}");
+        assert!(interpolate_with("${MISSING}", lookup).is_err());
+        assert!(interpolate_with("${EMPTY}", lookup).is_err());
+        assert!(interpolate_with("${TOKEN", lookup).is_err());
+        assert!(interpolate_with("${UNREADABLE}", lookup).is_err());
+    }
+
+    #[test]
+    fn test_file_fallback() {
+        let path = std::env::temp_dir().join(format!("dns_updater_secret_{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+
+        let with_file = |var: &str| format!("${{{var}:file:{}}}", path.display());
+        assert_eq!(
+            interpolate_with(&with_file("TOKEN"), lookup).unwrap(),
+            "s3cr3t"
+        );
+        assert_eq!(
+            interpolate_with(&with_file("MISSING"), lookup).unwrap(),
+            "from-file"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(interpolate_with(&with_file("MISSING"), lookup).is_err());
+    }
+}
@@ -1,100 +1,129 @@
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
 use std::{
+    fmt::Debug,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     num::ParseIntError,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
     fs::File,
     io::{AsyncBufReadExt, BufReader},
-    sync::mpsc::Sender,
+    sync::watch,
+    task::JoinSet,
 };
 
-use crate::IpVersion; // Use Tokio's async Sender
+use crate::{IpVersion, health::Reporter, scheduler::Schedule, vcr};
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum ParseError {
+    #[error("expected 32 hex digits")]
     LenMismatch,
-    InvalidStr(ParseIntError),
+    #[error("invalid hex digits: {0}")]
+    InvalidStr(#[from] ParseIntError),
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    OpenFileError(tokio::io::Error),
-    ReadLineError(tokio::io::Error),
-    ParseError(ParseError),
+    #[error("couldn't open the interface addresses: {0}")]
+    OpenFileError(#[source] tokio::io::Error),
+    #[error("couldn't read the interface addresses: {0}")]
+    ReadLineError(#[source] tokio::io::Error),
+    #[error("invalid interface address: {0}")]
+    ParseError(#[from] ParseError),
+    #[error("no matching address found")]
     NoneMatched,
-    HttpError(reqwest::Error),
-    AddrParseError(std::net::AddrParseError),
+    #[error("couldn't ask for the IP: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("invalid IP in the answer: {0}")]
+    AddrParseError(#[from] std::net::AddrParseError),
+    #[error("no longer receiving pushed IPs")]
+    PushesStopped,
+    #[error("no answer in time")]
+    Timeout,
+    #[error("{0} can't be the address of this host")]
+    Unexpected(IpAddr),
+    #[error("every source failed: {0}")]
+    AllFailed(String),
 }
 
-pub struct IpGrabber {
-    iface: String,
-    ip_version: IpVersion,
-    poll_secs: u64,
-    last_ip: Option<IpAddr>,
+/// A way of finding out the current IP address.
+#[async_trait]
+pub trait IpSource: Send + Sync + Debug {
+    /// Short name shown to users, e.g. "ipify"
+    fn name(&self) -> &str;
+    fn ip_version(&self) -> IpVersion;
+    /// Finds the current IP, asking over HTTP with `client` when needed
+    async fn detect(&self, client: &Client) -> Result<IpAddr, Error>;
+    /// Whether [`IpSource::detect`] waits for the IP to be pushed, so it's
+    /// called again right away instead of on the schedule
+    fn is_pushed(&self) -> bool {
+        false
+    }
 }
 
-impl IpGrabber {
-    pub fn new(iface: String, ip_version: IpVersion, poll_secs: u64) -> Result<Self, Error> {
-        Ok(Self {
-            iface,
-            ip_version,
-            poll_secs,
-            last_ip: None,
-        })
+/// Asks api.ipify.org for the public IPv4 address.
+#[derive(Debug, Default)]
+pub struct Ipify;
+
+#[async_trait]
+impl IpSource for Ipify {
+    fn name(&self) -> &str {
+        "ipify"
     }
 
-    async fn get_updated(&self) -> Result<IpAddr, Error> {
-        match self.ip_version {
-            IpVersion::V4 => self.get_public_ipv4().await.map(IpAddr::V4),
-            IpVersion::V6 => self.get_stable_global_ipv6().await.map(IpAddr::V6),
-        }
+    fn ip_version(&self) -> IpVersion {
+        IpVersion::V4
     }
 
-    /// Monitors the interface for a stable Global IPv6 address.
-    /// Only sends the IP if it is found and is DIFFERENT from the last one sent.
-    pub async fn run(&mut self, sender: Sender<IpAddr>) {
-        let mut interval = tokio::time::interval(Duration::from_secs(self.poll_secs));
-        let mut err_interval = tokio::time::interval(Duration::from_secs(self.poll_secs / 10));
-        loop {
-            match self.get_updated().await {
-                Ok(current_ip) => {
-                    // Check if the IP has changed since the last successful check
-                    if let Some(last_ip) = self.last_ip
-                        && current_ip == last_ip
-                    {
-                        interval.tick().await;
-                        continue;
-                    }
+    async fn detect(&self, client: &Client) -> Result<IpAddr, Error> {
+        let response = vcr::send(client.get("https://api.ipify.org"))
+            .await
+            .map_err(Error::HttpError)?;
 
-                    self.last_ip = Some(current_ip);
+        let content = response.text().await.map_err(Error::HttpError)?;
+        let ip: Ipv4Addr = content.trim().parse().map_err(Error::AddrParseError)?;
+        Ok(IpAddr::V4(ip))
+    }
+}
 
-                    log::info!("New Stable ip detected: {}", current_ip);
+/// Reads the stable global IPv6 address of an interface from `/proc/net/if_inet6`.
+#[derive(Debug)]
+pub struct IfInet6 {
+    iface: String,
+}
 
-                    // Send the new IP. If the receiver dropped, stop the loop.
-                    if sender.send(current_ip).await.is_err() {
-                        log::warn!("Receiver dropped. Stopping monitor.");
-                        break;
-                    }
-                }
-                Err(e) => {
-                    log::debug!("Couldn't find an IP now, will try again, error: {e:?}");
-                    err_interval.tick().await;
-                }
-            }
+impl IfInet6 {
+    pub fn new(iface: String) -> Self {
+        Self { iface }
+    }
+
+    fn parse_ipv6(hex: &str) -> Result<Ipv6Addr, ParseError> {
+        if hex.len() != 32 {
+            return Err(ParseError::LenMismatch);
+        }
+        let mut segments = [0u16; 8];
+        for i in 0..8 {
+            segments[i] = u16::from_str_radix(&hex[i * 4..(i + 1) * 4], 16)
+                .map_err(ParseError::InvalidStr)?;
         }
+        Ok(Ipv6Addr::from(segments))
     }
+}
 
-    pub async fn get_public_ipv4(&self) -> Result<Ipv4Addr, Error> {
-        let response = reqwest::get("https://api.ipify.org")
-            .await
-            .map_err(Error::HttpError)?;
+#[async_trait]
+impl IpSource for IfInet6 {
+    fn name(&self) -> &str {
+        "if_inet6"
+    }
 
-        let content = response.text().await.map_err(Error::HttpError)?;
-        content.trim().parse().map_err(Error::AddrParseError)
+    fn ip_version(&self) -> IpVersion {
+        IpVersion::V6
     }
 
-    pub async fn get_stable_global_ipv6(&self) -> Result<Ipv6Addr, Error> {
+    async fn detect(&self, _client: &Client) -> Result<IpAddr, Error> {
         const FILE_PATH: &str = "/proc/net/if_inet6";
         let file = File::open(FILE_PATH).await.map_err(Error::OpenFileError)?;
         let reader = BufReader::new(file);
@@ -123,22 +152,369 @@ impl IpGrabber {
             let is_deprecated = (flags & 0x20) == 0x20;
 
             if !is_temporary && !is_deprecated {
-                return Self::parse_ipv6(parts[0]).map_err(Error::ParseError);
+                return Self::parse_ipv6(parts[0])
+                    .map(IpAddr::V6)
+                    .map_err(Error::ParseError);
             }
         }
 
         Err(Error::NoneMatched)
     }
+}
 
-    fn parse_ipv6(hex: &str) -> Result<Ipv6Addr, ParseError> {
-        if hex.len() != 32 {
-            return Err(ParseError::LenMismatch);
+/// Asks a URL answering with the caller's IP in plain text
+#[derive(Debug)]
+pub struct PlainText {
+    name: String,
+    url: String,
+    ip_version: IpVersion,
+}
+
+impl PlainText {
+    pub fn new(name: &str, url: &str, ip_version: IpVersion) -> Self {
+        Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            ip_version,
         }
-        let mut segments = [0u16; 8];
-        for i in 0..8 {
-            segments[i] = u16::from_str_radix(&hex[i * 4..(i + 1) * 4], 16)
-                .map_err(ParseError::InvalidStr)?;
+    }
+}
+
+#[async_trait]
+impl IpSource for PlainText {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    async fn detect(&self, client: &Client) -> Result<IpAddr, Error> {
+        let response = vcr::send(client.get(&self.url))
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::HttpError)?;
+        let content = response.text().await.map_err(Error::HttpError)?;
+        content.trim().parse().map_err(Error::AddrParseError)
+    }
+}
+
+/// A source named in the `ip_sources` option of a provider
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SourceSpec {
+    Ipify,
+    Icanhazip,
+    IdentMe,
+    IfInet6,
+    /// Any URL answering with the IP in plain text
+    Url(String),
+}
+
+impl TryFrom<String> for SourceSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, String> {
+        match spec.trim() {
+            "ipify" => Ok(SourceSpec::Ipify),
+            "icanhazip" => Ok(SourceSpec::Icanhazip),
+            "ident.me" => Ok(SourceSpec::IdentMe),
+            "if_inet6" => Ok(SourceSpec::IfInet6),
+            url => match Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {
+                    Ok(SourceSpec::Url(url.to_string()))
+                }
+                _ => Err(format!(
+                    "Unknown IP source '{url}', expected ipify, icanhazip, ident.me, if_inet6 or an http(s) URL"
+                )),
+            },
         }
-        Ok(Ipv6Addr::from(segments))
+    }
+}
+
+impl From<SourceSpec> for String {
+    fn from(spec: SourceSpec) -> Self {
+        match spec {
+            SourceSpec::Ipify => "ipify".to_string(),
+            SourceSpec::Icanhazip => "icanhazip".to_string(),
+            SourceSpec::IdentMe => "ident.me".to_string(),
+            SourceSpec::IfInet6 => "if_inet6".to_string(),
+            SourceSpec::Url(url) => url,
+        }
+    }
+}
+
+impl SourceSpec {
+    /// The source detecting the IP of `ip_version`, on `iface` for if_inet6
+    pub fn source(&self, iface: &str, ip_version: IpVersion) -> Box<dyn IpSource> {
+        let v4 = ip_version == IpVersion::V4;
+        let (name, url) = match self {
+            SourceSpec::Ipify if v4 => return Box::new(Ipify),
+            SourceSpec::Ipify => ("ipify", "https://api6.ipify.org"),
+            SourceSpec::Icanhazip if v4 => ("icanhazip", "https://ipv4.icanhazip.com"),
+            SourceSpec::Icanhazip => ("icanhazip", "https://ipv6.icanhazip.com"),
+            SourceSpec::IdentMe if v4 => ("ident.me", "https://v4.ident.me"),
+            SourceSpec::IdentMe => ("ident.me", "https://v6.ident.me"),
+            // Answers IPv6 addresses only, rejected by the race for IPv4
+            SourceSpec::IfInet6 => return Box::new(IfInet6::new(iface.to_string())),
+            SourceSpec::Url(url) => (url.as_str(), url.as_str()),
+        };
+        Box::new(PlainText::new(name, url, ip_version))
+    }
+}
+
+/// Asks every source at once and takes the first valid answer, so a slow or
+/// unreachable source doesn't delay the detection
+#[derive(Debug)]
+pub struct Race {
+    name: String,
+    sources: Vec<Arc<dyn IpSource>>,
+    ip_version: IpVersion,
+    /// How long each source gets to answer
+    timeout: Duration,
+}
+
+impl Race {
+    pub fn new(sources: Vec<Box<dyn IpSource>>, ip_version: IpVersion, timeout: Duration) -> Self {
+        let name = sources
+            .iter()
+            .map(|source| source.name())
+            .collect::<Vec<_>>()
+            .join("+");
+        Self {
+            name,
+            sources: sources.into_iter().map(Arc::from).collect(),
+            ip_version,
+            timeout,
+        }
+    }
+
+    /// `ip` when it may be the address of this host for the race's version
+    fn validate(&self, ip: IpAddr) -> Result<IpAddr, Error> {
+        if IpVersion::from(&ip) != self.ip_version
+            || ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_multicast()
+        {
+            return Err(Error::Unexpected(ip));
+        }
+        Ok(ip)
+    }
+}
+
+#[async_trait]
+impl IpSource for Race {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    async fn detect(&self, client: &Client) -> Result<IpAddr, Error> {
+        let mut racing = JoinSet::new();
+        for source in &self.sources {
+            let (source, client, timeout) = (source.clone(), client.clone(), self.timeout);
+            racing.spawn(vcr::inherit(async move {
+                let detected = tokio::time::timeout(timeout, source.detect(&client)).await;
+                (source, detected.unwrap_or(Err(Error::Timeout)))
+            }));
+        }
+        let mut errors = Vec::new();
+        // The slower sources are aborted once the set is dropped
+        while let Some(finished) = racing.join_next().await {
+            let Ok((source, detected)) = finished else {
+                continue;
+            };
+            match detected.and_then(|ip| self.validate(ip)) {
+                Ok(ip) => {
+                    tracing::debug!(%ip, source = source.name(), "First IP detected");
+                    return Ok(ip);
+                }
+                Err(e) => errors.push(format!("{}: {e}", source.name())),
+            }
+        }
+        Err(Error::AllFailed(errors.join(", ")))
+    }
+}
+
+/// The default source for an IP version: ipify for IPv4, the interface for IPv6
+pub fn default_source(iface: &str, ip_version: IpVersion) -> Box<dyn IpSource> {
+    match ip_version {
+        IpVersion::V4 => Box::new(Ipify),
+        IpVersion::V6 => Box::new(IfInet6::new(iface.to_string())),
+    }
+}
+
+/// Builds the source of a provider from the interface and its IP version,
+/// [`default_source`] unless a runner is given another
+pub type SourceFactory = Arc<dyn Fn(&str, IpVersion) -> Box<dyn IpSource> + Send + Sync>;
+
+pub struct IpGrabber {
+    source: Box<dyn IpSource>,
+    schedule: Schedule,
+    last_ip: Option<IpAddr>,
+    reporter: Option<Reporter>,
+}
+
+impl IpGrabber {
+    pub fn new(iface: String, ip_version: IpVersion, schedule: Schedule) -> Result<Self, Error> {
+        Ok(Self::with_source(
+            default_source(&iface, ip_version),
+            schedule,
+        ))
+    }
+
+    /// Detects the IP with `source` on `schedule`
+    pub fn with_source(source: Box<dyn IpSource>, schedule: Schedule) -> Self {
+        Self {
+            source,
+            schedule,
+            last_ip: None,
+            reporter: None,
+        }
+    }
+
+    /// Records the outcome of every detection of [`IpGrabber::run`] in `reporter`
+    pub fn report_to(&mut self, reporter: Reporter) {
+        self.reporter = Some(reporter);
+    }
+
+    pub fn source(&self) -> &dyn IpSource {
+        self.source.as_ref()
+    }
+
+    /// Detects the current IP a single time
+    pub async fn get_updated(&self, client: &Client) -> Result<IpAddr, Error> {
+        self.source.detect(client).await
+    }
+
+    /// Monitors the source for IP changes on the configured schedule.
+    /// Only publishes the IP if it is found and is DIFFERENT from the last one sent,
+    /// receivers only ever observe the latest value.
+    pub async fn run(&mut self, client: &Client, sender: watch::Sender<Option<IpAddr>>) {
+        loop {
+            match self.get_updated(client).await {
+                Ok(current_ip) => {
+                    if let Some(reporter) = &self.reporter {
+                        reporter.detected(current_ip);
+                    }
+                    // Check if the IP has changed since the last successful check
+                    if self.last_ip != Some(current_ip) {
+                        self.last_ip = Some(current_ip);
+
+                        tracing::info!(ip = %current_ip, "New stable IP detected");
+
+                        // Publish the new IP. If the receiver dropped, stop the loop.
+                        if sender.send(Some(current_ip)).is_err() {
+                            tracing::warn!("Receiver dropped. Stopping monitor.");
+                            break;
+                        }
+                    }
+
+                    if self.source.is_pushed() {
+                        continue;
+                    }
+                    match self.schedule.next_delay() {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => {
+                            tracing::debug!("Schedule has no further checks. Stopping monitor.");
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!(error = %e, "Couldn't find an IP now, will try again");
+                    if let Some(reporter) = &self.reporter {
+                        reporter.detection_failed(&e.to_string());
+                    }
+                    tokio::time::sleep(self.schedule.retry_delay()).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use async_trait::async_trait;
+    use reqwest::Client;
+
+    use super::{Error, IpSource, Race, SourceSpec};
+    use crate::IpVersion;
+
+    /// Answers `ip` after `delay`
+    #[derive(Debug)]
+    struct Slow(&'static str, Duration, Option<IpAddr>);
+
+    #[async_trait]
+    impl IpSource for Slow {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn ip_version(&self) -> IpVersion {
+            IpVersion::V4
+        }
+
+        async fn detect(&self, _client: &Client) -> Result<IpAddr, Error> {
+            tokio::time::sleep(self.1).await;
+            self.2.ok_or(Error::NoneMatched)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let (ms, client) = (Duration::from_millis, Client::new());
+        let ip = "192.0.2.1".parse().ok();
+        let race = |sources: Vec<Slow>| {
+            let sources = sources.into_iter().map(|s| Box::new(s) as _).collect();
+            Race::new(sources, IpVersion::V4, ms(500))
+        };
+
+        // The failing and invalid answers are skipped, the hanging one isn't waited for
+        let sources = race(vec![
+            Slow("hanging", Duration::from_secs(60), ip),
+            Slow("failing", ms(0), None),
+            Slow("loopback", ms(0), "127.0.0.1".parse().ok()),
+            Slow("v6", ms(0), "2001:db8::1".parse().ok()),
+            Slow("slow", ms(50), ip),
+        ]);
+        assert_eq!(sources.name(), "hanging+failing+loopback+v6+slow");
+        let started = tokio::time::Instant::now();
+        assert_eq!(sources.detect(&client).await.ok(), ip);
+        assert!(started.elapsed() < ms(400));
+
+        let sources = race(vec![
+            Slow("hanging", Duration::from_secs(60), ip),
+            Slow("failing", ms(0), None),
+        ]);
+        let error = sources.detect(&client).await.unwrap_err().to_string();
+        assert!(
+            error.contains("failing: no matching address found"),
+            "{error}"
+        );
+        assert!(error.contains("hanging: no answer in time"), "{error}");
+    }
+
+    #[test]
+    fn test_source_specs() {
+        let spec = |s: &str| SourceSpec::try_from(s.to_string());
+        assert_eq!(spec("ident.me"), Ok(SourceSpec::IdentMe));
+        assert_eq!(
+            spec("https://ip.example/plain"),
+            Ok(SourceSpec::Url("https://ip.example/plain".to_string()))
+        );
+        assert!(spec("ftp://ip.example").is_err());
+        assert!(spec("ipfy").is_err());
+        let source = SourceSpec::Icanhazip.source("eth0", IpVersion::V6);
+        assert_eq!(
+            (source.name(), source.ip_version()),
+            ("icanhazip", IpVersion::V6)
+        );
     }
 }
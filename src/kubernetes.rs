@@ -0,0 +1,292 @@
+//! Providers kept in Kubernetes, so the updater can run as a cluster add-on
+//! whose records are managed like any other resource: ConfigMaps and Secrets
+//! mounted as directories, or listed through the API by a label selector and
+//! copied to one.
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use reqwest::{Certificate, Client};
+use serde::Deserialize;
+
+use crate::{http, persistence::write_private, secrets::Secret, vcr};
+
+/// Where Kubernetes mounts the credentials of the pod's service account
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// Between two listings of the labelled ConfigMaps and Secrets
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Records of every file of `dir` in the `DNS_TUPLES` syntax, in the order
+/// of their names. Hidden files are skipped, such as the `..data` link
+/// Kubernetes swaps to update a mounted volume at once.
+pub fn read_providers_dir(dir: &Path) -> io::Result<String> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // Mounted keys are links into the current `..data` directory
+        if fs::metadata(entry.path())?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    let mut tuples = String::new();
+    for path in paths {
+        tuples.push_str(&fs::read_to_string(path)?);
+        tuples.push('\n');
+    }
+    Ok(tuples)
+}
+
+/// The ConfigMaps and Secrets labelled with a selector, read with the
+/// service account of the pod, see [`Cluster::sync`]
+#[derive(Debug)]
+pub struct Cluster {
+    /// e.g. `https://10.96.0.1:443`
+    api: String,
+    namespace: String,
+    selector: String,
+    /// Read before each listing, since projected tokens are rotated
+    token_file: Option<PathBuf>,
+    client: Client,
+    /// Where their keys are copied, one file each
+    dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct List {
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    metadata: Metadata,
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    name: String,
+}
+
+impl Cluster {
+    /// The cluster the pod runs in, through `KUBERNETES_SERVICE_HOST` and
+    /// `KUBERNETES_SERVICE_PORT`, in `namespace` or the pod's own
+    pub fn in_cluster(
+        selector: String,
+        namespace: Option<String>,
+        dir: PathBuf,
+    ) -> Result<Self, String> {
+        let host = env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| "KUBERNETES_SERVICE_HOST is not set, not running in a pod")?;
+        let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".to_string());
+        // IPv6 clusters give a bare address
+        let api = match host.contains(':') {
+            true => format!("https://[{host}]:{port}"),
+            false => format!("https://{host}:{port}"),
+        };
+        let account = Path::new(SERVICE_ACCOUNT);
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => fs::read_to_string(account.join("namespace"))
+                .map_err(|e| format!("Couldn't read the namespace of the pod: {e}"))?
+                .trim()
+                .to_string(),
+        };
+        let ca = fs::read(account.join("ca.crt"))
+            .map_err(|e| format!("Couldn't read the CA of the cluster: {e}"))?;
+        let ca =
+            Certificate::from_pem(&ca).map_err(|e| format!("Invalid CA of the cluster: {e}"))?;
+        let client = http::builder()
+            .add_root_certificate(ca)
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| format!("Couldn't create an HTTP client: {e}"))?;
+        Ok(Self {
+            api,
+            namespace,
+            selector,
+            token_file: Some(account.join("token")),
+            client,
+            dir,
+        })
+    }
+
+    /// Copies every key of the labelled ConfigMaps and Secrets to a file of
+    /// the directory, named `KIND.NAME.KEY`, removing those of the ones gone.
+    /// Returns whether any file changed.
+    pub async fn sync(&self) -> Result<bool, String> {
+        let mut files = BTreeMap::new();
+        for (kind, encoded) in [("configmaps", false), ("secrets", true)] {
+            for item in self.list(kind).await? {
+                for (key, value) in item.data {
+                    let content = match encoded {
+                        true => STANDARD.decode(&value).map_err(|e| {
+                            format!("Invalid key {key} of secret {}: {e}", item.metadata.name)
+                        })?,
+                        false => value.into_bytes(),
+                    };
+                    let kind = kind.trim_end_matches('s');
+                    files.insert(format!("{kind}.{}.{key}", item.metadata.name), content);
+                }
+            }
+        }
+
+        let error = |e: io::Error| format!("Couldn't write to {}: {e}", self.dir.display());
+        fs::create_dir_all(&self.dir).map_err(error)?;
+        let mut changed = false;
+        for entry in fs::read_dir(&self.dir).map_err(error)? {
+            let name = entry.map_err(error)?.file_name();
+            if !files.contains_key(&*name.to_string_lossy()) {
+                fs::remove_file(self.dir.join(&name)).map_err(error)?;
+                changed = true;
+            }
+        }
+        for (name, content) in files {
+            let path = self.dir.join(name);
+            if fs::read(&path).ok().as_ref() != Some(&content) {
+                write_private(&path, &content).await.map_err(error)?;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Syncs every [`POLL_INTERVAL`], a changed file reloading the
+    /// configuration through the watch of the directory
+    pub async fn watch(self) {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            match self.sync().await {
+                Ok(true) => {
+                    tracing::info!(selector = self.selector, "Kubernetes providers changed")
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!(error = %e, "Couldn't list the Kubernetes providers, keeping the current ones")
+                }
+            }
+        }
+    }
+
+    async fn list(&self, kind: &str) -> Result<Vec<Item>, String> {
+        let url = format!("{}/api/v1/namespaces/{}/{kind}", self.api, self.namespace);
+        let mut request = self
+            .client
+            .get(url)
+            .query(&[("labelSelector", &self.selector)]);
+        if let Some(path) = &self.token_file {
+            let token = fs::read_to_string(path)
+                .map(|token| Secret::from(token.trim().to_string()))
+                .map_err(|e| format!("Couldn't read the service account token: {e}"))?;
+            request = request.bearer_auth(token.expose());
+        }
+        let response = vcr::send(request)
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Listing the {kind} of {} answered HTTP {}",
+                self.namespace,
+                response.status()
+            ));
+        }
+        let body = response
+            .text()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        let list: List =
+            serde_json::from_str(&body).map_err(|e| format!("Invalid list of {kind}: {e}"))?;
+        Ok(list.items)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use reqwest::Client;
+
+    use super::{Cluster, read_providers_dir};
+    use crate::vcr::{Cassette, Interaction};
+
+    fn list(kind: &str, body: &str) -> Interaction {
+        Interaction {
+            method: "GET".to_string(),
+            url: format!(
+                "https://10.96.0.1/api/v1/namespaces/ddns/{kind}?labelSelector=app%3Ddns-updater"
+            ),
+            status: 200,
+            body: body.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_kube_{}", std::process::id()));
+        let providers = dir.join("kubernetes");
+        let cluster = Cluster {
+            api: "https://10.96.0.1".to_string(),
+            namespace: "ddns".to_string(),
+            selector: "app=dns-updater".to_string(),
+            token_file: None,
+            client: Client::new(),
+            dir: providers.clone(),
+        };
+        fs::create_dir_all(&providers).unwrap();
+        fs::write(providers.join("configmap.removed.tuples"), "x").unwrap();
+        // DuckDNS;ingress;token
+        let secret = r#"{"items": [{"metadata": {"name": "duckdns"}, "data": {"tuples": "RHVja0ROUztpbmdyZXNzO3Rva2Vu"}}]}"#;
+        let cassette = Cassette::replay(vec![
+            list(
+                "configmaps",
+                r#"{"items": [{"metadata": {"name": "ingress"}, "data": {"tuples": "DuckDNS;home;token"}}]}"#,
+            ),
+            list("secrets", secret),
+            list(
+                "configmaps",
+                r#"{"items": [{"metadata": {"name": "ingress"}, "data": {"tuples": "DuckDNS;home;token"}}]}"#,
+            ),
+            list("secrets", secret),
+        ]);
+        let (first, second) = cassette
+            .scope(async { (cluster.sync().await, cluster.sync().await) })
+            .await;
+        assert_eq!(first, Ok(true));
+        assert_eq!(second, Ok(false));
+        assert_eq!(
+            read_providers_dir(&providers).unwrap(),
+            "DuckDNS;home;token\nDuckDNS;ingress;token\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_providers_dir() {
+        let dir =
+            std::env::temp_dir().join(format!("dns_updater_providers_{}", std::process::id()));
+        let data = dir.join("..2024_05_01");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("home"), "DuckDNS;home;token").unwrap();
+        fs::write(dir.join("office"), "DuckDNS;office;token\n\n").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("..2024_05_01", dir.join("..data")).unwrap();
+            std::os::unix::fs::symlink("..data/home", dir.join("home")).unwrap();
+        }
+        let tuples = read_providers_dir(&dir).unwrap();
+        #[cfg(unix)]
+        assert_eq!(tuples, "DuckDNS;home;token\nDuckDNS;office;token\n\n\n");
+        #[cfg(not(unix))]
+        assert_eq!(tuples, "DuckDNS;office;token\n\n\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
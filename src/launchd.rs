@@ -0,0 +1,219 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use dns_updater::{SimpleName, config::ConfigFormat};
+
+use crate::cli::GlobalArgs;
+
+/// Files changed by macOS whenever the network configuration does, watched
+/// to start on-demand jobs
+const NETWORK_PATHS: [&str; 2] = [
+    "/etc/resolv.conf",
+    "/Library/Preferences/SystemConfiguration/com.apple.network.identification.plist",
+];
+
+/// What the generated LaunchDaemon runs.
+pub struct Daemon {
+    pub executable: PathBuf,
+    /// Absolute path of the configuration file
+    pub config: PathBuf,
+    pub config_format: Option<ConfigFormat>,
+    pub interface: Option<String>,
+    pub profile: Option<String>,
+    /// Run `once` on network changes and every `interval` seconds instead of
+    /// keeping `run` alive
+    pub on_demand: Option<u64>,
+}
+
+impl Daemon {
+    pub fn from_args(global: &GlobalArgs, on_demand: Option<u64>) -> Result<Self, String> {
+        let config = global
+            .config
+            .as_ref()
+            .ok_or("launchd needs a configuration file, see the migrate-config subcommand")?;
+        let config = std::path::absolute(config)
+            .map_err(|e| format!("Invalid configuration path {}: {e}", config.display()))?;
+        let executable =
+            std::env::current_exe().map_err(|e| format!("Couldn't locate dns_updater: {e}"))?;
+        Ok(Self {
+            executable,
+            config,
+            config_format: global.config_format,
+            interface: global.interface.clone(),
+            profile: global.profile.clone(),
+            on_demand,
+        })
+    }
+
+    /// Renders the plist of a daemon labelled `label`, with its state in
+    /// [`state_dir`] and its log in `/Library/Logs/<label>.log`.
+    pub fn render(&self, label: &str) -> String {
+        let mut arguments = vec![
+            self.executable.to_string_lossy().into_owned(),
+            "--config".to_string(),
+            self.config.to_string_lossy().into_owned(),
+        ];
+        if let Some(format) = self.config_format {
+            arguments.extend(["--config-format".to_string(), format.simple_name().into()]);
+        }
+        if let Some(interface) = &self.interface {
+            arguments.extend(["--interface".to_string(), interface.clone()]);
+        }
+        if let Some(profile) = &self.profile {
+            arguments.extend(["--profile".to_string(), profile.clone()]);
+        }
+        // The default for root, /var/lib, isn't where macOS keeps it
+        arguments.extend([
+            "--state-dir".to_string(),
+            state_dir(label).to_string_lossy().into_owned(),
+        ]);
+        let command = match self.on_demand {
+            Some(_) => "once",
+            None => "run",
+        };
+        arguments.push(command.to_string());
+        let arguments: String = arguments
+            .iter()
+            .map(|a| format!("\n        <string>{}</string>", escape(a)))
+            .collect();
+
+        let start = match self.on_demand {
+            // Started again by launchd whenever it exited on a failure
+            None => "\
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>30</integer>"
+                .to_string(),
+            Some(interval) => {
+                let paths: String = NETWORK_PATHS
+                    .iter()
+                    .map(|p| format!("\n        <string>{p}</string>"))
+                    .collect();
+                format!(
+                    "\
+    <key>RunAtLoad</key>
+    <true/>
+    <key>WatchPaths</key>
+    <array>{paths}
+    </array>
+    <key>StartInterval</key>
+    <integer>{interval}</integer>"
+                )
+            }
+        };
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>{arguments}
+    </array>
+    {start}
+    <key>WorkingDirectory</key>
+    <string>{state_dir}</string>
+    <key>EnvironmentVariables</key>
+    <dict>
+        <key>RUST_LOG</key>
+        <string>info</string>
+    </dict>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#,
+            label = escape(label),
+            state_dir = escape(&state_dir(label).to_string_lossy()),
+            log = escape(&log_path(label).to_string_lossy()),
+        )
+    }
+}
+
+/// Escapes a plist `<string>`
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Where the daemon keeps its state files and history
+pub fn state_dir(label: &str) -> PathBuf {
+    Path::new("/Library/Application Support").join(label)
+}
+
+fn log_path(label: &str) -> PathBuf {
+    Path::new("/Library/Logs").join(format!("{label}.log"))
+}
+
+pub fn default_path(label: &str) -> PathBuf {
+    Path::new("/Library/LaunchDaemons").join(format!("{label}.plist"))
+}
+
+/// Runs `launchctl bootstrap system <plist>`, after `launchctl bootout` of a
+/// previous version of the daemon
+pub fn load(label: &str, plist: &Path) -> io::Result<()> {
+    // Fails when the daemon wasn't loaded, which is fine
+    Command::new("launchctl")
+        .args(["bootout", &format!("system/{label}")])
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    let status = Command::new("launchctl")
+        .arg("bootstrap")
+        .arg("system")
+        .arg(plist)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "launchctl bootstrap failed with {status}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::Daemon;
+
+    #[test]
+    fn test_render() {
+        let mut daemon = Daemon {
+            executable: PathBuf::from("/usr/local/bin/dns_updater"),
+            config: PathBuf::from("/usr/local/etc/dns-updater & co.toml"),
+            config_format: None,
+            interface: Some("en0".to_string()),
+            profile: None,
+            on_demand: None,
+        };
+        let rendered = daemon.render("dns-updater");
+        assert!(rendered.contains("<string>/usr/local/etc/dns-updater &amp; co.toml</string>"));
+        assert!(rendered.contains(
+            "<string>en0</string>\n        <string>--state-dir</string>\n        <string>/Library/Application Support/dns-updater</string>\n        <string>run</string>\n"
+        ));
+        assert!(rendered.contains("<key>SuccessfulExit</key>"));
+        assert!(rendered.contains("<string>/Library/Application Support/dns-updater</string>"));
+        assert!(!rendered.contains("WatchPaths"));
+
+        daemon.on_demand = Some(600);
+        let rendered = daemon.render("dns-updater");
+        assert!(rendered.contains("<string>once</string>"));
+        assert!(rendered.contains("<key>StartInterval</key>\n    <integer>600</integer>"));
+        assert!(rendered.contains("<key>WatchPaths</key>"));
+        assert!(!rendered.contains("KeepAlive"));
+    }
+}
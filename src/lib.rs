@@ -1,13 +1,75 @@
 use std::net::IpAddr;
 
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("dns_updater needs a TLS backend, enable the rustls or native-tls feature");
+
+pub mod archive;
+pub mod aws;
+pub mod blackout;
+pub mod budget;
+pub mod config;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+pub mod dbus;
+#[cfg(feature = "dns-server")]
+pub mod dns_server;
 pub mod dyn_dns;
+pub mod dyndns_server;
+#[cfg(feature = "encrypted")]
+pub mod encrypted;
+pub mod flap;
+pub mod geoip;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod heartbeat;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod interpolate;
 pub mod ip_grabber;
+pub mod kubernetes;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod notify;
+pub mod online;
 pub mod persistence;
+#[cfg(feature = "wasm")]
+pub mod plugin;
+pub mod priority;
+pub mod provider;
+pub mod records;
+#[cfg(feature = "redb")]
+pub mod redb;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod registry;
+pub mod resolve;
+pub mod reverse_dns;
+pub mod rfc2136;
 pub mod runner;
+pub mod scheduler;
+#[cfg(feature = "rhai")]
+pub mod script;
+pub mod secrets;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod vault;
+pub mod vcr;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
 pub enum IpVersion {
+    #[serde(rename = "ipv4")]
     V4,
+    #[serde(rename = "ipv6")]
     V6,
 }
 
@@ -0,0 +1,185 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use dns_updater::persistence::private_file_options;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// When the log file is moved aside for a new one, and how many old ones are
+/// kept as `FILE.1` (the newest) to `FILE.N`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+/// Appends the log lines to a file, rotating it as configured. A failed
+/// rotation keeps appending to the current file rather than losing lines.
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<Inner>>);
+
+struct Inner {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    /// When the current file was started, as far as its metadata tells
+    started: SystemTime,
+}
+
+fn open(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    // Errors may quote URLs with credentials
+    let file = private_file_options().append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let started = metadata.created().unwrap_or_else(|_| SystemTime::now());
+    Ok((file, metadata.len(), started))
+}
+
+impl LogFile {
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.into();
+        let (file, size, started) = open(&path)?;
+        Ok(Self(Arc::new(Mutex::new(Inner {
+            path,
+            rotation,
+            file,
+            size,
+            started,
+        }))))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Inner {
+    fn is_due(&self, now: SystemTime) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size >= max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max| now.duration_since(self.started).is_ok_and(|age| age >= max));
+        too_big || too_old
+    }
+
+    /// Shifts `FILE.N-1` to `FILE.N` and so on, dropping the oldest, then
+    /// starts a new `FILE`
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        let (file, size, _) = open(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.started = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    /// Each call is a whole line, so files are only ever rotated between lines
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        if inner.is_due(SystemTime::now()) {
+            // Stderr is all that's left to tell about it
+            if let Err(e) = inner.rotate() {
+                eprintln!("Couldn't rotate {}: {e}", inner.path.display());
+            }
+        }
+        let written = inner.file.write(buf)?;
+        inner.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Parses a size such as 10M, 512K, 1G or a number of bytes
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid size '{s}', expected e.g. 10M, 512K or 1G");
+    let (amount, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    amount.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, time::Duration};
+
+    use super::{LogFile, Rotation, parse_size};
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert!(parse_size("10T").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_logfile_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dns-updater.log");
+        let rotation = Rotation {
+            max_size: Some(10),
+            max_age: Some(Duration::from_secs(3600)),
+            keep: 2,
+        };
+        let mut log = LogFile::open(&path, rotation).unwrap();
+        for line in [
+            "first line\n",
+            "second line\n",
+            "third line\n",
+            "fourth line\n",
+        ] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("dns-updater.log"), "fourth line\n");
+        assert_eq!(read("dns-updater.log.1"), "third line\n");
+        assert_eq!(read("dns-updater.log.2"), "second line\n");
+        assert!(!dir.join("dns-updater.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
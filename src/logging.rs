@@ -0,0 +1,156 @@
+use tracing_subscriber::{
+    EnvFilter, Layer, filter::LevelFilter, fmt::MakeWriter, layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+use crate::cli::GlobalArgs;
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, for log pipelines: `timestamp`, `level`,
+    /// `target`, `message` and the event fields at the top level, and the
+    /// enclosing spans with their fields in `spans`, outermost first
+    Json,
+    /// Straight to the systemd journal, keeping the levels as priorities and
+    /// the fields of the event and its spans as journal fields
+    #[cfg(target_os = "linux")]
+    Journald,
+    /// To a syslog daemon, local or remote, with the levels as priorities
+    #[cfg(unix)]
+    Syslog,
+}
+
+impl LogFormat {
+    /// Journald when stderr is connected to the journal, i.e. when started
+    /// by systemd, text otherwise
+    fn detect() -> Self {
+        #[cfg(target_os = "linux")]
+        if journal_stream() {
+            return LogFormat::Journald;
+        }
+        LogFormat::Text
+    }
+}
+
+/// Whether stderr is the journal stream systemd announces in `JOURNAL_STREAM`
+#[cfg(target_os = "linux")]
+fn journal_stream() -> bool {
+    use std::os::{fd::AsFd, unix::fs::MetadataExt};
+
+    let Some(stream) = std::env::var_os("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(stderr) = std::io::stderr().as_fd().try_clone_to_owned() else {
+        return false;
+    };
+    let Ok(metadata) = std::fs::File::from(stderr).metadata() else {
+        return false;
+    };
+    stream.to_str() == Some(&format!("{}:{}", metadata.dev(), metadata.ino()))
+}
+
+impl TryFrom<&str> for LogFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            #[cfg(target_os = "linux")]
+            "journald" => Ok(LogFormat::Journald),
+            #[cfg(unix)]
+            "syslog" => Ok(LogFormat::Syslog),
+            _ => Err(format!(
+                "Unknown log format {value}, expected text, json, journald (Linux only) or syslog (Unix only)"
+            )),
+        }
+    }
+}
+
+/// Keeps the logging (and the trace export) going until dropped
+pub struct Logging {
+    #[cfg(feature = "otel")]
+    _exporter: Option<crate::otel::Exporter>,
+}
+
+/// Logs to `writer` what `RUST_LOG` lets through, `default` and above when
+/// it's unset, in the format of `global` (detected by default), and sends
+/// the traces to its collector if any.
+/// Records of dependencies still using the `log` crate are included.
+pub fn init<W>(
+    global: &GlobalArgs,
+    writer: W,
+    ansi: bool,
+    default: LevelFilter,
+) -> Result<Logging, String>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::builder()
+        .with_default_directive(default.into())
+        .from_env_lossy();
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+    let fmt = match global.log_format.unwrap_or_else(LogFormat::detect) {
+        LogFormat::Text => fmt.with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+        #[cfg(target_os = "linux")]
+        LogFormat::Journald => tracing_journald::layer()
+            .map_err(|e| format!("Couldn't connect to journald: {e}"))?
+            .with_field_prefix(None)
+            .boxed(),
+        #[cfg(unix)]
+        LogFormat::Syslog => {
+            let server = global
+                .syslog_server
+                .clone()
+                .unwrap_or(crate::syslog::Server::Local);
+            let syslog = crate::syslog::Syslog::connect(&server, global.syslog_facility)?;
+            // The daemon adds its own timestamp
+            tracing_subscriber::fmt::layer()
+                .with_writer(syslog)
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .boxed()
+        }
+    };
+    let registry = tracing_subscriber::registry().with(fmt.with_filter(filter));
+
+    #[cfg(feature = "otel")]
+    {
+        let (layer, exporter) = match global.otlp_endpoint.as_deref() {
+            Some(endpoint) => {
+                let (layer, exporter) = crate::otel::layer(endpoint)?;
+                // A provider's span lasts as long as it runs, each of its
+                // updates is a trace of its own
+                let layer = layer.with_filter(tracing_subscriber::filter::filter_fn(|meta| {
+                    meta.name() != "provider"
+                }));
+                (Some(layer), Some(exporter))
+            }
+            None => (None, None),
+        };
+        registry
+            .with(layer)
+            .try_init()
+            .map_err(|e| format!("Couldn't set up logging: {e}"))?;
+        Ok(Logging {
+            _exporter: exporter,
+        })
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry
+            .try_init()
+            .map_err(|e| format!("Couldn't set up logging: {e}"))?;
+        Ok(Logging {})
+    }
+}
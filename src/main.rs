@@ -1,17 +1,1374 @@
-use std::env;
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
 
-use dns_updater::{dyn_dns::parse_dns_tuples, runner::Runner};
+use chrono::Utc;
+use clap::Parser;
+use dns_updater::{
+    SimpleName,
+    archive::Archive,
+    config::{Config, ConfigFormat},
+    dyn_dns::{CredentialCheck, DynDns, ProviderOptions},
+    dyndns_server::{self, Pushes},
+    health::{self, Health},
+    heartbeat::{self, Heartbeat},
+    history, http,
+    ip_grabber::{SourceFactory, default_source},
+    kubernetes::Cluster,
+    online,
+    persistence::{self, ProviderState, StateBackend, StateStore},
+    registry,
+    runner::{self, Runner},
+    stats::{IpStability, UpdateStats, format_duration, published_at},
+    vcr::Cassette,
+};
+use tracing_subscriber::filter::LevelFilter;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    env_logger::init();
+use crate::{
+    cli::{Cli, Command},
+    logfile::LogFile,
+};
 
-    let iface = env::var("INTERFACE").expect("The INTERFACE env flag should be set");
-    let dyn_dnss =
-        parse_dns_tuples(&env::var("DNS_TUPLES").expect("You should supply some DNS_TUPLES"))
-            .unwrap();
+mod cli;
+#[cfg(unix)]
+mod control;
+mod daemon;
+mod launchd;
+mod logfile;
+mod logging;
+#[cfg(feature = "otel")]
+mod otel;
+mod reload;
+#[cfg(unix)]
+mod syslog;
+mod systemd;
+#[cfg(windows)]
+mod winservice;
 
-    let runner = Runner::new(iface, dyn_dnss).unwrap();
+fn main() -> ExitCode {
+    let mut cli = Cli::parse();
+    #[cfg(windows)]
+    if let Some(Command::Run(args)) = &cli.command
+        && args.service
+    {
+        return winservice::run(cli.global);
+    }
+    // Paths given on the command line are relative to where it's run from
+    let mut paths: Vec<&mut PathBuf> = [&mut cli.global.log_file, &mut cli.global.record_http]
+        .into_iter()
+        .flatten()
+        .collect();
+    match cli.command.as_mut() {
+        Some(
+            Command::Export {
+                output: Some(path), ..
+            }
+            | Command::Import { archive: path, .. },
+        ) => paths.push(path),
+        Some(Command::Run(args)) => {
+            paths.extend(args.pid_file.as_mut());
+            #[cfg(feature = "tls")]
+            paths.extend(
+                [&mut args.tls_cert, &mut args.tls_key]
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+        _ => {}
+    }
+    for path in paths {
+        if let Ok(absolute) = std::path::absolute(&path) {
+            *path = absolute;
+        }
+    }
+    if cli.command.as_ref().is_none_or(Command::uses_state)
+        && let Err(e) = cli.global.enter_state_dir()
+    {
+        eprintln!("Couldn't use the state directory: {e}");
+        return ExitCode::FAILURE;
+    }
 
-    runner.run().await
+    // Publishing commands own the state directory, checked before detaching
+    // so a second instance fails on the terminal
+    let mut lock = None;
+    if cli.command.as_ref().is_none_or(Command::writes_state) {
+        match daemon::InstanceLock::acquire() {
+            Ok(acquired) => lock = Some(acquired),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    #[cfg(unix)]
+    if let Some(Command::Run(args)) = &cli.command
+        && args.daemon
+        && let Err(e) = daemon::daemonize()
+    {
+        eprintln!("Couldn't start as a daemon: {e}");
+        return ExitCode::FAILURE;
+    }
+    // After daemonizing, the exporter's thread wouldn't survive the fork
+    let logging = match &cli.global.log_file {
+        Some(path) => LogFile::open(path, cli.global.log_rotation())
+            .map_err(|e| format!("Couldn't open {}: {e}", path.display()))
+            .and_then(|file| logging::init(&cli.global, file, false, LevelFilter::ERROR)),
+        None => logging::init(
+            &cli.global,
+            io::stderr,
+            io::stderr().is_terminal(),
+            LevelFilter::ERROR,
+        ),
+    };
+    let _logging = match logging {
+        Ok(logging) => logging,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(lock) = lock.as_mut()
+        && let Err(e) = lock.write_pid()
+    {
+        tracing::warn!(path = daemon::LOCK_FILE, error = %e, "Couldn't write the PID");
+    }
+
+    match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => match cli.global.record_http.clone() {
+            Some(path) => runtime.block_on(Cassette::record(path).scope(run(cli))),
+            None => runtime.block_on(run(cli)),
+        },
+        Err(e) => {
+            eprintln!("Couldn't start the runtime: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(mut cli: Cli) -> ExitCode {
+    // Commands that only read local state don't need a configuration, only
+    // where the state is kept when one is given
+    if let Some(Command::History {
+        provider,
+        since,
+        json,
+    }) = &cli.command
+    {
+        let backend = match &cli.global.config {
+            Some(_) => match cli.global.load_config() {
+                Ok(config) => config.state,
+                Err(e) => {
+                    eprintln!("Invalid configuration: {e}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            None => StateBackend::default(),
+        };
+        let store = match open_store(&cli.global, &backend, &[]).await {
+            Ok(store) => store,
+            Err(code) => return code,
+        };
+        return history(store.as_ref(), provider.as_deref(), since.as_deref(), *json).await;
+    }
+    #[cfg(feature = "keyring")]
+    if let Some(Command::StoreSecret { name }) = &cli.command {
+        return store_secret(name);
+    }
+    if let Some(Command::Import { archive, force }) = &cli.command {
+        return import(&cli.global, archive, *force).await;
+    }
+    #[cfg(unix)]
+    if let Some(Command::Ctl { action }) = &cli.command {
+        return ctl(action).await;
+    }
+    if let Some(Command::ListProviders { json }) = &cli.command {
+        list_providers(*json);
+        return ExitCode::SUCCESS;
+    }
+
+    // Listed first, the configuration reading the directory they're kept in
+    let cluster = match kube_providers(&mut cli.global).await {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            eprintln!("Couldn't list the Kubernetes providers: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = match cli.global.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let client = match http::client(&config.http) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let dyn_dnss = match config.dyn_dnss() {
+        Ok(dyn_dnss) => dyn_dnss,
+        Err(e) => {
+            eprintln!("Invalid providers: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match cli.command.unwrap_or(Command::Run(Box::default())) {
+        Command::Validate => {
+            if let Err(e) = config.validate() {
+                eprintln!("Invalid configuration: {e}");
+                return ExitCode::FAILURE;
+            }
+            let disabled = dyn_dnss.iter().filter(|dd| !dd.options().enabled).count();
+            println!(
+                "Configuration is valid: {} provider(s) on {}, {disabled} disabled",
+                dyn_dnss.len(),
+                config.interface
+            );
+            for name in config.profiles.keys() {
+                // Checked by Config::validate
+                let profile = config.clone().profile(name);
+                if let Ok((count, interface)) =
+                    profile.and_then(|p| Ok((p.dyn_dnss()?.len(), p.interface)))
+                {
+                    println!("Profile {name}: {count} provider(s) on {interface}")
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Command::Check => {
+            let store = match open_store(&cli.global, &config.state, &dyn_dnss).await {
+                Ok(store) => store,
+                Err(code) => return code,
+            };
+            check(store.as_ref(), &client, &config.interface, &dyn_dnss).await;
+            ExitCode::SUCCESS
+        }
+        Command::Status => {
+            let store = match open_store(&cli.global, &config.state, &dyn_dnss).await {
+                Ok(store) => store,
+                Err(code) => return code,
+            };
+            for dd in &dyn_dnss {
+                let (last_ip, stats) = match store.load_state(dd.file_name()).await {
+                    Ok(state) => (describe_state(&state), describe_stats(&state)),
+                    Err(e) => (format!("unreadable state ({e})"), None),
+                };
+                let disabled = if dd.options().enabled {
+                    ""
+                } else {
+                    " (disabled)"
+                };
+                println!(
+                    "{} {} ({}): {last_ip}{disabled}",
+                    dd.provider_name(),
+                    dd.hostname().unwrap_or("-"),
+                    dd.get_ip_version().simple_name()
+                );
+                if let Some(stats) = stats {
+                    println!("    {stats}");
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Command::Stats { json } => {
+            let store = match open_store(&cli.global, &config.state, &dyn_dnss).await {
+                Ok(store) => store,
+                Err(code) => return code,
+            };
+            stats(store.as_ref(), &dyn_dnss, json).await;
+            ExitCode::SUCCESS
+        }
+        Command::History { .. } => unreachable!("Handled before loading the configuration"),
+        Command::ListProviders { .. } | Command::Import { .. } => {
+            unreachable!("Handled before loading the configuration")
+        }
+        Command::Export {
+            output,
+            strip_secrets,
+            force,
+        } => {
+            let archive = match export(&cli.global, &config, &dyn_dnss, strip_secrets).await {
+                Ok(archive) => archive,
+                Err(e) => {
+                    eprintln!("Couldn't export: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let Some(output) = output else {
+                println!("{archive}");
+                return ExitCode::SUCCESS;
+            };
+            if output.exists() && !force {
+                eprintln!(
+                    "{} already exists, use --force to overwrite it",
+                    output.display()
+                );
+                return ExitCode::FAILURE;
+            }
+            match write_private(&output, &archive) {
+                Ok(()) => {
+                    println!("Archive written to {}", output.display());
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Couldn't write {}: {e}", output.display());
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        #[cfg(feature = "keyring")]
+        Command::StoreSecret { .. } => unreachable!("Handled before loading the configuration"),
+        #[cfg(unix)]
+        Command::Ctl { .. } => unreachable!("Handled before loading the configuration"),
+        Command::MigrateConfig {
+            output,
+            format,
+            force,
+        } => {
+            let format = format
+                .or(output.as_deref().map(ConfigFormat::from_path))
+                .unwrap_or(ConfigFormat::Toml);
+            let content = match config.to_string(format) {
+                Ok(content) => content,
+                Err(e) => {
+                    eprintln!("Couldn't migrate the configuration: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let Some(output) = output else {
+                print!("{content}");
+                return ExitCode::SUCCESS;
+            };
+            if output.exists() && !force {
+                eprintln!(
+                    "{} already exists, use --force to overwrite it",
+                    output.display()
+                );
+                return ExitCode::FAILURE;
+            }
+            match std::fs::write(&output, content) {
+                Ok(()) => {
+                    println!(
+                        "Configuration written to {}, run with --config {0} from now on",
+                        output.display()
+                    );
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Couldn't write {}: {e}", output.display());
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Command::InstallService {
+            name,
+            output,
+            dry_run,
+            force,
+            enable,
+        } => {
+            let unit = match systemd::Unit::from_args(&cli.global, &name) {
+                Ok(unit) => unit.render(&name),
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            if dry_run {
+                print!("{unit}");
+                return ExitCode::SUCCESS;
+            }
+            let output = output.unwrap_or_else(|| systemd::default_path(&name));
+            if output.exists() && !force {
+                eprintln!(
+                    "{} already exists, use --force to overwrite it",
+                    output.display()
+                );
+                return ExitCode::FAILURE;
+            }
+            if let Err(e) = std::fs::write(&output, unit) {
+                eprintln!("Couldn't write {}: {e}", output.display());
+                return ExitCode::FAILURE;
+            }
+            println!("Service written to {}", output.display());
+            if !enable {
+                println!("Start it with: systemctl daemon-reload && systemctl enable --now {name}");
+                return ExitCode::SUCCESS;
+            }
+            match systemd::enable(&name) {
+                Ok(()) => {
+                    println!("{name} enabled and started");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Couldn't enable {name}: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Command::Launchd {
+            label,
+            output,
+            on_demand,
+            dry_run,
+            force,
+            load,
+        } => {
+            let plist = match launchd::Daemon::from_args(&cli.global, on_demand) {
+                Ok(daemon) => daemon.render(&label),
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            if dry_run {
+                print!("{plist}");
+                return ExitCode::SUCCESS;
+            }
+            let output = output.unwrap_or_else(|| launchd::default_path(&label));
+            if output.exists() && !force {
+                eprintln!(
+                    "{} already exists, use --force to overwrite it",
+                    output.display()
+                );
+                return ExitCode::FAILURE;
+            }
+            // launchd doesn't create the working directory
+            let state_dir = launchd::state_dir(&label);
+            if let Err(e) = cli::create_private_dir(&state_dir) {
+                eprintln!("Couldn't create {}: {e}", state_dir.display());
+                return ExitCode::FAILURE;
+            }
+            if let Err(e) = std::fs::write(&output, plist) {
+                eprintln!("Couldn't write {}: {e}", output.display());
+                return ExitCode::FAILURE;
+            }
+            println!("Daemon written to {}", output.display());
+            if !load {
+                println!(
+                    "Load it with: sudo launchctl bootstrap system {}",
+                    output.display()
+                );
+                return ExitCode::SUCCESS;
+            }
+            match launchd::load(&label, &output) {
+                Ok(()) => {
+                    println!("{label} loaded");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Couldn't load {label}: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        #[cfg(windows)]
+        Command::Service { action } => {
+            let result = match action {
+                cli::ServiceAction::Install => winservice::install(&cli.global),
+                cli::ServiceAction::Uninstall => winservice::uninstall(),
+            };
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("{e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Command::Test { provider } => {
+            let mut all_ok = true;
+            let mut tested = 0;
+            for mut dd in dyn_dnss {
+                if provider
+                    .as_deref()
+                    .is_some_and(|p| !matches_provider(dd.as_ref(), p))
+                {
+                    continue;
+                }
+                tested += 1;
+                let name = format!("{} {}", dd.provider_name(), dd.hostname().unwrap_or("-"));
+                match dd.test(&client).await {
+                    Ok(CredentialCheck::Valid(details)) => println!("{name}: OK, {details}"),
+                    Ok(CredentialCheck::RecordFound(details)) => {
+                        println!("{name}: record found, {details}; credentials not checked")
+                    }
+                    Ok(CredentialCheck::Untestable(reason)) => {
+                        println!("{name}: not checked, {reason}")
+                    }
+                    Err(e) => {
+                        all_ok = false;
+                        println!("{name}: FAILED, {e}");
+                    }
+                }
+            }
+            if tested == 0 {
+                eprintln!("No provider matches {}", provider.unwrap_or_default());
+                return ExitCode::FAILURE;
+            }
+            match all_ok {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        Command::Update { provider, ip } => {
+            let dyn_dnss: Vec<_> = dyn_dnss
+                .into_iter()
+                .filter(|dd| {
+                    provider
+                        .as_deref()
+                        .is_none_or(|p| matches_provider(dd.as_ref(), p))
+                })
+                .collect();
+            if dyn_dnss.is_empty() {
+                eprintln!("No provider matches {}", provider.unwrap_or_default());
+                return ExitCode::FAILURE;
+            }
+            if dyn_dnss.iter().all(|dd| !dd.options().enabled) {
+                eprintln!("Every matching provider is disabled");
+                return ExitCode::FAILURE;
+            }
+            let store = match open_store(&cli.global, &config.state, &dyn_dnss).await {
+                Ok(store) => store,
+                Err(code) => return code,
+            };
+            // Along with the PTR and follow-up records and the GeoIP lookups
+            // of the configuration, only the matching providers
+            let runner = Runner::configured(config, client)
+                .and_then(|builder| builder.with_providers(dyn_dnss).with_store(store).build());
+            let runner = match runner {
+                Ok(runner) => runner,
+                Err(e) => {
+                    eprintln!("Couldn't start: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            match runner.publish_once(ip).await {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        command @ (Command::Run(_) | Command::Once) => {
+            if dyn_dnss.iter().all(|dd| !dd.options().enabled) {
+                eprintln!("Every provider is disabled, enable one with enabled=true");
+                return ExitCode::FAILURE;
+            }
+            // Started at boot, the interface may still be waiting for its lease
+            let mut versions = Vec::new();
+            for dd in dyn_dnss.iter().filter(|dd| dd.options().enabled) {
+                if !versions.contains(&dd.get_ip_version()) {
+                    versions.push(dd.get_ip_version());
+                }
+            }
+            online::wait_online(&versions, cli.global.wait_online, online::has_route).await;
+            let store = match open_store(&cli.global, &config.state, &dyn_dnss).await {
+                Ok(store) => store,
+                Err(code) => return code,
+            };
+            let health = Health::default();
+            #[cfg(feature = "dashboard")]
+            let dashboard_store = store.clone();
+            // The IPs pushed by a router, published instead of detected ones
+            let pushes = match &command {
+                Command::Run(args) if args.dyndns_listen.is_some() => Some(Pushes::default()),
+                _ => None,
+            };
+            let running = config.clone();
+            let runner = Runner::configured(config, client.clone()).and_then(|builder| {
+                let builder = match &pushes {
+                    Some(pushes) => builder.with_ip_sources(pushes.sources()),
+                    None => builder,
+                };
+                builder
+                    .with_store(store)
+                    .with_health(health.clone())
+                    .build()
+            });
+            let runner = match runner {
+                Ok(runner) => runner,
+                Err(e) => {
+                    eprintln!("Couldn't start: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let Command::Run(args) = command else {
+                return match runner.run_once().await {
+                    true => ExitCode::SUCCESS,
+                    false => ExitCode::FAILURE,
+                };
+            };
+            // Removed when dropped, on shutdown
+            let _pid_file = match args.pid_file.as_deref().map(daemon::PidFile::create) {
+                Some(Err(e)) => {
+                    eprintln!("Couldn't write the PID file: {e}");
+                    return ExitCode::FAILURE;
+                }
+                pid_file => pid_file,
+            };
+            #[cfg(feature = "dashboard")]
+            let config_file = cli.global.config.clone().map(|path| {
+                let format = cli
+                    .global
+                    .config_format
+                    .unwrap_or_else(|| ConfigFormat::from_path(&path));
+                (path, format)
+            });
+            if let Some(cluster) = cluster {
+                tokio::spawn(cluster.watch());
+            }
+            let (reloads, reload) = reload::spawn(cli.global, running, client.clone());
+            #[cfg(feature = "tls")]
+            let tls = match tls_acceptor(&args).await {
+                Ok(tls) => tls,
+                Err(e) => {
+                    eprintln!("Couldn't set up TLS: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            if let Some(addr) = args.health_listen {
+                let access = health::Access {
+                    token: args.health_token.clone().map(Into::into),
+                    #[cfg(feature = "tls")]
+                    tls: tls.clone(),
+                };
+                match tokio::net::TcpListener::bind(addr).await {
+                    Ok(listener) => {
+                        tokio::spawn(health::serve(listener, health.clone(), access));
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't listen for health probes on {addr}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            if let (Some(addr), Some(pushes)) = (args.dyndns_listen, pushes) {
+                let server = dyndns_server::Server {
+                    pushes,
+                    username: args.dyndns_username.clone(),
+                    // Required along with the address by the argument parser
+                    password: args.dyndns_password.clone().unwrap_or_default().into(),
+                    #[cfg(feature = "tls")]
+                    tls: tls.clone(),
+                };
+                match tokio::net::TcpListener::bind(addr).await {
+                    Ok(listener) => {
+                        tokio::spawn(dyndns_server::serve(listener, server));
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't accept DynDNS2 updates on {addr}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            #[cfg(feature = "dashboard")]
+            if let Some(addr) = args.dashboard_listen {
+                let editor = match (args.dashboard_edit_config, config_file) {
+                    (false, _) => None,
+                    (true, Some((path, format))) => Some(dns_updater::dashboard::ConfigEditor {
+                        path,
+                        format,
+                        reload: Some(reload.clone()),
+                    }),
+                    (true, None) => {
+                        eprintln!("--dashboard-edit-config needs a --config file");
+                        return ExitCode::FAILURE;
+                    }
+                };
+                let dashboard = dns_updater::dashboard::Dashboard {
+                    health: health.clone(),
+                    store: dashboard_store,
+                    // Required along with the address by the argument parser
+                    token: args.dashboard_token.unwrap_or_default().into(),
+                    editor,
+                    #[cfg(feature = "tls")]
+                    tls: tls.clone(),
+                };
+                match tokio::net::TcpListener::bind(addr).await {
+                    Ok(listener) => {
+                        tokio::spawn(dns_updater::dashboard::serve(listener, dashboard));
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't serve the dashboard on {addr}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            let heartbeats = [
+                args.healthchecks_url.map(Heartbeat::Healthchecks),
+                args.uptime_kuma_url.map(Heartbeat::UptimeKuma),
+            ];
+            for heartbeat in heartbeats.into_iter().flatten() {
+                tokio::spawn(heartbeat::run(heartbeat, health.clone(), client.clone()));
+            }
+            #[cfg(feature = "mqtt")]
+            if let Some(url) = args.mqtt_url {
+                let mqtt = dns_updater::mqtt::Mqtt {
+                    // Checked by the argument parser
+                    url: dns_updater::secrets::Secret::new(url.parse().expect("valid URL")),
+                    topic: args.mqtt_topic,
+                    discovery_prefix: (!args.mqtt_no_discovery)
+                        .then_some(args.mqtt_discovery_prefix),
+                };
+                tokio::spawn(dns_updater::mqtt::run(mqtt, health.clone()));
+            }
+            #[cfg(all(feature = "dbus", target_os = "linux"))]
+            if let Some(bus) = args.dbus {
+                tokio::spawn(dns_updater::dbus::run(bus, health.clone()));
+            }
+            // Relative to the state directory, the working directory by now
+            tokio::spawn(health::write_status("status.json".into(), health.clone()));
+            #[cfg(feature = "grpc")]
+            if let Some(addr) = args.grpc_listen {
+                let plane = dns_updater::grpc::ControlPlane {
+                    health: health.clone(),
+                    reload: Some(reload.clone()),
+                    // Required along with the address by the argument parser
+                    token: args.grpc_token.map(Into::into),
+                };
+                match tokio::net::TcpListener::bind(addr).await {
+                    Ok(listener) => {
+                        tokio::spawn(async move {
+                            if let Err(e) = dns_updater::grpc::serve(listener, plane).await {
+                                tracing::error!(error = %e, "The gRPC control plane stopped");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't serve the gRPC control plane on {addr}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            #[cfg(feature = "dns-server")]
+            if let (Some(addr), Some(zone)) = (args.dns_listen, &args.dns_zone) {
+                let server = match dns_updater::dns_server::DnsServer::new(zone) {
+                    Ok(server) => server,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return ExitCode::FAILURE;
+                    }
+                };
+                let sockets = tokio::try_join!(
+                    tokio::net::UdpSocket::bind(addr),
+                    tokio::net::TcpListener::bind(addr)
+                );
+                match sockets {
+                    Ok((socket, listener)) => {
+                        let health = health.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = server.serve(socket, listener, health).await {
+                                tracing::error!(error = %e, "The DNS server stopped");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        eprintln!("Couldn't answer DNS queries on {addr}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            // Removed once the runtime drops the task, on shutdown
+            #[cfg(unix)]
+            match control::Socket::bind() {
+                Ok(socket) => {
+                    let health = health.clone();
+                    tokio::spawn(async move { socket.serve(health, reload).await });
+                }
+                Err(e) => {
+                    tracing::warn!(path = control::SOCKET, error = %e, "Couldn't listen for ctl")
+                }
+            }
+            #[cfg(not(unix))]
+            drop(reload);
+            #[cfg(target_os = "linux")]
+            let notify = systemd::notify(health);
+            #[cfg(not(target_os = "linux"))]
+            let notify = std::future::pending::<()>();
+            tokio::select! {
+                _ = runner.run_reloading(reloads) => {}
+                _ = notify => {}
+                _ = daemon::terminated() => tracing::info!("Shutting down"),
+            }
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// The acceptor of --tls-cert and --tls-key, or of --tls-self-signed, `None`
+/// to serve plain HTTP
+#[cfg(feature = "tls")]
+async fn tls_acceptor(args: &cli::RunArgs) -> io::Result<Option<dns_updater::tls::TlsAcceptor>> {
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        return dns_updater::tls::acceptor(cert, key).map(Some);
+    }
+    if !args.tls_self_signed {
+        return Ok(None);
+    }
+    #[cfg(feature = "dashboard")]
+    let listens = [
+        args.health_listen,
+        args.dyndns_listen,
+        args.dashboard_listen,
+    ];
+    #[cfg(not(feature = "dashboard"))]
+    let listens = [args.health_listen, args.dyndns_listen];
+    let ips: Vec<_> = listens
+        .into_iter()
+        .flatten()
+        .map(|addr| addr.ip())
+        .filter(|ip| !ip.is_unspecified())
+        .collect();
+    // Relative to the state directory, the working directory by now
+    let (cert, key) = (Path::new("tls-cert.pem"), Path::new("tls-key.pem"));
+    dns_updater::tls::self_signed(cert, key, &ips)
+        .await
+        .map(Some)
+}
+
+/// Prints every registered provider type with its fields, and the options
+/// shared by all of them
+fn list_providers(json: bool) {
+    let providers = registry::providers();
+    if json {
+        let providers: Vec<_> = providers
+            .iter()
+            .map(|(kind, schema)| {
+                serde_json::json!({
+                    "kind": kind,
+                    "name": schema.name,
+                    "syntax": schema.syntax(kind),
+                    "fields": schema.fields,
+                    "options": ProviderOptions::FIELDS,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(providers));
+        return;
+    }
+
+    let width = providers
+        .iter()
+        .flat_map(|(_, schema)| schema.fields)
+        .chain(ProviderOptions::FIELDS)
+        .map(|f| f.name.len())
+        .max()
+        .unwrap_or(0);
+    for (kind, schema) in &providers {
+        println!("{} ({})", schema.syntax(kind), schema.name);
+        for field in schema.fields {
+            println!("    {:width$}  {}", field.name, field.description);
+        }
+        println!();
+    }
+    println!("Options, appended to any batch as ;KEY=VALUE:");
+    for field in ProviderOptions::FIELDS {
+        let default = field
+            .default
+            .map(|d| format!(" (default {d})"))
+            .unwrap_or_default();
+        println!("    {:width$}  {}{default}", field.name, field.description);
+    }
+}
+
+/// Prints, per detection source, the detected IP and what each provider would
+/// do with it. Providers racing their own `ip_sources` get theirs, like under `run`.
+async fn check(
+    store: &dyn StateStore,
+    client: &reqwest::Client,
+    iface: &str,
+    dyn_dnss: &[Box<dyn DynDns>],
+) {
+    let mut groups: Vec<(_, Vec<&dyn DynDns>)> = Vec::new();
+    for dd in dyn_dnss {
+        let options = dd.options();
+        let key = (
+            dd.get_ip_version(),
+            &options.ip_sources,
+            options.ip_source_timeout_secs,
+        );
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, group)) => group.push(dd.as_ref()),
+            None => groups.push((key, vec![dd.as_ref()])),
+        }
+    }
+
+    let defaults: SourceFactory = Arc::new(default_source);
+    for ((version, ..), group) in groups {
+        let source = runner::source(&defaults, iface, group[0]);
+        let detected = source.detect(client).await;
+        match &detected {
+            Ok(ip) => println!(
+                "{} via {} on {iface}: {ip}",
+                version.simple_name(),
+                source.name()
+            ),
+            Err(e) => println!(
+                "{} via {} on {iface}: detection failed: {e}",
+                version.simple_name(),
+                source.name()
+            ),
+        }
+
+        let Ok(ip) = detected else {
+            continue;
+        };
+        for dd in group {
+            let action = match store.load_ip(dd.file_name()).await {
+                Ok(Some(last)) if last == ip => "up to date".to_string(),
+                Ok(Some(last)) => format!("would update (last published {last})"),
+                Ok(None) | Err(_) => "would update (never published)".to_string(),
+            };
+            println!(
+                "  {} {}: {action}",
+                dd.provider_name(),
+                dd.hostname().unwrap_or("-")
+            );
+        }
+    }
+}
+
+/// Syncs the `--kube-selector` providers into a providers directory, `None` without a selector
+async fn kube_providers(global: &mut cli::GlobalArgs) -> Result<Option<Cluster>, String> {
+    let Some(selector) = global.kube_selector.clone() else {
+        return Ok(None);
+    };
+    let dir = std::path::absolute("kubernetes").map_err(|e| e.to_string())?;
+    let cluster = Cluster::in_cluster(selector, global.kube_namespace.clone(), dir.clone())?;
+    cluster.sync().await?;
+    global.providers_dirs.push(dir);
+    Ok(Some(cluster))
+}
+
+/// Opens where the state is kept, printing why it couldn't be, and moves the
+/// state of `dyn_dnss` saved by older versions
+async fn open_store(
+    global: &cli::GlobalArgs,
+    backend: &StateBackend,
+    dyn_dnss: &[Box<dyn DynDns>],
+) -> Result<Arc<dyn StateStore>, ExitCode> {
+    let store = backend.open().map_err(|e| {
+        eprintln!("Couldn't open the state: {e}");
+        ExitCode::FAILURE
+    })?;
+    let legacy_dirs = global.legacy_state_dirs(backend);
+    persistence::migrate_legacy_state(store.as_ref(), dyn_dnss, &legacy_dirs).await;
+    Ok(store)
+}
+
+/// The last published IP of a provider, and its failures since
+fn describe_state(state: &ProviderState) -> String {
+    let mut description = match (state.ip, state.last_success) {
+        (Some(ip), Some(at)) => {
+            format!("{ip} (last updated {})", at.format("%Y-%m-%d %H:%M:%S UTC"))
+        }
+        (Some(ip), None) => ip.to_string(),
+        (None, _) => "never updated".to_string(),
+    };
+    if state.consecutive_failures > 0 {
+        description.push_str(&format!(
+            ", {} failed attempt(s) since: {}",
+            state.consecutive_failures,
+            state.last_response.as_deref().unwrap_or("-")
+        ));
+    }
+    description
+}
+
+/// How reliable a provider has been since its state was created, `None`
+/// before its first attempt
+fn describe_stats(state: &ProviderState) -> Option<String> {
+    let attempts = state.successes + state.failures;
+    if attempts == 0 {
+        return None;
+    }
+    let mut description = format!(
+        "{} of {attempts} update(s) accepted ({:.1}%)",
+        state.successes,
+        state.successes as f64 * 100.0 / attempts as f64
+    );
+    if let Some(latency) = state.average_latency_ms {
+        description.push_str(&format!(", {latency} ms on average"));
+    }
+    if state.consecutive_failures == 0
+        && let Some(error) = &state.last_error
+    {
+        description.push_str(&format!(", last error: {error}"));
+    }
+    Some(description)
+}
+
+/// Prints the update statistics and IP stability of every provider, the
+/// ones failing most often first, then the slowest
+async fn stats(store: &dyn StateStore, dyn_dnss: &[Box<dyn DynDns>], json: bool) {
+    let history = store.history().await.unwrap_or_else(|e| {
+        eprintln!("Unreadable history ({e}), the IP stability is left out");
+        Vec::new()
+    });
+    let now = Utc::now();
+    let stability = |dd: &dyn DynDns| {
+        let published = published_at(
+            &history,
+            dd.provider_name(),
+            dd.hostname(),
+            dd.get_ip_version(),
+        );
+        IpStability::of(&published, now)
+    };
+    let mut rows = Vec::new();
+    for dd in dyn_dnss {
+        match store.load_state(dd.file_name()).await {
+            Ok(state) => rows.push((dd, UpdateStats::of(&state))),
+            Err(e) => eprintln!(
+                "{} {}: unreadable state ({e})",
+                dd.provider_name(),
+                dd.hostname().unwrap_or("-")
+            ),
+        }
+    }
+    rows.sort_by(|(_, a), (_, b)| {
+        let key = |s: &Option<UpdateStats>| {
+            s.as_ref()
+                .map(|s| (s.error_rate, s.p90_latency_ms.unwrap_or(0)))
+        };
+        key(b)
+            .partial_cmp(&key(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    if json {
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(dd, stats)| {
+                serde_json::json!({
+                    "provider": dd.provider_name(),
+                    "hostname": dd.hostname(),
+                    "ip_version": dd.get_ip_version().simple_name(),
+                    "stats": stats,
+                    "stability": stability(dd.as_ref()),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(rows));
+        return;
+    }
+    let ms = |latency: Option<u64>| latency.map_or("-".to_string(), |l| format!("{l} ms"));
+    for (dd, stats) in rows {
+        let name = format!(
+            "{} {} ({})",
+            dd.provider_name(),
+            dd.hostname().unwrap_or("-"),
+            dd.get_ip_version().simple_name()
+        );
+        match stats {
+            Some(stats) => println!(
+                "{name}: {} update(s), {:.1}% failed, latency p50 {}, p90 {}, p99 {}",
+                stats.attempts,
+                stats.error_rate * 100.0,
+                ms(stats.p50_latency_ms),
+                ms(stats.p90_latency_ms),
+                ms(stats.p99_latency_ms)
+            ),
+            None => println!("{name}: never updated"),
+        }
+        if let Some(stability) = stability(dd.as_ref()) {
+            let mut line = format!(
+                "    current IP for {}, {} change(s)",
+                format_duration(stability.current_ip_age_secs),
+                stability.changes
+            );
+            if let Some(per_week) = stability.changes_per_week {
+                line.push_str(&format!(", {per_week:.1} per week"));
+            }
+            if let Some(lease) = stability.average_lease_secs {
+                line.push_str(&format!(", average lease {}", format_duration(lease)));
+            }
+            line.push_str(&format!(
+                ", longest stable {}",
+                format_duration(stability.longest_stable_secs)
+            ));
+            println!("{line}");
+        }
+    }
+}
+
+/// Whether `name` is the provider type or the hostname of `dd`, ignoring case
+fn matches_provider(dd: &dyn DynDns, name: &str) -> bool {
+    dd.provider_name().eq_ignore_ascii_case(name)
+        || dd.hostname().is_some_and(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Builds the archive of the configuration file, or of the configuration
+/// taken from the environment, and the state of its providers
+async fn export(
+    global: &cli::GlobalArgs,
+    config: &Config,
+    dyn_dnss: &[Box<dyn DynDns>],
+    strip_secrets: bool,
+) -> Result<String, String> {
+    let (content, format) = match &global.config {
+        Some(path) => {
+            let format = global
+                .config_format
+                .unwrap_or_else(|| ConfigFormat::from_path(path));
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+            (content, format)
+        }
+        None => {
+            let content = config
+                .to_string(ConfigFormat::Toml)
+                .map_err(|e| e.to_string())?;
+            (content, ConfigFormat::Toml)
+        }
+    };
+    let content = if strip_secrets {
+        let mut raw = Config::parse_raw(&content, format).map_err(|e| e.to_string())?;
+        raw.strip_secrets().map_err(|e| e.to_string())?;
+        raw.to_string(format).map_err(|e| e.to_string())?
+    } else {
+        content
+    };
+    let store = config.state.open().map_err(|e| format!("{e}"))?;
+    let legacy_dirs = global.legacy_state_dirs(&config.state);
+    persistence::migrate_legacy_state(store.as_ref(), dyn_dnss, &legacy_dirs).await;
+    let archive = Archive::capture(content, format, strip_secrets, dyn_dnss, store.as_ref())
+        .await
+        .map_err(|e| e.to_string())?;
+    serde_json::to_string_pretty(&archive).map_err(|e| e.to_string())
+}
+
+/// Writes a file only the current user may read, as it may hold credentials
+fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+/// Writes the configuration of an archive to the --config file, then
+/// restores the state of its providers
+async fn import(global: &cli::GlobalArgs, archive: &Path, force: bool) -> ExitCode {
+    let archive = match fs::read_to_string(archive)
+        .map_err(|e| e.to_string())
+        .and_then(|content| Archive::parse(&content).map_err(|e| e.to_string()))
+    {
+        Ok(archive) => archive,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {e}", archive.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let format = match archive.config_format() {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("Invalid archive: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(path) = &global.config else {
+        eprintln!("Pass --config to choose where to write the configuration");
+        return ExitCode::FAILURE;
+    };
+    if path.exists() && !force {
+        eprintln!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        );
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = write_private(path, &archive.config) {
+        eprintln!("Couldn't write {}: {e}", path.display());
+        return ExitCode::FAILURE;
+    }
+    println!("Configuration written to {}", path.display());
+    let guessed = global
+        .config_format
+        .unwrap_or_else(|| ConfigFormat::from_path(path));
+    if guessed != format {
+        println!(
+            "It's in {} format, run with --config-format {0} from now on",
+            format.simple_name()
+        );
+    }
+
+    let global = cli::GlobalArgs {
+        config_format: Some(format),
+        ..global.clone()
+    };
+    let (dyn_dnss, backend) = match global
+        .load_config()
+        .and_then(|config| Ok((config.dyn_dnss()?, config.state)))
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            let hint = match archive.secrets_stripped {
+                true => {
+                    ", set the variables replacing the stripped secrets and import again with --force"
+                }
+                false => "",
+            };
+            eprintln!("Couldn't load the imported configuration: {e}{hint}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let store = match open_store(&global, &backend, &dyn_dnss).await {
+        Ok(store) => store,
+        Err(code) => return code,
+    };
+    match archive.restore_state(&dyn_dnss, store.as_ref()).await {
+        Ok(()) => {
+            println!(
+                "State of {} provider(s) and {} history entries restored",
+                dyn_dnss.len(),
+                archive.history.len()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Couldn't restore the state: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn history(
+    store: &dyn StateStore,
+    provider: Option<&str>,
+    since: Option<&str>,
+    json: bool,
+) -> ExitCode {
+    let since = match since
+        .map(|s| history::parse_since(s, Utc::now()))
+        .transpose()
+    {
+        Ok(since) => since,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let entries = match store.history().await {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Couldn't read the history: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let matching = entries.iter().filter(|e| {
+        since.is_none_or(|since| e.timestamp >= since)
+            && provider.is_none_or(|p| {
+                e.provider.eq_ignore_ascii_case(p)
+                    || e.hostname
+                        .as_deref()
+                        .is_some_and(|h| h.eq_ignore_ascii_case(p))
+            })
+    });
+    for entry in matching {
+        if json {
+            println!("{}", serde_json::to_string(entry).unwrap_or_default());
+        } else {
+            let mut outcome = match &entry.error {
+                Some(e) => format!(" failed: {e}"),
+                None => String::new(),
+            };
+            if let Some(latency) = entry.latency_ms {
+                outcome.push_str(&format!(" ({latency} ms)"));
+            }
+            if let Some(networks) = &entry.networks {
+                outcome.push_str(&format!(" [{}]", networks.summary()));
+            }
+            println!(
+                "{} {} {}: {} -> {}{outcome}",
+                entry.timestamp.to_rfc3339(),
+                entry.provider,
+                entry.hostname.as_deref().unwrap_or("-"),
+                entry
+                    .old_ip
+                    .map(|ip| ip.to_string())
+                    .unwrap_or("none".to_string()),
+                entry.new_ip
+            );
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Sends `action` to the updater running in the state directory and prints
+/// its answer
+#[cfg(unix)]
+async fn ctl(action: &cli::CtlAction) -> ExitCode {
+    let request = match action {
+        cli::CtlAction::Status { .. } => control::Request::Status,
+        cli::CtlAction::ForceUpdate { provider } => control::Request::ForceUpdate {
+            provider: *provider,
+        },
+        cli::CtlAction::Reload => control::Request::Reload,
+    };
+    let answer = match control::send(&request).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match action {
+        cli::CtlAction::Status { json: true } => println!("{answer}"),
+        cli::CtlAction::Status { json: false } => {
+            for p in answer["providers"].as_array().into_iter().flatten() {
+                let field = |name: &str| p[name].as_str().unwrap_or("-");
+                let error = p["detection_error"].as_str().or(p["update_error"].as_str());
+                let status = match error {
+                    _ if p["paused"] == true => "paused".to_string(),
+                    Some(e) => format!("failing: {e}"),
+                    None => "ok".to_string(),
+                };
+                println!(
+                    "[{}] {} {} ({}): detected {}, published {}, {status}",
+                    p["id"],
+                    field("provider"),
+                    field("hostname"),
+                    field("ip_version"),
+                    field("detected_ip"),
+                    field("published_ip"),
+                );
+            }
+        }
+        cli::CtlAction::ForceUpdate { .. } => {
+            println!("Update forced for {} provider(s)", answer["forced"]);
+        }
+        cli::CtlAction::Reload => println!("Reloading the configuration"),
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(feature = "keyring")]
+fn store_secret(name: &str) -> ExitCode {
+    let mut value = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut value) {
+        eprintln!("Couldn't read the secret from stdin: {e}");
+        return ExitCode::FAILURE;
+    }
+    match dns_updater::secrets::store_in_keyring(name, value.trim()) {
+        Ok(()) => {
+            println!("Stored {name}, reference it as ${{keyring:{name}}}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
 }
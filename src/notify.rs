@@ -0,0 +1,1363 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use minijinja::{Environment, UndefinedBehavior};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::{
+    IpVersion, SimpleName,
+    geoip::Networks,
+    history::parse_duration,
+    hooks::HookContext,
+    interpolate::interpolate,
+    secrets::{Secret, env_or_file},
+};
+
+/// What a notification is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    /// The detected IP differs from the last one published for the provider
+    Change,
+    /// The provider update failed
+    Failure,
+    /// The provider accepted an update after failed ones
+    Recovery,
+    /// The IP changes too often, updates are held back until it settles
+    Flapping,
+}
+
+/// How bad an event is, for [`Rules::min_severity`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl NotificationEvent {
+    pub fn severity(&self) -> Severity {
+        match self {
+            NotificationEvent::Change | NotificationEvent::Recovery => Severity::Info,
+            NotificationEvent::Flapping => Severity::Warning,
+            NotificationEvent::Failure => Severity::Error,
+        }
+    }
+}
+
+impl SimpleName for NotificationEvent {
+    fn simple_name(&self) -> &str {
+        match self {
+            NotificationEvent::Change => "change",
+            NotificationEvent::Failure => "failure",
+            NotificationEvent::Recovery => "recovery",
+            NotificationEvent::Flapping => "flapping",
+        }
+    }
+}
+
+/// An event of one provider, as sent to every [`Notifier`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    pub provider: String,
+    pub hostname: Option<String>,
+    pub ip_version: String,
+    pub old_ip: Option<IpAddr>,
+    pub new_ip: IpAddr,
+    pub error: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// Who announces the old and new IPs of a change, when `geoip` is
+    /// configured
+    pub networks: Option<Networks>,
+}
+
+impl Notification {
+    pub fn new(event: NotificationEvent, ctx: &HookContext<'_>) -> Self {
+        Self {
+            event,
+            provider: ctx.provider.to_string(),
+            hostname: ctx.hostname.map(str::to_string),
+            ip_version: IpVersion::from(&ctx.new_ip).simple_name().to_string(),
+            old_ip: ctx.old_ip,
+            new_ip: ctx.new_ip,
+            error: ctx.error.map(str::to_string),
+            timestamp: Utc::now(),
+            networks: None,
+        }
+    }
+
+    /// Tells who announces the IPs, see [`crate::geoip::GeoIp::networks`]
+    pub fn with_networks(mut self, networks: Option<Networks>) -> Self {
+        self.networks = networks;
+        self
+    }
+
+    /// One line telling what happened, for the title of a message
+    pub fn title(&self) -> String {
+        let name = self.hostname.as_deref().unwrap_or(&self.provider);
+        match self.event {
+            NotificationEvent::Change => format!("IP of {name} changed"),
+            NotificationEvent::Failure => format!("Couldn't update {name}"),
+            NotificationEvent::Recovery => format!("{name} updated again"),
+            NotificationEvent::Flapping => format!("IP of {name} keeps changing"),
+        }
+    }
+
+    /// The details, for the body of a plain text message
+    pub fn text(&self) -> String {
+        let old_ip = self
+            .old_ip
+            .map_or_else(|| "none".to_string(), |ip| ip.to_string());
+        let mut text = format!(
+            "{} {}: {old_ip} -> {}",
+            self.provider, self.ip_version, self.new_ip
+        );
+        if let Some(networks) = &self.networks {
+            text.push_str(&format!("\n{}", networks.summary()));
+        }
+        if let Some(error) = &self.error {
+            text.push_str(&format!("\nError: {error}"));
+        }
+        text
+    }
+}
+
+/// A channel notifications are sent to, e.g. a webhook or a chat
+#[async_trait]
+pub trait Notifier: Send + Sync + Debug {
+    /// Names the notifier in logs
+    fn name(&self) -> &str;
+    async fn notify(&self, notification: &Notification) -> Result<(), String>;
+}
+
+/// Every configured notifier, all sent each notification
+#[derive(Debug, Clone, Default)]
+pub struct Notifiers(Vec<Arc<dyn Notifier>>);
+
+impl Notifiers {
+    pub fn new(notifiers: Vec<Arc<dyn Notifier>>) -> Self {
+        Self(notifiers)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Sends `notification` to every notifier at once and waits for them.
+    /// Failures are logged and never abort the update cycle.
+    pub async fn send(&self, notification: Notification) {
+        let notification = Arc::new(notification);
+        let mut sends = JoinSet::new();
+        for notifier in &self.0 {
+            let notifier = notifier.clone();
+            let notification = notification.clone();
+            let send = async move {
+                let event = notification.event.simple_name();
+                if let Err(e) = notifier.notify(&notification).await {
+                    let notifier = notifier.name();
+                    tracing::warn!(notifier, event, error = %e, "Couldn't send a notification");
+                }
+            };
+            sends.spawn(send.in_current_span());
+        }
+        sends.join_all().await;
+    }
+}
+
+/// Notification channels set up by the user, in the `notifications` table
+/// of a configuration file
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub discord: Vec<DiscordConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ntfy: Vec<NtfyConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub gotify: Vec<GotifyConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pushover: Vec<PushoverConfig>,
+    #[serde(skip_serializing_if = "Templates::is_empty")]
+    pub templates: Templates,
+}
+
+/// Templates of the title and message of the notifiers sending text, in the
+/// Jinja syntax with the fields of a [`Notification`] as variables, e.g.
+/// `{{ hostname }} is now {{ new_ip }}`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Templates {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.message.is_none()
+    }
+}
+
+/// URL the notifications are POSTed to as JSON
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: Secret,
+    /// Key of the HMAC-SHA256 signature of the body, sent in the
+    /// `X-DNS-Updater-Signature` header as `sha256=HEX`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<Secret>,
+    /// Which notifications it gets, and how often
+    #[serde(default, skip_serializing_if = "Rules::is_empty")]
+    pub rules: Rules,
+}
+
+/// Discord incoming webhook the notifications are posted to as embeds
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub url: Secret,
+    /// Which notifications it gets, and how often
+    #[serde(default, skip_serializing_if = "Rules::is_empty")]
+    pub rules: Rules,
+}
+
+/// ntfy topic the notifications are published to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NtfyConfig {
+    /// `https://ntfy.sh` by default
+    #[serde(default = "default_ntfy_server")]
+    pub server: String,
+    pub topic: String,
+    /// Access token of a protected topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret>,
+    /// Or user and password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    /// Priority of the messages of an event, from 1 (min) to 5 (max),
+    /// overriding [`ntfy_priority`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub priorities: BTreeMap<NotificationEvent, u8>,
+    /// Tags of the messages of an event, which ntfy shows as emojis when
+    /// they name one, overriding [`ntfy_tags`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<NotificationEvent, Vec<String>>,
+    /// Which notifications it gets, and how often
+    #[serde(default, skip_serializing_if = "Rules::is_empty")]
+    pub rules: Rules,
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+/// Default priority of the messages of `event`: failures and flapping are
+/// high, so phones ring for them
+pub fn ntfy_priority(event: NotificationEvent) -> u8 {
+    match event {
+        NotificationEvent::Change | NotificationEvent::Recovery => 3,
+        NotificationEvent::Failure | NotificationEvent::Flapping => 4,
+    }
+}
+
+/// Default tags of the messages of `event`
+pub fn ntfy_tags(event: NotificationEvent) -> Vec<String> {
+    let tag = match event {
+        NotificationEvent::Change => "globe_with_meridians",
+        NotificationEvent::Failure => "warning",
+        NotificationEvent::Recovery => "white_check_mark",
+        NotificationEvent::Flapping => "repeat",
+    };
+    vec![tag.to_string()]
+}
+
+/// Gotify server the notifications are pushed to as messages of an app
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GotifyConfig {
+    pub server: String,
+    /// Token of the app the messages are sent as
+    pub token: Secret,
+    /// Which notifications it gets, and how often
+    #[serde(default, skip_serializing_if = "Rules::is_empty")]
+    pub rules: Rules,
+}
+
+/// Gotify priority of the messages of `event`, from 0 to 10: clients show a
+/// notification from 4 and ring from 8, which is what failures get
+fn gotify_priority(event: NotificationEvent) -> u8 {
+    match event {
+        NotificationEvent::Change | NotificationEvent::Recovery => 5,
+        NotificationEvent::Flapping => 6,
+        NotificationEvent::Failure => 8,
+    }
+}
+
+/// Pushover user or group the notifications are sent to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushoverConfig {
+    /// Key of the user or group
+    pub user: Secret,
+    /// Token of the application the messages are sent from
+    pub token: Secret,
+    /// Priority of the messages of an event, from -2 (no notification) to
+    /// 2 (repeated until acknowledged), overriding [`pushover_priority`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub priorities: BTreeMap<NotificationEvent, i8>,
+    /// Which notifications it gets, and how often
+    #[serde(default, skip_serializing_if = "Rules::is_empty")]
+    pub rules: Rules,
+}
+
+/// Default Pushover priority of the messages of `event`: high for failures,
+/// which bypasses quiet hours, normal otherwise
+pub fn pushover_priority(event: NotificationEvent) -> i8 {
+    match event {
+        NotificationEvent::Failure => 1,
+        _ => 0,
+    }
+}
+
+/// The URLs listed in variable `name`, comma separated
+fn env_urls(name: &str) -> Result<Vec<String>, String> {
+    Ok(env_or_file(name)?
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+impl Notifications {
+    /// Reads `WEBHOOK_URLS`, comma separated, and `WEBHOOK_SECRET` signing
+    /// all of them, then `DISCORD_WEBHOOK_URLS`, `NTFY_TOPIC` with
+    /// `NTFY_SERVER` and `NTFY_TOKEN`, `GOTIFY_SERVER` with `GOTIFY_TOKEN`,
+    /// `PUSHOVER_USER` with `PUSHOVER_TOKEN`, and the templates in
+    /// `NOTIFICATION_TITLE` and `NOTIFICATION_MESSAGE`. All may come from
+    /// files like `DNS_TUPLES`.
+    pub fn from_env() -> Result<Self, String> {
+        let secret = env_or_file("WEBHOOK_SECRET")?.map(Secret::new);
+        let webhooks = env_urls("WEBHOOK_URLS")?
+            .into_iter()
+            .map(|url| WebhookConfig {
+                url: url.into(),
+                secret: secret.clone(),
+                rules: Rules::default(),
+            })
+            .collect();
+        let discord = env_urls("DISCORD_WEBHOOK_URLS")?
+            .into_iter()
+            .map(|url| DiscordConfig {
+                url: url.into(),
+                rules: Rules::default(),
+            })
+            .collect();
+        let ntfy = match env_or_file("NTFY_TOPIC")? {
+            Some(topic) => vec![NtfyConfig {
+                server: env_or_file("NTFY_SERVER")?.unwrap_or_else(default_ntfy_server),
+                topic,
+                token: env_or_file("NTFY_TOKEN")?.map(Secret::new),
+                username: None,
+                password: None,
+                priorities: BTreeMap::new(),
+                tags: BTreeMap::new(),
+                rules: Rules::default(),
+            }],
+            None => Vec::new(),
+        };
+        let gotify = match (env_or_file("GOTIFY_SERVER")?, env_or_file("GOTIFY_TOKEN")?) {
+            (Some(server), Some(token)) => vec![GotifyConfig {
+                server,
+                token: token.into(),
+                rules: Rules::default(),
+            }],
+            (None, None) => Vec::new(),
+            _ => return Err("GOTIFY_SERVER and GOTIFY_TOKEN go together".to_string()),
+        };
+        let pushover = match (
+            env_or_file("PUSHOVER_USER")?,
+            env_or_file("PUSHOVER_TOKEN")?,
+        ) {
+            (Some(user), Some(token)) => vec![PushoverConfig {
+                user: user.into(),
+                token: token.into(),
+                priorities: BTreeMap::new(),
+                rules: Rules::default(),
+            }],
+            (None, None) => Vec::new(),
+            _ => return Err("PUSHOVER_USER and PUSHOVER_TOKEN go together".to_string()),
+        };
+        Ok(Self {
+            webhooks,
+            discord,
+            ntfy,
+            gotify,
+            pushover,
+            templates: Templates {
+                title: env_or_file("NOTIFICATION_TITLE")?,
+                message: env_or_file("NOTIFICATION_MESSAGE")?,
+            },
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty()
+            && self.discord.is_empty()
+            && self.ntfy.is_empty()
+            && self.gotify.is_empty()
+            && self.pushover.is_empty()
+    }
+
+    /// Expands `${VAR}` references, which keep secrets out of the file
+    pub fn interpolate(&mut self) -> Result<(), String> {
+        let secret = |secret: &mut Secret| -> Result<(), String> {
+            *secret.expose_mut() = interpolate(secret.expose())?;
+            Ok(())
+        };
+        for webhook in self.webhooks.iter_mut() {
+            secret(&mut webhook.url)?;
+            webhook.secret.as_mut().map(secret).transpose()?;
+        }
+        for discord in self.discord.iter_mut() {
+            secret(&mut discord.url)?;
+        }
+        for ntfy in self.ntfy.iter_mut() {
+            ntfy.server = interpolate(&ntfy.server)?;
+            ntfy.topic = interpolate(&ntfy.topic)?;
+            if let Some(username) = ntfy.username.as_mut() {
+                *username = interpolate(username)?;
+            }
+            for field in [&mut ntfy.token, &mut ntfy.password] {
+                field.as_mut().map(secret).transpose()?;
+            }
+        }
+        for gotify in self.gotify.iter_mut() {
+            gotify.server = interpolate(&gotify.server)?;
+            secret(&mut gotify.token)?;
+        }
+        for pushover in self.pushover.iter_mut() {
+            secret(&mut pushover.user)?;
+            secret(&mut pushover.token)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the URLs and secrets, which often grant access on their
+    /// own, with the variables `placeholder` names
+    pub fn strip_secrets(&mut self, placeholder: &mut impl FnMut(&str, &str) -> String) {
+        for webhook in self.webhooks.iter_mut() {
+            webhook.url = placeholder("WEBHOOK", "URL").into();
+            if let Some(secret) = webhook.secret.as_mut() {
+                *secret = placeholder("WEBHOOK", "SECRET").into();
+            }
+        }
+        // The webhook token is part of the URL
+        for discord in self.discord.iter_mut() {
+            discord.url = placeholder("DISCORD_WEBHOOK", "URL").into();
+        }
+        for ntfy in self.ntfy.iter_mut() {
+            if let Some(token) = ntfy.token.as_mut() {
+                *token = placeholder("NTFY", "TOKEN").into();
+            }
+            if let Some(password) = ntfy.password.as_mut() {
+                *password = placeholder("NTFY", "PASSWORD").into();
+            }
+        }
+        for gotify in self.gotify.iter_mut() {
+            gotify.token = placeholder("GOTIFY", "TOKEN").into();
+        }
+        for pushover in self.pushover.iter_mut() {
+            pushover.user = placeholder("PUSHOVER", "USER").into();
+            pushover.token = placeholder("PUSHOVER", "TOKEN").into();
+        }
+    }
+
+    /// The notifiers, sending through `client`, failing on invalid settings
+    /// or templates
+    pub fn notifiers(&self, client: &Client) -> Result<Notifiers, String> {
+        let formatter = Formatter::new(&self.templates)?;
+        let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+        for webhook in &self.webhooks {
+            let notifier = Arc::new(Webhook::new(webhook, client)?);
+            notifiers.push(Filtered::wrap(notifier, &webhook.rules)?);
+        }
+        for discord in &self.discord {
+            let notifier = Arc::new(Discord::new(discord, formatter.clone(), client)?);
+            notifiers.push(Filtered::wrap(notifier, &discord.rules)?);
+        }
+        for ntfy in &self.ntfy {
+            let notifier = Arc::new(Ntfy::new(ntfy, formatter.clone(), client)?);
+            notifiers.push(Filtered::wrap(notifier, &ntfy.rules)?);
+        }
+        for gotify in &self.gotify {
+            let notifier = Arc::new(Gotify::new(gotify, formatter.clone(), client)?);
+            notifiers.push(Filtered::wrap(notifier, &gotify.rules)?);
+        }
+        for pushover in &self.pushover {
+            let notifier = Arc::new(Pushover::new(pushover, formatter.clone(), client)?);
+            notifiers.push(Filtered::wrap(notifier, &pushover.rules)?);
+        }
+        Ok(Notifiers::new(notifiers))
+    }
+}
+
+/// Writes the title and message of notifications, with the [`Templates`]
+/// that are set and the built-in English text otherwise
+#[derive(Debug, Clone, Default)]
+pub struct Formatter(Option<Arc<Environment<'static>>>);
+
+impl Formatter {
+    pub fn new(templates: &Templates) -> Result<Self, String> {
+        if templates.is_empty() {
+            return Ok(Self(None));
+        }
+        let mut env = Environment::new();
+        // Catches misspelled variables, e.g. `{{ new_ipv4 }}`
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        for (name, template) in [("title", &templates.title), ("message", &templates.message)] {
+            if let Some(template) = template {
+                env.add_template_owned(name, template.clone())
+                    .map_err(|e| format!("Invalid {name} template: {e}"))?;
+            }
+        }
+        let formatter = Self(Some(Arc::new(env)));
+        // Every variable is set in this one, so rendering it tells whether
+        // the templates only use existing ones
+        let sample = Notification {
+            event: NotificationEvent::Failure,
+            provider: "DuckDNS".to_string(),
+            hostname: Some("my-domain".to_string()),
+            ip_version: "ipv4".to_string(),
+            old_ip: Some(IpAddr::from([192, 0, 2, 1])),
+            new_ip: IpAddr::from([192, 0, 2, 2]),
+            error: Some("HTTP 500".to_string()),
+            timestamp: Utc::now(),
+            networks: Some(Networks::default()),
+        };
+        for name in ["title", "message"] {
+            formatter.try_render(name, &sample)?;
+        }
+        Ok(formatter)
+    }
+
+    /// `None` when there's no template `name`
+    fn try_render(
+        &self,
+        name: &str,
+        notification: &Notification,
+    ) -> Result<Option<String>, String> {
+        let Some(env) = &self.0 else { return Ok(None) };
+        let Ok(template) = env.get_template(name) else {
+            return Ok(None);
+        };
+        template
+            .render(notification)
+            .map(Some)
+            .map_err(|e| format!("Invalid {name} template: {e}"))
+    }
+
+    fn render(&self, name: &str, notification: &Notification) -> Option<String> {
+        self.try_render(name, notification).unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "Couldn't render a notification, sending the default text");
+            None
+        })
+    }
+
+    pub fn title(&self, notification: &Notification) -> String {
+        self.render("title", notification)
+            .unwrap_or_else(|| notification.title())
+    }
+
+    pub fn text(&self, notification: &Notification) -> String {
+        self.custom_text(notification)
+            .unwrap_or_else(|| notification.text())
+    }
+
+    /// The message rendered from its template, `None` without one
+    fn custom_text(&self, notification: &Notification) -> Option<String> {
+        self.render("message", notification)
+    }
+}
+
+/// Which notifications a notifier gets, and how often, so flapping or a
+/// provider that keeps failing doesn't flood anyone's phone
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// Events sent, all of them when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<NotificationEvent>>,
+    /// Events less severe are dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_severity: Option<Severity>,
+    /// A notification like one sent less than this long ago, e.g. `30m`, is
+    /// dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup_window: Option<String>,
+    /// Most notifications of an event sent for a record in a period, e.g.
+    /// `failure = "1/1h"` for at most one failure alert an hour
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rate_limits: BTreeMap<NotificationEvent, String>,
+}
+
+impl Rules {
+    pub fn is_empty(&self) -> bool {
+        *self == Rules::default()
+    }
+}
+
+/// Parses a rate limit such as `1/1h` or `5/1d`
+fn parse_rate_limit(s: &str) -> Result<(usize, chrono::Duration), String> {
+    let invalid = || format!("Invalid rate limit '{s}', expected e.g. 1/1h");
+    let (count, period) = s.split_once('/').ok_or_else(invalid)?;
+    let count = count.trim().parse().map_err(|_| invalid())?;
+    if count == 0 {
+        return Err(invalid());
+    }
+    Ok((count, parse_duration(period)?))
+}
+
+/// Whether `a` and `b` are about the same record
+fn same_record(a: &Notification, b: &Notification) -> bool {
+    (&a.provider, &a.hostname, &a.ip_version) == (&b.provider, &b.hostname, &b.ip_version)
+}
+
+/// Sends to a notifier only what its [`Rules`] let through
+#[derive(Debug)]
+struct Filtered {
+    notifier: Arc<dyn Notifier>,
+    rules: Rules,
+    dedup_window: Option<chrono::Duration>,
+    rate_limits: BTreeMap<NotificationEvent, (usize, chrono::Duration)>,
+    /// Notifications let through within the longest of the windows
+    sent: Mutex<Vec<Notification>>,
+}
+
+impl Filtered {
+    /// `notifier` itself when `rules` let everything through
+    fn wrap(notifier: Arc<dyn Notifier>, rules: &Rules) -> Result<Arc<dyn Notifier>, String> {
+        if rules.is_empty() {
+            return Ok(notifier);
+        }
+        Ok(Arc::new(Self::new(notifier, rules)?))
+    }
+
+    fn new(notifier: Arc<dyn Notifier>, rules: &Rules) -> Result<Self, String> {
+        let dedup_window = rules
+            .dedup_window
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?;
+        let rate_limits = rules
+            .rate_limits
+            .iter()
+            .map(|(event, limit)| Ok((*event, parse_rate_limit(limit)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            notifier,
+            rules: rules.clone(),
+            dedup_window,
+            rate_limits,
+            sent: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether `notification` goes through, remembering it if so
+    fn allows(&self, notification: &Notification) -> bool {
+        let event = notification.event;
+        if self
+            .rules
+            .events
+            .as_ref()
+            .is_some_and(|e| !e.contains(&event))
+        {
+            return false;
+        }
+        if self
+            .rules
+            .min_severity
+            .is_some_and(|min| event.severity() < min)
+        {
+            return false;
+        }
+
+        let now = notification.timestamp;
+        let longest = self
+            .rate_limits
+            .values()
+            .map(|(_, period)| *period)
+            .chain(self.dedup_window)
+            .max();
+        let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+        sent.retain(|n| longest.is_some_and(|longest| now - n.timestamp < longest));
+        let within = |window: chrono::Duration| {
+            sent.iter()
+                .filter(move |n| now - n.timestamp < window && same_record(n, notification))
+        };
+        if let Some(window) = self.dedup_window {
+            let duplicate = within(window).any(|n| {
+                (n.event, n.old_ip, n.new_ip, &n.error)
+                    == (
+                        event,
+                        notification.old_ip,
+                        notification.new_ip,
+                        &notification.error,
+                    )
+            });
+            if duplicate {
+                return false;
+            }
+        }
+        if let Some((count, period)) = self.rate_limits.get(&event)
+            && within(*period).filter(|n| n.event == event).count() >= *count
+        {
+            return false;
+        }
+        sent.push(notification.clone());
+        true
+    }
+}
+
+#[async_trait]
+impl Notifier for Filtered {
+    fn name(&self) -> &str {
+        self.notifier.name()
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        if !self.allows(notification) {
+            let (notifier, event) = (self.name(), notification.event.simple_name());
+            tracing::debug!(notifier, event, "Notification held back by the rules");
+            return Ok(());
+        }
+        self.notifier.notify(notification).await
+    }
+}
+
+/// POSTs each [`Notification`] as JSON, for n8n, Zapier or custom endpoints
+#[derive(Debug)]
+pub struct Webhook {
+    url: Secret<Url>,
+    secret: Option<Secret>,
+    client: Client,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig, client: &Client) -> Result<Self, String> {
+        let url =
+            Url::parse(config.url.expose()).map_err(|e| format!("Invalid webhook URL: {e}"))?;
+        Ok(Self {
+            url: Secret::new(url),
+            secret: config.secret.clone(),
+            client: client.clone(),
+        })
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` keyed with `secret`
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let body = serde_json::to_vec(notification).map_err(|e| e.to_string())?;
+        let mut request = self
+            .client
+            .post(self.url.expose().clone())
+            .header("Content-Type", "application/json");
+        if let Some(secret) = &self.secret {
+            let signature = format!("sha256={}", sign(secret.expose(), &body));
+            request = request.header("X-DNS-Updater-Signature", signature);
+        }
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Posts each [`Notification`] to a Discord channel as an embed
+#[derive(Debug)]
+pub struct Discord {
+    url: Secret<Url>,
+    formatter: Formatter,
+    client: Client,
+}
+
+impl Discord {
+    pub fn new(
+        config: &DiscordConfig,
+        formatter: Formatter,
+        client: &Client,
+    ) -> Result<Self, String> {
+        let url = Url::parse(config.url.expose())
+            .map_err(|e| format!("Invalid Discord webhook URL: {e}"))?;
+        Ok(Self {
+            url: Secret::new(url),
+            formatter,
+            client: client.clone(),
+        })
+    }
+
+    /// The webhook message: an embed titled after the event, colored green
+    /// when things work and red when they don't, with one field per detail
+    /// and the message of the template as its description
+    fn message(&self, notification: &Notification) -> serde_json::Value {
+        let color = match notification.event {
+            NotificationEvent::Change => 0x3498db,
+            NotificationEvent::Failure => 0xe74c3c,
+            NotificationEvent::Recovery => 0x2ecc71,
+            NotificationEvent::Flapping => 0xf39c12,
+        };
+        let old_ip = notification
+            .old_ip
+            .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        let mut fields = vec![
+            json!({"name": "Provider", "value": notification.provider, "inline": true}),
+            json!({"name": "IP version", "value": notification.ip_version, "inline": true}),
+            json!({"name": "Old IP", "value": old_ip, "inline": true}),
+            json!({"name": "New IP", "value": notification.new_ip.to_string(), "inline": true}),
+        ];
+        if let Some(networks) = &notification.networks {
+            let summary = networks.summary();
+            fields.push(json!({"name": "Network", "value": summary, "inline": false}));
+        }
+        if let Some(error) = &notification.error {
+            // Embed field values are limited to 1024 characters
+            let error: String = error.chars().take(1024).collect();
+            fields.push(json!({"name": "Error", "value": error, "inline": false}));
+        }
+        let mut embed = json!({
+            "title": self.formatter.title(notification),
+            "color": color,
+            "fields": fields,
+            "timestamp": notification.timestamp.to_rfc3339(),
+        });
+        if let Some(text) = self.formatter.custom_text(notification) {
+            embed["description"] = text.into();
+        }
+        json!({
+            "username": "dns-updater",
+            "embeds": [embed],
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Discord {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let response = self
+            .client
+            .post(self.url.expose().clone())
+            .header("Content-Type", "application/json")
+            .body(self.message(notification).to_string())
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Publishes each [`Notification`] to an ntfy topic, for push
+/// notifications on phones
+#[derive(Debug)]
+pub struct Ntfy {
+    /// The server's root, messages are published as JSON
+    url: Url,
+    config: NtfyConfig,
+    formatter: Formatter,
+    client: Client,
+}
+
+impl Ntfy {
+    pub fn new(config: &NtfyConfig, formatter: Formatter, client: &Client) -> Result<Self, String> {
+        let url = Url::parse(&config.server).map_err(|e| format!("Invalid ntfy server: {e}"))?;
+        if config.topic.is_empty() {
+            return Err("The ntfy topic is empty".to_string());
+        }
+        if let Some(p) = config.priorities.values().find(|p| !(1..=5).contains(*p)) {
+            return Err(format!("Invalid ntfy priority {p}, expected 1 to 5"));
+        }
+        if config.token.is_some() && config.username.is_some() {
+            return Err("ntfy takes a token or a username, not both".to_string());
+        }
+        Ok(Self {
+            url,
+            config: config.clone(),
+            formatter,
+            client: client.clone(),
+        })
+    }
+
+    fn message(&self, notification: &Notification) -> serde_json::Value {
+        let event = notification.event;
+        let priority = self.config.priorities.get(&event).copied();
+        let tags = self.config.tags.get(&event).cloned();
+        json!({
+            "topic": self.config.topic,
+            "title": self.formatter.title(notification),
+            "message": self.formatter.text(notification),
+            "priority": priority.unwrap_or_else(|| ntfy_priority(event)),
+            "tags": tags.unwrap_or_else(|| ntfy_tags(event)),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Ntfy {
+    fn name(&self) -> &str {
+        "ntfy"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header("Content-Type", "application/json")
+            .body(self.message(notification).to_string());
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token.expose());
+        } else if let Some(username) = &self.config.username {
+            let password = self.config.password.as_ref().map(Secret::expose);
+            request = request.basic_auth(username, password);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Pushes each [`Notification`] to a self-hosted Gotify server
+#[derive(Debug)]
+pub struct Gotify {
+    /// The server's `/message` endpoint
+    url: Url,
+    token: Secret,
+    formatter: Formatter,
+    client: Client,
+}
+
+impl Gotify {
+    pub fn new(
+        config: &GotifyConfig,
+        formatter: Formatter,
+        client: &Client,
+    ) -> Result<Self, String> {
+        let mut url =
+            Url::parse(&config.server).map_err(|e| format!("Invalid Gotify server: {e}"))?;
+        // The server may be served under a path
+        url.path_segments_mut()
+            .map_err(|_| "Invalid Gotify server: not a base URL".to_string())?
+            .pop_if_empty()
+            .push("message");
+        Ok(Self {
+            url,
+            token: config.token.clone(),
+            formatter,
+            client: client.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Gotify {
+    fn name(&self) -> &str {
+        "gotify"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let message = json!({
+            "title": self.formatter.title(notification),
+            "message": self.formatter.text(notification),
+            "priority": gotify_priority(notification.event),
+        });
+        let response = self
+            .client
+            .post(self.url.clone())
+            .header("Content-Type", "application/json")
+            // Rather than the `token` parameter, which would end up in logs
+            .header("X-Gotify-Key", self.token.expose())
+            .body(message.to_string())
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Pushover's message API
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+/// How often an emergency (priority 2) message is repeated until
+/// acknowledged, and for how long, in seconds
+const PUSHOVER_RETRY: u32 = 300;
+const PUSHOVER_EXPIRE: u32 = 3600;
+
+/// Sends each [`Notification`] to Pushover users
+#[derive(Debug)]
+pub struct Pushover {
+    config: PushoverConfig,
+    formatter: Formatter,
+    client: Client,
+}
+
+impl Pushover {
+    pub fn new(
+        config: &PushoverConfig,
+        formatter: Formatter,
+        client: &Client,
+    ) -> Result<Self, String> {
+        if let Some(p) = config.priorities.values().find(|p| !(-2..=2).contains(*p)) {
+            return Err(format!("Invalid Pushover priority {p}, expected -2 to 2"));
+        }
+        Ok(Self {
+            config: config.clone(),
+            formatter,
+            client: client.clone(),
+        })
+    }
+
+    fn message(&self, notification: &Notification) -> serde_json::Value {
+        let event = notification.event;
+        let priority = self.config.priorities.get(&event).copied();
+        let priority = priority.unwrap_or_else(|| pushover_priority(event));
+        let mut message = json!({
+            "token": self.config.token,
+            "user": self.config.user,
+            "title": self.formatter.title(notification),
+            "message": self.formatter.text(notification),
+            "priority": priority,
+            "timestamp": notification.timestamp.timestamp(),
+        });
+        // Required by emergency messages
+        if priority == 2 {
+            message["retry"] = PUSHOVER_RETRY.into();
+            message["expire"] = PUSHOVER_EXPIRE.into();
+        }
+        message
+    }
+}
+
+#[async_trait]
+impl Notifier for Pushover {
+    fn name(&self) -> &str {
+        "pushover"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let response = self
+            .client
+            .post(PUSHOVER_URL)
+            .header("Content-Type", "application/json")
+            .body(self.message(notification).to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            // Pushover tells what's wrong, e.g. an invalid user key
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("HTTP {status}: {}", body.trim()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use reqwest::Client;
+
+    use super::{
+        Discord, DiscordConfig, Filtered, Formatter, Notification, NotificationEvent,
+        Notifications, Notifier, Notifiers, Ntfy, Pushover, Rules, Severity, Templates, sign,
+    };
+    use crate::hooks::HookContext;
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<Notification>>);
+
+    #[async_trait]
+    impl Notifier for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        async fn notify(&self, notification: &Notification) -> Result<(), String> {
+            self.0.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct Failing;
+
+    #[async_trait]
+    impl Notifier for Failing {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        async fn notify(&self, _: &Notification) -> Result<(), String> {
+            Err("unreachable".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_to_every_notifier() {
+        let recorder = Arc::new(Recorder::default());
+        let notifiers = Notifiers::new(vec![Arc::new(Failing), recorder.clone()]);
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("name"),
+            old_ip: Some("192.0.2.1".parse().unwrap()),
+            new_ip: "192.0.2.2".parse().unwrap(),
+            error: None,
+        };
+        notifiers
+            .send(Notification::new(NotificationEvent::Change, &ctx))
+            .await;
+
+        let sent = recorder.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].event, NotificationEvent::Change);
+        assert_eq!(sent[0].ip_version, "ipv4");
+        assert_eq!(sent[0].hostname.as_deref(), Some("name"));
+    }
+
+    #[test]
+    fn test_sign() {
+        // echo -n '{"event":"change"}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", br#"{"event":"change"}"#),
+            "cc31bec7debda26e1e2191d427dd3defb851a0229e37dd9390fcf5ec22a2ee40"
+        );
+    }
+
+    #[test]
+    fn test_discord_message() {
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("name"),
+            old_ip: None,
+            new_ip: "192.0.2.2".parse().unwrap(),
+            error: Some("KO"),
+        };
+        let config = DiscordConfig {
+            url: "https://discord.com/api/webhooks/1/token".into(),
+            rules: Rules::default(),
+        };
+        let discord = Discord::new(&config, Formatter::default(), &Client::new()).unwrap();
+        let message = discord.message(&Notification::new(NotificationEvent::Failure, &ctx));
+        let embed = &message["embeds"][0];
+        assert_eq!(embed["title"], "Couldn't update name");
+        assert_eq!(embed["color"], 0xe74c3c);
+        assert_eq!(embed["fields"][2]["value"], "-");
+        assert_eq!(embed["fields"][4]["value"], "KO");
+    }
+
+    #[test]
+    fn test_ntfy_message() {
+        let toml = r#"
+            [[ntfy]]
+            topic = "dns"
+            priorities = { failure = 5 }
+            tags = { change = ["house"] }
+        "#;
+        let notifications: Notifications = toml::from_str(toml).unwrap();
+        assert_eq!(notifications.ntfy[0].server, "https://ntfy.sh");
+        let ntfy = Ntfy::new(&notifications.ntfy[0], Formatter::default(), &Client::new()).unwrap();
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("name"),
+            old_ip: Some("192.0.2.1".parse().unwrap()),
+            new_ip: "192.0.2.2".parse().unwrap(),
+            error: None,
+        };
+        let change = ntfy.message(&Notification::new(NotificationEvent::Change, &ctx));
+        assert_eq!(change["title"], "IP of name changed");
+        assert_eq!(change["message"], "DuckDNS ipv4: 192.0.2.1 -> 192.0.2.2");
+        assert_eq!(
+            (&change["priority"], &change["tags"][0]),
+            (&3.into(), &"house".into())
+        );
+        let failure = ntfy.message(&Notification::new(NotificationEvent::Failure, &ctx));
+        assert_eq!(
+            (&failure["priority"], &failure["tags"][0]),
+            (&5.into(), &"warning".into())
+        );
+
+        let mut invalid = notifications.ntfy[0].clone();
+        invalid.priorities.insert(NotificationEvent::Change, 6);
+        assert!(Ntfy::new(&invalid, Formatter::default(), &Client::new()).is_err());
+    }
+
+    #[test]
+    fn test_pushover_message() {
+        let toml = r#"
+            [[pushover]]
+            user = "u"
+            token = "t"
+            priorities = { failure = 2 }
+        "#;
+        let notifications: Notifications = toml::from_str(toml).unwrap();
+        let pushover = Pushover::new(
+            &notifications.pushover[0],
+            Formatter::default(),
+            &Client::new(),
+        )
+        .unwrap();
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("name"),
+            old_ip: Some("192.0.2.1".parse().unwrap()),
+            new_ip: "192.0.2.2".parse().unwrap(),
+            error: Some("KO"),
+        };
+        let change = pushover.message(&Notification::new(NotificationEvent::Change, &ctx));
+        assert_eq!(change["priority"], 0);
+        assert!(change.get("retry").is_none());
+        let failure = pushover.message(&Notification::new(NotificationEvent::Failure, &ctx));
+        assert_eq!(failure["priority"], 2);
+        assert_eq!(failure["retry"], 300);
+        assert_eq!(
+            failure["message"],
+            "DuckDNS ipv4: 192.0.2.1 -> 192.0.2.2\nError: KO"
+        );
+    }
+
+    #[test]
+    fn test_templates() {
+        let templates = Templates {
+            title: Some("{{ hostname }}: {{ event }}".to_string()),
+            message: Some("{{ old_ip or 'nothing' }} -> {{ new_ip }}".to_string()),
+        };
+        let formatter = Formatter::new(&templates).unwrap();
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("name"),
+            old_ip: None,
+            new_ip: "192.0.2.2".parse().unwrap(),
+            error: None,
+        };
+        let notification = Notification::new(NotificationEvent::Change, &ctx);
+        assert_eq!(formatter.title(&notification), "name: change");
+        assert_eq!(formatter.text(&notification), "nothing -> 192.0.2.2");
+
+        // Unknown variables and syntax errors are caught on load
+        for title in ["{{ new_ipv4 }}", "{{ new_ip "] {
+            let templates = Templates {
+                title: Some(title.to_string()),
+                message: None,
+            };
+            assert!(Formatter::new(&templates).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let toml = r#"
+            events = ["change", "failure", "recovery"]
+            min_severity = "info"
+            dedup_window = "30m"
+            rate_limits = { failure = "2/1h" }
+        "#;
+        let rules: Rules = toml::from_str(toml).unwrap();
+        let filtered = Filtered::new(Arc::new(Failing), &rules).unwrap();
+
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("name"),
+            old_ip: Some("192.0.2.1".parse().unwrap()),
+            new_ip: "192.0.2.2".parse().unwrap(),
+            error: Some("KO"),
+        };
+        let start = Notification::new(NotificationEvent::Failure, &ctx).timestamp;
+        let at = |event, minutes, error: &str| {
+            let mut notification = Notification::new(
+                event,
+                &HookContext {
+                    error: Some(error),
+                    ..ctx
+                },
+            );
+            notification.timestamp = start + chrono::Duration::minutes(minutes);
+            notification
+        };
+        assert!(!filtered.allows(&at(NotificationEvent::Flapping, 0, "KO")));
+        assert!(filtered.allows(&at(NotificationEvent::Failure, 0, "KO")));
+        // Same failure within 30 minutes
+        assert!(!filtered.allows(&at(NotificationEvent::Failure, 10, "KO")));
+        assert!(filtered.allows(&at(NotificationEvent::Failure, 20, "timeout")));
+        // At most two failures an hour
+        assert!(!filtered.allows(&at(NotificationEvent::Failure, 40, "HTTP 500")));
+        assert!(filtered.allows(&at(NotificationEvent::Change, 40, "KO")));
+        assert!(filtered.allows(&at(NotificationEvent::Failure, 61, "HTTP 500")));
+
+        let errors_only = Rules {
+            min_severity: Some(Severity::Error),
+            ..Default::default()
+        };
+        let filtered = Filtered::new(Arc::new(Failing), &errors_only).unwrap();
+        assert!(!filtered.allows(&at(NotificationEvent::Recovery, 0, "KO")));
+        assert!(filtered.allows(&at(NotificationEvent::Failure, 0, "KO")));
+        let invalid = Rules {
+            rate_limits: [(NotificationEvent::Failure, "0/1h".to_string())].into(),
+            ..Default::default()
+        };
+        assert!(Filtered::new(Arc::new(Failing), &invalid).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Waiting for the network at boot, when the updater often starts before the
+//! interface is configured: detecting and publishing right away would only
+//! log errors, or fail `once` runs, until the first retry.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{IpVersion, SimpleName};
+
+/// Addresses a route to the internet is looked up for, Cloudflare's DNS
+const PROBE_V4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const PROBE_V6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
+/// Between the first two probes, doubled after each one up to [`MAX_RETRY`]
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(10);
+
+/// How [`wait_online`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Online {
+    /// Every route was there from the start
+    Already,
+    /// The last route showed up after this long
+    After(Duration),
+    /// A route was still missing once the timeout elapsed
+    TimedOut,
+}
+
+/// Whether there's a route to the internet for `version`. Connecting a UDP
+/// socket sends nothing, it only fails while no route leads to the address.
+pub fn has_route(version: IpVersion) -> bool {
+    let (local, remote) = match version {
+        IpVersion::V4 => (
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((PROBE_V4, 53)),
+        ),
+        IpVersion::V6 => (
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((PROBE_V6, 53)),
+        ),
+    };
+    UdpSocket::bind(local)
+        .and_then(|socket| socket.connect(remote))
+        .is_ok()
+}
+
+/// Waits up to `timeout` until `probe` finds a route for every IP version of
+/// `versions`, probing again after 1s, 2s, 4s... up to every 10s. Logs when
+/// it starts waiting and how it ended, never failing: the providers retry
+/// on their own schedule once started.
+pub async fn wait_online(
+    versions: &[IpVersion],
+    timeout: Duration,
+    probe: impl Fn(IpVersion) -> bool,
+) -> Online {
+    let start = Instant::now();
+    let mut retry = FIRST_RETRY;
+    let mut waiting = false;
+    loop {
+        let offline: Vec<_> = versions
+            .iter()
+            .filter(|version| !probe(**version))
+            .map(|version| version.simple_name())
+            .collect();
+        if offline.is_empty() {
+            if !waiting {
+                return Online::Already;
+            }
+            let waited = start.elapsed();
+            tracing::info!(waited = ?waited, "The network is online");
+            return Online::After(waited);
+        }
+        let left = timeout.saturating_sub(start.elapsed());
+        if left.is_zero() {
+            tracing::warn!(
+                offline = offline.join(","),
+                timeout = ?timeout,
+                "The network is still offline, starting anyway"
+            );
+            return Online::TimedOut;
+        }
+        if !waiting {
+            tracing::info!(offline = offline.join(","), "Waiting for the network");
+            waiting = true;
+        }
+        tokio::time::sleep(retry.min(left)).await;
+        retry = (retry * 2).min(MAX_RETRY);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    use super::{Online, wait_online};
+    use crate::IpVersion;
+
+    #[tokio::test]
+    async fn test_wait_online() {
+        let versions = [IpVersion::V4, IpVersion::V6];
+        let probes = AtomicU32::new(0);
+        // IPv6 comes up on the second probe
+        let boot = |version| version == IpVersion::V4 || probes.fetch_add(1, Ordering::SeqCst) > 0;
+        let (up, down, already) = tokio::join!(
+            wait_online(&versions, Duration::from_secs(5), boot),
+            wait_online(&versions, Duration::from_millis(1500), |version| {
+                version == IpVersion::V4
+            }),
+            wait_online(&[IpVersion::V4], Duration::ZERO, |_| true),
+        );
+        assert!(matches!(up, Online::After(waited) if waited >= Duration::from_secs(1)));
+        assert_eq!(probes.load(Ordering::SeqCst), 2);
+        assert_eq!(down, Online::TimedOut);
+        assert_eq!(already, Online::Already);
+        assert_eq!(
+            wait_online(&versions, Duration::ZERO, |_| false).await,
+            Online::TimedOut
+        );
+    }
+}
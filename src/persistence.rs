@@ -1,89 +1,737 @@
-use std::fs;
-use std::fs::OpenOptions;
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::io;
 use std::net::{AddrParseError, IpAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
-#[derive(Debug)]
-pub enum CreateError {
-    NoFileNames,
-    CannotUseFile(String),
-}
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::dyn_dns::DynDns;
+use crate::history::{self, History, HistoryEntry};
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    CE(CreateError),
-    Io(io::Error),
-    Parse(AddrParseError),
-    NoFileNames,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid IP: {0}")]
+    Parse(#[from] AddrParseError),
+    #[error("invalid state: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("history: {0}")]
+    History(#[from] history::Error),
+    /// Failure of a store outside this crate
+    #[error("{0}")]
+    Backend(String),
+    /// Error of the library behind a store
+    #[error("{0}")]
+    Store(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// What is known of one provider: the IP it was last updated to and how its
+/// latest attempts went
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProviderState {
+    /// The last IP published, `None` when it never was
+    pub ip: Option<IpAddr>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Failed attempts since the last success
+    pub consecutive_failures: u32,
+    /// What the provider answered to the last attempt, or why it couldn't be reached
+    pub last_response: Option<String>,
+    /// Updates accepted since the state was created
+    pub successes: u64,
+    /// Updates that failed since the state was created
+    pub failures: u64,
+    /// Why the latest failed attempt failed, kept after later successes
+    pub last_error: Option<String>,
+    /// Moving average of the time the provider takes to answer, in
+    /// milliseconds, mostly weighted by the last ten attempts
+    pub average_latency_ms: Option<u64>,
+    /// Time the provider took to answer each of the last
+    /// [`RECENT_LATENCIES`] attempts, in milliseconds, oldest first
+    pub recent_latencies_ms: Vec<u64>,
+}
+
+/// How many latencies [`ProviderState::recent_latencies_ms`] keeps
+pub const RECENT_LATENCIES: usize = 100;
+
+impl ProviderState {
+    pub fn record_success(
+        &mut self,
+        ip: IpAddr,
+        response: String,
+        at: DateTime<Utc>,
+        latency: Duration,
+    ) {
+        self.ip = Some(ip);
+        self.last_attempt = Some(at);
+        self.last_success = Some(at);
+        self.consecutive_failures = 0;
+        self.last_response = Some(response);
+        self.successes += 1;
+        self.record_latency(latency);
+    }
+
+    pub fn record_failure(&mut self, error: String, at: DateTime<Utc>, latency: Duration) {
+        self.last_attempt = Some(at);
+        self.consecutive_failures += 1;
+        self.last_error = Some(error.clone());
+        self.last_response = Some(error);
+        self.failures += 1;
+        self.record_latency(latency);
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        let latency = latency.as_millis().try_into().unwrap_or(u64::MAX);
+        self.average_latency_ms = Some(match self.average_latency_ms {
+            Some(average) => (average.saturating_mul(4).saturating_add(latency)) / 5,
+            None => latency,
+        });
+        if self.recent_latencies_ms.len() >= RECENT_LATENCIES {
+            let excess = self.recent_latencies_ms.len() + 1 - RECENT_LATENCIES;
+            self.recent_latencies_ms.drain(..excess);
+        }
+        self.recent_latencies_ms.push(latency);
+    }
+}
+
+/// Where the state of every provider and the history of changes are kept.
+/// Providers are identified by their
+/// [`crate::dyn_dns::PersistsToFile::file_name`].
+#[async_trait]
+pub trait StateStore: Send + Sync + Debug {
+    /// The state of `key`, the default one when nothing was saved yet
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error>;
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error>;
+    /// Forgets the state of `key`, if any
+    async fn remove_state(&self, key: &str) -> Result<(), Error>;
+    /// Every provider with a saved IP
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error>;
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error>;
+    /// Every recorded change, oldest first
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error>;
+    /// Overwrites the history with `entries`, e.g. when restoring a backup
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error>;
+
+    /// Drops the history entries recorded before `before`. Stores override
+    /// it when entries appended meanwhile could otherwise be lost.
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        let entries = self.history().await?;
+        let kept: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.timestamp >= before)
+            .cloned()
+            .collect();
+        if kept.len() < entries.len() {
+            self.replace_history(&kept).await?;
+        }
+        Ok(())
+    }
+
+    /// The last IP published for `key`, `None` when it never was
+    async fn load_ip(&self, key: &str) -> Result<Option<IpAddr>, Error> {
+        Ok(self.load_state(key).await?.ip)
+    }
 }
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Self {
-        Error::Io(err)
+/// Moves the state saved under the names of older versions, which held
+/// credentials, to the current ones of `dyn_dnss`. A state already saved
+/// under the current name is kept.
+pub async fn migrate_legacy_names(store: &dyn StateStore, dyn_dnss: &[Box<dyn DynDns>]) {
+    for dd in dyn_dnss {
+        let Some(legacy) = dd.legacy_file_name() else {
+            continue;
+        };
+        // The legacy name isn't logged, it's what shouldn't be seen
+        match migrate_name(store, legacy, dd.file_name()).await {
+            Ok(true) => tracing::info!(
+                provider = dd.provider_name(),
+                hostname = dd.hostname().unwrap_or("-"),
+                key = dd.file_name(),
+                "State moved to its current key"
+            ),
+            Ok(false) => {}
+            Err(e) => tracing::warn!(
+                provider = dd.provider_name(),
+                hostname = dd.hostname().unwrap_or("-"),
+                key = dd.file_name(),
+                error = %e,
+                "Couldn't move the state to its current key"
+            ),
+        }
     }
 }
 
-impl From<AddrParseError> for Error {
-    fn from(err: AddrParseError) -> Self {
-        Error::Parse(err)
+/// Imports the state files older versions left in `dir`, e.g. the working
+/// directory they ran from, into `store`. Files are looked up under the
+/// legacy and current names of `dyn_dnss`, and renamed with a `.migrated`
+/// suffix once imported so they're only imported once. A state already in
+/// `store` is kept.
+pub async fn import_legacy_files(store: &dyn StateStore, dyn_dnss: &[Box<dyn DynDns>], dir: &Path) {
+    for dd in dyn_dnss {
+        let names = dd.legacy_file_name().into_iter().chain([dd.file_name()]);
+        for name in names {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            match import_file(store, &path, dd.file_name()).await {
+                Ok(()) => tracing::info!(
+                    provider = dd.provider_name(),
+                    hostname = dd.hostname().unwrap_or("-"),
+                    dir = %dir.display(),
+                    "Imported the state"
+                ),
+                Err(e) => tracing::warn!(
+                    provider = dd.provider_name(),
+                    hostname = dd.hostname().unwrap_or("-"),
+                    dir = %dir.display(),
+                    error = ?e,
+                    "Couldn't import the state"
+                ),
+            }
+        }
     }
 }
 
-pub struct Persistence {
-    pub file_paths: Vec<PathBuf>,
+async fn import_file(store: &dyn StateStore, path: &Path, key: &str) -> Result<(), Error> {
+    let state = FileStore::read_state(path).await?;
+    if state.ip.is_some() && store.load_state(key).await?.ip.is_none() {
+        store.save_state(key, &state).await?;
+    }
+    let mut migrated = path.as_os_str().to_owned();
+    migrated.push(".migrated");
+    tokio::fs::rename(path, migrated).await?;
+    Ok(())
 }
 
-impl Persistence {
-    pub fn new<'a, T: IntoIterator<Item = &'a str>>(file_names: T) -> Result<Self, Error> {
-        let fps: Result<Vec<PathBuf>, CreateError> = file_names
-            .into_iter()
-            .map(|name| {
-                let pb = PathBuf::from(name);
-                let _ = OpenOptions::new()
-                    .write(true)
-                    .create(true) // Create if it doesn't exist
-                    .truncate(false) // Do NOT wipe the file if it exists
-                    .open(&pb)
-                    .map_err(|e| CreateError::CannotUseFile(e.to_string()))?;
-                Ok(pb)
-            })
-            .collect();
-        let fps = fps.map_err(Error::CE)?;
-        if fps.is_empty() {
-            Err(Error::CE(CreateError::NoFileNames))?
+/// Brings the state of older versions into `store`: moves it from the
+/// legacy names with [`migrate_legacy_names`], then imports the files left
+/// in `legacy_dirs` with [`import_legacy_files`].
+pub async fn migrate_legacy_state(
+    store: &dyn StateStore,
+    dyn_dnss: &[Box<dyn DynDns>],
+    legacy_dirs: &[PathBuf],
+) {
+    migrate_legacy_names(store, dyn_dnss).await;
+    for dir in legacy_dirs {
+        import_legacy_files(store, dyn_dnss, dir).await;
+    }
+}
+
+/// Whether a state was moved from `legacy` to `key`
+async fn migrate_name(store: &dyn StateStore, legacy: &str, key: &str) -> Result<bool, Error> {
+    let state = store.load_state(legacy).await?;
+    let moved = state != ProviderState::default()
+        && store.load_state(key).await? == ProviderState::default();
+    if moved {
+        store.save_state(key, &state).await?;
+    }
+    store.remove_state(legacy).await?;
+    Ok(moved)
+}
+
+/// Which [`StateStore`] keeps the state, the `state` table of the
+/// configuration file. Relative paths are resolved from the state directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StateBackend {
+    /// A [`FileStore`] in the state directory
+    #[default]
+    Files,
+    /// A [`MemoryStore`], nothing being kept across restarts. Every record
+    /// is published again on start.
+    #[serde(rename = "none", alias = "memory")]
+    Memory,
+    /// A [`crate::sqlite::SqliteStore`], with the `sqlite` feature
+    Sqlite {
+        #[serde(default = "default_sqlite_path")]
+        path: PathBuf,
+    },
+    /// A [`crate::redb::RedbStore`], with the `redb` feature
+    Redb {
+        #[serde(default = "default_redb_path")]
+        path: PathBuf,
+    },
+    /// A [`crate::redis::RedisStore`], with the `redis` feature. Instances
+    /// sharing the server and `prefix` share the state.
+    Redis {
+        /// e.g. `redis://:${REDIS_PASSWORD}@redis:6379/0`
+        url: String,
+        #[serde(default = "default_redis_prefix")]
+        prefix: String,
+    },
+    /// A [`crate::encrypted::EncryptedStore`], with the `encrypted` feature.
+    /// The key is given either in `key`, e.g. `${keyring:state-key}`, or
+    /// in `key_file`.
+    Encrypted {
+        #[serde(default = "default_encrypted_path")]
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_file: Option<PathBuf>,
+    },
+}
+
+fn default_sqlite_path() -> PathBuf {
+    PathBuf::from("state.db")
+}
+
+fn default_redb_path() -> PathBuf {
+    PathBuf::from("state.redb")
+}
+
+fn default_encrypted_path() -> PathBuf {
+    PathBuf::from("state.enc")
+}
+
+fn default_redis_prefix() -> String {
+    "dns-updater".to_string()
+}
+
+/// Options creating files only the current user may read or write on Unix.
+/// State files name the accounts they update, and whoever may write them
+/// decides what the updater believes it has published.
+pub fn private_file_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+/// Replaces the file at `path` with `content`, through a temporary file
+/// renamed over it, so that readers never see it half written
+pub(crate) async fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or(io::Error::other("Not a file path"))?
+        .to_string_lossy();
+    // Hidden, so that it's never listed as a state
+    let tmp = path.with_file_name(format!(".{name}.tmp"));
+    let mut options = private_file_options();
+    options.write(true).truncate(true);
+    let mut file = tokio::fs::OpenOptions::from(options).open(&tmp).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+impl StateBackend {
+    pub fn is_default(&self) -> bool {
+        *self == Self::Files
+    }
+
+    /// Opens the store, creating it when needed
+    pub fn open(&self) -> Result<Arc<dyn StateStore>, Error> {
+        match self {
+            StateBackend::Files => Ok(Arc::new(FileStore::current_dir())),
+            StateBackend::Memory => Ok(Arc::new(MemoryStore::default())),
+            #[cfg(feature = "sqlite")]
+            StateBackend::Sqlite { path } => Ok(Arc::new(crate::sqlite::SqliteStore::open(path)?)),
+            #[cfg(not(feature = "sqlite"))]
+            StateBackend::Sqlite { .. } => Err(Error::Backend(
+                "dns_updater was built without the sqlite feature".to_string(),
+            )),
+            #[cfg(feature = "redb")]
+            StateBackend::Redb { path } => Ok(Arc::new(crate::redb::RedbStore::open(path)?)),
+            #[cfg(not(feature = "redb"))]
+            StateBackend::Redb { .. } => Err(Error::Backend(
+                "dns_updater was built without the redb feature".to_string(),
+            )),
+            #[cfg(feature = "redis")]
+            StateBackend::Redis { url, prefix } => Ok(Arc::new(crate::redis::RedisStore::open(
+                url,
+                prefix.clone(),
+            )?)),
+            #[cfg(not(feature = "redis"))]
+            StateBackend::Redis { .. } => Err(Error::Backend(
+                "dns_updater was built without the redis feature".to_string(),
+            )),
+            #[cfg(feature = "encrypted")]
+            StateBackend::Encrypted {
+                path,
+                key,
+                key_file,
+            } => {
+                use crate::encrypted::EncryptedStore;
+                let store = match (key, key_file) {
+                    (Some(key), None) => EncryptedStore::open(path, key)?,
+                    (None, Some(key_file)) => EncryptedStore::open_with_key_file(path, key_file)?,
+                    _ => Err(Error::Backend(
+                        "The encrypted state needs either a key or a key_file".to_string(),
+                    ))?,
+                };
+                Ok(Arc::new(store))
+            }
+            #[cfg(not(feature = "encrypted"))]
+            StateBackend::Encrypted { .. } => Err(Error::Backend(
+                "dns_updater was built without the encrypted feature".to_string(),
+            )),
+        }
+    }
+}
+
+/// One JSON file per provider, named after it, and
+/// [`history::DEFAULT_FILE_NAME`], all in one directory. Files of older
+/// versions holding only the IP are still read, and rewritten as JSON on the
+/// next save.
+///
+/// Files are replaced atomically, and the changes to each of them are
+/// serialized between clones of the store.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+    history: History,
+    locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self {
+            history: History::new(dir.join(history::DEFAULT_FILE_NAME)),
+            dir,
+            locks: Arc::default(),
+        }
+    }
+
+    /// Keeps the state in the working directory, the default
+    pub fn current_dir() -> Self {
+        Self::new(".")
+    }
+
+    /// Records changes in `history` instead of the default file
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Held while the state of `key` changes
+    async fn lock(&self, key: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Reads a state file, the default state when it's missing or empty
+    async fn read_state(path: &Path) -> Result<ProviderState, Error> {
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ProviderState::default()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        match content.trim() {
+            "" => Ok(ProviderState::default()),
+            json if json.starts_with('{') => Ok(serde_json::from_str(json)?),
+            // Written by older versions
+            ip => Ok(ProviderState {
+                ip: Some(ip.parse()?),
+                ..Default::default()
+            }),
         }
-        Ok(Self { file_paths: fps })
+    }
+}
+
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::current_dir()
+    }
+}
+
+#[async_trait]
+impl StateStore for FileStore {
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error> {
+        Self::read_state(&self.dir.join(key)).await
+    }
+
+    /// Replaces the file with the new state
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(state)?;
+        let _lock = self.lock(key).await;
+        write_private(&self.dir.join(key), json.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn remove_state(&self, key: &str) -> Result<(), Error> {
+        let _lock = self.lock(key).await;
+        match tokio::fs::remove_file(self.dir.join(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Io(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every file of the directory holding a state with an IP, other files
+    /// are skipped
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error> {
+        let mut saved = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(key) = name.to_str().filter(|key| !key.starts_with('.')) else {
+                continue;
+            };
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            if let Ok(state) = Self::read_state(&entry.path()).await
+                && state.ip.is_some()
+            {
+                saved.push((key.to_string(), state));
+            }
+        }
+        saved.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(saved)
+    }
+
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        Ok(self.history.append(entry).await?)
     }
 
-    fn match_file_name(&self, file_name: &str) -> Result<&PathBuf, Error> {
-        self.file_paths
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        Ok(self.history.read().await?)
+    }
+
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        Ok(self.history.replace(entries).await?)
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        Ok(self.history.prune(before).await?)
+    }
+}
+
+/// Keeps the state in memory only, for containers without a durable disk
+/// that would rather publish every record again on start than mount a
+/// volume.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    states: Mutex<HashMap<String, ProviderState>>,
+    history: Mutex<Vec<HistoryEntry>>,
+}
+
+impl MemoryStore {
+    fn lock_states(&self) -> MutexGuard<'_, HashMap<String, ProviderState>> {
+        self.states.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_history(&self) -> MutexGuard<'_, Vec<HistoryEntry>> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl StateStore for MemoryStore {
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error> {
+        Ok(self.lock_states().get(key).cloned().unwrap_or_default())
+    }
+
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error> {
+        self.lock_states().insert(key.to_string(), state.clone());
+        Ok(())
+    }
+
+    async fn remove_state(&self, key: &str) -> Result<(), Error> {
+        self.lock_states().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error> {
+        let mut saved: Vec<_> = self
+            .lock_states()
             .iter()
-            .filter_map(|fp| {
-                fp.to_str().and_then(|s| {
-                    if s.ends_with(&file_name.to_string()) {
-                        Some(fp)
-                    } else {
-                        None
-                    }
-                })
-            })
-            .next()
-            .ok_or(Error::NoFileNames)
-    }
-
-    /// Overwrites the file with the new IP address
-    pub async fn replace_ip(&self, ip: &IpAddr, file_name: &str) -> Result<(), Error> {
-        let fp = self.match_file_name(file_name)?;
-        tokio::fs::write(fp, ip.to_string()).await?;
+            .filter(|(_, state)| state.ip.is_some())
+            .map(|(key, state)| (key.clone(), state.clone()))
+            .collect();
+        saved.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(saved)
+    }
+
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        self.lock_history().push(entry.clone());
         Ok(())
     }
 
-    /// Reads the IP from the file
-    pub fn load_ip(&self, file_name: &str) -> Result<IpAddr, Error> {
-        let fp = self.match_file_name(file_name)?;
-        let content = fs::read_to_string(fp)?;
-        let ip = content.trim().parse()?;
-        Ok(ip)
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        Ok(self.lock_history().clone())
+    }
+
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        *self.lock_history() = entries.to_vec();
+        Ok(())
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        self.lock_history()
+            .retain(|entry| entry.timestamp >= before);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use chrono::Utc;
+
+    use super::{
+        FileStore, MemoryStore, ProviderState, StateStore, import_legacy_files,
+        migrate_legacy_names,
+    };
+    use crate::{dyn_dns::parse_dns_tuples, history::HistoryEntry};
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_store_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = FileStore::new(&dir);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(store.load_ip("DuckDNS_tok_name").await.unwrap(), None);
+        let mut state = store.load_state("DuckDNS_tok_name").await.unwrap();
+        state.record_failure("KO".to_string(), Utc::now(), Duration::from_millis(120));
+        state.record_failure("KO".to_string(), Utc::now(), Duration::from_millis(120));
+        assert_eq!(state.consecutive_failures, 2);
+        state.record_success(ip, "OK".to_string(), Utc::now(), Duration::from_millis(620));
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!((state.successes, state.failures), (1, 2));
+        assert_eq!(state.last_error.as_deref(), Some("KO"));
+        assert_eq!(state.average_latency_ms, Some(220));
+        assert_eq!(state.recent_latencies_ms, [120, 120, 620]);
+        store.save_state("DuckDNS_tok_name", &state).await.unwrap();
+        assert_eq!(store.load_state("DuckDNS_tok_name").await.unwrap(), state);
+        assert_eq!(store.load_ip("DuckDNS_tok_name").await.unwrap(), Some(ip));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let meta = std::fs::metadata(dir.join("DuckDNS_tok_name")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        }
+        // Legacy empty files were created before the first update
+        std::fs::write(dir.join("FreeDNS_tok_ipv4"), "").unwrap();
+        assert_eq!(store.load_ip("FreeDNS_tok_ipv4").await.unwrap(), None);
+        // Legacy files hold the bare IP
+        std::fs::write(dir.join("FreeDNS_old_ipv4"), "192.0.2.1\n").unwrap();
+        let legacy = store.load_state("FreeDNS_old_ipv4").await.unwrap();
+        assert_eq!(legacy.ip, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(legacy.last_success, None);
+
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            provider: "DuckDNS".to_string(),
+            hostname: Some("name".to_string()),
+            old_ip: None,
+            new_ip: ip,
+            error: None,
+            latency_ms: None,
+            networks: None,
+        };
+        let old = HistoryEntry {
+            timestamp: entry.timestamp - chrono::Duration::days(100),
+            error: Some("badauth".to_string()),
+            ..entry.clone()
+        };
+        store.append_history(&old).await.unwrap();
+        store.append_history(&entry).await.unwrap();
+        assert_eq!(store.history().await.unwrap().len(), 2);
+        store
+            .prune_history(entry.timestamp - chrono::Duration::days(90))
+            .await
+            .unwrap();
+        assert_eq!(store.history().await.unwrap(), vec![entry]);
+        // The history isn't mistaken for a provider
+        let keys: Vec<_> = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["DuckDNS_tok_name", "FreeDNS_old_ipv4"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_names() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_migrate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = FileStore::new(&dir);
+        let dyn_dnss = parse_dns_tuples("DD;tok;ipv4;60;home,FD;tok;ipv4;60").unwrap();
+        std::fs::write(dir.join("DuckDNS_tok_home"), "192.0.2.1").unwrap();
+        std::fs::write(dir.join("FreeDNS_tok_ipv4"), "").unwrap();
+
+        migrate_legacy_names(&store, &dyn_dnss).await;
+        let ip = store.load_ip(dyn_dnss[0].file_name()).await.unwrap();
+        assert_eq!(ip, Some("192.0.2.1".parse().unwrap()));
+        assert!(!dir.join("DuckDNS_tok_home").exists());
+        assert!(!dir.join("FreeDNS_tok_ipv4").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_legacy_files() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_import_{}", std::process::id()));
+        let legacy = dir.join("legacy");
+        std::fs::create_dir_all(&legacy).unwrap();
+        let store = FileStore::new(&dir);
+        let dyn_dnss = parse_dns_tuples("DD;tok;ipv4;60;home,DD;tok;ipv4;60;office").unwrap();
+        std::fs::write(legacy.join("DuckDNS_tok_home"), "192.0.2.1\n").unwrap();
+        std::fs::write(legacy.join("DuckDNS_tok_office"), "192.0.2.2\n").unwrap();
+        let mut newer = ProviderState::default();
+        newer.record_success(
+            "192.0.2.3".parse().unwrap(),
+            "OK".to_string(),
+            Utc::now(),
+            Duration::from_millis(120),
+        );
+        let office = dyn_dnss[1].file_name();
+        store.save_state(office, &newer).await.unwrap();
+
+        import_legacy_files(&store, &dyn_dnss, &legacy).await;
+        let ip = store.load_ip(dyn_dnss[0].file_name()).await.unwrap();
+        assert_eq!(ip, Some("192.0.2.1".parse().unwrap()));
+        // What the store already knows wins
+        assert_eq!(store.load_state(office).await.unwrap(), newer);
+        assert!(!legacy.join("DuckDNS_tok_home").exists());
+        assert!(legacy.join("DuckDNS_tok_home.migrated").exists());
+        assert!(legacy.join("DuckDNS_tok_office.migrated").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(store.load_ip("DuckDNS_a").await.unwrap(), None);
+        let mut state = ProviderState::default();
+        state.record_success(ip, "OK".to_string(), Utc::now(), Duration::ZERO);
+        store.save_state("DuckDNS_b", &state).await.unwrap();
+        store
+            .save_state("DuckDNS_a", &ProviderState::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list().await.unwrap(),
+            vec![("DuckDNS_b".to_string(), state)]
+        );
+        store.remove_state("DuckDNS_b").await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
     }
 }
@@ -0,0 +1,396 @@
+//! Providers loaded from WebAssembly components implementing the `provider`
+//! world of `wit/provider.wit`, so providers can be maintained outside of
+//! this crate and dropped in without recompiling it. Plugins are sandboxed:
+//! they can only reach the network through the `http-request` function the
+//! updater lends them, and their fuel and memory are bounded.
+
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use reqwest::{Client, Method};
+use wasmtime::{
+    Config, Engine, Store, StoreLimits, StoreLimitsBuilder,
+    component::{Component, HasSelf, Linker},
+};
+
+use crate::{
+    IpVersion, SimpleName,
+    dyn_dns::{
+        CredentialCheck, DynDns, POLL_SECS_FIELD, PersistsToFile, ProviderOptions, UpdateError,
+        VERSION_FIELD, ip_version_part, required_part, schedule_part, state_file_name,
+    },
+    registry::{Field, ProviderSchema},
+    scheduler::Schedule,
+    secrets::Secret,
+    vcr,
+};
+
+wasmtime::component::bindgen!({
+    path: "wit/provider.wit",
+    world: "provider",
+    imports: { default: async },
+    exports: { default: async },
+});
+
+use ddns::provider::host;
+
+/// Bounds the work of a call, so a plugin stuck in a loop fails instead of
+/// keeping the updater busy forever
+const MAX_FUEL: u64 = 1_000_000_000;
+
+/// Bounds the memory a plugin may grow to
+const MAX_MEMORY: usize = 64 << 20;
+
+/// Name of the providers of [`PluginProvider`]
+const PROVIDER: &str = "Plugin";
+
+/// A provider publishing the IP by calling the `update` export of a
+/// WebAssembly component. What it returns is recorded as the provider's
+/// answer, and an error fails the update.
+pub struct PluginProvider {
+    path: PathBuf,
+    engine: Engine,
+    component: Component,
+    name: String,
+    config: Secret,
+    file_name: String,
+    ip_version: IpVersion,
+    schedule: Schedule,
+    options: ProviderOptions,
+}
+
+impl std::fmt::Debug for PluginProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PluginProvider")
+            .field("path", &self.path)
+            .field("name", &self.name)
+            .field("file_name", &self.file_name)
+            .field("ip_version", &self.ip_version)
+            .field("schedule", &self.schedule.to_string())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// What the calls of a plugin can reach
+struct State {
+    client: Client,
+    timeout: Option<Duration>,
+    limits: StoreLimits,
+}
+
+impl host::Host for State {
+    async fn http_request(
+        &mut self,
+        method: String,
+        url: String,
+        headers: Vec<(String, String)>,
+        body: Option<String>,
+    ) -> Result<host::Response, String> {
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|_| format!("invalid HTTP method '{method}'"))?;
+        let mut request = self.client.request(method, &url);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let answer = async {
+            let response = vcr::send(request).await?;
+            let status = response.status();
+            response.text().await.map(|body| (status, body))
+        };
+        // The URL may hold a token
+        let (status, body) = answer.await.map_err(|e| e.without_url().to_string())?;
+        Ok(host::Response {
+            status: status.as_u16(),
+            body,
+        })
+    }
+
+    async fn log(&mut self, message: String) {
+        tracing::info!(message, "Plugin output");
+    }
+}
+
+impl PluginProvider {
+    /// Compiles the component at `path`, which must implement the
+    /// `provider` world
+    pub fn new(
+        path: PathBuf,
+        name: String,
+        config: Secret,
+        ip_version: IpVersion,
+        schedule: Schedule,
+    ) -> Result<Self, String> {
+        let engine = engine()?;
+        let component = Component::from_file(&engine, &path)
+            .map_err(|e| format!("Invalid plugin {}: {e}", path.display()))?;
+        // Checks the imports and exports before the first update
+        linker(&engine)?
+            .instantiate_pre(&component)
+            .and_then(ProviderPre::new)
+            .map_err(|e| format!("Plugin {} isn't a provider: {e}", path.display()))?;
+        let file_name = state_file_name(
+            PROVIDER,
+            &[&path.to_string_lossy(), &name, ip_version.simple_name()],
+        );
+        let s = Self {
+            path,
+            engine,
+            component,
+            name,
+            config,
+            file_name,
+            ip_version,
+            schedule,
+            options: ProviderOptions::default(),
+        };
+        tracing::info!(dyn_dns = ?s, "Created DynDns");
+        Ok(s)
+    }
+
+    /// Calls `update` in a new instance, so no state survives between calls
+    async fn call(&self, client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let failed = |response: String| UpdateError::Rejected {
+            provider: PROVIDER,
+            response,
+        };
+        let state = State {
+            client: client.clone(),
+            timeout: self.options.timeout_secs.map(Duration::from_secs),
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        store
+            .set_fuel(MAX_FUEL)
+            .map_err(|e| failed(e.to_string()))?;
+        let linker = linker(&self.engine).map_err(failed)?;
+        let provider = Provider::instantiate_async(&mut store, &self.component, &linker)
+            .await
+            .map_err(|e| failed(format!("the plugin couldn't start: {e}")))?;
+        let ip = ip.to_string();
+        let called = provider
+            .call_update(&mut store, &ip, &self.name, self.config.expose())
+            .await;
+        match called {
+            Ok(answer) => answer.map_err(failed),
+            Err(e) => Err(failed(format!("the plugin crashed: {e}"))),
+        }
+    }
+}
+
+/// An engine with the fuel and async support the calls need
+fn engine() -> Result<Engine, String> {
+    let mut config = Config::new();
+    config.async_support(true).consume_fuel(true);
+    Engine::new(&config).map_err(|e| format!("Couldn't start the WebAssembly engine: {e}"))
+}
+
+/// The functions plugins may import
+fn linker(engine: &Engine) -> Result<Linker<State>, String> {
+    let mut linker = Linker::new(engine);
+    Provider::add_to_linker::<_, HasSelf<State>>(&mut linker, |state| state)
+        .map_err(|e| e.to_string())?;
+    Ok(linker)
+}
+
+impl PersistsToFile for PluginProvider {
+    fn file_name(&self) -> &str {
+        &self.file_name
+    }
+}
+
+#[async_trait]
+impl DynDns for PluginProvider {
+    async fn update(&mut self, client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let response = self.call(client, ip).await?;
+        tracing::info!(%ip, plugin = %self.path.display(), "Plugin update successful");
+        Ok(response)
+    }
+
+    async fn test(&mut self, _client: &Client) -> Result<CredentialCheck, UpdateError> {
+        Ok(CredentialCheck::Untestable(
+            "plugins only export update(ip, name, config)",
+        ))
+    }
+
+    fn get_ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut ProviderOptions {
+        &mut self.options
+    }
+
+    fn provider_name(&self) -> &'static str {
+        PROVIDER
+    }
+
+    fn hostname(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+pub(crate) const PLUGIN_SCHEMA: ProviderSchema = ProviderSchema {
+    name: PROVIDER,
+    fields: &[
+        Field {
+            name: "PATH",
+            description: "WebAssembly component implementing wit/provider.wit",
+            default: None,
+            secret: false,
+        },
+        VERSION_FIELD,
+        POLL_SECS_FIELD,
+        Field {
+            name: "NAME",
+            description: "Record handed to the plugin",
+            default: None,
+            secret: false,
+        },
+        Field {
+            name: "CONFIG",
+            description: "Settings handed to the plugin as they are, e.g. its token",
+            default: None,
+            secret: true,
+        },
+    ],
+};
+
+/// `WASM;PATH;VERSION;POLL_SECS;NAME;CONFIG`
+pub(crate) fn parse_plugin(
+    parts: &mut dyn Iterator<Item = &str>,
+) -> Result<Box<dyn DynDns>, String> {
+    let path = required_part(parts, "PATH")?.into();
+    let version = ip_version_part(parts)?;
+    let schedule = schedule_part(parts)?;
+    let name = required_part(parts, "NAME")?.to_string();
+    let config = required_part(parts, "CONFIG")?.to_string();
+    Ok(Box::new(PluginProvider::new(
+        path,
+        name,
+        config.into(),
+        version,
+        schedule,
+    )?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        path::PathBuf,
+    };
+
+    use reqwest::Client;
+
+    use crate::dyn_dns::{CredentialCheck, UpdateError, parse_dns_tuples};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    /// A component whose `update` runs `body`, with the lengths of its
+    /// arguments in locals 1, 3 and 5. Results are written at 0: the
+    /// discriminant, then the pointer and length of the string.
+    fn plugin(dir: &std::path::Path, name: &str, body: &str) -> PathBuf {
+        let path = dir.join(name);
+        let component = format!(
+            r#"
+            (component
+              (core module $m
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (data (i32.const 16) "no config")
+                (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                  (local $at i32)
+                  (local.set $at (global.get $next))
+                  (global.set $next (i32.add (global.get $next) (local.get 3)))
+                  (local.get $at))
+                (func (export "update") (param i32 i32 i32 i32 i32 i32) (result i32)
+                  {body}))
+              (core instance $i (instantiate $m))
+              (func (export "update")
+                (param "ip" string) (param "name" string) (param "config" string)
+                (result (result string (error string)))
+                (canon lift (core func $i "update")
+                  (memory $i "memory") (realloc (func $i "realloc")))))
+            "#
+        );
+        std::fs::write(&path, component).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_plugin_provider() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_plugin_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Answers with its config, or fails without one
+        let path = plugin(
+            &dir,
+            "echo.wat",
+            r#"
+            (if (i32.eqz (local.get 5))
+              (then
+                (i32.store8 (i32.const 0) (i32.const 1))
+                (i32.store (i32.const 4) (i32.const 16))
+                (i32.store (i32.const 8) (i32.const 9)))
+              (else
+                (i32.store8 (i32.const 0) (i32.const 0))
+                (i32.store (i32.const 4) (local.get 4))
+                (i32.store (i32.const 8) (local.get 5))))
+            (i32.const 0)
+            "#,
+        );
+        let tuples = format!("WASM;{};ipv4;300;home;tok;priority=2", path.display());
+        let mut provider = parse_dns_tuples(&tuples).unwrap().remove(0);
+        assert_eq!(provider.provider_name(), "Plugin");
+        assert_eq!(provider.hostname(), Some("home"));
+        assert_eq!(provider.options().priority, 2);
+        assert!(!format!("{provider:?}").contains("tok"));
+        let response = provider.update(&Client::new(), IP).await.unwrap();
+        assert_eq!(response, "tok");
+        let check = provider.test(&Client::new()).await.unwrap();
+        assert!(matches!(check, CredentialCheck::Untestable(_)));
+
+        let tuples = format!("WASM;{};ipv4;300;home;\"\"", path.display());
+        let mut provider = parse_dns_tuples(&tuples).unwrap().remove(0);
+        let error = provider.update(&Client::new(), IP).await.unwrap_err();
+        assert!(
+            matches!(&error, UpdateError::Rejected { response, .. } if response == "no config"),
+            "{error}"
+        );
+
+        // Runs out of fuel instead of hanging
+        let path = plugin(
+            &dir,
+            "loop.wat",
+            "(loop $forever (br $forever)) (i32.const 0)",
+        );
+        let tuples = format!("WASM;{};ipv4;300;home;tok", path.display());
+        let mut provider = parse_dns_tuples(&tuples).unwrap().remove(0);
+        let error = provider.update(&Client::new(), IP).await.unwrap_err();
+        assert!(error.to_string().contains("crashed"), "{error}");
+
+        // Not a provider
+        let path = dir.join("empty.wat");
+        std::fs::write(&path, "(component)").unwrap();
+        let tuples = format!("WASM;{};ipv4;300;home;tok", path.display());
+        assert!(parse_dns_tuples(&tuples).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
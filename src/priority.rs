@@ -0,0 +1,106 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+use crate::IpVersion;
+
+/// Longest time a provider waits for higher priority ones before updating anyway,
+/// so a slow or failing critical record can't block the rest forever.
+pub const MAX_PRIORITY_WAIT: Duration = Duration::from_secs(120);
+
+struct Peer {
+    priority: i32,
+    ip_version: IpVersion,
+    settled: watch::Receiver<Option<IpAddr>>,
+}
+
+/// Orders updates between providers of the same IP version: a provider only
+/// updates to an IP once every provider with a higher priority has settled on
+/// it, i.e. finished its own update attempt for that IP.
+#[derive(Clone, Default)]
+pub struct PriorityGate {
+    peers: Arc<RwLock<Vec<Peer>>>,
+}
+
+/// Handle used by a provider to announce the IP it settled on.
+pub type Settled = watch::Sender<Option<IpAddr>>;
+
+impl PriorityGate {
+    /// Adds a provider to the gate, when it starts or after a configuration
+    /// reload. Dropping the returned handle removes it.
+    pub fn join(&self, priority: i32, ip_version: IpVersion) -> Settled {
+        let (tx, settled) = watch::channel(None);
+        let mut peers = self.peers.write().expect("Priority gate poisoned");
+        // Forget providers that stopped
+        peers.retain(|p| p.settled.has_changed().is_ok());
+        peers.push(Peer {
+            priority,
+            ip_version,
+            settled,
+        });
+        tx
+    }
+
+    /// Waits, bounded by [`MAX_PRIORITY_WAIT`], for every higher priority
+    /// provider to settle on `ip`.
+    pub async fn wait_for_higher(&self, priority: i32, ip: IpAddr) {
+        let ip_version = IpVersion::from(&ip);
+        let higher: Vec<_> = self
+            .peers
+            .read()
+            .expect("Priority gate poisoned")
+            .iter()
+            .filter(|p| p.priority > priority && p.ip_version == ip_version)
+            .map(|p| p.settled.clone())
+            .collect();
+
+        let wait_all = async {
+            for mut settled in higher {
+                // An error means that provider stopped, nothing to wait for
+                let _ = settled.wait_for(|s| *s == Some(ip)).await;
+            }
+        };
+        if tokio::time::timeout(MAX_PRIORITY_WAIT, wait_all)
+            .await
+            .is_err()
+        {
+            tracing::warn!(%ip, "Higher priority providers didn't settle in time, updating anyway");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use super::PriorityGate;
+    use crate::IpVersion;
+
+    #[tokio::test]
+    async fn test_lower_priority_waits() {
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let gate = PriorityGate::default();
+        let higher = gate.join(10, IpVersion::V4);
+        let _lower = gate.join(0, IpVersion::V4);
+
+        let lower = tokio::spawn({
+            let gate = gate.clone();
+            async move { gate.wait_for_higher(0, ip).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!lower.is_finished());
+
+        higher.send_replace(Some(ip));
+        lower.await.unwrap();
+
+        // Nothing ranks above the highest priority
+        gate.wait_for_higher(10, ip).await;
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, MapAccess, Visitor, value::MapAccessDeserializer},
+};
+
+use crate::{
+    IpVersion,
+    dyn_dns::{
+        DuckDns, DynDns, FreeDns, Ovh, ParseError, ProviderOptions, parse_dns_tuples, strip_secrets,
+    },
+    interpolate::interpolate,
+    scheduler::Schedule,
+    secrets::Secret,
+};
+
+/// A built-in provider declared field by field, the typed counterpart of a
+/// `DNS_TUPLES` record shared by configuration files and programs
+///
+/// ```toml
+/// [[providers]]
+/// type = "duckdns"
+/// token = "${DUCKDNS_TOKEN}"
+/// name = "my-domain"
+/// ip_version = "ipv4"
+/// poll_secs = 300
+/// options = { priority = 10 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ProviderConfig {
+    #[serde(alias = "FD")]
+    FreeDns {
+        /// Update token, the end of the record's direct update URL
+        token: Secret,
+        ip_version: IpVersion,
+        /// Seconds between IP checks, or a cron expression
+        poll_secs: Schedule,
+        #[serde(default, skip_serializing_if = "ProviderOptions::is_default")]
+        options: ProviderOptions,
+    },
+    #[serde(alias = "DD")]
+    DuckDns {
+        token: Secret,
+        /// Subdomain, without .duckdns.org
+        name: String,
+        ip_version: IpVersion,
+        poll_secs: Schedule,
+        #[serde(default, skip_serializing_if = "ProviderOptions::is_default")]
+        options: ProviderOptions,
+    },
+    #[serde(alias = "OVH")]
+    Ovh {
+        /// DynHost login
+        username: String,
+        password: Secret,
+        /// Full hostname of the DynHost record
+        hostname: String,
+        ip_version: IpVersion,
+        poll_secs: Schedule,
+        #[serde(default, skip_serializing_if = "ProviderOptions::is_default")]
+        options: ProviderOptions,
+    },
+}
+
+impl ProviderConfig {
+    /// Type of the provider in `DNS_TUPLES`, e.g. "DD"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProviderConfig::FreeDns { .. } => "FD",
+            ProviderConfig::DuckDns { .. } => "DD",
+            ProviderConfig::Ovh { .. } => "OVH",
+        }
+    }
+
+    /// Expands the `${VAR}` references of the credentials and names
+    pub fn interpolate(&mut self) -> Result<(), String> {
+        let secret = |s: &mut Secret| -> Result<(), String> {
+            *s = Secret::new(interpolate(s.expose())?);
+            Ok(())
+        };
+        match self {
+            ProviderConfig::FreeDns { token, .. } => secret(token),
+            ProviderConfig::DuckDns { token, name, .. } => {
+                secret(token)?;
+                *name = interpolate(name)?;
+                Ok(())
+            }
+            ProviderConfig::Ovh {
+                username,
+                password,
+                hostname,
+                ..
+            } => {
+                *username = interpolate(username)?;
+                secret(password)?;
+                *hostname = interpolate(hostname)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Replaces the credentials with `placeholder(kind, field)`, named like
+    /// those of the records, see [`crate::dyn_dns::strip_secrets`]
+    pub fn strip_secrets(&mut self, placeholder: &mut dyn FnMut(&str, &str) -> String) {
+        let kind = self.kind();
+        let (secret, field) = match self {
+            ProviderConfig::FreeDns { token, .. } | ProviderConfig::DuckDns { token, .. } => {
+                (token, "TOKEN")
+            }
+            ProviderConfig::Ovh { password, .. } => (password, "PASSWORD"),
+        };
+        if !secret.expose().contains("${") {
+            *secret = Secret::new(placeholder(kind, field));
+        }
+    }
+}
+
+impl From<ProviderConfig> for Box<dyn DynDns> {
+    fn from(config: ProviderConfig) -> Self {
+        let (mut dyn_dns, options): (Box<dyn DynDns>, _) = match config {
+            ProviderConfig::FreeDns {
+                token,
+                ip_version,
+                poll_secs,
+                options,
+            } => (
+                Box::new(FreeDns::new(token.expose().clone(), ip_version, poll_secs)),
+                options,
+            ),
+            ProviderConfig::DuckDns {
+                token,
+                name,
+                ip_version,
+                poll_secs,
+                options,
+            } => (
+                Box::new(DuckDns::new(
+                    token.expose().clone(),
+                    name,
+                    ip_version,
+                    poll_secs,
+                )),
+                options,
+            ),
+            ProviderConfig::Ovh {
+                username,
+                password,
+                hostname,
+                ip_version,
+                poll_secs,
+                options,
+            } => (
+                Box::new(Ovh::new(
+                    username,
+                    password.expose().clone(),
+                    hostname,
+                    ip_version,
+                    poll_secs,
+                )),
+                options,
+            ),
+        };
+        *dyn_dns.options_mut() = options;
+        dyn_dns
+    }
+}
+
+/// An entry of the providers of a configuration file: records in the
+/// `DNS_TUPLES` syntax, which registered providers need, or a table
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ProviderEntry {
+    Tuples(String),
+    Table(ProviderConfig),
+}
+
+impl ProviderEntry {
+    /// The providers of the entry, one per record
+    pub fn dyn_dnss(&self) -> Result<Vec<Box<dyn DynDns>>, ParseError> {
+        match self {
+            ProviderEntry::Tuples(tuples) => parse_dns_tuples(tuples),
+            ProviderEntry::Table(config) => Ok(vec![config.clone().into()]),
+        }
+    }
+
+    /// Changes whenever the entry does, to tell which providers a reload
+    /// changed
+    pub fn key(&self) -> String {
+        match self {
+            ProviderEntry::Tuples(tuples) => tuples.clone(),
+            ProviderEntry::Table(config) => serde_json::to_string(config).unwrap_or_default(),
+        }
+    }
+
+    pub fn interpolate(&mut self) -> Result<(), String> {
+        match self {
+            ProviderEntry::Tuples(tuples) => {
+                *tuples = interpolate(tuples)?;
+                Ok(())
+            }
+            ProviderEntry::Table(config) => config.interpolate(),
+        }
+    }
+
+    pub fn strip_secrets(
+        &mut self,
+        placeholder: &mut dyn FnMut(&str, &str) -> String,
+    ) -> Result<(), ParseError> {
+        match self {
+            ProviderEntry::Tuples(tuples) => *tuples = strip_secrets(tuples, placeholder)?,
+            ProviderEntry::Table(config) => config.strip_secrets(placeholder),
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for ProviderEntry {
+    fn from(tuples: String) -> Self {
+        ProviderEntry::Tuples(tuples)
+    }
+}
+
+impl From<ProviderConfig> for ProviderEntry {
+    fn from(config: ProviderConfig) -> Self {
+        ProviderEntry::Table(config)
+    }
+}
+
+impl PartialEq<&str> for ProviderEntry {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, ProviderEntry::Tuples(tuples) if tuples == other)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProviderEntry {
+    /// A string or a table, with the errors of the table instead of those of
+    /// an untagged enum
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = ProviderEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("records in the DNS_TUPLES syntax or a provider table")
+            }
+
+            fn visit_str<E: de::Error>(self, tuples: &str) -> Result<Self::Value, E> {
+                Ok(ProviderEntry::Tuples(tuples.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                ProviderConfig::deserialize(MapAccessDeserializer::new(map))
+                    .map(ProviderEntry::Table)
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ProviderConfig, ProviderEntry};
+    use crate::{IpVersion, scheduler::Schedule};
+
+    #[test]
+    fn test_provider_table() {
+        let entries: Vec<ProviderEntry> = toml::from_str::<toml::Table>(
+            r#"
+            providers = [
+                "FD;tok;ipv6;0",
+                { type = "duckdns", token = "tok", name = "home", ip_version = "ipv4", poll_secs = 300, options = { priority = 3 } },
+                { type = "OVH", username = "u", password = "p;w", hostname = "h.example.com", ip_version = "ipv6", poll_secs = "*/5 * * * *" },
+            ]
+            "#,
+        )
+        .unwrap()["providers"]
+            .clone()
+            .try_into()
+            .unwrap();
+        assert_eq!(entries[0], "FD;tok;ipv6;0");
+        let ProviderEntry::Table(ProviderConfig::DuckDns {
+            poll_secs, options, ..
+        }) = &entries[1]
+        else {
+            panic!("Expected a DuckDNS table, got {:?}", entries[1]);
+        };
+        assert_eq!(*poll_secs, Schedule::Every(Duration::from_secs(300)));
+        assert_eq!(options.priority, 3);
+
+        let dyn_dnss = entries[2].dyn_dnss().unwrap();
+        assert_eq!(dyn_dnss[0].hostname(), Some("h.example.com"));
+        assert_eq!(dyn_dnss[0].get_ip_version(), IpVersion::V6);
+        // Written back the same way
+        let json = serde_json::to_value(&entries[1]).unwrap();
+        assert_eq!(
+            (&json["type"], &json["poll_secs"]),
+            (&"duckdns".into(), &300.into())
+        );
+
+        let typo = r#"{ "type": "duckdns", "tokn": "tok", "name": "home", "ip_version": "ipv4", "poll_secs": 0 }"#;
+        let error = serde_json::from_str::<ProviderEntry>(typo).unwrap_err();
+        assert!(error.to_string().contains("tokn"));
+        assert!(serde_json::from_str::<ProviderEntry>(r#"{ "type": "xyz" }"#).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! MX, SRV and TXT records kept following a provider, e.g. the SRV record of a
+//! game server pointing at its dynamic hostname, or an SPF record listing its
+//! IP. The DynDNS providers only update addresses, so these are set through a
+//! DNS server accepting dynamic updates (RFC 2136) of the zone.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    interpolate::interpolate,
+    rfc2136::{RecordData, Zone},
+    secrets::Secret,
+};
+
+/// Records of a zone following a provider, an entry of the `dns_records`
+/// list of a configuration file
+///
+/// ```toml
+/// [[dns_records]]
+/// api = "rfc2136"
+/// follows = "home.example.com"
+/// server = "192.0.2.53:53"
+/// zone = "example.com"
+/// key_name = "ddns-key"
+/// key = "${TSIG_KEY}"
+///
+/// [[dns_records.records]]
+/// name = "_minecraft._tcp.example.com"
+/// type = "SRV"
+/// value = "0 5 25565 {hostname}"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "api", rename_all = "lowercase", deny_unknown_fields)]
+pub enum RecordsConfig {
+    Rfc2136 {
+        /// Hostname of the provider whose published IPs update the records
+        follows: String,
+        /// Address of the primary server, e.g. `192.0.2.53:53`
+        server: SocketAddr,
+        zone: String,
+        /// Name of the TSIG key signing the updates, unsigned when omitted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_name: Option<String>,
+        /// The base64 HMAC-SHA256 secret of `key_name`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<Secret>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+        records: Vec<RecordConfig>,
+    },
+}
+
+fn default_ttl() -> u32 {
+    3600
+}
+
+/// A record replacing those of its name and type
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    /// As in a zone file, `{hostname}` standing for the followed hostname
+    /// and `{ip}` for its new IP, e.g. `10 {hostname}` for an MX record
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordKind {
+    Mx,
+    Srv,
+    Txt,
+}
+
+impl RecordKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordKind::Mx => "MX",
+            RecordKind::Srv => "SRV",
+            RecordKind::Txt => "TXT",
+        }
+    }
+}
+
+impl RecordConfig {
+    /// The data of the record once `hostname` published `ip`
+    pub fn data(&self, hostname: &str, ip: IpAddr) -> Result<RecordData, String> {
+        let value = self
+            .value
+            .replace("{hostname}", hostname.trim_end_matches('.'))
+            .replace("{ip}", &ip.to_string());
+        RecordData::parse(self.kind.as_str(), &value)
+    }
+}
+
+impl RecordsConfig {
+    /// Hostname of the provider whose published IPs update the records
+    pub fn follows(&self) -> &str {
+        match self {
+            RecordsConfig::Rfc2136 { follows, .. } => follows,
+        }
+    }
+
+    pub fn records(&self) -> &[RecordConfig] {
+        match self {
+            RecordsConfig::Rfc2136 { records, .. } => records,
+        }
+    }
+
+    fn zone(&self) -> Zone<'_> {
+        match self {
+            RecordsConfig::Rfc2136 {
+                server,
+                zone,
+                key_name,
+                key,
+                ..
+            } => Zone {
+                server: *server,
+                name: zone,
+                key_name: key_name.as_deref(),
+                key: key.as_ref(),
+            },
+        }
+    }
+
+    /// Expands the `${VAR}` reference of the TSIG key
+    pub fn interpolate(&mut self) -> Result<(), String> {
+        match self {
+            RecordsConfig::Rfc2136 { key, .. } => {
+                if let Some(key) = key {
+                    *key.expose_mut() = interpolate(key.expose())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the TSIG key with a reference to a variable named after it,
+    /// i.e. `${RFC2136_KEY}`
+    pub fn strip_secrets(&mut self, placeholder: &mut impl FnMut(&str, &str) -> String) {
+        match self {
+            RecordsConfig::Rfc2136 { key, .. } => {
+                if let Some(key) = key {
+                    *key = placeholder("RFC2136", "KEY").into();
+                }
+            }
+        }
+    }
+
+    /// Checks the key, and that every record is in the zone and has a valid
+    /// value whatever the IP
+    fn validate(&self) -> Result<(), String> {
+        let zone = self.zone();
+        for record in self.records() {
+            zone.check(&record.name)
+                .and_then(|_| record.data(self.follows(), IpAddr::from([192, 0, 2, 1])))
+                .map_err(|e| format!("{} record {}: {e}", record.kind.as_str(), record.name))?;
+        }
+        Ok(())
+    }
+
+    /// Sets every record for `ip`, returning the errors of those that
+    /// couldn't be
+    pub async fn set(&self, ip: IpAddr) -> Vec<(&RecordConfig, String)> {
+        let (zone, ttl) = match self {
+            RecordsConfig::Rfc2136 { ttl, .. } => (self.zone(), *ttl),
+        };
+        let mut errors = Vec::new();
+        for record in self.records() {
+            let result = match record.data(self.follows(), ip) {
+                Ok(data) => zone.replace(&record.name, ttl, &data).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                errors.push((record, e));
+            }
+        }
+        errors
+    }
+}
+
+/// Every record to keep, see [`Records::update`]
+#[derive(Debug, Clone, Default)]
+pub struct Records(Arc<[RecordsConfig]>);
+
+impl Records {
+    /// Checks the settings of `configs`
+    pub fn new(configs: Vec<RecordsConfig>) -> Result<Self, String> {
+        for config in &configs {
+            config
+                .validate()
+                .map_err(|e| format!("Records following {}: {e}", config.follows()))?;
+        }
+        Ok(Self(configs.into()))
+    }
+
+    /// Sets the records following the provider `hostname` for `ip`, logging
+    /// why one couldn't be
+    pub async fn update(&self, hostname: Option<&str>, ip: IpAddr) {
+        let Some(hostname) = hostname else {
+            return;
+        };
+        let hostname = hostname.trim_end_matches('.');
+        for config in self.0.iter() {
+            if !config
+                .follows()
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(hostname)
+            {
+                continue;
+            }
+            let errors = config.set(ip).await;
+            for (record, e) in &errors {
+                tracing::error!(
+                    %ip,
+                    name = record.name,
+                    kind = record.kind.as_str(),
+                    error = %e,
+                    "Couldn't update the DNS record"
+                );
+            }
+            let updated = config.records().len() - errors.len();
+            if updated > 0 {
+                tracing::info!(%ip, follows = hostname, updated, "DNS records updated");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::UdpSocket;
+
+    use super::{RecordConfig, RecordKind, Records, RecordsConfig};
+    use crate::rfc2136::RecordData;
+
+    fn records(server: &str, records: Vec<RecordConfig>) -> RecordsConfig {
+        RecordsConfig::Rfc2136 {
+            follows: "home.example.com".to_string(),
+            server: server.parse().unwrap(),
+            zone: "example.com".to_string(),
+            key_name: None,
+            key: None,
+            ttl: 300,
+            records,
+        }
+    }
+
+    fn record(name: &str, kind: RecordKind, value: &str) -> RecordConfig {
+        RecordConfig {
+            name: name.to_string(),
+            kind,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_values() {
+        let ip = "2001:db8::1".parse().unwrap();
+        let spf = record("example.com", RecordKind::Txt, "v=spf1 ip6:{ip} -all");
+        assert_eq!(
+            spf.data("home.example.com", ip),
+            Ok(RecordData::Txt("v=spf1 ip6:2001:db8::1 -all".to_string()))
+        );
+        let srv = record(
+            "_minecraft._tcp.example.com",
+            RecordKind::Srv,
+            "0 5 25565 {hostname}",
+        );
+        assert_eq!(
+            srv.data("home.example.com.", ip),
+            Ok(RecordData::Srv {
+                priority: 0,
+                weight: 5,
+                port: 25565,
+                target: "home.example.com".to_string()
+            })
+        );
+
+        let valid = records("127.0.0.1:53", vec![srv.clone(), spf]);
+        assert!(Records::new(vec![valid]).is_ok());
+        let invalid = record("example.com", RecordKind::Mx, "{hostname}");
+        assert!(Records::new(vec![records("127.0.0.1:53", vec![invalid])]).is_err());
+        let outside = record("example.org", RecordKind::Mx, "10 {hostname}");
+        assert!(Records::new(vec![records("127.0.0.1:53", vec![outside])]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = records(
+            &server.local_addr().unwrap().to_string(),
+            vec![
+                record("example.com", RecordKind::Mx, "10 {hostname}"),
+                record(
+                    "_minecraft._tcp.example.com",
+                    RecordKind::Srv,
+                    "0 5 25565 {hostname}",
+                ),
+            ],
+        );
+        let answer = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let mut request = [0; 512];
+                let (len, client) = server.recv_from(&mut request).await.unwrap();
+                let mut answer = request[..12].to_vec();
+                answer[2] |= 0x80;
+                server.send_to(&answer, client).await.unwrap();
+                requests.push(request[..len].to_vec());
+            }
+            requests
+        });
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(config.set(ip).await.is_empty());
+        Records::new(vec![config])
+            .unwrap()
+            .update(Some("other.example.com"), ip)
+            .await;
+
+        let requests = answer.await.unwrap();
+        // The MX record, then the SRV one, both pointing at the hostname
+        let target = b"\x04home\x07example\x03com\x00";
+        assert!(requests[0].ends_with(&[&b"\x00\x0a"[..], target].concat()));
+        assert!(requests[1].ends_with(&[&b"\x63\xdd"[..], target].concat()));
+    }
+}
@@ -0,0 +1,201 @@
+use std::path::Path;
+
+use ::redb::{Database, ReadableTable, TableDefinition};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    history::HistoryEntry,
+    persistence::{Error, ProviderState, StateStore, private_file_options},
+};
+
+/// State of every provider as JSON, by key
+const PROVIDERS: TableDefinition<&str, &str> = TableDefinition::new("providers");
+/// History entries as JSON, numbered in the order they were appended
+const HISTORY: TableDefinition<u64, &str> = TableDefinition::new("history");
+
+fn backend(err: impl Into<::redb::Error>) -> Error {
+    Error::Store(Box::new(err.into()))
+}
+
+/// Every provider and the history in one redb database, a pure Rust
+/// embedded key-value store. Each write is a transaction, so the database is
+/// never left half written.
+pub struct RedbStore {
+    db: Database,
+}
+
+impl std::fmt::Debug for RedbStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedbStore").finish_non_exhaustive()
+    }
+}
+
+impl RedbStore {
+    /// Opens the database at `path`, creating it and its tables if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        // redb initializes an empty file
+        private_file_options().write(true).open(&path)?;
+        let db = Database::create(path).map_err(backend)?;
+        let tx = db.begin_write().map_err(backend)?;
+        tx.open_table(PROVIDERS).map_err(backend)?;
+        tx.open_table(HISTORY).map_err(backend)?;
+        tx.commit().map_err(backend)?;
+        Ok(Self { db })
+    }
+}
+
+#[async_trait]
+impl StateStore for RedbStore {
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error> {
+        let tx = self.db.begin_read().map_err(backend)?;
+        let table = tx.open_table(PROVIDERS).map_err(backend)?;
+        match table.get(key).map_err(backend)? {
+            Some(json) => Ok(serde_json::from_str(json.value())?),
+            None => Ok(ProviderState::default()),
+        }
+    }
+
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error> {
+        let json = serde_json::to_string(state)?;
+        let tx = self.db.begin_write().map_err(backend)?;
+        tx.open_table(PROVIDERS)
+            .map_err(backend)?
+            .insert(key, json.as_str())
+            .map_err(backend)?;
+        tx.commit().map_err(backend)
+    }
+
+    async fn remove_state(&self, key: &str) -> Result<(), Error> {
+        let tx = self.db.begin_write().map_err(backend)?;
+        tx.open_table(PROVIDERS)
+            .map_err(backend)?
+            .remove(key)
+            .map_err(backend)?;
+        tx.commit().map_err(backend)
+    }
+
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error> {
+        let tx = self.db.begin_read().map_err(backend)?;
+        let table = tx.open_table(PROVIDERS).map_err(backend)?;
+        let mut saved = Vec::new();
+        for row in table.iter().map_err(backend)? {
+            let (key, json) = row.map_err(backend)?;
+            let state: ProviderState = serde_json::from_str(json.value())?;
+            if state.ip.is_some() {
+                saved.push((key.value().to_string(), state));
+            }
+        }
+        Ok(saved)
+    }
+
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        let json = serde_json::to_string(entry)?;
+        let tx = self.db.begin_write().map_err(backend)?;
+        {
+            let mut table = tx.open_table(HISTORY).map_err(backend)?;
+            let next = match table.last().map_err(backend)? {
+                Some((id, _)) => id.value() + 1,
+                None => 0,
+            };
+            table.insert(next, json.as_str()).map_err(backend)?;
+        }
+        tx.commit().map_err(backend)
+    }
+
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let tx = self.db.begin_read().map_err(backend)?;
+        let table = tx.open_table(HISTORY).map_err(backend)?;
+        let mut entries = Vec::new();
+        for row in table.iter().map_err(backend)? {
+            let (_, json) = row.map_err(backend)?;
+            entries.push(serde_json::from_str(json.value())?);
+        }
+        Ok(entries)
+    }
+
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        let tx = self.db.begin_write().map_err(backend)?;
+        {
+            let mut table = tx.open_table(HISTORY).map_err(backend)?;
+            table.retain(|_, _| false).map_err(backend)?;
+            for (id, entry) in (0..).zip(entries) {
+                let json = serde_json::to_string(entry)?;
+                table.insert(id, json.as_str()).map_err(backend)?;
+            }
+        }
+        tx.commit().map_err(backend)
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        let tx = self.db.begin_write().map_err(backend)?;
+        tx.open_table(HISTORY)
+            .map_err(backend)?
+            // Entries that can't be read are left for the history to report
+            .retain(|_, json| {
+                serde_json::from_str::<HistoryEntry>(json)
+                    .map(|entry| entry.timestamp >= before)
+                    .unwrap_or(true)
+            })
+            .map_err(backend)?;
+        tx.commit().map_err(backend)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use chrono::Utc;
+
+    use super::RedbStore;
+    use crate::{
+        history::HistoryEntry,
+        persistence::{ProviderState, StateStore},
+    };
+
+    #[tokio::test]
+    async fn test_redb_store() {
+        let path = std::env::temp_dir().join(format!("dns_updater_redb_{}", std::process::id()));
+        let store = RedbStore::open(&path).unwrap();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+
+        assert_eq!(
+            store.load_state("DuckDNS_tok_name").await.unwrap(),
+            ProviderState::default()
+        );
+        let mut state = ProviderState::default();
+        state.record_success(ip, "OK".to_string(), Utc::now(), Duration::from_millis(120));
+        store.save_state("DuckDNS_tok_name", &state).await.unwrap();
+        assert_eq!(store.load_state("DuckDNS_tok_name").await.unwrap(), state);
+        store
+            .save_state("FreeDNS_tok_ipv4", &ProviderState::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list().await.unwrap(),
+            vec![("DuckDNS_tok_name".to_string(), state)]
+        );
+
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            provider: "DuckDNS".to_string(),
+            hostname: Some("name".to_string()),
+            old_ip: None,
+            new_ip: ip,
+            error: None,
+            latency_ms: Some(80),
+            networks: None,
+        };
+        store.append_history(&entry).await.unwrap();
+        store.append_history(&entry).await.unwrap();
+        assert_eq!(store.history().await.unwrap().len(), 2);
+        store
+            .replace_history(std::slice::from_ref(&entry))
+            .await
+            .unwrap();
+        assert_eq!(store.history().await.unwrap(), vec![entry]);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use ::redis::{
+    AsyncCommands, Client,
+    aio::{ConnectionManager, ConnectionManagerConfig},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::OnceCell;
+
+use crate::{
+    history::HistoryEntry,
+    persistence::{Error, ProviderState, StateStore},
+};
+
+impl From<::redis::RedisError> for Error {
+    fn from(err: ::redis::RedisError) -> Self {
+        Error::Store(Box::new(err))
+    }
+}
+
+/// The state in a Redis server, shared by every instance using the same
+/// `prefix`: the state of each provider as JSON in the `PREFIX:state` hash,
+/// and the history as a list of JSON entries in `PREFIX:history`.
+///
+/// The connection is made on first use, and made again whenever it's lost.
+pub struct RedisStore {
+    client: Client,
+    prefix: String,
+    conn: OnceCell<ConnectionManager>,
+}
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The URL may hold a password
+        f.debug_struct("RedisStore")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    /// Checks `url`, e.g. `redis://:password@host:6379/0`, without connecting
+    pub fn open(url: &str, prefix: impl Into<String>) -> Result<Self, Error> {
+        Ok(Self {
+            client: Client::open(url)?,
+            prefix: prefix.into(),
+            conn: OnceCell::new(),
+        })
+    }
+
+    async fn conn(&self) -> Result<ConnectionManager, Error> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| {
+                // Fail fast when the server is down, a later call tries again
+                let config = ConnectionManagerConfig::new()
+                    .set_number_of_retries(2)
+                    .set_factor(2)
+                    .set_max_delay(2000)
+                    .set_connection_timeout(Duration::from_secs(5))
+                    .set_response_timeout(Duration::from_secs(5));
+                ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await?;
+        // Clones share the underlying connection
+        Ok(conn.clone())
+    }
+
+    fn state_key(&self) -> String {
+        format!("{}:state", self.prefix)
+    }
+
+    fn history_key(&self) -> String {
+        format!("{}:history", self.prefix)
+    }
+}
+
+#[async_trait]
+impl StateStore for RedisStore {
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error> {
+        let json: Option<String> = self.conn().await?.hget(self.state_key(), key).await?;
+        match json {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(ProviderState::default()),
+        }
+    }
+
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error> {
+        let json = serde_json::to_string(state)?;
+        let () = self.conn().await?.hset(self.state_key(), key, json).await?;
+        Ok(())
+    }
+
+    async fn remove_state(&self, key: &str) -> Result<(), Error> {
+        let _: usize = self.conn().await?.hdel(self.state_key(), key).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error> {
+        let all: Vec<(String, String)> = self.conn().await?.hgetall(self.state_key()).await?;
+        let mut saved = Vec::new();
+        for (key, json) in all {
+            let state: ProviderState = serde_json::from_str(&json)?;
+            if state.ip.is_some() {
+                saved.push((key, state));
+            }
+        }
+        saved.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(saved)
+    }
+
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        let json = serde_json::to_string(entry)?;
+        let _: usize = self.conn().await?.rpush(self.history_key(), json).await?;
+        Ok(())
+    }
+
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let lines: Vec<String> = self.conn().await?.lrange(self.history_key(), 0, -1).await?;
+        lines
+            .iter()
+            .map(|json| Ok(serde_json::from_str(json)?))
+            .collect()
+    }
+
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        let mut pipe = ::redis::pipe();
+        pipe.atomic().del(self.history_key()).ignore();
+        for entry in entries {
+            pipe.rpush(self.history_key(), serde_json::to_string(entry)?)
+                .ignore();
+        }
+        let () = pipe.query_async(&mut self.conn().await?).await?;
+        Ok(())
+    }
+
+    /// Entries are appended in order, so the old ones are trimmed from the
+    /// head of the list, which leaves those appended meanwhile alone
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        let entries = self.history().await?;
+        let old = entries
+            .iter()
+            .take_while(|entry| entry.timestamp < before)
+            .count();
+        if old > 0 {
+            let () = self
+                .conn()
+                .await?
+                .ltrim(self.history_key(), old as isize, -1)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RedisStore;
+
+    #[test]
+    fn test_open() {
+        let store = RedisStore::open("redis://:hunter2@localhost:6379/0", "dns-updater").unwrap();
+        assert!(!format!("{store:?}").contains("hunter2"));
+        assert_eq!(store.state_key(), "dns-updater:state");
+        assert!(RedisStore::open("localhost:6379", "dns-updater").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use serde::Serialize;
+
+use crate::dyn_dns::{
+    DUCK_DNS_SCHEMA, DynDns, FREE_DNS_SCHEMA, OVH_SCHEMA, parse_duck_dns, parse_free_dns, parse_ovh,
+};
+
+/// Builds a provider from the parts of a batch following its type, e.g.
+/// `TOKEN;VERSION;POLL_SECS` for `FD`. Parts left unconsumed are parsed as
+/// [`crate::dyn_dns::ProviderOptions`].
+pub type ProviderFactory =
+    Arc<dyn Fn(&mut dyn Iterator<Item = &str>) -> Result<Box<dyn DynDns>, String> + Send + Sync>;
+
+/// Describes the parts a provider type expects, for users and tooling
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ProviderSchema {
+    /// Name of the created providers, e.g. "DuckDNS"
+    pub name: &'static str,
+    /// Parts following the type, in order
+    pub fields: &'static [Field],
+}
+
+/// A part of a batch
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Value used when the field is left out, required fields have none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<&'static str>,
+    /// Credentials, left out of exports with `--strip-secrets`
+    pub secret: bool,
+}
+
+impl ProviderSchema {
+    /// The batch syntax, e.g. `DD;TOKEN;VERSION;POLL_SECS;NAME`
+    pub fn syntax(&self, kind: &str) -> String {
+        let mut syntax = kind.to_string();
+        for field in self.fields {
+            syntax.push(';');
+            syntax.push_str(field.name);
+        }
+        syntax
+    }
+}
+
+type Entry = (ProviderFactory, ProviderSchema);
+
+static REGISTRY: LazyLock<RwLock<HashMap<String, Entry>>> = LazyLock::new(|| {
+    let builtin: Vec<(&str, Entry)> = vec![
+        ("FD", (Arc::new(parse_free_dns), FREE_DNS_SCHEMA)),
+        ("DD", (Arc::new(parse_duck_dns), DUCK_DNS_SCHEMA)),
+        ("OVH", (Arc::new(parse_ovh), OVH_SCHEMA)),
+        #[cfg(feature = "rhai")]
+        (
+            "SCRIPT",
+            (
+                Arc::new(crate::script::parse_script),
+                crate::script::SCRIPT_SCHEMA,
+            ),
+        ),
+        #[cfg(feature = "wasm")]
+        (
+            "WASM",
+            (
+                Arc::new(crate::plugin::parse_plugin),
+                crate::plugin::PLUGIN_SCHEMA,
+            ),
+        ),
+    ];
+    RwLock::new(
+        builtin
+            .into_iter()
+            .map(|(kind, entry)| (kind.to_string(), entry))
+            .collect(),
+    )
+});
+
+/// Makes batches of type `kind` parse with `factory`, wherever providers are
+/// configured (`DNS_TUPLES`, configuration files, ...). `schema` describes
+/// the parts `factory` takes, as listed by `list-providers`. Returns the
+/// factory it replaced, if `kind` was already registered.
+///
+/// ```ignore
+/// use dns_updater::{dyn_dns::*, registry::*};
+///
+/// const XYZ: ProviderSchema = ProviderSchema {
+///     name: "Xyz",
+///     fields: &[
+///         Field { name: "TOKEN", description: "Xyz API token", default: None, secret: true },
+///         VERSION_FIELD,
+///         POLL_SECS_FIELD,
+///     ],
+/// };
+///
+/// register_provider("XYZ", XYZ, |parts| {
+///     let token = required_part(parts, "TOKEN")?.to_string();
+///     let version = ip_version_part(parts)?;
+///     let schedule = schedule_part(parts)?;
+///     Ok(Box::new(Xyz::new(token, version, schedule)))
+/// });
+/// ```
+pub fn register_provider(
+    kind: impl Into<String>,
+    schema: ProviderSchema,
+    factory: impl Fn(&mut dyn Iterator<Item = &str>) -> Result<Box<dyn DynDns>, String>
+    + Send
+    + Sync
+    + 'static,
+) -> Option<ProviderFactory> {
+    REGISTRY
+        .write()
+        .expect("Provider registry poisoned")
+        .insert(kind.into(), (Arc::new(factory), schema))
+        .map(|(factory, _)| factory)
+}
+
+/// The factory registered for `kind`.
+pub fn factory(kind: &str) -> Option<ProviderFactory> {
+    REGISTRY
+        .read()
+        .expect("Provider registry poisoned")
+        .get(kind)
+        .map(|(factory, _)| factory.clone())
+}
+
+/// Every registered provider type, sorted.
+pub fn provider_kinds() -> Vec<String> {
+    providers().into_iter().map(|(kind, _)| kind).collect()
+}
+
+/// Every registered provider type with its schema, sorted by type.
+pub fn providers() -> Vec<(String, ProviderSchema)> {
+    let mut providers: Vec<_> = REGISTRY
+        .read()
+        .expect("Provider registry poisoned")
+        .iter()
+        .map(|(kind, (_, schema))| (kind.clone(), *schema))
+        .collect();
+    providers.sort_by(|(a, _), (b, _)| a.cmp(b));
+    providers
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Field, ProviderSchema, provider_kinds, providers, register_provider};
+    use crate::dyn_dns::{DUCK_DNS_SCHEMA, parse_dns_tuples, parse_duck_dns, required_part};
+
+    #[test]
+    fn test_custom_provider() {
+        assert!(parse_dns_tuples("TEST;tok;ipv4;60;name").is_err());
+
+        // Reuses the DuckDNS parser behind a new type, skipping a leading part
+        const ZONE: Field = Field {
+            name: "ZONE",
+            description: "Skipped",
+            default: None,
+            secret: false,
+        };
+        let schema = ProviderSchema {
+            name: "DuckDNS",
+            fields: &[ZONE],
+        };
+        let replaced = register_provider("TEST", schema, |parts| {
+            required_part(parts, "ZONE")?;
+            parse_duck_dns(parts)
+        });
+        assert!(replaced.is_none());
+        assert!(provider_kinds().contains(&"TEST".to_string()));
+
+        let parsed = parse_dns_tuples("TEST;zone;tok;ipv4;60;name;priority=2").unwrap();
+        assert_eq!(parsed[0].provider_name(), "DuckDNS");
+        assert_eq!(parsed[0].options().priority, 2);
+        assert!(parse_dns_tuples("TEST;zone").is_err());
+    }
+
+    #[test]
+    fn test_builtin_schemas() {
+        let providers = providers();
+        let (_, dd) = providers.iter().find(|(kind, _)| kind == "DD").unwrap();
+        assert_eq!(dd.name, DUCK_DNS_SCHEMA.name);
+        assert_eq!(dd.syntax("DD"), "DD;TOKEN;VERSION;POLL_SECS;NAME");
+        // Every built-in schema lists the parts its parser takes
+        // Scripts and plugins are read when parsed, see the tests of
+        // `script` and `plugin`
+        let tested = providers
+            .iter()
+            .filter(|(k, _)| !["TEST", "SCRIPT", "WASM"].contains(&k.as_str()));
+        for (kind, schema) in tested {
+            let example: Vec<_> = schema
+                .fields
+                .iter()
+                .map(|f| match f.name {
+                    "VERSION" => "ipv4",
+                    "POLL_SECS" => "60",
+                    _ => "x",
+                })
+                .collect();
+            let parsed = parse_dns_tuples(&format!("{kind};{}", example.join(";")));
+            assert_eq!(parsed.unwrap()[0].provider_name(), schema.name);
+            let missing = &example[..example.len() - 1];
+            assert!(parse_dns_tuples(&format!("{kind};{}", missing.join(";"))).is_err());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use dns_updater::{config::Config, runner::Reload, vault};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use reqwest::Client;
+use tokio::sync::mpsc;
+
+use crate::cli::GlobalArgs;
+
+/// Editors often write a file in several steps, wait for them to finish
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Asks for a reload, with its cause
+pub type Trigger = mpsc::UnboundedSender<&'static str>;
+
+/// Reloads the configuration on SIGHUP, through the returned trigger, once
+/// the lease of a Vault secret it references runs out and, when it comes from
+/// a file, whenever that file or a providers directory changes. An invalid
+/// configuration is logged and ignored, keeping the running one, and changes
+/// to the settings of `running` only read at startup are warned about. The
+/// reloaded notifiers send through `client`, the one of the runner.
+pub fn spawn(
+    global: GlobalArgs,
+    running: Config,
+    client: Client,
+) -> (mpsc::Receiver<Reload>, Trigger) {
+    let (reload_tx, reloads) = mpsc::channel(1);
+    let (trigger_tx, mut triggers) = mpsc::unbounded_channel();
+
+    #[cfg(unix)]
+    {
+        let trigger_tx = trigger_tx.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{SignalKind, signal};
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => return tracing::error!(error = %e, "Couldn't listen for SIGHUP"),
+            };
+            while hangups.recv().await.is_some() {
+                let _ = trigger_tx.send("SIGHUP");
+            }
+        });
+    }
+
+    let trigger = trigger_tx.clone();
+    let mut watchers: Vec<_> = global
+        .providers_dirs
+        .iter()
+        .filter_map(|dir| watch_dir(dir, trigger_tx.clone()))
+        .collect();
+    let watcher = global.config.as_ref().and_then(|path| {
+        let file_name = path.file_name()?.to_owned();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => ".".into(),
+        };
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(&file_name));
+            if relevant {
+                let _ = trigger_tx.send("configuration file change");
+            }
+        })
+        // Watching the directory also catches editors replacing the file
+        .and_then(|mut watcher| {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        match &watcher {
+            Ok(_) => tracing::info!(path = %path.display(), "Watching the configuration for changes"),
+            Err(e) => tracing::error!(path = %path.display(), error = %e, "Couldn't watch the configuration"),
+        }
+        watcher.ok()
+    });
+    watchers.extend(watcher);
+
+    tokio::spawn(async move {
+        // Dropping the watchers stops them
+        let _watchers = watchers;
+        loop {
+            let cause = tokio::select! {
+                cause = triggers.recv() => match cause {
+                    Some(cause) => cause,
+                    None => return,
+                },
+                () = sleep_until(vault::next_expiry()) => "Vault lease expiry",
+            };
+            tokio::time::sleep(SETTLE).await;
+            while triggers.try_recv().is_ok() {}
+
+            tracing::info!(cause, "Reloading the configuration");
+            let reload = global.load_config().and_then(|config| {
+                config.validate()?;
+                for setting in running.restart_needed(&config) {
+                    tracing::warn!(setting, "Setting changed, restart to apply it");
+                }
+                Ok(Reload {
+                    dyn_dnss: config.keyed_dyn_dnss()?,
+                    notifiers: config.notifiers(&client)?,
+                    iface: config.interface,
+                    hooks: config.hooks,
+                })
+            });
+            match reload {
+                Ok(reload) => {
+                    if reload_tx.send(reload).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Invalid configuration, keeping the current one")
+                }
+            }
+        }
+    });
+    (reloads, trigger)
+}
+
+/// Waits until `at`, forever when `None`
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Triggers a reload whenever a file of `dir` changes, including Kubernetes
+/// swapping the `..data` link of a mounted volume
+fn watch_dir(dir: &Path, trigger_tx: Trigger) -> Option<RecommendedWatcher> {
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            )
+        {
+            let _ = trigger_tx.send("providers directory change");
+        }
+    })
+    .and_then(|mut watcher| {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    match &watcher {
+        Ok(_) => tracing::info!(path = %dir.display(), "Watching the providers directory"),
+        Err(e) => {
+            tracing::error!(path = %dir.display(), error = %e, "Couldn't watch the providers directory")
+        }
+    }
+    watcher.ok()
+}
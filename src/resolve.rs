@@ -0,0 +1,505 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::{
+    Client, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{ACCEPT, CONTENT_TYPE},
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{IpVersion, http};
+
+/// How long each bootstrap resolver gets to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const A: u16 = 1;
+const AAAA: u16 = 28;
+const SOA: u16 = 6;
+/// Lists the system's resolvers on Unix
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DNS-over-HTTPS servers known by name, with their addresses so that
+/// reaching them doesn't take the resolver they replace
+const DOH_SERVERS: [(&str, &str, [IpAddr; 2]); 2] = [
+    (
+        "cloudflare",
+        "https://cloudflare-dns.com/dns-query",
+        [
+            IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+            IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)),
+        ],
+    ),
+    (
+        "quad9",
+        "https://dns.quad9.net/dns-query",
+        [
+            IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9)),
+            IpAddr::V4(Ipv4Addr::new(149, 112, 112, 112)),
+        ],
+    ),
+];
+
+/// Resolves the hosts of the HTTP client with the system resolver and, when
+/// it fails, which is often why the records are stale in the first place,
+/// with the bootstrap resolvers and then the pinned IPs of the host.
+///
+/// With DNS-over-HTTPS, only the DoH server is asked before the pinned IPs,
+/// so that a misconfigured or spoofing local resolver isn't involved.
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    servers: Arc<[IpAddr]>,
+    pinned: Arc<BTreeMap<String, Vec<IpAddr>>>,
+    doh: Option<(Client, Url)>,
+}
+
+impl Resolver {
+    /// `servers` are plain DNS servers asked in order on port 53, `pinned`
+    /// the IPs of each host used as a last resort
+    pub fn new(servers: &[IpAddr], pinned: &BTreeMap<String, Vec<IpAddr>>) -> Self {
+        Self {
+            servers: servers.into(),
+            pinned: Arc::new(
+                pinned
+                    .iter()
+                    .map(|(host, ips)| (host.to_ascii_lowercase(), ips.clone()))
+                    .collect(),
+            ),
+            doh: None,
+        }
+    }
+
+    /// Resolves with the DNS-over-HTTPS server `server`, `cloudflare`,
+    /// `quad9` or the URL of another one, whose own host is resolved like
+    /// without DoH. Its queries identify themselves with `user_agent`, like
+    /// the requests they resolve the hosts of.
+    pub fn doh(
+        server: &str,
+        user_agent: &str,
+        servers: &[IpAddr],
+        pinned: &BTreeMap<String, Vec<IpAddr>>,
+    ) -> Result<Self, String> {
+        let (url, addrs) = match DOH_SERVERS.iter().find(|(name, ..)| *name == server) {
+            Some((_, url, ips)) => (*url, ips.map(|ip| SocketAddr::new(ip, 443)).to_vec()),
+            None => (server, Vec::new()),
+        };
+        let url = Url::parse(url)
+            .ok()
+            .filter(|url| url.scheme() == "https")
+            .ok_or_else(|| {
+                format!("Invalid DNS-over-HTTPS server '{server}', expected cloudflare, quad9 or an https:// URL")
+            })?;
+        let mut builder = http::builder()
+            .user_agent(user_agent)
+            .dns_resolver(Self::new(servers, pinned))
+            .timeout(QUERY_TIMEOUT);
+        if let Some(host) = url.host_str() {
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Couldn't create the DNS-over-HTTPS client: {e}"))?;
+        Ok(Self {
+            doh: Some((client, url)),
+            ..Self::new(&[], pinned)
+        })
+    }
+
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let resolved = match &self.doh {
+            Some((client, url)) => ask_doh(client, url, host).await,
+            None => tokio::net::lookup_host((host, 0))
+                .await
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+                .map_err(|e| e.to_string()),
+        };
+        let error = match resolved {
+            Ok(ips) if !ips.is_empty() => return Ok(ips),
+            Ok(_) => "no addresses".to_string(),
+            Err(e) => e,
+        };
+        for server in self.servers.iter() {
+            match ask(*server, host).await {
+                Ok(ips) if !ips.is_empty() => {
+                    tracing::warn!(host, %server, error, "The system resolver failed, used a bootstrap resolver");
+                    return Ok(ips);
+                }
+                Ok(_) => tracing::debug!(host, %server, "The bootstrap resolver has no addresses"),
+                Err(e) => {
+                    tracing::debug!(host, %server, error = %e, "The bootstrap resolver failed")
+                }
+            }
+        }
+        match self.pinned.get(&host.to_ascii_lowercase()) {
+            Some(ips) if !ips.is_empty() => {
+                tracing::warn!(host, error, "Couldn't resolve, using the pinned IPs");
+                Ok(ips.clone())
+            }
+            _ => Err(format!("Couldn't resolve {host}: {error}")),
+        }
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let ips = resolver.lookup(name.as_str()).await?;
+            // Port 0 is replaced by that of the URL
+            let addrs: Addrs = Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// The addresses of `version` of `host` according to the primary server of
+/// its zone, so they're those of the record even when resolvers still cache
+/// older ones. The primary server is named by the SOA record the system's
+/// resolvers answer with.
+pub async fn authoritative(host: &str, version: IpVersion) -> Result<Vec<IpAddr>, String> {
+    let mut error = format!("No resolver in {RESOLV_CONF}");
+    let mut primary = None;
+    for server in system_servers() {
+        let id = RandomState::new().hash_one(server) as u16;
+        match exchange(server, &query(id, host, SOA)?)
+            .await
+            .and_then(|response| primary_server(id, &response))
+        {
+            Ok(name) => {
+                primary = Some(name);
+                break;
+            }
+            Err(e) => error = e,
+        }
+    }
+    let primary = primary.ok_or(error)?;
+    let server = tokio::net::lookup_host((primary.as_str(), 53))
+        .await
+        .map_err(|e| format!("Couldn't resolve {primary}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("{primary} has no addresses"))?
+        .ip();
+    let qtype = match version {
+        IpVersion::V4 => A,
+        IpVersion::V6 => AAAA,
+    };
+    let id = RandomState::new().hash_one(host) as u16;
+    let response = exchange(server, &query(id, host, qtype)?).await?;
+    answers(id, &response)
+}
+
+/// The resolvers of `/etc/resolv.conf`, none elsewhere
+fn system_servers() -> Vec<IpAddr> {
+    let conf = fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        // Link-local servers with a zone, e.g. fe80::1%eth0, are skipped
+        .filter_map(|server| server.trim().parse().ok())
+        .collect()
+}
+
+/// The A and AAAA records of `host` according to the DNS server `server`
+async fn ask(server: IpAddr, host: &str) -> Result<Vec<IpAddr>, String> {
+    let mut ips = Vec::new();
+    for qtype in [A, AAAA] {
+        // Good enough against blind spoofing, DNS-over-HTTPS is the real fix
+        let id = RandomState::new().hash_one(qtype) as u16;
+        let response = exchange(server, &query(id, host, qtype)?).await?;
+        ips.extend(answers(id, &response)?);
+    }
+    Ok(ips)
+}
+
+/// Sends `query` to the DNS server `server`, returning its answer
+async fn exchange(server: IpAddr, query: &[u8]) -> Result<Vec<u8>, String> {
+    let local = match server {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|e| e.to_string())?;
+    socket
+        .connect((server, 53))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.send(query).await.map_err(|e| e.to_string())?;
+    let mut response = [0; 512];
+    let len = timeout(QUERY_TIMEOUT, socket.recv(&mut response))
+        .await
+        .map_err(|_| "Timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(response[..len].to_vec())
+}
+
+/// The A and AAAA records of `host` according to the DNS-over-HTTPS server
+/// at `url`
+async fn ask_doh(client: &Client, url: &Url, host: &str) -> Result<Vec<IpAddr>, String> {
+    let mut ips = Vec::new();
+    for qtype in [A, AAAA] {
+        // HTTPS already matches answers to queries, and a fixed id lets them
+        // be cached (RFC 8484)
+        let response = client
+            .post(url.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(query(0, host, qtype)?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        ips.extend(answers(0, &body)?);
+    }
+    Ok(ips)
+}
+
+/// A recursive query for the records of type `qtype` of `host`
+fn query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut packet = Vec::with_capacity(18 + host.len());
+    packet.extend(id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("Invalid host name '{host}'"));
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend(qtype.to_be_bytes());
+    // Class IN
+    packet.extend(1u16.to_be_bytes());
+    Ok(packet)
+}
+
+/// The addresses in the answer to the query `id`, none if the host doesn't
+/// exist
+fn answers(id: u16, response: &[u8]) -> Result<Vec<IpAddr>, String> {
+    let truncated = || "Truncated DNS answer".to_string();
+    let u16_at = |i: usize| {
+        response
+            .get(i..i + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    if u16_at(0)? != id {
+        return Err("Unexpected DNS answer".to_string());
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 || flags & 0x0200 != 0 {
+        return Err("Not a complete DNS answer".to_string());
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN
+        3 => return Ok(Vec::new()),
+        rcode => return Err(format!("DNS error code {rcode}")),
+    }
+
+    let mut i = 12;
+    for _ in 0..u16_at(4)? {
+        // Type and class follow the name
+        i = skip_name(response, i)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..u16_at(6)? {
+        i = skip_name(response, i)?;
+        let (rtype, len) = (u16_at(i)?, u16_at(i + 8)? as usize);
+        let data = response.get(i + 10..i + 10 + len).ok_or_else(truncated)?;
+        // Other records, like CNAMEs, precede the addresses they lead to
+        match rtype {
+            A => {
+                if let Ok(octets) = <[u8; 4]>::try_from(data) {
+                    ips.push(IpAddr::from(octets));
+                }
+            }
+            AAAA => {
+                if let Ok(octets) = <[u8; 16]>::try_from(data) {
+                    ips.push(IpAddr::from(octets));
+                }
+            }
+            _ => {}
+        }
+        i += 10 + len;
+    }
+    Ok(ips)
+}
+
+/// The primary server named by the SOA record answering the query `id`: in
+/// its answer for the apex of a zone, in its authority section for the
+/// other names, even those that don't exist
+fn primary_server(id: u16, response: &[u8]) -> Result<String, String> {
+    let truncated = || "Truncated DNS answer".to_string();
+    let u16_at = |i: usize| {
+        response
+            .get(i..i + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    if u16_at(0)? != id {
+        return Err("Unexpected DNS answer".to_string());
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 || flags & 0x0200 != 0 {
+        return Err("Not a complete DNS answer".to_string());
+    }
+    // NXDOMAIN still names the zone
+    if !matches!(flags & 0x000f, 0 | 3) {
+        return Err(format!("DNS error code {}", flags & 0x000f));
+    }
+
+    let mut i = 12;
+    for _ in 0..u16_at(4)? {
+        i = skip_name(response, i)? + 4;
+    }
+    for _ in 0..u16_at(6)? + u16_at(8)? {
+        i = skip_name(response, i)?;
+        let (rtype, len) = (u16_at(i)?, u16_at(i + 8)? as usize);
+        if rtype == SOA {
+            return read_name(response, i + 10);
+        }
+        i += 10 + len;
+    }
+    Err("No SOA record in the DNS answer".to_string())
+}
+
+/// The name starting at `i`, following compression pointers
+fn read_name(packet: &[u8], mut i: usize) -> Result<String, String> {
+    let truncated = || "Truncated DNS answer".to_string();
+    let mut labels = Vec::new();
+    // Bounds the pointers followed, should they loop
+    for _ in 0..128 {
+        match packet.get(i) {
+            None => return Err(truncated()),
+            Some(0) => return Ok(labels.join(".")),
+            Some(len) if len & 0xc0 == 0xc0 => {
+                let low = packet.get(i + 1).ok_or_else(truncated)?;
+                i = u16::from_be_bytes([len & 0x3f, *low]) as usize;
+            }
+            Some(len) => {
+                let label = packet
+                    .get(i + 1..i + 1 + *len as usize)
+                    .ok_or_else(truncated)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                i += 1 + *len as usize;
+            }
+        }
+    }
+    Err("Invalid name in the DNS answer".to_string())
+}
+
+/// Index following the name starting at `i`
+fn skip_name(packet: &[u8], mut i: usize) -> Result<usize, String> {
+    loop {
+        match packet.get(i) {
+            None => return Err("Truncated DNS answer".to_string()),
+            Some(0) => return Ok(i + 1),
+            // Compressed, a pointer to the rest of the name
+            Some(len) if len & 0xc0 == 0xc0 => return Ok(i + 2),
+            Some(len) => i += 1 + *len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{A, AAAA, Resolver, SOA, answers, primary_server, query};
+
+    #[test]
+    fn test_answers() {
+        let mut response = query(0x1234, "freedns.afraid.org", A).unwrap();
+        assert_eq!(&response[12..32], b"\x07freedns\x06afraid\x03org\x00");
+        assert!(query(1, "a..b", A).is_err());
+
+        // Response, recursion available, one answer pointing at the question
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[7] = 1;
+        response.extend([0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 192, 0, 2, 1]);
+        assert_eq!(
+            answers(0x1234, &response),
+            Ok(vec!["192.0.2.1".parse().unwrap()])
+        );
+        assert!(answers(0x4321, &response).is_err());
+        assert!(answers(0x1234, &response[..response.len() - 2]).is_err());
+
+        // NXDOMAIN
+        let mut response = query(7, "missing.example", AAAA).unwrap();
+        response[2..4].copy_from_slice(&[0x81, 0x83]);
+        assert_eq!(answers(7, &response), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_primary_server() {
+        // The SOA record of the zone in the authority section, its name
+        // pointing at the question's
+        let mut response = query(9, "home.example.com", SOA).unwrap();
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[9] = 1;
+        response.extend([0xc0, 0x11, 0, 6, 0, 1, 0, 0, 0x0e, 0x10, 0, 24]);
+        response.extend(b"\x03ns1\xc0\x11\x0ahostmaster\xc0\x11");
+        response.extend([0; 20]);
+        assert_eq!(
+            primary_server(9, &response),
+            Ok("ns1.example.com".to_string())
+        );
+        assert!(primary_server(9, &response[..response.len() - 35]).is_err());
+
+        // A pointer to itself
+        let mut looping = query(9, "example.com", SOA).unwrap();
+        looping[2..4].copy_from_slice(&[0x81, 0x80]);
+        looping[7] = 1;
+        let at = looping.len() as u8 + 12;
+        looping.extend([0xc0, 0x0c, 0, 6, 0, 1, 0, 0, 0x0e, 0x10, 0, 2, 0xc0, at]);
+        assert!(primary_server(9, &looping).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pinned() {
+        let pinned = BTreeMap::from([(
+            "Pinned.invalid".to_string(),
+            vec!["192.0.2.7".parse().unwrap()],
+        )]);
+        let resolver = Resolver::new(&[], &pinned);
+        assert_eq!(
+            resolver.lookup("pinned.invalid").await,
+            Ok(vec!["192.0.2.7".parse().unwrap()])
+        );
+        assert!(resolver.lookup("other.invalid").await.is_err());
+    }
+
+    #[test]
+    fn test_doh_server() {
+        let (_, url) = Resolver::doh("quad9", "dns-updater", &[], &BTreeMap::new())
+            .unwrap()
+            .doh
+            .unwrap();
+        assert_eq!(url.as_str(), "https://dns.quad9.net/dns-query");
+        assert!(
+            Resolver::doh(
+                "https://doh.example/dns-query",
+                "dns-updater",
+                &[],
+                &BTreeMap::new()
+            )
+            .is_ok()
+        );
+        assert!(
+            Resolver::doh(
+                "http://doh.example/dns-query",
+                "dns-updater",
+                &[],
+                &BTreeMap::new()
+            )
+            .is_err()
+        );
+        assert!(Resolver::doh("google", "dns-updater", &[], &BTreeMap::new()).is_err());
+    }
+}
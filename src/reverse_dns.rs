@@ -0,0 +1,501 @@
+//! PTR records pointing the published IPs back at their hostname, which mail
+//! servers need, e.g. on an IPv6 prefix that changes. They're set through
+//! whoever runs the reverse zone of the IP: the OVH, Hetzner Cloud or Linode
+//! API, or an RFC 2136 server of a delegated in-addr.arpa or ip6.arpa zone.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use chrono::Utc;
+use reqwest::{Client, RequestBuilder, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    interpolate::interpolate,
+    rfc2136::{RecordData, Zone},
+    secrets::Secret,
+    vcr,
+};
+
+const OVH_ENDPOINT: &str = "https://eu.api.ovh.com/1.0";
+const HETZNER_URL: &str = "https://api.hetzner.cloud/v1";
+const LINODE_URL: &str = "https://api.linode.com/v4";
+
+/// A PTR record kept pointing at a hostname, an entry of the `reverse_dns`
+/// list of a configuration file
+///
+/// ```toml
+/// [[reverse_dns]]
+/// api = "linode"
+/// hostname = "mail.example.com"
+/// token = "${LINODE_TOKEN}"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "api", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ReverseDnsConfig {
+    /// IPs of OVH servers, through the OVH API
+    Ovh {
+        /// Name the PTR record points at
+        hostname: String,
+        /// Hostname of the provider whose IP it follows, `hostname` itself
+        /// when omitted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        follows: Option<String>,
+        /// e.g. `https://ca.api.ovh.com/1.0`, the European API by default
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+        application_key: String,
+        application_secret: Secret,
+        /// Allowed to `POST /ip/*/reverse`
+        consumer_key: Secret,
+        /// Block the IP belongs to, e.g. `2001:db8:1234::/56`, the IP alone
+        /// when omitted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ip_block: Option<String>,
+    },
+    /// Primary IPs of Hetzner Cloud servers
+    Hetzner {
+        hostname: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        follows: Option<String>,
+        /// API token of the project, with write access
+        token: Secret,
+        /// ID of the primary IP, or of the /64 network of an IPv6 one
+        primary_ip: u64,
+    },
+    /// IPs of Linodes, including their IPv6 ranges
+    Linode {
+        hostname: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        follows: Option<String>,
+        /// Personal access token with the IPs read/write scope
+        token: Secret,
+    },
+    /// A DNS server accepting dynamic updates (RFC 2136) of the reverse zone
+    Rfc2136 {
+        hostname: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        follows: Option<String>,
+        /// Address of the primary server, e.g. `192.0.2.53:53`
+        server: SocketAddr,
+        /// The reverse zone, e.g. `8.b.d.0.1.0.0.2.ip6.arpa`
+        zone: String,
+        /// Name of the TSIG key signing the updates, unsigned when omitted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_name: Option<String>,
+        /// The base64 HMAC-SHA256 secret of `key_name`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<Secret>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+}
+
+fn default_ttl() -> u32 {
+    3600
+}
+
+impl ReverseDnsConfig {
+    /// Name the PTR record points at
+    pub fn hostname(&self) -> &str {
+        match self {
+            ReverseDnsConfig::Ovh { hostname, .. }
+            | ReverseDnsConfig::Hetzner { hostname, .. }
+            | ReverseDnsConfig::Linode { hostname, .. }
+            | ReverseDnsConfig::Rfc2136 { hostname, .. } => hostname,
+        }
+    }
+
+    /// Hostname of the provider whose published IPs get the PTR record
+    pub fn follows(&self) -> &str {
+        match self {
+            ReverseDnsConfig::Ovh { follows, .. }
+            | ReverseDnsConfig::Hetzner { follows, .. }
+            | ReverseDnsConfig::Linode { follows, .. }
+            | ReverseDnsConfig::Rfc2136 { follows, .. } => {
+                follows.as_deref().unwrap_or(self.hostname())
+            }
+        }
+    }
+
+    /// Name of the API, e.g. `linode`
+    pub fn api(&self) -> &'static str {
+        match self {
+            ReverseDnsConfig::Ovh { .. } => "ovh",
+            ReverseDnsConfig::Hetzner { .. } => "hetzner",
+            ReverseDnsConfig::Linode { .. } => "linode",
+            ReverseDnsConfig::Rfc2136 { .. } => "rfc2136",
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Secret)> {
+        match self {
+            ReverseDnsConfig::Ovh {
+                application_secret,
+                consumer_key,
+                ..
+            } => vec![
+                ("APPLICATION_SECRET", application_secret),
+                ("CONSUMER_KEY", consumer_key),
+            ],
+            ReverseDnsConfig::Hetzner { token, .. } | ReverseDnsConfig::Linode { token, .. } => {
+                vec![("TOKEN", token)]
+            }
+            ReverseDnsConfig::Rfc2136 { key, .. } => key.iter_mut().map(|k| ("KEY", k)).collect(),
+        }
+    }
+
+    /// Expands the `${VAR}` references of the credentials
+    pub fn interpolate(&mut self) -> Result<(), String> {
+        for (_, secret) in self.secrets_mut() {
+            *secret.expose_mut() = interpolate(secret.expose())?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the credentials with references to variables named after
+    /// them, e.g. `${LINODE_TOKEN}`
+    pub fn strip_secrets(&mut self, placeholder: &mut impl FnMut(&str, &str) -> String) {
+        let kind = self.api().to_uppercase();
+        for (field, secret) in self.secrets_mut() {
+            *secret = placeholder(&kind, field).into();
+        }
+    }
+
+    /// The reverse zone of an RFC 2136 server
+    fn zone(&self) -> Option<Zone<'_>> {
+        match self {
+            ReverseDnsConfig::Rfc2136 {
+                server,
+                zone,
+                key_name,
+                key,
+                ..
+            } => Some(Zone {
+                server: *server,
+                name: zone,
+                key_name: key_name.as_deref(),
+                key: key.as_ref(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(zone) = self.zone() {
+            let name = zone.name.trim_end_matches('.').to_ascii_lowercase();
+            if !name.ends_with("in-addr.arpa") && !name.ends_with("ip6.arpa") {
+                return Err(format!("{name} isn't a reverse zone"));
+            }
+            zone.check(zone.name)?;
+        }
+        Ok(())
+    }
+
+    /// Points the PTR record of `ip` at the hostname
+    pub async fn set(&self, client: &Client, ip: IpAddr) -> Result<(), String> {
+        let hostname = self.hostname().trim_end_matches('.');
+        match self {
+            ReverseDnsConfig::Ovh {
+                endpoint,
+                application_key,
+                application_secret,
+                consumer_key,
+                ip_block,
+                ..
+            } => {
+                let mut url = Url::parse(endpoint.as_deref().unwrap_or(OVH_ENDPOINT))
+                    .map_err(|e| format!("Invalid OVH endpoint: {e}"))?;
+                let block = match ip_block {
+                    Some(block) => block.clone(),
+                    None => format!("{ip}/{}", if ip.is_ipv4() { 32 } else { 128 }),
+                };
+                url.path_segments_mut()
+                    .map_err(|_| "Invalid OVH endpoint".to_string())?
+                    .extend(["ip", &block, "reverse"]);
+                let body = json!({ "ipReverse": ip, "reverse": format!("{hostname}.") });
+                let body = body.to_string();
+                let timestamp = Utc::now().timestamp().to_string();
+                let signature = ovh_signature(
+                    application_secret.expose(),
+                    consumer_key.expose(),
+                    "POST",
+                    url.as_str(),
+                    &body,
+                    &timestamp,
+                );
+                let request = client
+                    .post(url)
+                    .header("X-Ovh-Application", application_key)
+                    .header("X-Ovh-Consumer", consumer_key.expose())
+                    .header("X-Ovh-Timestamp", timestamp)
+                    .header("X-Ovh-Signature", signature)
+                    .header("Content-Type", "application/json")
+                    .body(body);
+                send(request, "OVH").await
+            }
+            ReverseDnsConfig::Hetzner {
+                token, primary_ip, ..
+            } => {
+                let url = format!("{HETZNER_URL}/primary_ips/{primary_ip}/actions/change_dns_ptr");
+                let request = client
+                    .post(url)
+                    .bearer_auth(token.expose())
+                    .header("Content-Type", "application/json")
+                    .body(json!({ "ip": ip, "dns_ptr": hostname }).to_string());
+                send(request, "Hetzner Cloud").await
+            }
+            ReverseDnsConfig::Linode { token, .. } => {
+                let request = client
+                    .put(format!("{LINODE_URL}/networking/ips/{ip}"))
+                    .bearer_auth(token.expose())
+                    .header("Content-Type", "application/json")
+                    .body(json!({ "rdns": hostname }).to_string());
+                send(request, "Linode").await
+            }
+            ReverseDnsConfig::Rfc2136 { ttl, .. } => {
+                let zone = self.zone().expect("an RFC 2136 server");
+                let target = RecordData::Ptr(hostname.to_string());
+                zone.replace(&ptr_name(ip), *ttl, &target).await
+            }
+        }
+    }
+}
+
+/// Sends a request to an API, whose answer only tells whether it succeeded
+async fn send(request: RequestBuilder, api: &str) -> Result<(), String> {
+    let response = vcr::send(request)
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    if response.status().is_success() {
+        return Ok(());
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(format!("{api} answered HTTP {status}: {}", body.trim()))
+}
+
+/// The `X-Ovh-Signature` of a request, see
+/// <https://help.ovhcloud.com/csm/en-api-getting-started-ovhcloud-api>
+fn ovh_signature(
+    application_secret: &str,
+    consumer_key: &str,
+    method: &str,
+    url: &str,
+    body: &str,
+    timestamp: &str,
+) -> String {
+    let signed = [
+        application_secret,
+        consumer_key,
+        method,
+        url,
+        body,
+        timestamp,
+    ]
+    .join("+");
+    format!("$1${}", hex::encode(Sha1::digest(signed.as_bytes())))
+}
+
+/// Name of the PTR record of `ip`, e.g. `1.2.0.192.in-addr.arpa`
+fn ptr_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(ip) => {
+            let mut labels: Vec<_> = ip
+                .octets()
+                .iter()
+                .flat_map(|byte| [byte >> 4, byte & 0xf])
+                .map(|nibble| format!("{nibble:x}"))
+                .collect();
+            labels.reverse();
+            format!("{}.ip6.arpa", labels.join("."))
+        }
+    }
+}
+
+/// Every PTR record to keep, see [`ReverseDns::update`]
+#[derive(Debug, Clone, Default)]
+pub struct ReverseDns(Arc<[ReverseDnsConfig]>);
+
+impl ReverseDns {
+    /// Checks the settings of `configs`
+    pub fn new(configs: Vec<ReverseDnsConfig>) -> Result<Self, String> {
+        for config in &configs {
+            config
+                .validate()
+                .map_err(|e| format!("PTR record of {}: {e}", config.hostname()))?;
+        }
+        Ok(Self(configs.into()))
+    }
+
+    /// Points `ip` back at the hostname of every record following the
+    /// provider `hostname`, logging why one couldn't be set
+    pub async fn update(&self, client: &Client, hostname: Option<&str>, ip: IpAddr) {
+        let Some(hostname) = hostname else {
+            return;
+        };
+        let hostname = hostname.trim_end_matches('.');
+        for config in self.0.iter() {
+            if !config
+                .follows()
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(hostname)
+            {
+                continue;
+            }
+            match config.set(client, ip).await {
+                Ok(()) => tracing::info!(
+                    %ip,
+                    ptr = config.hostname(),
+                    api = config.api(),
+                    "PTR record updated"
+                ),
+                Err(e) => tracing::error!(
+                    %ip,
+                    ptr = config.hostname(),
+                    api = config.api(),
+                    error = %e,
+                    "Couldn't update the PTR record"
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use reqwest::Client;
+    use tokio::net::UdpSocket;
+
+    use super::{ReverseDns, ReverseDnsConfig, ovh_signature, ptr_name};
+    use crate::vcr::{Cassette, Interaction};
+
+    #[test]
+    fn test_ptr_name() {
+        assert_eq!(
+            ptr_name("192.0.2.1".parse().unwrap()),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            ptr_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert_eq!(
+            ovh_signature(
+                "AS",
+                "CK",
+                "POST",
+                "https://eu.api.ovh.com/1.0/ip",
+                "{}",
+                "1"
+            ),
+            "$1$bbb4aaacc2646da4a2e626018fb56cf9f8f8f76c"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apis() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let reverse_dns = ReverseDns::new(vec![
+            ReverseDnsConfig::Linode {
+                hostname: "mail.example.com".to_string(),
+                follows: Some("home.example.com".to_string()),
+                token: "token".into(),
+            },
+            ReverseDnsConfig::Hetzner {
+                hostname: "other.example.com".to_string(),
+                follows: None,
+                token: "token".into(),
+                primary_ip: 42,
+            },
+        ])
+        .unwrap();
+        let cassette = Cassette::replay(vec![Interaction {
+            method: "PUT".to_string(),
+            url: "https://api.linode.com/v4/networking/ips/2001:db8::1".to_string(),
+            status: 200,
+            body: r#"{"address": "2001:db8::1", "rdns": "mail.example.com"}"#.to_string(),
+        }]);
+        let client = Client::new();
+        cassette
+            .clone()
+            .scope(reverse_dns.update(&client, Some("home.example.com."), ip))
+            .await;
+        // Every interaction was played, the Hetzner record follows another host
+        assert!(cassette.interactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rfc2136() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = ReverseDnsConfig::Rfc2136 {
+            hostname: "mail.example.com".to_string(),
+            follows: None,
+            server: server.local_addr().unwrap(),
+            zone: "2.0.192.in-addr.arpa".to_string(),
+            key_name: Some("ddns-key".to_string()),
+            key: Some("c2VjcmV0".into()),
+            ttl: 3600,
+        };
+        assert!(ReverseDns::new(vec![config.clone()]).is_ok());
+        let answer = tokio::spawn(async move {
+            let mut request = [0; 512];
+            let (len, client) = server.recv_from(&mut request).await.unwrap();
+            let request = request[..len].to_vec();
+            let mut answer = request[..12].to_vec();
+            // A successful response, with a refusal the second time
+            answer[2] |= 0x80;
+            server.send_to(&answer, client).await.unwrap();
+            let mut second = [0; 512];
+            let (_, client) = server.recv_from(&mut second).await.unwrap();
+            answer[..2].copy_from_slice(&second[..2]);
+            answer[3] = 5;
+            server.send_to(&answer, client).await.unwrap();
+            request
+        });
+        let client = Client::new();
+        let ip = "192.0.2.1".parse().unwrap();
+        assert_eq!(config.set(&client, ip).await, Ok(()));
+        assert!(
+            config
+                .set(&client, ip)
+                .await
+                .unwrap_err()
+                .contains("refused")
+        );
+
+        let request = answer.await.unwrap();
+        // UPDATE, one zone, two updates and the signature
+        assert_eq!(request[2] >> 3, 5);
+        assert_eq!(request[4..12], [0, 1, 0, 0, 0, 2, 0, 1]);
+        let contains = |part: &[u8]| request.windows(part.len()).any(|w| w == part);
+        assert!(contains(b"\x011\x012\x010\x03192\x07in-addr\x04arpa\x00"));
+        assert!(contains(b"\x04mail\x07example\x03com\x00"));
+        assert!(contains(b"\x0bhmac-sha256\x00"));
+
+        let rfc2136 =
+            |zone: &str, key_name: Option<&str>, key: Option<&str>| ReverseDnsConfig::Rfc2136 {
+                hostname: "mail.example.com".to_string(),
+                follows: None,
+                server: "127.0.0.1:53".parse().unwrap(),
+                zone: zone.to_string(),
+                key_name: key_name.map(str::to_string),
+                key: key.map(Into::into),
+                ttl: 3600,
+            };
+        let outside = rfc2136("8.b.d.0.1.0.0.2.ip6.arpa", None, None);
+        assert!(outside.set(&client, ip).await.is_err());
+        let unsigned = rfc2136("2.0.192.in-addr.arpa", Some("ddns-key"), None);
+        assert!(ReverseDns::new(vec![unsigned]).is_err());
+    }
+}
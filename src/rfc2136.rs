@@ -0,0 +1,336 @@
+//! Dynamic updates of a DNS server (RFC 2136), e.g. BIND, Knot or PowerDNS,
+//! signed with an HMAC-SHA256 TSIG key (RFC 8945) when one is given
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::secrets::Secret;
+
+/// How long the server gets to answer an update
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+const SOA: u16 = 6;
+const TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+/// Seconds of clock skew the server accepts for a TSIG signature
+const TSIG_FUDGE: u16 = 300;
+
+/// Data of a record, see [`Zone::replace`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    Ptr(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(String),
+}
+
+impl RecordData {
+    /// Parses the value of a record of type `kind` as written in a zone
+    /// file: `10 mail.example.com` for MX, `0 5 25565 mc.example.com` for
+    /// SRV, the text itself for TXT
+    pub fn parse(kind: &str, value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid {kind} value '{value}'");
+        let parts: Vec<_> = value.split_whitespace().collect();
+        let number = |part: &str| part.parse().map_err(|_| invalid());
+        match (kind.to_ascii_uppercase().as_str(), parts.as_slice()) {
+            ("PTR", [target]) => Ok(RecordData::Ptr(target.to_string())),
+            ("MX", [preference, exchange]) => Ok(RecordData::Mx {
+                preference: number(preference)?,
+                exchange: exchange.to_string(),
+            }),
+            ("SRV", [priority, weight, port, target]) => Ok(RecordData::Srv {
+                priority: number(priority)?,
+                weight: number(weight)?,
+                port: number(port)?,
+                target: target.to_string(),
+            }),
+            ("TXT", _) => Ok(RecordData::Txt(value.to_string())),
+            ("PTR" | "MX" | "SRV", _) => Err(invalid()),
+            _ => Err(format!("Unsupported record type {kind}")),
+        }
+    }
+
+    fn record_type(&self) -> u16 {
+        match self {
+            RecordData::Ptr(_) => 12,
+            RecordData::Mx { .. } => 15,
+            RecordData::Txt(_) => 16,
+            RecordData::Srv { .. } => 33,
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        match self {
+            RecordData::Ptr(target) => push_name(&mut data, target)?,
+            RecordData::Mx {
+                preference,
+                exchange,
+            } => {
+                data.extend(preference.to_be_bytes());
+                push_name(&mut data, exchange)?;
+            }
+            RecordData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                for field in [priority, weight, port] {
+                    data.extend(field.to_be_bytes());
+                }
+                push_name(&mut data, target)?;
+            }
+            // Split in strings of at most 255 bytes
+            RecordData::Txt(text) => {
+                for chunk in text.as_bytes().chunks(255) {
+                    data.push(chunk.len() as u8);
+                    data.extend(chunk);
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// A zone of a server accepting updates
+#[derive(Debug, Clone, Copy)]
+pub struct Zone<'a> {
+    /// Address of the primary server, e.g. `192.0.2.53:53`
+    pub server: SocketAddr,
+    /// e.g. `example.com` or `8.b.d.0.1.0.0.2.ip6.arpa`
+    pub name: &'a str,
+    /// Name of the TSIG key, updates being unsigned without one
+    pub key_name: Option<&'a str>,
+    /// The base64 secret of `key_name`
+    pub key: Option<&'a Secret>,
+}
+
+impl Zone<'_> {
+    /// Checks that the key is complete and base64, and that `name` is in
+    /// the zone
+    pub fn check(&self, name: &str) -> Result<(), String> {
+        self.key()?;
+        let (zone, name) = (
+            self.name.trim_end_matches('.').to_ascii_lowercase(),
+            name.trim_end_matches('.').to_ascii_lowercase(),
+        );
+        if name != zone && !name.ends_with(&format!(".{zone}")) {
+            return Err(format!("{name} isn't in the zone {zone}"));
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Result<Option<(&str, Vec<u8>)>, String> {
+        match (self.key_name, self.key) {
+            (Some(name), Some(key)) => STANDARD
+                .decode(key.expose())
+                .map(|key| Some((name, key)))
+                .map_err(|e| format!("The TSIG key isn't base64: {e}")),
+            (None, None) => Ok(None),
+            _ => Err("Set both key_name and key, or neither".to_string()),
+        }
+    }
+
+    /// Replaces the records of `name` of the type of `data` with one
+    /// holding `data`
+    pub async fn replace(&self, name: &str, ttl: u32, data: &RecordData) -> Result<(), String> {
+        self.check(name)?;
+        let id = RandomState::new().hash_one(name) as u16;
+        let update = update(id, self.name, name, ttl, data)?;
+        let update = match self.key()? {
+            Some((key_name, key)) => sign(update, key_name, &key, Utc::now().timestamp() as u64)?,
+            None => update,
+        };
+        send_update(self.server, id, &update).await
+    }
+}
+
+/// Appends `name` in the uncompressed wire format, lowercased as TSIG wants
+fn push_name(packet: &mut Vec<u8>, name: &str) -> Result<(), String> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("Invalid name '{name}'"));
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.to_ascii_lowercase().as_bytes());
+    }
+    packet.push(0);
+    Ok(())
+}
+
+/// An update of `zone` replacing the records of `name` of the type of
+/// `data` with one holding it
+fn update(id: u16, zone: &str, name: &str, ttl: u32, data: &RecordData) -> Result<Vec<u8>, String> {
+    let record_type = data.record_type().to_be_bytes();
+    let mut packet = Vec::new();
+    packet.extend(id.to_be_bytes());
+    // UPDATE opcode, one zone, no prerequisite, two updates, no additional
+    packet.extend([0x28, 0x00, 0, 1, 0, 0, 0, 2, 0, 0]);
+    push_name(&mut packet, zone)?;
+    packet.extend(SOA.to_be_bytes());
+    packet.extend(CLASS_IN.to_be_bytes());
+    // Deletes every record of the name and type...
+    push_name(&mut packet, name)?;
+    packet.extend(record_type);
+    packet.extend(CLASS_ANY.to_be_bytes());
+    packet.extend([0; 6]);
+    // ...then adds the new one
+    push_name(&mut packet, name)?;
+    packet.extend(record_type);
+    packet.extend(CLASS_IN.to_be_bytes());
+    packet.extend(ttl.to_be_bytes());
+    let data = data.encode()?;
+    packet.extend((data.len() as u16).to_be_bytes());
+    packet.extend(data);
+    Ok(packet)
+}
+
+/// `message` with a TSIG record signing it with the HMAC-SHA256 `key` named
+/// `key_name` at `time`
+fn sign(mut message: Vec<u8>, key_name: &str, key: &[u8], time: u64) -> Result<Vec<u8>, String> {
+    let (mut name, mut algorithm) = (Vec::new(), Vec::new());
+    push_name(&mut name, key_name)?;
+    push_name(&mut algorithm, TSIG_ALGORITHM)?;
+    let time = &time.to_be_bytes()[2..];
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&message);
+    mac.update(&name);
+    mac.update(&CLASS_ANY.to_be_bytes());
+    // TTL
+    mac.update(&[0; 4]);
+    mac.update(&algorithm);
+    mac.update(time);
+    mac.update(&TSIG_FUDGE.to_be_bytes());
+    // No error nor other data
+    mac.update(&[0; 4]);
+    let mac = mac.finalize().into_bytes();
+
+    let mut rdata = algorithm;
+    rdata.extend(time);
+    rdata.extend(TSIG_FUDGE.to_be_bytes());
+    rdata.extend((mac.len() as u16).to_be_bytes());
+    rdata.extend(mac);
+    // The original ID, then no error nor other data
+    rdata.extend(&message[..2]);
+    rdata.extend([0; 4]);
+
+    message.extend(name);
+    message.extend(TSIG.to_be_bytes());
+    message.extend(CLASS_ANY.to_be_bytes());
+    message.extend([0; 4]);
+    message.extend((rdata.len() as u16).to_be_bytes());
+    message.extend(rdata);
+    // One additional record, the signature
+    message[11] += 1;
+    Ok(message)
+}
+
+/// Sends `update` to `server`, checking its answer
+async fn send_update(server: SocketAddr, id: u16, update: &[u8]) -> Result<(), String> {
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|e| e.to_string())?;
+    socket.connect(server).await.map_err(|e| e.to_string())?;
+    socket.send(update).await.map_err(|e| e.to_string())?;
+    let mut response = [0; 512];
+    let len = timeout(UPDATE_TIMEOUT, socket.recv(&mut response))
+        .await
+        .map_err(|_| format!("{server} didn't answer the update"))?
+        .map_err(|e| e.to_string())?;
+    let response = &response[..len];
+    if response.len() < 4 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+        return Err(format!("Unexpected answer from {server}"));
+    }
+    match response[3] & 0x0f {
+        0 => Ok(()),
+        5 => Err(format!("{server} refused the update")),
+        9 => Err(format!(
+            "{server} isn't authoritative for the zone, or refused the key"
+        )),
+        rcode => Err(format!("{server} answered DNS error code {rcode}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RecordData, Zone, sign, update};
+    use crate::secrets::Secret;
+
+    #[test]
+    fn test_record_data() {
+        assert_eq!(
+            RecordData::parse("mx", "10 mail.example.com")
+                .unwrap()
+                .encode(),
+            Ok(b"\x00\x0a\x04mail\x07example\x03com\x00".to_vec())
+        );
+        let srv = RecordData::parse("SRV", "0 5 25565 mc.example.com.").unwrap();
+        assert_eq!(
+            srv.encode().unwrap(),
+            b"\x00\x00\x00\x05\x63\xdd\x02mc\x07example\x03com\x00"
+        );
+        let txt = RecordData::parse("TXT", &"a".repeat(300)).unwrap();
+        assert_eq!(txt.encode().unwrap().len(), 302);
+        assert!(RecordData::parse("MX", "mail.example.com").is_err());
+        assert!(RecordData::parse("SRV", "0 5 http mc.example.com").is_err());
+        assert!(RecordData::parse("CNAME", "example.com").is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let key = Secret::from("c2VjcmV0");
+        let zone = Zone {
+            server: "192.0.2.53:53".parse().unwrap(),
+            name: "example.com.",
+            key_name: Some("ddns-key"),
+            key: Some(&key),
+        };
+        assert_eq!(zone.check("_minecraft._tcp.Example.com"), Ok(()));
+        assert!(zone.check("example.org").is_err());
+        assert!(zone.check("badexample.com").is_err());
+        assert!(Zone { key: None, ..zone }.check("example.com").is_err());
+        let invalid = Secret::from("not base64!");
+        let invalid = Zone {
+            key: Some(&invalid),
+            ..zone
+        };
+        assert!(invalid.check("example.com").is_err());
+
+        let mx = RecordData::parse("MX", "10 mail.example.com").unwrap();
+        let update = update(0x1234, zone.name, "example.com", 300, &mx).unwrap();
+        assert_eq!(update[..12], [0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 0]);
+        // The deletion of every MX record, then the new one
+        let contains = |part: &[u8]| update.windows(part.len()).any(|w| w == part);
+        assert!(contains(
+            b"\x07example\x03com\x00\x00\x0f\x00\xff\x00\x00\x00\x00\x00\x00"
+        ));
+        assert!(update.ends_with(
+            b"\x00\x0f\x00\x01\x00\x00\x01\x2c\x00\x14\x00\x0a\x04mail\x07example\x03com\x00"
+        ));
+        let signed = sign(update.clone(), "ddns-key", b"secret", 0).unwrap();
+        assert_eq!(signed[11], 1);
+        assert!(signed.starts_with(&update[..11]));
+    }
+}
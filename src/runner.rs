@@ -1,79 +1,1226 @@
-use tokio::sync::mpsc;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{Local, Utc};
+use reqwest::Client;
+use tokio::{
+    sync::{mpsc, watch},
+    task::{AbortHandle, JoinSet},
+};
+use tracing::{Instrument, Span, field};
 
 use crate::{
-    dyn_dns::DynDns,
-    ip_grabber::{self, IpGrabber},
-    persistence::{self, Persistence},
+    IpVersion, SimpleName, blackout,
+    budget::UpdateBudget,
+    config::{self, Config},
+    dyn_dns::{self, DynDns, KeyedDynDns},
+    flap::FlapDetector,
+    geoip::{GeoIp, Networks},
+    health::{Health, Reporter},
+    history::HistoryEntry,
+    hooks::{HookContext, HookEvent, Hooks},
+    http::{self, HttpConfig},
+    ip_grabber::{self, IpGrabber, IpSource, Race, SourceFactory},
+    notify::{Notification, NotificationEvent, Notifiers},
+    persistence::{self, ProviderState, StateBackend, StateStore},
+    priority::{PriorityGate, Settled},
+    records::Records,
+    reverse_dns::ReverseDns,
+    stats, vcr,
 };
 
 pub type DynGrabber = (Box<dyn DynDns>, IpGrabber);
 
 pub struct Runner {
-    pers: Persistence,
+    iface: String,
+    store: Arc<dyn StateStore>,
     dyn_dnss: Vec<DynGrabber>,
+    /// Identifies each provider across reloads, see [`Reload`]
+    keys: Vec<String>,
+    hooks: Arc<Hooks>,
+    history_retention: Option<chrono::Duration>,
+    health: Health,
+    notifiers: Notifiers,
+    client: Client,
+    sources: SourceFactory,
+    geoip: Option<GeoIp>,
+    reverse_dns: ReverseDns,
+    dns_records: Records,
 }
 
-#[derive(Debug)]
+/// Configuration applied to a running [`Runner`], see [`Runner::run_reloading`].
+pub struct Reload {
+    pub iface: String,
+    /// Providers with a key identifying them, typically the configuration entry
+    /// they were parsed from: a provider keeps running while its key doesn't change
+    pub dyn_dnss: Vec<KeyedDynDns>,
+    pub hooks: Hooks,
+    pub notifiers: Notifiers,
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    PersistenceError(persistence::Error),
-    GrabberError(ip_grabber::Error),
+    #[error("invalid configuration: {0}")]
+    Config(config::Error),
+    #[error("couldn't open the state: {0}")]
+    Store(#[from] persistence::Error),
+    #[error("{0}")]
+    Http(#[from] http::Error),
 }
 
-impl Runner {
-    pub fn new(iface: String, dyn_dnss: Vec<Box<dyn DynDns>>) -> Result<Self, Error> {
-        let pers = Persistence::new(dyn_dnss.iter().map(|dd| dd.file_name()))
-            .map_err(Error::PersistenceError)?;
+/// Where [`RunnerBuilder`] gets the store from
+enum Store {
+    Open(Arc<dyn StateStore>),
+    Backend(StateBackend),
+}
+
+/// Where [`RunnerBuilder`] gets the HTTP client from
+enum HttpClient {
+    Built(Client),
+    Config(HttpConfig),
+}
+
+/// Sets up a [`Runner`], see [`Runner::builder`]. Nothing is opened or
+/// created before [`RunnerBuilder::build`], which returns what failed
+/// instead of panicking.
+pub struct RunnerBuilder {
+    iface: String,
+    dyn_dnss: Vec<KeyedDynDns>,
+    store: Store,
+    hooks: Hooks,
+    history_retention: Option<chrono::Duration>,
+    health: Health,
+    notifiers: Notifiers,
+    client: HttpClient,
+    sources: SourceFactory,
+    geoip: Option<GeoIp>,
+    reverse_dns: ReverseDns,
+    dns_records: Records,
+}
 
-        let dyn_dnss: Result<Vec<DynGrabber>, ip_grabber::Error> = dyn_dnss
+impl RunnerBuilder {
+    /// Sets the providers to keep up to date, replacing those set before
+    pub fn with_providers(self, dyn_dnss: Vec<Box<dyn DynDns>>) -> Self {
+        let keyed = dyn_dnss
+            .into_iter()
+            .enumerate()
+            .map(|(i, dd)| (i.to_string(), dd))
+            .collect();
+        self.with_keyed_providers(keyed)
+    }
+
+    /// Like [`RunnerBuilder::with_providers`], with the keys
+    /// [`Runner::run_reloading`] uses to tell which providers changed
+    pub fn with_keyed_providers(mut self, dyn_dnss: Vec<KeyedDynDns>) -> Self {
+        self.dyn_dnss = dyn_dnss;
+        self
+    }
+
+    /// Sets where the published IPs and their history are kept, files in the
+    /// working directory by default
+    pub fn with_store(mut self, store: Arc<dyn StateStore>) -> Self {
+        self.store = Store::Open(store);
+        self
+    }
+
+    /// Like [`RunnerBuilder::with_store`], with the store opened by
+    /// [`RunnerBuilder::build`]
+    pub fn with_state(mut self, backend: StateBackend) -> Self {
+        self.store = Store::Backend(backend);
+        self
+    }
+
+    /// Sets the commands run on IP changes and update results
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Drops history entries once they're older than `retention`, every
+    /// entry is kept by default
+    pub fn with_history_retention(mut self, retention: Option<chrono::Duration>) -> Self {
+        self.history_retention = retention;
+        self
+    }
+
+    /// Reports how every provider is doing to `health`, e.g. to serve it
+    /// with [`crate::health::serve`]. One-off runs don't report.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Sends IP changes, failed updates, recoveries and flapping IPs to
+    /// `notifiers`
+    pub fn with_notifiers(mut self, notifiers: Notifiers) -> Self {
+        self.notifiers = notifiers;
+        self
+    }
+
+    /// Sets the HTTP client every provider and IP source shares, see
+    /// [`http::client`], one with the default timeouts otherwise
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = HttpClient::Built(client);
+        self
+    }
+
+    /// Like [`RunnerBuilder::with_client`], with the client built by
+    /// [`RunnerBuilder::build`]
+    pub fn with_http(mut self, config: HttpConfig) -> Self {
+        self.client = HttpClient::Config(config);
+        self
+    }
+
+    /// Sets how the IP of each provider is detected, e.g. with the mocks of
+    /// `test_util`, [`ip_grabber::default_source`] otherwise. Providers with
+    /// `ip_sources` in their options race those instead
+    pub fn with_ip_sources(mut self, sources: SourceFactory) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Looks up who announces the old and new IPs of every change, for the
+    /// history, health and notifications
+    pub fn with_geoip(mut self, geoip: Option<GeoIp>) -> Self {
+        self.geoip = geoip;
+        self
+    }
+
+    /// Points every changed IP back at the hostname of its provider with
+    /// the PTR records of `reverse_dns`
+    pub fn with_reverse_dns(mut self, reverse_dns: ReverseDns) -> Self {
+        self.reverse_dns = reverse_dns;
+        self
+    }
+
+    /// Keeps the MX, SRV and TXT records of `dns_records` following the
+    /// hostname and IP of their provider
+    pub fn with_dns_records(mut self, dns_records: Records) -> Self {
+        self.dns_records = dns_records;
+        self
+    }
+
+    /// Opens the store and builds the HTTP client when they weren't given.
+    /// Disabled providers are left out.
+    pub fn build(self) -> Result<Runner, Error> {
+        let RunnerBuilder {
+            iface,
+            dyn_dnss,
+            store,
+            hooks,
+            history_retention,
+            health,
+            notifiers,
+            client,
+            sources,
+            geoip,
+            reverse_dns,
+            dns_records,
+        } = self;
+        let store = match store {
+            Store::Open(store) => store,
+            Store::Backend(backend) => backend.open()?,
+        };
+        let client = match client {
+            HttpClient::Built(client) => client,
+            HttpClient::Config(config) => http::client(&config)?,
+        };
+        let (keys, dyn_dnss): (Vec<_>, Vec<_>) = enabled(dyn_dnss).unzip();
+        let dyn_dnss = dyn_dnss
             .into_iter()
             .map(|dyn_dns| {
-                let ipv = dyn_dns.get_ip_version();
-                let ps = dyn_dns.get_poll_secs();
-                Ok((dyn_dns, IpGrabber::new(iface.clone(), ipv, ps)?))
+                let grabber = grabber(&sources, &iface, dyn_dns.as_ref());
+                (dyn_dns, grabber)
             })
             .collect();
-        let dyn_dnss = dyn_dnss.map_err(Error::GrabberError)?;
 
-        Ok(Self { pers, dyn_dnss })
+        Ok(Runner {
+            iface,
+            store,
+            dyn_dnss,
+            keys,
+            hooks: Arc::new(hooks),
+            history_retention,
+            health,
+            notifiers,
+            client,
+            sources,
+            geoip,
+            reverse_dns,
+            dns_records,
+        })
+    }
+}
+
+impl Runner {
+    /// Starts setting up a runner of the providers of the interface `iface`
+    pub fn builder(iface: String) -> RunnerBuilder {
+        RunnerBuilder {
+            iface,
+            dyn_dnss: Vec::new(),
+            store: Store::Backend(StateBackend::Files),
+            hooks: Hooks::default(),
+            history_retention: None,
+            health: Health::default(),
+            notifiers: Notifiers::default(),
+            client: HttpClient::Config(HttpConfig::default()),
+            sources: Arc::new(ip_grabber::default_source),
+            geoip: None,
+            reverse_dns: ReverseDns::default(),
+            dns_records: Records::default(),
+        }
+    }
+
+    /// Starts setting up a runner of everything `config` declares: the
+    /// interface, providers, hooks, notifications, state, history retention,
+    /// GeoIP databases and PTR and other records, as the binary runs them.
+    /// The providers, IP sources and notifiers share `client`, see
+    /// [`Config::client`].
+    pub fn configured(config: Config, client: Client) -> Result<RunnerBuilder, Error> {
+        let dyn_dnss = config.keyed_dyn_dnss().map_err(Error::Config)?;
+        let retention = config.history_retention().map_err(Error::Config)?;
+        let notifiers = config.notifiers(&client).map_err(Error::Config)?;
+        let geoip = config.geoip().map_err(Error::Config)?;
+        let reverse_dns = config.reverse_dns().map_err(Error::Config)?;
+        let dns_records = config.dns_records().map_err(Error::Config)?;
+        Ok(Self::builder(config.interface)
+            .with_keyed_providers(dyn_dnss)
+            .with_hooks(config.hooks)
+            .with_notifiers(notifiers)
+            .with_history_retention(retention)
+            .with_geoip(geoip)
+            .with_reverse_dns(reverse_dns)
+            .with_dns_records(dns_records)
+            .with_state(config.state)
+            .with_client(client))
+    }
+
+    /// A runner with the defaults of [`Runner::builder`]
+    pub fn new(iface: String, dyn_dnss: Vec<Box<dyn DynDns>>) -> Result<Self, Error> {
+        Self::builder(iface).with_providers(dyn_dnss).build()
+    }
+
+    /// Like [`Runner::new`], with the keys [`Runner::run_reloading`] uses to
+    /// tell which providers changed. Disabled providers are left out.
+    pub fn keyed(iface: String, dyn_dnss: Vec<KeyedDynDns>) -> Result<Self, Error> {
+        Self::builder(iface).with_keyed_providers(dyn_dnss).build()
+    }
+
+    /// Detects and publishes the IP of every provider a single time, higher
+    /// priorities first. Returns whether every provider was updated.
+    pub async fn run_once(self) -> bool {
+        self.publish_once(None).await
+    }
+
+    /// Like [`Runner::run_once`], but publishes `ip` instead of detecting it when
+    /// given. Providers of the other IP version are reported as failed.
+    pub async fn publish_once(self, ip: Option<IpAddr>) -> bool {
+        let Runner {
+            store,
+            mut dyn_dnss,
+            hooks,
+            history_retention,
+            notifiers,
+            client,
+            geoip,
+            reverse_dns,
+            dns_records,
+            ..
+        } = self;
+        dyn_dnss.sort_by_key(|(dns, _)| std::cmp::Reverse(dns.options().priority));
+
+        let with = Publishing {
+            client: &client,
+            store: store.as_ref(),
+            hooks: &hooks,
+            notifiers: &notifiers,
+            history_retention,
+            geoip: geoip.as_ref(),
+            reverse_dns: &reverse_dns,
+            dns_records: &dns_records,
+        };
+        let mut all_ok = true;
+        for (dns, grabber) in dyn_dnss {
+            let span = provider_span(dns.as_ref());
+            let published = publish_ip(dns, &grabber, ip, &with);
+            all_ok &= published.instrument(span).await;
+        }
+        all_ok
     }
 
+    /// Keeps every record up to date until every provider stopped.
     pub async fn run(self) {
-        let Runner { pers, dyn_dnss } = self;
-
-        let (sender, mut receiver) = mpsc::channel(10000);
-
-        let it = dyn_dnss.into_iter().map(|(mut dns, mut grabber)| {
-            let (gs, mut gr) = mpsc::channel(10000);
-            tokio::spawn(async move { grabber.run(gs).await });
-            let sender = sender.clone();
-            let file_name = dns.file_name().to_string();
-            async move {
-                while let Some(ip) = gr.recv().await {
-                    match dns.update(ip).await {
-                        Ok(()) => {
-                            // Update successful, now persist the new IP
-                            if let Err(e) = sender.send((ip, file_name.clone())).await {
-                                log::error!("DNS update succeeded, but failed to send IP to persistence. The IP might be updated again unnecessarily on next check. Error: {e:?}");
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("Error updating DNS: {e:?}")
+        // Without a sender the runner never reloads
+        let (_, reloads) = mpsc::channel(1);
+        self.run_reloading(reloads).await
+    }
+
+    /// Like [`Runner::run`], applying every [`Reload`] received: providers
+    /// whose entry (or the interface) changed are restarted, removed ones are
+    /// stopped, new ones started and unchanged ones keep running untouched.
+    pub async fn run_reloading(self, mut reloads: mpsc::Receiver<Reload>) {
+        let Runner {
+            iface,
+            store,
+            dyn_dnss,
+            keys,
+            hooks,
+            history_retention,
+            health,
+            notifiers,
+            client,
+            sources,
+            geoip,
+            reverse_dns,
+            dns_records,
+        } = self;
+        let (hooks_tx, hooks) = watch::channel(hooks);
+        let (notifiers_tx, notifiers) = watch::channel(notifiers);
+        let shared = Shared {
+            gate: PriorityGate::default(),
+            hooks,
+            store,
+            history_retention,
+            health,
+            notifiers,
+            client,
+            geoip,
+            reverse_dns,
+            dns_records,
+        };
+
+        let mut tasks = JoinSet::new();
+        let mut running = HashMap::new();
+        for (key, (dns, grabber)) in keys.into_iter().zip(dyn_dnss) {
+            let task = tasks.spawn(vcr::inherit(provider_task(dns, grabber, &shared)));
+            running.insert((iface.clone(), key), task);
+        }
+
+        loop {
+            tokio::select! {
+                Some(reload) = reloads.recv() => {
+                    let Reload { iface, dyn_dnss, hooks, notifiers } = reload;
+                    let dyn_dnss: Vec<_> = enabled(dyn_dnss).collect();
+                    let mut stopped = 0;
+                    running.retain(|(running_iface, key), task: &mut AbortHandle| {
+                        let keep = *running_iface == iface && dyn_dnss.iter().any(|(k, _)| k == key);
+                        if !keep {
+                            task.abort();
+                            stopped += 1;
                         }
+                        keep
+                    });
+
+                    let added: Vec<_> = dyn_dnss
+                        .into_iter()
+                        .filter(|(key, _)| !running.contains_key(&(iface.clone(), key.clone())))
+                        .collect();
+                    let started = added.len();
+                    for (key, dns) in added {
+                        let grabber = grabber(&sources, &iface, dns.as_ref());
+                        let task = tasks.spawn(vcr::inherit(provider_task(dns, grabber, &shared)));
+                        running.insert((iface.clone(), key), task);
                     }
+                    hooks_tx.send_replace(Arc::new(hooks));
+                    notifiers_tx.send_replace(notifiers);
+                    tracing::info!(
+                        stopped,
+                        started,
+                        unchanged = running.len() - started,
+                        "Configuration reloaded"
+                    );
                 }
+                Some(res) = tasks.join_next() => {
+                    match res {
+                        Err(e) if e.is_cancelled() => {}
+                        Err(e) => tracing::error!(error = ?e, "Provider task stopped unexpectedly"),
+                        Ok(()) => {}
+                    }
+                }
+                else => break,
             }
-        });
+        }
+    }
+}
+
+/// Keeps the records of the configuration read from the environment up to
+/// date, see [`Config::from_env`]. The state is kept in the working
+/// directory.
+pub async fn run_from_env() -> Result<(), Error> {
+    let config = Config::from_env().map_err(Error::Config)?;
+    let client = http::client(&config.http)?;
+    Runner::configured(config, client)?.build()?.run().await;
+    Ok(())
+}
+
+/// Like [`run_from_env`], with the configuration file at `path`
+pub async fn run_from_config(path: impl AsRef<Path>) -> Result<(), Error> {
+    let config = Config::from_file(path, None).map_err(Error::Config)?;
+    let client = http::client(&config.http)?;
+    Runner::configured(config, client)?.build()?.run().await;
+    Ok(())
+}
+
+/// Detects the IP of `dns` with its [`source`]
+fn grabber(sources: &SourceFactory, iface: &str, dns: &dyn DynDns) -> IpGrabber {
+    IpGrabber::with_source(source(sources, iface, dns), dns.get_schedule().clone())
+}
+
+/// The source `sources` gives for `dns`, or a race between the `ip_sources`
+/// of its options, which take precedence
+pub fn source(sources: &SourceFactory, iface: &str, dns: &dyn DynDns) -> Box<dyn IpSource> {
+    let (options, ip_version) = (dns.options(), dns.get_ip_version());
+    if options.ip_sources.is_empty() {
+        return sources(iface, ip_version);
+    }
+    let racing = options
+        .ip_sources
+        .iter()
+        .map(|spec| spec.source(iface, ip_version))
+        .collect();
+    let timeout = Duration::from_secs(options.ip_source_timeout_secs);
+    Box::new(Race::new(racing, ip_version, timeout))
+}
 
-        for fut in it {
-            tokio::spawn(fut);
+/// Leaves out the providers disabled in their options
+fn enabled(dyn_dnss: Vec<KeyedDynDns>) -> impl Iterator<Item = KeyedDynDns> {
+    dyn_dnss.into_iter().filter(|(_, dd)| {
+        if !dd.options().enabled {
+            tracing::info!(
+                provider = dd.provider_name(),
+                hostname = dd.hostname().unwrap_or("-"),
+                "Provider disabled, skipping it"
+            );
         }
+        dd.options().enabled
+    })
+}
 
-        drop(sender);
+/// What providers are published with, see [`publish`]
+#[derive(Clone, Copy)]
+struct Publishing<'a> {
+    client: &'a Client,
+    store: &'a dyn StateStore,
+    hooks: &'a Hooks,
+    notifiers: &'a Notifiers,
+    history_retention: Option<chrono::Duration>,
+    geoip: Option<&'a GeoIp>,
+    reverse_dns: &'a ReverseDns,
+    dns_records: &'a Records,
+}
 
-        while let Some((ip, file_name)) = receiver.recv().await {
-            if let Err(e) = pers.replace_ip(&ip, &file_name).await {
-                log::error!("Error when saving IP: {e:?}");
+/// Detects the IP of `dns`, unless `ip` is given, and publishes it. Returns
+/// whether it was published.
+async fn publish_ip(
+    mut dns: Box<dyn DynDns>,
+    grabber: &IpGrabber,
+    ip: Option<IpAddr>,
+    with: &Publishing<'_>,
+) -> bool {
+    let Publishing { client, store, .. } = *with;
+    // Nothing waits for the window to end, the next run publishes instead
+    if ip.is_none()
+        && let Some(left) = blackout::remaining(&dns.options().blackout, Local::now().time())
+    {
+        tracing::info!(
+            left_secs = left.as_secs(),
+            "In a maintenance window, skipping the update"
+        );
+        return true;
+    }
+    let cycle = cycle_span(dns.as_ref());
+    let detected = match ip {
+        Some(ip) if IpVersion::from(&ip) == dns.get_ip_version() => Ok(ip),
+        Some(ip) => {
+            tracing::error!(%ip, "Can't publish the IP, the provider is configured for the other IP version");
+            return false;
+        }
+        None => {
+            let source = grabber.source().name();
+            grabber
+                .get_updated(client)
+                .instrument(tracing::info_span!(parent: &cycle, "detect", source))
+                .await
+        }
+    };
+    let ip = match detected {
+        Ok(ip) => ip,
+        Err(e) => {
+            tracing::error!(error = %e, "Couldn't detect the IP");
+            record_error(&cycle, &e.to_string());
+            return false;
+        }
+    };
+    let mut state = load_state(store, dns.file_name()).await;
+    publish(dns.as_mut(), ip, false, &mut state, with, None, &cycle).await
+}
+
+/// What a provider task adds to [`publish`]: the health it reports to and
+/// its turn among the priorities
+struct Task<'a> {
+    reporter: &'a Reporter,
+    gate: &'a PriorityGate,
+    priority: i32,
+    settled: &'a Settled,
+}
+
+/// Publishes `ip` for `dns`, even when unchanged if `forced`: the change hooks
+/// and notifications, the update, its outcome's hooks and notifications, the
+/// history, PTR and other records of a change, then `state` is saved. Returns
+/// whether the update succeeded.
+async fn publish(
+    dns: &mut dyn DynDns,
+    ip: IpAddr,
+    forced: bool,
+    state: &mut ProviderState,
+    with: &Publishing<'_>,
+    task: Option<&Task<'_>>,
+    cycle: &Span,
+) -> bool {
+    let Publishing {
+        client,
+        store,
+        hooks,
+        notifiers,
+        history_retention,
+        geoip,
+        reverse_dns,
+        dns_records,
+    } = *with;
+    cycle.record("ip", field::display(ip));
+    if let Some(old_ip) = state.ip {
+        cycle.record("old_ip", field::display(old_ip));
+    }
+    let attempt = state.consecutive_failures + 1;
+    cycle.record("attempt", attempt);
+    let file_name = dns.file_name().to_string();
+    let hostname = dns.hostname().map(str::to_string);
+    let ctx = HookContext {
+        provider: dns.provider_name(),
+        hostname: hostname.as_deref(),
+        old_ip: state.ip,
+        new_ip: ip,
+        error: None,
+    };
+    let reporter = task.map(|task| task.reporter);
+    let networks = networks(geoip, client, &ctx, reporter)
+        .instrument(geoip_span(cycle))
+        .await;
+    if ctx.old_ip != Some(ip) {
+        hooks
+            .run(HookEvent::Change, &ctx, client)
+            .instrument(hooks_span(cycle))
+            .await;
+        notifiers
+            .send(
+                Notification::new(NotificationEvent::Change, &ctx).with_networks(networks.clone()),
+            )
+            .instrument(notify_span(cycle))
+            .await;
+    }
+
+    if let Some(task) = task {
+        let waiting = tracing::info_span!(parent: cycle, "wait_for_priority", task.priority);
+        task.gate
+            .wait_for_higher(task.priority, ip)
+            .instrument(waiting)
+            .await;
+    }
+    let started = Instant::now();
+    let res = dyn_dns::update_if_changed(dns, client, ip, forced)
+        .instrument(update_span(cycle))
+        .await;
+    let latency = started.elapsed();
+    if let Some(task) = task {
+        // Lower priority providers may go ahead, whatever the outcome
+        task.settled.send_replace(Some(ip));
+    }
+    let ok = res.is_ok();
+    match res {
+        Ok(response) => {
+            hooks
+                .run(HookEvent::Success, &ctx, client)
+                .instrument(hooks_span(cycle))
+                .await;
+            if state.consecutive_failures > 0 {
+                notifiers
+                    .send(Notification::new(NotificationEvent::Recovery, &ctx))
+                    .instrument(notify_span(cycle))
+                    .await;
+            }
+            if ctx.old_ip != Some(ip) {
+                record_change(store, &ctx, networks.clone(), latency, history_retention)
+                    .instrument(history_span(cycle))
+                    .await;
+                reverse_dns
+                    .update(client, ctx.hostname, ip)
+                    .instrument(reverse_dns_span(cycle))
+                    .await;
+                dns_records
+                    .update(ctx.hostname, ip)
+                    .instrument(dns_records_span(cycle))
+                    .await;
+                if let Some(reporter) = reporter {
+                    reporter.published(Utc::now());
+                }
             }
+            state.record_success(ip, response, Utc::now(), latency);
+        }
+        Err(e) => {
+            tracing::error!(%ip, attempt, error = %e, "Error updating DNS");
+            let e = e.to_string();
+            record_error(cycle, &e);
+            let ctx = HookContext {
+                error: Some(&e),
+                ..ctx
+            };
+            hooks
+                .run(HookEvent::Failure, &ctx, client)
+                .instrument(hooks_span(cycle))
+                .await;
+            notifiers
+                .send(Notification::new(NotificationEvent::Failure, &ctx))
+                .instrument(notify_span(cycle))
+                .await;
+            // Retries of the same change aren't recorded again
+            if ctx.old_ip != Some(ip) && state.consecutive_failures == 0 {
+                record_change(store, &ctx, networks, latency, history_retention)
+                    .instrument(history_span(cycle))
+                    .await;
+            }
+            state.record_failure(e, Utc::now(), latency);
+        }
+    }
+    if let Some(reporter) = reporter {
+        reporter.record(state);
+    }
+    if let Err(e) = store
+        .save_state(&file_name, state)
+        .instrument(save_span(cycle))
+        .await
+    {
+        match ok {
+            true => tracing::error!(
+                error = %e,
+                "DNS update succeeded, but failed to save the IP. The IP might be updated again unnecessarily on next check"
+            ),
+            false => tracing::error!(error = %e, "Couldn't save the failed attempt"),
+        }
+    }
+    ok
+}
+
+/// What every provider task shares, live across reloads
+struct Shared {
+    gate: PriorityGate,
+    hooks: watch::Receiver<Arc<Hooks>>,
+    store: Arc<dyn StateStore>,
+    history_retention: Option<chrono::Duration>,
+    health: Health,
+    notifiers: watch::Receiver<Notifiers>,
+    client: Client,
+    geoip: Option<GeoIp>,
+    reverse_dns: ReverseDns,
+    dns_records: Records,
+}
+
+/// Detects the IP of one provider and publishes every change
+fn provider_task(
+    mut dns: Box<dyn DynDns>,
+    mut grabber: IpGrabber,
+    shared: &Shared,
+) -> impl Future<Output = ()> + Send + 'static {
+    let hooks = shared.hooks.clone();
+    let store = shared.store.clone();
+    let gate = shared.gate.clone();
+    let history_retention = shared.history_retention;
+    let notifiers = shared.notifiers.clone();
+    let client = shared.client.clone();
+    let geoip = shared.geoip.clone();
+    let reverse_dns = shared.reverse_dns.clone();
+    let dns_records = shared.dns_records.clone();
+    let priority = dns.options().priority;
+    let ip_version = dns.get_ip_version();
+    let settled = gate.join(priority, ip_version);
+    let file_name = dns.file_name().to_string();
+    let provider = dns.provider_name();
+    let hostname = dns.hostname().map(str::to_string);
+    // Detection runs on its own schedule, so traces start once an IP is received
+    let source = grabber.source().name().to_string();
+    let mut budget = dns.options().max_updates_per_day.map(UpdateBudget::new);
+    let mut flap = dns.options().flap_max_changes.map(|max| {
+        FlapDetector::new(
+            max,
+            Duration::from_secs(dns.options().flap_window_mins * 60),
+            Duration::from_secs(dns.options().flap_debounce_secs),
+        )
+    });
+    let span = provider_span(dns.as_ref());
+    // Unregistered when the task stops, with the grabber
+    let reporter = shared
+        .health
+        .register(provider, hostname.as_deref(), dns.get_ip_version());
+    grabber.report_to(reporter.clone());
+
+    async move {
+        let mut state = load_state(store.as_ref(), &file_name).await;
+        reporter.record(&state);
+        match store.history().await {
+            Ok(history) => reporter.published_at(stats::published_at(
+                &history,
+                provider,
+                hostname.as_deref(),
+                ip_version,
+            )),
+            Err(e) => tracing::warn!(error = %e, "Couldn't read the history"),
         }
+        // Only the latest detected IP matters, older values are simply overwritten
+        let (gs, mut gr) = watch::channel(None);
+        let grabber_client = client.clone();
+        let publish = async move {
+            loop {
+                let forced = tokio::select! {
+                    changed = gr.changed() => match changed {
+                        Ok(()) => false,
+                        Err(_) => break,
+                    },
+                    () = reporter.forced() => true,
+                };
+                // A forced update republishes the same IP, it's no change
+                if !forced && let Some(flap) = flap.as_mut() {
+                    if flap.record(Instant::now()) {
+                        tracing::warn!(
+                            debounce_secs = flap.debounce().as_secs(),
+                            "IP is flapping, waiting for it to be stable before updating"
+                        );
+                        let latest = *gr.borrow();
+                        if let Some(ip) = latest {
+                            let ctx = HookContext {
+                                provider,
+                                hostname: hostname.as_deref(),
+                                old_ip: state.ip,
+                                new_ip: ip,
+                                error: None,
+                            };
+                            let hooks = hooks.borrow().clone();
+                            hooks.run(HookEvent::Flapping, &ctx, &client).await;
+                            let notifiers = notifiers.borrow().clone();
+                            notifiers
+                                .send(Notification::new(NotificationEvent::Flapping, &ctx))
+                                .await;
+                        }
+                    }
+                    // While dampened, only publish once the IP stopped changing
+                    while flap.is_dampened(Instant::now()) {
+                        match tokio::time::timeout(flap.debounce(), gr.changed()).await {
+                            Err(_) => break,
+                            Ok(Ok(())) => {
+                                flap.record(Instant::now());
+                            }
+                            Ok(Err(_)) => return,
+                        }
+                    }
+                }
+
+                if reporter.is_paused() {
+                    tracing::info!("Updates paused, waiting to be resumed");
+                    reporter.unpaused().await;
+                }
+                // The IP detected last, once the window ends, is the one published
+                while !forced
+                    && let Some(left) =
+                        blackout::remaining(&dns.options().blackout, Local::now().time())
+                {
+                    tracing::info!(
+                        left_secs = left.as_secs(),
+                        "In a maintenance window, updating once it ends"
+                    );
+                    tokio::time::sleep(left).await;
+                }
+                // Nothing to publish yet, e.g. forced before the first detection
+                if gr.borrow_and_update().is_none() {
+                    continue;
+                }
+                if let Some(budget) = budget.as_mut() {
+                    // Deferring keeps the watch value fresh, so the latest IP is used
+                    budget.acquire(provider).await;
+                }
+                let Some(ip) = *gr.borrow_and_update() else {
+                    continue;
+                };
+                if forced {
+                    tracing::info!(%ip, "Update forced");
+                }
+                let cycle = cycle_span(dns.as_ref());
+                cycle.record("source", source.as_str());
+                let hooks = hooks.borrow().clone();
+                let notifiers = notifiers.borrow().clone();
+                let with = Publishing {
+                    client: &client,
+                    store: store.as_ref(),
+                    hooks: &hooks,
+                    notifiers: &notifiers,
+                    history_retention,
+                    geoip: geoip.as_ref(),
+                    reverse_dns: &reverse_dns,
+                    dns_records: &dns_records,
+                };
+                let task = Task {
+                    reporter: &reporter,
+                    gate: &gate,
+                    priority,
+                    settled: &settled,
+                };
+                publish(
+                    dns.as_mut(),
+                    ip,
+                    forced,
+                    &mut state,
+                    &with,
+                    Some(&task),
+                    &cycle,
+                )
+                .await;
+            }
+        };
+        // Stopping the task (e.g. on reload) stops its grabber too. A grabber
+        // ending on its own, e.g. on a one-shot schedule, leaves the IP it
+        // sent last to be published.
+        tokio::pin!(publish);
+        tokio::select! {
+            _ = grabber.run(&grabber_client, gs) => publish.await,
+            _ = &mut publish => {}
+        }
+    }
+    .instrument(span)
+}
+
+/// Span of everything `dns` does, so that its events carry which provider
+/// they're about and can be filtered on it. At the error level so that it's
+/// kept whenever its errors are.
+fn provider_span(dns: &dyn DynDns) -> Span {
+    tracing::error_span!(
+        "provider",
+        provider = dns.provider_name(),
+        hostname = dns.hostname().unwrap_or("-"),
+        ip_version = dns.get_ip_version().simple_name(),
+    )
+}
+
+/// Span of publishing an IP to `dns`, the root of its trace when exported.
+/// The IPs and outcome are recorded once known.
+fn cycle_span(dns: &dyn DynDns) -> Span {
+    tracing::info_span!(
+        parent: None,
+        "ip_change",
+        provider = dns.provider_name(),
+        hostname = dns.hostname().unwrap_or("-"),
+        ip_version = dns.get_ip_version().simple_name(),
+        ip = field::Empty,
+        old_ip = field::Empty,
+        source = field::Empty,
+        attempt = field::Empty,
+        error = field::Empty,
+        "otel.status_code" = field::Empty,
+    )
+}
+
+fn hooks_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "hooks")
+}
+
+fn geoip_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "geoip")
+}
+
+fn notify_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "notify")
+}
+
+fn update_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "update")
+}
+
+fn reverse_dns_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "reverse_dns")
+}
+
+fn dns_records_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "dns_records")
+}
+
+fn history_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "record_history")
+}
+
+fn save_span(cycle: &Span) -> Span {
+    tracing::info_span!(parent: cycle, "save_state")
+}
+
+/// Marks the trace of `cycle` as failed
+fn record_error(cycle: &Span, error: &str) {
+    cycle.record("error", error);
+    cycle.record("otel.status_code", "ERROR");
+}
+
+/// The state of `key`, logging why it couldn't be read
+async fn load_state(store: &dyn StateStore, key: &str) -> ProviderState {
+    store.load_state(key).await.unwrap_or_else(|e| {
+        tracing::warn!(key, error = %e, "Couldn't read the state");
+        ProviderState::default()
+    })
+}
+
+/// Who announces the IPs of `ctx` when it's a change and `geoip` is set,
+/// reported to `reporter` when given
+async fn networks(
+    geoip: Option<&GeoIp>,
+    client: &Client,
+    ctx: &HookContext<'_>,
+    reporter: Option<&Reporter>,
+) -> Option<Networks> {
+    let geoip = geoip.filter(|_| ctx.old_ip != Some(ctx.new_ip))?;
+    let networks = geoip.networks(client, ctx.old_ip, ctx.new_ip).await?;
+    tracing::info!(summary = networks.summary(), "Network of the IP looked up");
+    if let Some(reporter) = reporter {
+        reporter.network(networks.new.clone());
+    }
+    Some(networks)
+}
+
+/// Records the outcome of publishing a change, then drops the entries older
+/// than `retention`
+async fn record_change(
+    store: &dyn StateStore,
+    ctx: &HookContext<'_>,
+    networks: Option<Networks>,
+    latency: Duration,
+    retention: Option<chrono::Duration>,
+) {
+    let now = Utc::now();
+    let entry = HistoryEntry {
+        timestamp: now,
+        provider: ctx.provider.to_string(),
+        hostname: ctx.hostname.map(str::to_string),
+        old_ip: ctx.old_ip,
+        new_ip: ctx.new_ip,
+        error: ctx.error.map(str::to_string),
+        latency_ms: Some(latency.as_millis().try_into().unwrap_or(u64::MAX)),
+        networks,
+    };
+    if let Err(e) = store.append_history(&entry).await {
+        tracing::error!(error = %e, "Couldn't record the IP change in the history");
+    }
+    if let Some(retention) = retention
+        && let Err(e) = store.prune_history(now - retention).await
+    {
+        tracing::error!(error = %e, "Couldn't drop the old history entries");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use reqwest::Client;
+
+    use super::{Error, Runner};
+    use crate::{
+        IpVersion,
+        config::{self, Config},
+        dyn_dns::{self, DynDns, PersistsToFile, UpdateError},
+        http::HttpConfig,
+        persistence::{MemoryStore, StateBackend, StateStore},
+        scheduler::Schedule,
+        test_util::{self, MockDynDns, MockIpSource},
+    };
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    /// A runner of `dyn_dnss` detecting the IPs of `source`
+    fn runner(
+        dyn_dnss: Vec<Box<dyn DynDns>>,
+        store: &Arc<MemoryStore>,
+        source: &MockIpSource,
+    ) -> Runner {
+        Runner::builder("eth0".to_string())
+            .with_providers(dyn_dnss)
+            .with_store(store.clone())
+            .with_ip_sources(test_util::sources([source.clone()]))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_once() {
+        let store = Arc::new(MemoryStore::default());
+        let source = MockIpSource::new(IpVersion::V4);
+        source.push(IP);
+        let home = MockDynDns::new("home.example", IpVersion::V4);
+        // No IPv6 source, so nothing to publish and the run fails
+        let v6 = MockDynDns::new("v6.example", IpVersion::V6);
+
+        let dyn_dnss: Vec<Box<dyn DynDns>> = vec![Box::new(home.clone()), Box::new(v6.clone())];
+        assert!(!runner(dyn_dnss, &store, &source).run_once().await);
+        assert_eq!(home.published(), [IP]);
+        assert!(v6.published().is_empty());
+        assert_eq!(store.list().await.unwrap().len(), 1);
+        assert_eq!(store.history().await.unwrap().len(), 1);
+
+        // Published again on the next run
+        assert!(
+            runner(vec![Box::new(home.clone())], &store, &source)
+                .run_once()
+                .await
+        );
+        assert_eq!(home.published(), [IP, IP]);
+        assert_eq!(source.detections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_once_failure() {
+        let store = Arc::new(MemoryStore::default());
+        let source = MockIpSource::new(IpVersion::V4);
+        source.push(IP);
+        let office = MockDynDns::new("office.example", IpVersion::V4);
+        office.fail_next(UpdateError::RateLimited {
+            provider: "Mock",
+            response: "abuse".to_string(),
+        });
+
+        assert!(
+            !runner(vec![Box::new(office.clone())], &store, &source)
+                .run_once()
+                .await
+        );
+        assert!(office.published().is_empty());
+        let history = store.history().await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].error.as_deref().unwrap().contains("abuse"));
+
+        // The failed update is retried
+        assert!(
+            runner(vec![Box::new(office.clone())], &store, &source)
+                .run_once()
+                .await
+        );
+        assert_eq!(office.published(), [IP]);
+        assert_eq!(store.history().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_record_set_elsewhere() {
+        let store = Arc::new(MemoryStore::default());
+        let source = MockIpSource::new(IpVersion::V4);
+        source.push(IP);
+        // Set by another tool, the record is left as it is
+        let other = MockDynDns::new("other.example", IpVersion::V4);
+        other.set_record(vec![IP]);
+
+        assert!(
+            runner(vec![Box::new(other.clone())], &store, &source)
+                .run_once()
+                .await
+        );
+        assert!(other.published().is_empty());
+        let state = store.load_state(other.file_name()).await.unwrap();
+        assert_eq!(state.ip, Some(IP));
+    }
+
+    #[tokio::test]
+    async fn test_forced_update() {
+        let other = MockDynDns::new("other.example", IpVersion::V4);
+        other.set_record(vec![IP]);
+        // Published even though the record matches, e.g. to fix a record read
+        // wrong
+        let mut forced = other.clone();
+        let answer = dyn_dns::update_if_changed(&mut forced, &Client::new(), IP, true).await;
+        assert_eq!(answer.unwrap(), format!("good {IP}"));
+        assert_eq!(other.published(), [IP]);
+    }
+
+    #[tokio::test]
+    async fn test_blackout() {
+        let store = Arc::new(MemoryStore::default());
+        let source = MockIpSource::new(IpVersion::V4);
+        source.push(IP);
+        let mut night = MockDynDns::new("night.example", IpVersion::V4);
+        let now = chrono::Local::now().time();
+        let window = |from: i64, to: i64| {
+            let at = |mins| (now + chrono::TimeDelta::minutes(mins)).format("%H:%M");
+            format!("{}-{}", at(from), at(to)).parse().unwrap()
+        };
+
+        // Within a maintenance window nothing is detected nor published
+        night.options_mut().blackout = vec![window(-1, 2)];
+        assert!(
+            runner(vec![Box::new(night.clone())], &store, &source)
+                .run_once()
+                .await
+        );
+        assert!(night.published().is_empty());
+        assert_eq!(source.detections(), 0);
+
+        night.options_mut().blackout = vec![window(2, 5)];
+        assert!(
+            runner(vec![Box::new(night.clone())], &store, &source)
+                .run_once()
+                .await
+        );
+        assert_eq!(night.published(), [IP]);
+    }
+
+    #[tokio::test]
+    async fn test_run_one_shot() {
+        let source = MockIpSource::new(IpVersion::V4);
+        source.push(IP);
+        // Checks once on startup, then stops
+        let home = MockDynDns::new("home.example", IpVersion::V4).with_schedule(Schedule::Once);
+        let runner = Runner::builder("eth0".to_string())
+            .with_providers(vec![Box::new(home.clone())])
+            .with_state(StateBackend::Memory)
+            .with_ip_sources(test_util::sources([source]))
+            .build()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), runner.run())
+            .await
+            .expect("The runner stops once the provider published");
+        assert_eq!(home.published(), [IP]);
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let http = HttpConfig {
+            doh: Some("ftp://doh.example".to_string()),
+            ..HttpConfig::default()
+        };
+        let built = Runner::builder("eth0".to_string())
+            .with_state(StateBackend::Memory)
+            .with_http(http)
+            .build();
+        assert!(matches!(built, Err(Error::Http(e)) if e.to_string().contains("DNS-over-HTTPS")));
+        assert!(Runner::builder("eth0".to_string()).build().is_ok());
+
+        let mut config = Config::from_tuples("eth0".to_string(), "DD;tok;ipv4;0;home").unwrap();
+        config.state = StateBackend::Memory;
+        let runner = Runner::configured(config.clone(), Client::new())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(runner.dyn_dnss[0].0.hostname(), Some("home"));
+        config.history_retention = Some("forever".to_string());
+        assert!(matches!(
+            Runner::configured(config, Client::new()),
+            Err(Error::Config(config::Error::HistoryRetention(_)))
+        ));
     }
 }
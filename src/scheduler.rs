@@ -0,0 +1,149 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use chrono::Local;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// When a provider's IP detection should run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// Check a single time, on startup.
+    Once,
+    /// Check at a fixed interval.
+    Every(Duration),
+    /// Check at the times matched by a cron expression, in local time.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Every `secs` seconds, `0` meaning once
+    pub fn from_secs(secs: u64) -> Self {
+        match secs {
+            0 => Schedule::Once,
+            secs => Schedule::Every(Duration::from_secs(secs)),
+        }
+    }
+
+    /// How long to wait after a successful check before the next one,
+    /// `None` when no further checks should happen.
+    pub fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Schedule::Once => None,
+            Schedule::Every(period) => Some(*period),
+            Schedule::Cron(schedule) => schedule
+                .upcoming(Local)
+                .next()
+                .map(|next| (next - Local::now()).to_std().unwrap_or_default()),
+        }
+    }
+
+    /// How long to wait before trying again after a failed check.
+    pub fn retry_delay(&self) -> Duration {
+        const MIN_RETRY: Duration = Duration::from_secs(1);
+        const MAX_RETRY: Duration = Duration::from_secs(60);
+        match self {
+            Schedule::Once | Schedule::Cron(_) => MAX_RETRY,
+            Schedule::Every(period) => (*period / 10).max(MIN_RETRY),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parses either a number of seconds (`0` meaning once) or a cron
+    /// expression. Five field expressions are treated as standard cron and
+    /// run at second zero.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(secs) = s.parse::<u64>() {
+            return Ok(Schedule::from_secs(secs));
+        }
+
+        let expr = match s.split_whitespace().count() {
+            5 => format!("0 {s}"),
+            _ => s.to_string(),
+        };
+        cron::Schedule::from_str(&expr)
+            .map(|c| Schedule::Cron(Box::new(c)))
+            .map_err(|e| {
+                format!("Invalid schedule '{s}', expected seconds or a cron expression: {e}")
+            })
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Once => write!(f, "0"),
+            Schedule::Every(period) => write!(f, "{}", period.as_secs()),
+            Schedule::Cron(schedule) => write!(f, "{}", schedule.source()),
+        }
+    }
+}
+
+impl Serialize for Schedule {
+    /// Seconds as a number, cron expressions as a string
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Schedule::Once => serializer.serialize_u64(0),
+            Schedule::Every(period) => serializer.serialize_u64(period.as_secs()),
+            Schedule::Cron(_) => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    /// Seconds, as a number or a string, or a cron expression
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Secs(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Secs(secs) => Ok(Schedule::from_secs(secs)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Schedule;
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!("0".parse::<Schedule>().unwrap(), Schedule::Once);
+        assert_eq!(
+            "300".parse::<Schedule>().unwrap(),
+            Schedule::Every(Duration::from_secs(300))
+        );
+        assert!(matches!(
+            "*/5 8-22 * * *".parse::<Schedule>(),
+            Ok(Schedule::Cron(_))
+        ));
+        assert!(matches!(
+            "0 0 * * * *".parse::<Schedule>(),
+            Ok(Schedule::Cron(_))
+        ));
+        assert!("every minute".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_delays() {
+        assert_eq!(Schedule::Once.next_delay(), None);
+        let every = Schedule::Every(Duration::from_secs(100));
+        assert_eq!(every.next_delay(), Some(Duration::from_secs(100)));
+        assert_eq!(every.retry_delay(), Duration::from_secs(10));
+        assert_eq!(
+            Schedule::Every(Duration::from_secs(5)).retry_delay(),
+            Duration::from_secs(1)
+        );
+
+        let cron: Schedule = "* * * * *".parse().unwrap();
+        assert!(cron.next_delay().unwrap() <= Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,507 @@
+//! Providers and hooks written as [Rhai](https://rhai.rs) scripts, for
+//! one-off integrations that don't deserve a provider type of their own.
+//! Scripts can call `http_get(url)` and `http_post(url, body)`, both taking
+//! an optional map of headers last and returning `#{status, body}`, and
+//! `print` to the log. They run on a blocking thread, so they can't hold up
+//! the other providers.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder};
+use rhai::{AST, Dynamic, Engine, EvalAltResult, Map, Scope};
+use tokio::runtime::Handle;
+
+use crate::{
+    IpVersion, SimpleName,
+    dyn_dns::{
+        CredentialCheck, CurrentError, DynDns, POLL_SECS_FIELD, PersistsToFile, ProviderOptions,
+        UpdateError, VERSION_FIELD, ip_version_part, required_part, schedule_part, state_file_name,
+    },
+    hooks::{HookContext, HookEvent},
+    registry::{Field, ProviderSchema},
+    scheduler::Schedule,
+    vcr,
+};
+
+/// Bounds the work of a script, so one stuck in a loop fails instead of
+/// keeping its thread busy forever
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// Name of the providers of [`ScriptProvider`]
+const PROVIDER: &str = "Script";
+
+/// A provider publishing the IP by calling `update(ip, name)` in a script.
+/// What it returns is recorded as the provider's answer, and a `throw`
+/// fails the update. An optional `test(name)` checks the credentials.
+pub struct ScriptProvider {
+    path: PathBuf,
+    ast: Arc<AST>,
+    name: String,
+    file_name: String,
+    ip_version: IpVersion,
+    schedule: Schedule,
+    options: ProviderOptions,
+}
+
+impl std::fmt::Debug for ScriptProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScriptProvider")
+            .field("path", &self.path)
+            .field("name", &self.name)
+            .field("file_name", &self.file_name)
+            .field("ip_version", &self.ip_version)
+            .field("schedule", &self.schedule.to_string())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl ScriptProvider {
+    /// Compiles the script at `path`, which must define `update(ip, name)`
+    pub fn new(
+        path: PathBuf,
+        name: String,
+        ip_version: IpVersion,
+        schedule: Schedule,
+    ) -> Result<Self, String> {
+        let ast = compile(&path)?;
+        if !defines(&ast, "update", 2) {
+            return Err(format!(
+                "Script {} doesn't define update(ip, name)",
+                path.display()
+            ));
+        }
+        let file_name = state_file_name(
+            PROVIDER,
+            &[&path.to_string_lossy(), &name, ip_version.simple_name()],
+        );
+        let s = Self {
+            path,
+            ast: Arc::new(ast),
+            name,
+            file_name,
+            ip_version,
+            schedule,
+            options: ProviderOptions::default(),
+        };
+        tracing::info!(dyn_dns = ?s, "Created DynDns");
+        Ok(s)
+    }
+
+    /// Calls `function` with `args` on a blocking thread
+    async fn call(
+        &self,
+        client: &Client,
+        function: &'static str,
+        args: Vec<Dynamic>,
+    ) -> Result<String, UpdateError> {
+        let engine = engine(client, self.options.timeout_secs.map(Duration::from_secs));
+        let ast = self.ast.clone();
+        let called = tokio::task::spawn_blocking(move || {
+            engine.call_fn::<Dynamic>(&mut Scope::new(), &ast, function, args)
+        })
+        .await;
+        match called {
+            Ok(Ok(answer)) if answer.is_unit() => Ok(String::new()),
+            Ok(Ok(answer)) => Ok(answer.to_string()),
+            Ok(Err(e)) => Err(UpdateError::Rejected {
+                provider: PROVIDER,
+                response: thrown(*e),
+            }),
+            Err(e) => Err(UpdateError::Rejected {
+                provider: PROVIDER,
+                response: format!("the script panicked: {e}"),
+            }),
+        }
+    }
+}
+
+impl PersistsToFile for ScriptProvider {
+    fn file_name(&self) -> &str {
+        &self.file_name
+    }
+}
+
+#[async_trait]
+impl DynDns for ScriptProvider {
+    async fn update(&mut self, client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let args = vec![ip.to_string().into(), self.name.clone().into()];
+        let response = self.call(client, "update", args).await?;
+        tracing::info!(%ip, script = %self.path.display(), "Script update successful");
+        Ok(response)
+    }
+
+    async fn test(&mut self, client: &Client) -> Result<CredentialCheck, UpdateError> {
+        if !defines(&self.ast, "test", 1) {
+            return Ok(CredentialCheck::Untestable(
+                "the script doesn't define test(name)",
+            ));
+        }
+        let answer = self.call(client, "test", vec![self.name.clone().into()]);
+        Ok(CredentialCheck::Valid(answer.await?))
+    }
+
+    async fn current(&mut self, client: &Client) -> Result<Vec<IpAddr>, CurrentError> {
+        if !defines(&self.ast, "current", 1) {
+            return Ok(Vec::new());
+        }
+        let answer = self.call(client, "current", vec![self.name.clone().into()]);
+        let answer = answer.await?;
+        answer
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| {
+                ip.parse().map_err(|_| CurrentError::Invalid {
+                    provider: PROVIDER,
+                    response: format!("current(name) returned an invalid IP '{ip}'"),
+                })
+            })
+            .collect()
+    }
+
+    fn get_ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut ProviderOptions {
+        &mut self.options
+    }
+
+    fn provider_name(&self) -> &'static str {
+        PROVIDER
+    }
+
+    fn hostname(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+pub(crate) const SCRIPT_SCHEMA: ProviderSchema = ProviderSchema {
+    name: PROVIDER,
+    fields: &[
+        Field {
+            name: "PATH",
+            description: "Rhai script defining update(ip, name)",
+            default: None,
+            secret: false,
+        },
+        VERSION_FIELD,
+        POLL_SECS_FIELD,
+        Field {
+            name: "NAME",
+            description: "Record handed to the script",
+            default: None,
+            secret: false,
+        },
+    ],
+};
+
+/// `SCRIPT;PATH;VERSION;POLL_SECS;NAME`
+pub(crate) fn parse_script(
+    parts: &mut dyn Iterator<Item = &str>,
+) -> Result<Box<dyn DynDns>, String> {
+    let path = required_part(parts, "PATH")?.into();
+    let version = ip_version_part(parts)?;
+    let schedule = schedule_part(parts)?;
+    let name = required_part(parts, "NAME")?.to_string();
+    Ok(Box::new(ScriptProvider::new(
+        path, name, version, schedule,
+    )?))
+}
+
+/// Runs the function of the hook script at `path` named like the setting
+/// of `event`, e.g. `on_change(ctx)`, if it defines one. `ctx` holds the
+/// `event`, `provider`, `hostname`, `ip_version`, `new_ip`, `old_ip` and
+/// `error`, empty when unknown. Its HTTP functions use `client`. Failures are
+/// logged.
+pub async fn run_hook(path: &Path, event: HookEvent, ctx: &HookContext<'_>, client: &Client) {
+    let function = match event {
+        HookEvent::Change => "on_change",
+        HookEvent::Success => "on_success",
+        HookEvent::Failure => "on_failure",
+        HookEvent::Flapping => "on_flap",
+    };
+    let ast = match compile(path) {
+        Ok(ast) if defines(&ast, function, 1) => ast,
+        Ok(_) => return,
+        Err(e) => {
+            tracing::warn!(error = e, "Couldn't load the hook script");
+            return;
+        }
+    };
+    let mut map = Map::new();
+    let fields = [
+        ("event", event.simple_name().to_string()),
+        ("provider", ctx.provider.to_string()),
+        ("hostname", ctx.hostname.unwrap_or_default().to_string()),
+        (
+            "ip_version",
+            IpVersion::from(&ctx.new_ip).simple_name().to_string(),
+        ),
+        ("new_ip", ctx.new_ip.to_string()),
+        (
+            "old_ip",
+            ctx.old_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        ),
+        ("error", ctx.error.unwrap_or_default().to_string()),
+    ];
+    for (name, value) in fields {
+        map.insert(name.into(), value.into());
+    }
+
+    let engine = engine(client, None);
+    tracing::debug!(function, script = %path.display(), "Running hook script");
+    let called = tokio::task::spawn_blocking(move || {
+        engine.call_fn::<Dynamic>(&mut Scope::new(), &ast, function, (map,))
+    })
+    .await;
+    let error = match called {
+        Ok(Ok(_)) => return,
+        Ok(Err(e)) => thrown(*e),
+        Err(e) => format!("the script panicked: {e}"),
+    };
+    tracing::warn!(
+        function,
+        provider = ctx.provider,
+        error,
+        "Hook script failed"
+    );
+}
+
+/// Compiles the script at `path`, checking it's valid
+pub fn compile(path: &Path) -> Result<AST, String> {
+    let script = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read the script {}: {e}", path.display()))?;
+    Engine::new()
+        .compile(script)
+        .map_err(|e| format!("Invalid script {}: {e}", path.display()))
+}
+
+/// Whether `ast` defines `name` taking `params` parameters
+fn defines(ast: &AST, name: &str, params: usize) -> bool {
+    ast.iter_functions()
+        .any(|f| f.name == name && f.params.len() == params)
+}
+
+/// The message of a `throw`, or the error
+fn thrown(error: EvalAltResult) -> String {
+    match error {
+        EvalAltResult::ErrorRuntime(value, _) => value.to_string(),
+        e => e.to_string(),
+    }
+}
+
+/// An engine whose HTTP functions use `client`, and `timeout` when given
+fn engine(client: &Client, timeout: Option<Duration>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|message| tracing::info!(message, "Script output"));
+    engine.on_debug(|message, _, _| tracing::debug!(message, "Script output"));
+
+    let (client, handle) = (client.clone(), Handle::current());
+    let http = move |method: Method, url: &str, body: Option<String>, headers: Map| {
+        let mut request = client.request(method, url);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        for (name, value) in headers {
+            request = request.header(name.as_str(), value.to_string());
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        send(&handle, request)
+    };
+    let get = http.clone();
+    engine.register_fn("http_get", move |url: &str| {
+        get(Method::GET, url, None, Map::new())
+    });
+    let get = http.clone();
+    engine.register_fn("http_get", move |url: &str, headers: Map| {
+        get(Method::GET, url, None, headers)
+    });
+    let post = http.clone();
+    engine.register_fn("http_post", move |url: &str, body: &str| {
+        post(Method::POST, url, Some(body.to_string()), Map::new())
+    });
+    engine.register_fn("http_post", move |url: &str, body: &str, headers: Map| {
+        http(Method::POST, url, Some(body.to_string()), headers)
+    });
+    engine
+}
+
+/// Sends `request` from a blocking thread through the runtime of `handle`
+fn send(handle: &Handle, request: RequestBuilder) -> Result<Map, Box<EvalAltResult>> {
+    let answer = handle.block_on(async {
+        let response = vcr::send(request).await?;
+        let status = response.status();
+        response.text().await.map(|body| (status, body))
+    });
+    // The URL may hold a token
+    let (status, body) = answer.map_err(|e| e.without_url().to_string())?;
+    let mut map = Map::new();
+    map.insert("status".into(), i64::from(status.as_u16()).into());
+    map.insert("body".into(), body.into());
+    Ok(map)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::PathBuf,
+    };
+
+    use reqwest::Client;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::{ScriptProvider, run_hook};
+    use crate::{
+        IpVersion,
+        dyn_dns::{CredentialCheck, DynDns, UpdateError, parse_dns_tuples},
+        hooks::{HookContext, HookEvent},
+        scheduler::Schedule,
+    };
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    /// An address answering a single request with `answered`, and the
+    /// request line it got
+    async fn answer_once() -> (SocketAddr, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let asked = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            let response =
+                "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nanswered";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]);
+            request.lines().next().unwrap_or_default().to_string()
+        });
+        (addr, asked)
+    }
+
+    fn script(dir: &std::path::Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_script_provider() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_script_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (addr, asked) = answer_once().await;
+        let path = script(
+            &dir,
+            "provider.rhai",
+            &format!(
+                r#"
+                fn update(ip, name) {{
+                    let answer = http_get("http://{addr}/update?name=" + name + "&ip=" + ip);
+                    if answer.status != 200 {{ throw "HTTP " + answer.status; }}
+                    answer.body
+                }}
+                "#
+            ),
+        );
+        let tuples = format!("SCRIPT;{};ipv4;300;home;priority=2", path.display());
+        let mut provider = parse_dns_tuples(&tuples).unwrap().remove(0);
+        assert_eq!(provider.provider_name(), "Script");
+        assert_eq!(provider.hostname(), Some("home"));
+        assert_eq!(provider.options().priority, 2);
+        let response = provider.update(&Client::new(), IP).await.unwrap();
+        assert_eq!(response, "answered");
+        assert_eq!(
+            asked.await.unwrap(),
+            "GET /update?name=home&ip=192.0.2.1 HTTP/1.1"
+        );
+        let check = provider.test(&Client::new()).await.unwrap();
+        assert!(matches!(check, CredentialCheck::Untestable(_)));
+        assert!(provider.current(&Client::new()).await.unwrap().is_empty());
+
+        let path = script(
+            &dir,
+            "failing.rhai",
+            r#"
+            fn update(ip, name) { throw "no such record: " + name; }
+            fn test(name) { "valid" }
+            fn current(name) { "192.0.2.1" }
+            "#,
+        );
+        let mut provider =
+            ScriptProvider::new(path, "home".into(), IpVersion::V4, Schedule::Once).unwrap();
+        let error = provider.update(&Client::new(), IP).await.unwrap_err();
+        assert!(
+            matches!(&error, UpdateError::Rejected { response, .. } if response == "no such record: home"),
+            "{error}"
+        );
+        let check = provider.test(&Client::new()).await.unwrap();
+        assert_eq!(check, CredentialCheck::Valid("valid".into()));
+        assert_eq!(provider.current(&Client::new()).await.unwrap(), [IP]);
+
+        let path = script(&dir, "empty.rhai", "fn other() {}");
+        let error = ScriptProvider::new(path, "home".into(), IpVersion::V4, Schedule::Once);
+        assert!(error.unwrap_err().contains("update(ip, name)"));
+        let path = script(&dir, "invalid.rhai", "fn update(ip, name) {");
+        let error = ScriptProvider::new(path, "home".into(), IpVersion::V4, Schedule::Once);
+        assert!(error.unwrap_err().starts_with("Invalid script"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hook_script() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_hook_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (addr, asked) = answer_once().await;
+        let path = script(
+            &dir,
+            "hooks.rhai",
+            &format!(
+                r#"
+                fn on_change(ctx) {{
+                    http_post("http://{addr}/" + ctx.event + "/" + ctx.old_ip + "/" + ctx.new_ip, "");
+                }}
+                "#
+            ),
+        );
+        let ctx = HookContext {
+            provider: "DuckDNS",
+            hostname: Some("home"),
+            old_ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))),
+            new_ip: IP,
+            error: None,
+        };
+        // Not defined, nothing is called
+        run_hook(&path, HookEvent::Success, &ctx, &Client::new()).await;
+        run_hook(&path, HookEvent::Change, &ctx, &Client::new()).await;
+        assert_eq!(
+            asked.await.unwrap(),
+            "POST /change/192.0.2.2/192.0.2.1 HTTP/1.1"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+
+use crate::{
+    history::HistoryEntry,
+    persistence::{Error, ProviderState, StateStore, private_file_options},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS providers (
+    key TEXT PRIMARY KEY,
+    ip TEXT,
+    last_attempt TEXT,
+    last_success TEXT,
+    consecutive_failures INTEGER NOT NULL DEFAULT 0,
+    last_response TEXT
+);
+CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    hostname TEXT,
+    old_ip TEXT,
+    new_ip TEXT NOT NULL
+);
+";
+
+/// Changes to [`SCHEMA`], the `user_version` of a database counting those
+/// already applied
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE history ADD COLUMN error TEXT; ALTER TABLE history ADD COLUMN latency_ms INTEGER;",
+    "ALTER TABLE providers ADD COLUMN successes INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE providers ADD COLUMN failures INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE providers ADD COLUMN last_error TEXT;
+     ALTER TABLE providers ADD COLUMN average_latency_ms INTEGER;",
+    "ALTER TABLE providers ADD COLUMN recent_latencies_ms TEXT;",
+    "ALTER TABLE history ADD COLUMN networks TEXT;",
+];
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Store(Box::new(err))
+    }
+}
+
+/// Every provider and the history in one SQLite database, so that each
+/// write is atomic and both can be queried with `sqlite3`. Timestamps are
+/// stored in RFC 3339 and IPs as text.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it and its tables if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        // SQLite gives its journal the permissions of the database
+        if path != Path::new(":memory:") {
+            private_file_options().write(true).open(path)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(&format!(
+                "BEGIN; {migration} PRAGMA user_version = {}; COMMIT;",
+                i + 1
+            ))?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // A panic can't leave a connection half way through a statement
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn parse_ip(ip: Option<String>) -> Result<Option<IpAddr>, Error> {
+    Ok(ip.map(|ip| ip.parse()).transpose()?)
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| Error::Backend(format!("Invalid timestamp {time}: {e}")))
+}
+
+/// Columns of a `providers` row, parsed once the statement is done
+type StateRow = (
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    u32,
+    Option<String>,
+    (u64, u64, Option<String>, Option<u64>, Option<String>),
+);
+
+fn state_row(row: &Row) -> rusqlite::Result<StateRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        (
+            row.get(6)?,
+            row.get(7)?,
+            row.get(8)?,
+            row.get(9)?,
+            row.get(10)?,
+        ),
+    ))
+}
+
+fn parse_state(
+    (_, ip, last_attempt, last_success, consecutive_failures, last_response, stats): StateRow,
+) -> Result<ProviderState, Error> {
+    Ok(ProviderState {
+        ip: parse_ip(ip)?,
+        last_attempt: last_attempt.as_deref().map(parse_time).transpose()?,
+        last_success: last_success.as_deref().map(parse_time).transpose()?,
+        consecutive_failures,
+        last_response,
+        successes: stats.0,
+        failures: stats.1,
+        last_error: stats.2,
+        average_latency_ms: stats.3,
+        recent_latencies_ms: parse_latencies(stats.4.as_deref())?,
+    })
+}
+
+/// Latencies are kept as a comma separated list
+fn parse_latencies(latencies: Option<&str>) -> Result<Vec<u64>, Error> {
+    latencies
+        .into_iter()
+        .flat_map(|l| l.split(','))
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            l.parse()
+                .map_err(|_| Error::Backend(format!("Invalid latency {l}")))
+        })
+        .collect()
+}
+
+const STATE_COLUMNS: &str = "key, ip, last_attempt, last_success, consecutive_failures, \
+     last_response, successes, failures, last_error, average_latency_ms, recent_latencies_ms";
+
+#[async_trait]
+impl StateStore for SqliteStore {
+    async fn load_state(&self, key: &str) -> Result<ProviderState, Error> {
+        let row = self
+            .conn()
+            .query_row(
+                &format!("SELECT {STATE_COLUMNS} FROM providers WHERE key = ?1"),
+                [key],
+                state_row,
+            )
+            .optional()?;
+        row.map(parse_state)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    async fn save_state(&self, key: &str, state: &ProviderState) -> Result<(), Error> {
+        self.conn().execute(
+            &format!(
+                "INSERT OR REPLACE INTO providers ({STATE_COLUMNS}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+            ),
+            params![
+                key,
+                state.ip.map(|ip| ip.to_string()),
+                state.last_attempt.map(|t| t.to_rfc3339()),
+                state.last_success.map(|t| t.to_rfc3339()),
+                state.consecutive_failures,
+                state.last_response,
+                state.successes,
+                state.failures,
+                state.last_error,
+                state.average_latency_ms,
+                state
+                    .recent_latencies_ms
+                    .iter()
+                    .map(u64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn remove_state(&self, key: &str) -> Result<(), Error> {
+        self.conn()
+            .execute("DELETE FROM providers WHERE key = ?1", [key])?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<(String, ProviderState)>, Error> {
+        let rows = {
+            let conn = self.conn();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {STATE_COLUMNS} FROM providers WHERE ip IS NOT NULL ORDER BY key"
+            ))?;
+            stmt.query_map([], state_row)?
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        rows.into_iter()
+            .map(|row| Ok((row.0.clone(), parse_state(row)?)))
+            .collect()
+    }
+
+    async fn append_history(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        insert_history(&self.conn(), entry)
+    }
+
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let rows = {
+            let conn = self.conn();
+            let mut stmt = conn.prepare(
+                "SELECT timestamp, provider, hostname, old_ip, new_ip, error, latency_ms, networks FROM history ORDER BY id",
+            )?;
+            stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, Option<u64>>(6)?,
+                    row.get::<_, Option<String>>(7)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+        rows.into_iter()
+            .map(
+                |(timestamp, provider, hostname, old_ip, new_ip, error, latency_ms, networks)| {
+                    Ok(HistoryEntry {
+                        timestamp: parse_time(&timestamp)?,
+                        provider,
+                        hostname,
+                        old_ip: parse_ip(old_ip)?,
+                        new_ip: new_ip.parse()?,
+                        error,
+                        latency_ms,
+                        networks: networks.as_deref().map(serde_json::from_str).transpose()?,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    async fn replace_history(&self, entries: &[HistoryEntry]) -> Result<(), Error> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM history", [])?;
+        for entry in entries {
+            insert_history(&tx, entry)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        // Holding the connection keeps appends out until it's done
+        let conn = self.conn();
+        let rows = {
+            let mut stmt = conn.prepare("SELECT id, timestamp FROM history")?;
+            stmt.query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+        for (id, timestamp) in rows {
+            if parse_time(&timestamp)? < before {
+                conn.execute("DELETE FROM history WHERE id = ?1", [id])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn insert_history(conn: &Connection, entry: &HistoryEntry) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO history (timestamp, provider, hostname, old_ip, new_ip, error, latency_ms, networks) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            entry.timestamp.to_rfc3339(),
+            entry.provider,
+            entry.hostname,
+            entry.old_ip.map(|ip| ip.to_string()),
+            entry.new_ip.to_string(),
+            entry.error,
+            entry.latency_ms,
+            entry.networks.as_ref().map(serde_json::to_string).transpose()?,
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use chrono::Utc;
+
+    use super::SqliteStore;
+    use crate::{
+        geoip::{Network, Networks},
+        history::HistoryEntry,
+        persistence::{ProviderState, StateStore},
+    };
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+
+        assert_eq!(
+            store.load_state("DuckDNS_tok_name").await.unwrap(),
+            ProviderState::default()
+        );
+        let mut state = ProviderState::default();
+        state.record_failure("KO".to_string(), Utc::now(), Duration::from_millis(120));
+        state.record_success(ip, "OK".to_string(), Utc::now(), Duration::from_millis(120));
+        store.save_state("DuckDNS_tok_name", &state).await.unwrap();
+        // Timestamps keep their precision through RFC 3339
+        assert_eq!(store.load_state("DuckDNS_tok_name").await.unwrap(), state);
+        store
+            .save_state("FreeDNS_tok_ipv4", &ProviderState::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list().await.unwrap(),
+            vec![("DuckDNS_tok_name".to_string(), state)]
+        );
+
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            provider: "DuckDNS".to_string(),
+            hostname: Some("name".to_string()),
+            old_ip: None,
+            new_ip: ip,
+            error: Some("badauth".to_string()),
+            latency_ms: Some(250),
+            networks: Some(Networks {
+                old: None,
+                new: Network {
+                    asn: Some(3352),
+                    ..Network::default()
+                },
+            }),
+        };
+        store.append_history(&entry).await.unwrap();
+        store.append_history(&entry).await.unwrap();
+        assert_eq!(store.history().await.unwrap().len(), 2);
+        store
+            .replace_history(std::slice::from_ref(&entry))
+            .await
+            .unwrap();
+        assert_eq!(store.history().await.unwrap(), vec![entry]);
+    }
+}
@@ -0,0 +1,205 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{IpVersion, history::HistoryEntry, persistence::ProviderState};
+
+const WEEK_SECS: f64 = 7.0 * 24.0 * 3600.0;
+
+/// How the updates of a provider went, to spot the slow or flaky ones
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpdateStats {
+    pub attempts: u64,
+    pub failures: u64,
+    /// Share of the attempts that failed, from 0 to 1
+    pub error_rate: f64,
+    pub average_latency_ms: Option<u64>,
+    /// Percentiles of the latencies of the recent attempts
+    pub p50_latency_ms: Option<u64>,
+    pub p90_latency_ms: Option<u64>,
+    pub p99_latency_ms: Option<u64>,
+}
+
+impl UpdateStats {
+    /// `None` until the provider was updated at least once
+    pub fn of(state: &ProviderState) -> Option<Self> {
+        let attempts = state.successes + state.failures;
+        if attempts == 0 {
+            return None;
+        }
+        let mut latencies = state.recent_latencies_ms.clone();
+        latencies.sort_unstable();
+        Some(Self {
+            attempts,
+            failures: state.failures,
+            error_rate: state.failures as f64 / attempts as f64,
+            average_latency_ms: state.average_latency_ms,
+            p50_latency_ms: percentile(&latencies, 50),
+            p90_latency_ms: percentile(&latencies, 90),
+            p99_latency_ms: percentile(&latencies, 99),
+        })
+    }
+}
+
+/// How long the IPs of a provider last, from its history, to choose
+/// sensible poll intervals and TTLs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IpStability {
+    /// IP changes in the history, the first IP published left out
+    pub changes: u64,
+    /// When the current IP was published
+    pub current_ip_since: DateTime<Utc>,
+    pub current_ip_age_secs: u64,
+    /// How long the replaced IPs lasted, `None` until one was
+    pub average_lease_secs: Option<u64>,
+    /// Since the first IP in the history, `None` when it was just published
+    pub changes_per_week: Option<f64>,
+    /// Longest an IP lasted, the current one included
+    pub longest_stable_secs: u64,
+}
+
+impl IpStability {
+    /// `None` until an IP was published. `published` are the times the IPs
+    /// were, oldest first, see [`published_at`].
+    pub fn of(published: &[DateTime<Utc>], now: DateTime<Utc>) -> Option<Self> {
+        let (first, current) = (*published.first()?, *published.last()?);
+        let secs = |from: DateTime<Utc>, to: DateTime<Utc>| {
+            u64::try_from((to - from).num_seconds()).unwrap_or_default()
+        };
+        let leases: Vec<u64> = published.windows(2).map(|w| secs(w[0], w[1])).collect();
+        let changes = leases.len() as u64;
+        let current_ip_age_secs = secs(current, now);
+        let covered = secs(first, now);
+        Some(Self {
+            changes,
+            current_ip_since: current,
+            current_ip_age_secs,
+            average_lease_secs: (changes > 0).then(|| leases.iter().sum::<u64>() / changes),
+            changes_per_week: (covered > 0).then(|| changes as f64 / (covered as f64 / WEEK_SECS)),
+            longest_stable_secs: leases
+                .into_iter()
+                .chain([current_ip_age_secs])
+                .max()
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// When the IPs of a provider were published, oldest first: the changes of
+/// its `history` that it accepted
+pub fn published_at(
+    history: &[HistoryEntry],
+    provider: &str,
+    hostname: Option<&str>,
+    ip_version: IpVersion,
+) -> Vec<DateTime<Utc>> {
+    let mut published: Vec<_> = history
+        .iter()
+        .filter(|e| {
+            e.error.is_none()
+                && e.provider == provider
+                && e.hostname.as_deref() == hostname
+                && IpVersion::from(&e.new_ip) == ip_version
+        })
+        .map(|e| e.timestamp)
+        .collect();
+    published.sort_unstable();
+    published
+}
+
+/// A duration for people, its two largest units, e.g. `3d 4h` or `25m`
+pub fn format_duration(secs: u64) -> String {
+    let units = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+    let parts: Vec<_> = units
+        .iter()
+        .scan(secs, |rest, (unit, size)| {
+            let amount = *rest / size;
+            *rest %= size;
+            Some((amount, unit))
+        })
+        .skip_while(|(amount, _)| *amount == 0)
+        .take(2)
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, unit)| format!("{amount}{unit}"))
+        .collect();
+    match parts.is_empty() {
+        true => "0s".to_string(),
+        false => parts.join(" "),
+    }
+}
+
+/// The `p`th percentile of `sorted` by the nearest rank
+pub fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::{TimeDelta, Utc};
+
+    use super::{IpStability, UpdateStats, format_duration, percentile, published_at};
+    use crate::{IpVersion, history::HistoryEntry, persistence::ProviderState};
+
+    #[test]
+    fn test_update_stats() {
+        let sorted: Vec<u64> = (1..=10).map(|n| n * 10).collect();
+        assert_eq!(percentile(&sorted, 50), Some(50));
+        assert_eq!(percentile(&sorted, 90), Some(90));
+        assert_eq!(percentile(&sorted, 99), Some(100));
+        assert_eq!(percentile(&[], 50), None);
+
+        let mut state = ProviderState::default();
+        assert_eq!(UpdateStats::of(&state), None);
+        let ip = "192.0.2.1".parse().unwrap();
+        for ms in [300, 100, 200] {
+            let latency = Duration::from_millis(ms);
+            state.record_success(ip, "OK".to_string(), Utc::now(), latency);
+        }
+        state.record_failure("KO".to_string(), Utc::now(), Duration::from_millis(5000));
+        let stats = UpdateStats::of(&state).unwrap();
+        assert_eq!((stats.attempts, stats.failures), (4, 1));
+        assert_eq!(stats.error_rate, 0.25);
+        assert_eq!(stats.p50_latency_ms, Some(200));
+        assert_eq!(stats.p99_latency_ms, Some(5000));
+    }
+
+    #[test]
+    fn test_ip_stability() {
+        let now = Utc::now();
+        let entry = |days_ago, ip: &str, error: Option<&str>| HistoryEntry {
+            timestamp: now - TimeDelta::days(days_ago),
+            provider: "DuckDNS".to_string(),
+            hostname: Some("home".to_string()),
+            old_ip: None,
+            new_ip: ip.parse().unwrap(),
+            error: error.map(str::to_string),
+            latency_ms: None,
+            networks: None,
+        };
+        let history = [
+            entry(28, "192.0.2.1", None),
+            entry(21, "2001:db8::1", None),
+            entry(20, "192.0.2.2", None),
+            entry(18, "192.0.2.3", Some("badauth")),
+            entry(8, "192.0.2.3", None),
+            entry(2, "192.0.2.4", None),
+        ];
+        let published = published_at(&history, "DuckDNS", Some("home"), IpVersion::V4);
+        assert_eq!(published.len(), 4);
+        assert_eq!(IpStability::of(&[], now), None);
+
+        let stability = IpStability::of(&published, now).unwrap();
+        assert_eq!(stability.changes, 3);
+        assert_eq!(stability.current_ip_age_secs, 2 * 86400);
+        // 8, 12 and 6 days
+        assert_eq!(stability.average_lease_secs, Some(26 * 86400 / 3));
+        assert_eq!(stability.longest_stable_secs, 12 * 86400);
+        assert_eq!(stability.changes_per_week, Some(0.75));
+
+        assert_eq!(format_duration(3 * 86400 + 4 * 3600 + 5), "3d 4h");
+        assert_eq!(format_duration(25 * 60), "25m");
+        assert_eq!(format_duration(0), "0s");
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Where the log lines go, parsed from `--syslog-server`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Server {
+    /// The local daemon, at `/dev/log` or another usual socket
+    Local,
+    /// A local socket at another path
+    Socket(String),
+    /// `udp://host:port`
+    Udp(String),
+    /// `tcp://host:port`, each line ends with a newline
+    Tcp(String),
+}
+
+impl TryFrom<&str> for Server {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let with_port = |address: &str| match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                address.to_string()
+            }
+            _ => format!("{address}:514"),
+        };
+        if let Some(address) = value.strip_prefix("udp://") {
+            Ok(Server::Udp(with_port(address)))
+        } else if let Some(address) = value.strip_prefix("tcp://") {
+            Ok(Server::Tcp(with_port(address)))
+        } else if value.starts_with('/') {
+            Ok(Server::Socket(value.to_string()))
+        } else {
+            Err(format!(
+                "Invalid syslog server {value}, expected udp://HOST[:PORT], tcp://HOST[:PORT] or a socket path"
+            ))
+        }
+    }
+}
+
+pub fn parse_facility(s: &str) -> Result<Facility, String> {
+    s.parse()
+        .map_err(|()| format!("Unknown syslog facility {s}, e.g. daemon, user or local0"))
+}
+
+/// Writer sending every log line as a syslog message (RFC 3164), with the
+/// priority of its level
+#[derive(Clone)]
+pub struct Syslog {
+    logger: Arc<Mutex<Logger<LoggerBackend, Formatter3164>>>,
+    /// Stream transports need each message to end with a newline
+    newline: bool,
+}
+
+impl Syslog {
+    /// Connects to `server`, tagging messages with `facility`. Must be
+    /// called once the process won't fork anymore, the PID is sent along.
+    pub fn connect(server: &Server, facility: Facility) -> Result<Self, String> {
+        let formatter = Formatter3164 {
+            facility,
+            ..Default::default()
+        };
+        let logger = match server {
+            Server::Local => syslog::unix(formatter),
+            Server::Socket(path) => syslog::unix_custom(formatter, path),
+            Server::Udp(address) => {
+                let local = match address.starts_with('[') {
+                    true => "[::]:0",
+                    false => "0.0.0.0:0",
+                };
+                syslog::udp(formatter, local, address.as_str())
+            }
+            Server::Tcp(address) => syslog::tcp(formatter, address.as_str()),
+        }
+        .map_err(|e| match server {
+            Server::Local => format!("Couldn't connect to the local syslog daemon: {e}"),
+            Server::Socket(address) | Server::Udp(address) | Server::Tcp(address) => {
+                format!("Couldn't connect to syslog at {address}: {e}")
+            }
+        })?;
+        Ok(Self {
+            logger: Arc::new(Mutex::new(logger)),
+            newline: matches!(server, Server::Tcp(_)),
+        })
+    }
+
+    fn line(&self, level: Level) -> Line {
+        Line {
+            syslog: self.clone(),
+            level,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.line(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.line(*meta.level())
+    }
+}
+
+/// One log line, sent once written
+pub struct Line {
+    syslog: Syslog,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let mut message = String::from_utf8_lossy(&self.buf).trim_end().to_string();
+        if message.is_empty() {
+            return;
+        }
+        if self.syslog.newline {
+            message.push('\n');
+        }
+        let mut logger = self.syslog.logger.lock().unwrap_or_else(|e| e.into_inner());
+        // Nowhere left to report a failure
+        let _ = match self.level {
+            Level::ERROR => logger.err(message),
+            Level::WARN => logger.warning(message),
+            Level::INFO => logger.info(message),
+            _ => logger.debug(message),
+        };
+        let _ = logger.backend.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Server;
+
+    #[test]
+    fn test_parse_server() {
+        assert_eq!(
+            Server::try_from("udp://logs.lan"),
+            Ok(Server::Udp("logs.lan:514".to_string()))
+        );
+        assert_eq!(
+            Server::try_from("tcp://192.0.2.1:1514"),
+            Ok(Server::Tcp("192.0.2.1:1514".to_string()))
+        );
+        assert_eq!(
+            Server::try_from("/var/run/log"),
+            Ok(Server::Socket("/var/run/log".to_string()))
+        );
+        assert!(Server::try_from("logs.lan").is_err());
+    }
+}
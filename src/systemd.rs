@@ -0,0 +1,238 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use dns_updater::{SimpleName, config::ConfigFormat};
+
+use crate::cli::GlobalArgs;
+
+/// What the generated unit runs.
+pub struct Unit {
+    pub description: String,
+    pub executable: PathBuf,
+    /// Absolute path of the configuration file, loaded as a credential so it
+    /// may stay readable by root only
+    pub config: PathBuf,
+    pub config_format: Option<ConfigFormat>,
+    pub interface: Option<String>,
+    pub profile: Option<String>,
+}
+
+impl Unit {
+    pub fn from_args(global: &GlobalArgs, name: &str) -> Result<Self, String> {
+        let config = global.config.as_ref().ok_or(
+            "install-service needs a configuration file, see the migrate-config subcommand",
+        )?;
+        let config = std::path::absolute(config)
+            .map_err(|e| format!("Invalid configuration path {}: {e}", config.display()))?;
+        let executable =
+            std::env::current_exe().map_err(|e| format!("Couldn't locate dns_updater: {e}"))?;
+        Ok(Self {
+            description: format!("Dynamic DNS updater ({name})"),
+            executable,
+            config,
+            config_format: global.config_format,
+            interface: global.interface.clone(),
+            profile: global.profile.clone(),
+        })
+    }
+
+    /// Renders a hardened unit: no fixed user, state in `/var/lib/<name>`,
+    /// a read only system and only network access.
+    pub fn render(&self, name: &str) -> String {
+        // Keeps the extension so the format is still guessed right
+        let credential = match self.config.extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("config.{extension}"),
+            None => "config".to_string(),
+        };
+        let mut exec_start = format!(
+            "{} --config %d/{credential}",
+            quote(&self.executable.to_string_lossy())
+        );
+        if let Some(format) = self.config_format {
+            exec_start.push_str(&format!(" --config-format {}", format.simple_name()));
+        }
+        if let Some(interface) = &self.interface {
+            exec_start.push_str(&format!(" --interface {}", quote(interface)));
+        }
+        if let Some(profile) = &self.profile {
+            exec_start.push_str(&format!(" --profile {}", quote(profile)));
+        }
+        exec_start.push_str(" run");
+
+        format!(
+            "\
+[Unit]
+Description={description}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={exec_start}
+LoadCredential={credential}:{config}
+Restart=on-failure
+RestartSec=30
+# Restarted when it hangs
+WatchdogSec=5min
+
+# State files and the history live in /var/lib/{name}
+DynamicUser=yes
+StateDirectory={name}
+WorkingDirectory=%S/{name}
+
+# Hardening, hooks needing more access may require relaxing some of these
+NoNewPrivileges=yes
+CapabilityBoundingSet=
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+SystemCallFilter=@system-service
+
+[Install]
+WantedBy=multi-user.target
+",
+            description = self.description,
+            config = self.config.display(),
+        )
+    }
+}
+
+/// Quotes a word of an `ExecStart=` line when needed
+fn quote(word: &str) -> String {
+    if word.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        word.to_string()
+    }
+}
+
+/// Runs `systemctl daemon-reload` and `systemctl enable --now <name>`
+pub fn enable(name: &str) -> io::Result<()> {
+    for args in [&["daemon-reload"][..], &["enable", "--now", name]] {
+        let status = Command::new("systemctl").args(args).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "systemctl {} failed with {status}",
+                args.join(" ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub fn default_path(name: &str) -> PathBuf {
+    Path::new("/etc/systemd/system").join(format!("{name}.service"))
+}
+
+/// Tells systemd when a `Type=notify` service is started: `READY=1` once
+/// every provider tried to detect its IP, then `WATCHDOG=1` at half the
+/// `WatchdogSec=` interval so a hung updater gets restarted. Never returns,
+/// and does nothing outside such a service.
+#[cfg(target_os = "linux")]
+pub async fn notify(health: dns_updater::health::Health) {
+    if let Some(notifier) = Notifier::from_env() {
+        health.detection_tried().await;
+        notifier.send("READY=1");
+        if let Some(interval) = watchdog_interval() {
+            // Pinged by the runtime the providers run on, so it stops with them
+            let mut ticks = tokio::time::interval(interval / 2);
+            loop {
+                ticks.tick().await;
+                notifier.send("WATCHDOG=1");
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// The socket systemd listens on for notifications, in `NOTIFY_SOCKET`
+#[cfg(target_os = "linux")]
+struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    address: std::os::unix::net::SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+    fn from_env() -> Option<Self> {
+        use std::os::{linux::net::SocketAddrExt, unix::net};
+
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        let path = path.to_str()?;
+        // A leading @ stands for an abstract socket
+        let address = match path.strip_prefix('@') {
+            Some(name) => net::SocketAddr::from_abstract_name(name),
+            None => net::SocketAddr::from_pathname(path),
+        };
+        let notifier = address
+            .and_then(|address| Ok((net::UnixDatagram::unbound()?, address)))
+            .map(|(socket, address)| Self { socket, address });
+        match notifier {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                tracing::warn!(socket = path, error = %e, "Couldn't notify systemd");
+                None
+            }
+        }
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            tracing::warn!(state, error = %e, "Couldn't notify systemd");
+        }
+    }
+}
+
+/// The `WatchdogSec=` interval, when it's meant for this process
+#[cfg(target_os = "linux")]
+fn watchdog_interval() -> Option<std::time::Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match std::env::var("WATCHDOG_PID") {
+        Ok(pid) if pid.parse() != Ok(std::process::id()) => None,
+        _ => Some(std::time::Duration::from_micros(usec)).filter(|d| !d.is_zero()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::Unit;
+
+    #[test]
+    fn test_render() {
+        let unit = Unit {
+            description: "Dynamic DNS updater (dns-updater)".to_string(),
+            executable: PathBuf::from("/usr/local/bin/dns_updater"),
+            config: PathBuf::from("/etc/dns-updater/config.yaml"),
+            config_format: None,
+            interface: Some("eth 0".to_string()),
+            profile: Some("home".to_string()),
+        };
+        let rendered = unit.render("dns-updater");
+        assert!(rendered.contains(
+            "ExecStart=/usr/local/bin/dns_updater --config %d/config.yaml --interface \"eth 0\" --profile home run\n"
+        ));
+        assert!(rendered.contains("LoadCredential=config.yaml:/etc/dns-updater/config.yaml\n"));
+        assert!(rendered.contains("StateDirectory=dns-updater\n"));
+        assert!(rendered.contains("DynamicUser=yes\n"));
+        assert!(rendered.contains("Type=notify\n"));
+    }
+}
@@ -0,0 +1,257 @@
+//! Providers and IP sources answering from a script instead of the network,
+//! to test update flows. Clones share their script and what they recorded,
+//! so a test keeps one and hands the other to the [`crate::runner::Runner`].
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use reqwest::Client;
+
+use crate::{
+    IpVersion,
+    dyn_dns::{
+        CredentialCheck, CurrentError, DynDns, PersistsToFile, ProviderOptions, UpdateError,
+        state_file_name,
+    },
+    ip_grabber::{self, IpSource, SourceFactory},
+    scheduler::Schedule,
+};
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Default)]
+struct Updates {
+    failures: VecDeque<UpdateError>,
+    published: Vec<IpAddr>,
+    /// What [`DynDns::current`] answers
+    record: Vec<IpAddr>,
+}
+
+/// A provider accepting every update, unless told to fail the next ones,
+/// and recording the IPs it published
+#[derive(Debug, Clone)]
+pub struct MockDynDns {
+    hostname: String,
+    ip_version: IpVersion,
+    schedule: Schedule,
+    options: ProviderOptions,
+    file_name: String,
+    updates: Arc<Mutex<Updates>>,
+}
+
+impl MockDynDns {
+    /// Updates `hostname` once, see [`MockDynDns::with_schedule`]
+    pub fn new(hostname: &str, ip_version: IpVersion) -> Self {
+        let version = match ip_version {
+            IpVersion::V4 => "ipv4",
+            IpVersion::V6 => "ipv6",
+        };
+        Self {
+            hostname: hostname.to_string(),
+            ip_version,
+            schedule: Schedule::Once,
+            options: ProviderOptions::default(),
+            file_name: state_file_name("Mock", &[hostname, version]),
+            updates: Arc::default(),
+        }
+    }
+
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Answers the next update with `error`, queued after the failures
+    /// already set
+    pub fn fail_next(&self, error: UpdateError) {
+        lock(&self.updates).failures.push_back(error);
+    }
+
+    /// Makes the record hold `ips`, left as they are by updates
+    pub fn set_record(&self, ips: Vec<IpAddr>) {
+        lock(&self.updates).record = ips;
+    }
+
+    /// The IPs published so far, oldest first
+    pub fn published(&self) -> Vec<IpAddr> {
+        lock(&self.updates).published.clone()
+    }
+}
+
+impl PersistsToFile for MockDynDns {
+    fn file_name(&self) -> &str {
+        &self.file_name
+    }
+}
+
+#[async_trait]
+impl DynDns for MockDynDns {
+    async fn update(&mut self, _client: &Client, ip: IpAddr) -> Result<String, UpdateError> {
+        let mut updates = lock(&self.updates);
+        if let Some(error) = updates.failures.pop_front() {
+            return Err(error);
+        }
+        updates.published.push(ip);
+        Ok(format!("good {ip}"))
+    }
+
+    async fn test(&mut self, _client: &Client) -> Result<CredentialCheck, UpdateError> {
+        Ok(CredentialCheck::Valid("mock credentials".to_string()))
+    }
+
+    async fn current(&mut self, _client: &Client) -> Result<Vec<IpAddr>, CurrentError> {
+        Ok(lock(&self.updates).record.clone())
+    }
+
+    fn get_ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    fn get_schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    fn options_mut(&mut self) -> &mut ProviderOptions {
+        &mut self.options
+    }
+
+    fn provider_name(&self) -> &'static str {
+        "Mock"
+    }
+
+    fn hostname(&self) -> Option<&str> {
+        Some(&self.hostname)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Detections {
+    /// `None` for a failed detection
+    next: VecDeque<Option<IpAddr>>,
+    last: Option<IpAddr>,
+    count: usize,
+}
+
+/// An IP source detecting the IPs pushed to it in order, then the last one
+/// again and again
+#[derive(Debug, Clone)]
+pub struct MockIpSource {
+    ip_version: IpVersion,
+    detections: Arc<Mutex<Detections>>,
+}
+
+impl MockIpSource {
+    /// Fails to detect anything until IPs are pushed
+    pub fn new(ip_version: IpVersion) -> Self {
+        Self {
+            ip_version,
+            detections: Arc::default(),
+        }
+    }
+
+    pub fn push(&self, ip: IpAddr) {
+        lock(&self.detections).next.push_back(Some(ip));
+    }
+
+    /// Fails the detection coming after the IPs already pushed
+    pub fn push_failure(&self) {
+        lock(&self.detections).next.push_back(None);
+    }
+
+    /// How many times the IP was asked for
+    pub fn detections(&self) -> usize {
+        lock(&self.detections).count
+    }
+}
+
+#[async_trait]
+impl IpSource for MockIpSource {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    async fn detect(&self, _client: &Client) -> Result<IpAddr, ip_grabber::Error> {
+        let mut detections = lock(&self.detections);
+        detections.count += 1;
+        match detections.next.pop_front() {
+            Some(Some(ip)) => {
+                detections.last = Some(ip);
+                Ok(ip)
+            }
+            Some(None) => Err(ip_grabber::Error::NoneMatched),
+            None => detections.last.ok_or(ip_grabber::Error::NoneMatched),
+        }
+    }
+}
+
+/// Gives each provider the source of its IP version among `sources`, for
+/// [`crate::runner::RunnerBuilder::with_ip_sources`]. Providers of a version without
+/// one never detect an IP.
+pub fn sources(sources: impl IntoIterator<Item = MockIpSource>) -> SourceFactory {
+    let sources: Vec<_> = sources.into_iter().collect();
+    Arc::new(move |_iface, ip_version| {
+        let source = sources
+            .iter()
+            .find(|source| source.ip_version == ip_version)
+            .cloned()
+            .unwrap_or_else(|| MockIpSource::new(ip_version));
+        Box::new(source)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Client;
+
+    use super::{MockDynDns, MockIpSource};
+    use crate::{
+        IpVersion,
+        dyn_dns::{DynDns, UpdateError},
+        ip_grabber::IpSource,
+    };
+
+    #[tokio::test]
+    async fn test_mocks() {
+        let client = Client::new();
+        let source = MockIpSource::new(IpVersion::V4);
+        assert!(source.detect(&client).await.is_err());
+        source.push("192.0.2.1".parse().unwrap());
+        source.push_failure();
+        let detected = source.clone();
+        assert_eq!(
+            detected.detect(&client).await.ok(),
+            "192.0.2.1".parse().ok()
+        );
+        assert!(detected.detect(&client).await.is_err());
+        assert_eq!(
+            detected.detect(&client).await.ok(),
+            "192.0.2.1".parse().ok()
+        );
+        assert_eq!(source.detections(), 4);
+
+        let dns = MockDynDns::new("home.example", IpVersion::V4);
+        dns.fail_next(UpdateError::Rejected {
+            provider: "Mock",
+            response: "nohost".to_string(),
+        });
+        let mut updated = dns.clone();
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(updated.update(&client, ip).await.is_err());
+        assert_eq!(updated.update(&client, ip).await.unwrap(), "good 192.0.2.1");
+        assert_eq!(dns.published(), [ip]);
+    }
+}
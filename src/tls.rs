@@ -0,0 +1,369 @@
+//! HTTPS for the health probes, metrics and dashboard, with a certificate
+//! and key read from PEM files or a self-signed pair generated on first use.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use aws_lc_rs::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Datelike, Utc};
+use sha2::{Digest, Sha256};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        ServerConfig,
+        crypto::aws_lc_rs::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    server::TlsStream,
+};
+
+use crate::persistence::write_private;
+
+pub use tokio_rustls::TlsAcceptor;
+
+/// Longest a client may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Validity of the generated certificates
+const SELF_SIGNED_DAYS: i64 = 3650;
+
+/// Serves the certificate chain of `cert` with the private key of `key`,
+/// both PEM files
+pub fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", path.display()),
+        )
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert, &e))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, &e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(cert, &e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Like [`acceptor`], first generating a self-signed certificate for
+/// `localhost` and `ips` when `cert` doesn't exist, kept for the next runs
+/// so that clients can pin it. Its SHA-256 fingerprint is logged.
+pub async fn self_signed(cert: &Path, key: &Path, ips: &[IpAddr]) -> io::Result<TlsAcceptor> {
+    if !tokio::fs::try_exists(cert).await? {
+        let (cert_der, key_der) = generate(ips, Utc::now())?;
+        write_private(key, pem("PRIVATE KEY", &key_der).as_bytes()).await?;
+        write_private(cert, pem("CERTIFICATE", &cert_der).as_bytes()).await?;
+        tracing::info!(path = %cert.display(), "Generated a self-signed TLS certificate");
+    }
+    let acceptor = acceptor(cert, key)?;
+    if let Some(Ok(der)) = CertificateDer::pem_file_iter(cert)
+        .ok()
+        .and_then(|mut certs| certs.next())
+    {
+        let fingerprint = Sha256::digest(&der)
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        tracing::info!(%fingerprint, "Serving the self-signed TLS certificate");
+    }
+    Ok(acceptor)
+}
+
+/// Completes the handshake of a client connected to `stream`
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+}
+
+/// The clients of a TCP listener once their handshake is done, each done in
+/// its own task so slow clients don't hold back the others
+pub struct TlsListener {
+    connections: tokio::sync::mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Couldn't accept a connection");
+                        continue;
+                    }
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match accept(&acceptor, stream).await {
+                        Ok(stream) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Err(e) => tracing::debug!(%addr, error = %e, "TLS handshake failed"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+
+    /// The next client done with its handshake
+    pub async fn accept(&mut self) -> (TlsStream<TcpStream>, SocketAddr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accepting task only stops once this is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[cfg(feature = "dashboard")]
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        TlsListener::accept(self).await
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(TlsListener::local_addr(self))
+    }
+}
+
+/// `der` in a PEM block labelled `label`
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    // Base64 is ASCII, any split is on a character boundary
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+// DER tags and object identifiers of the certificates generated
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// `[0]`, the version of a certificate
+const VERSION: u8 = 0xa0;
+/// `[3]`, the extensions of a certificate
+const EXTENSIONS: u8 = 0xa3;
+/// `[2]` and `[7]` of a subject alternative name
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+/// 1.2.840.10045.4.3.2
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// 1.2.840.10045.2.1
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 2.5.4.3
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// A DER element of type `tag` holding `content`
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    match content.len() {
+        len @ 0..0x80 => element.push(len as u8),
+        len => {
+            let len = len.to_be_bytes();
+            let len = &len[len.iter().take_while(|byte| **byte == 0).count()..];
+            element.push(0x80 | len.len() as u8);
+            element.extend_from_slice(len);
+        }
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+fn sequence(elements: &[Vec<u8>]) -> Vec<u8> {
+    der(SEQUENCE, &elements.concat())
+}
+
+/// UTCTime until 2049, GeneralizedTime after, as RFC 5280 requires
+fn time(time: DateTime<Utc>) -> Vec<u8> {
+    match time.year() {
+        ..2050 => der(
+            UTC_TIME,
+            time.format("%y%m%d%H%M%SZ").to_string().as_bytes(),
+        ),
+        _ => der(
+            GENERALIZED_TIME,
+            time.format("%Y%m%d%H%M%SZ").to_string().as_bytes(),
+        ),
+    }
+}
+
+/// A self-signed ECDSA P-256 certificate for `localhost` and `ips`, valid
+/// from a day before `now`, and its private key in PKCS #8, both in DER
+fn generate(ips: &[IpAddr], now: DateTime<Utc>) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| io::Error::other("Couldn't generate a TLS key"))?;
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+        .map_err(|_| io::Error::other("Couldn't read the generated TLS key"))?;
+
+    let mut serial = [0; 16];
+    rng.fill(&mut serial)
+        .map_err(|_| io::Error::other("Couldn't generate a serial number"))?;
+    // Positive and without leading zeroes, as DER integers must be
+    serial[0] = serial[0] & 0x7f | 0x40;
+    let algorithm = sequence(&[der(OBJECT_IDENTIFIER, ECDSA_WITH_SHA256)]);
+    let name = sequence(&[der(
+        SET,
+        &sequence(&[
+            der(OBJECT_IDENTIFIER, COMMON_NAME),
+            der(UTF8_STRING, b"dns-updater"),
+        ]),
+    )]);
+    let public_key = [&[0][..], key_pair.public_key().as_ref()].concat();
+    let mut alt_names = vec![der(DNS_NAME, b"localhost")];
+    for ip in ips {
+        alt_names.push(match ip {
+            IpAddr::V4(ip) => der(IP_ADDRESS, &ip.octets()),
+            IpAddr::V6(ip) => der(IP_ADDRESS, &ip.octets()),
+        });
+    }
+    let tbs = sequence(&[
+        der(VERSION, &der(INTEGER, &[2])),
+        der(INTEGER, &serial),
+        algorithm.clone(),
+        name.clone(),
+        sequence(&[
+            time(now - chrono::Duration::days(1)),
+            time(now + chrono::Duration::days(SELF_SIGNED_DAYS)),
+        ]),
+        name,
+        sequence(&[
+            sequence(&[
+                der(OBJECT_IDENTIFIER, EC_PUBLIC_KEY),
+                der(OBJECT_IDENTIFIER, PRIME256V1),
+            ]),
+            der(BIT_STRING, &public_key),
+        ]),
+        der(
+            EXTENSIONS,
+            &sequence(&[sequence(&[
+                der(OBJECT_IDENTIFIER, SUBJECT_ALT_NAME),
+                der(OCTET_STRING, &sequence(&alt_names)),
+            ])]),
+        ),
+    ]);
+    let signature = key_pair
+        .sign(&rng, &tbs)
+        .map_err(|_| io::Error::other("Couldn't sign the TLS certificate"))?;
+    let signature = [&[0][..], signature.as_ref()].concat();
+    let cert = sequence(&[tbs, algorithm, der(BIT_STRING, &signature)]);
+    Ok((cert, pkcs8.as_ref().to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{
+            ClientConfig, RootCertStore,
+            crypto::aws_lc_rs::default_provider,
+            pki_types::{CertificateDer, ServerName, pem::PemObject},
+        },
+    };
+
+    use super::{TlsListener, der, self_signed};
+
+    #[test]
+    fn test_der_length() {
+        assert_eq!(der(0x04, &[1, 2]), [0x04, 2, 1, 2]);
+        let long = der(0x04, &[0; 300]);
+        assert_eq!(long[..4], [0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[tokio::test]
+    async fn test_self_signed() {
+        let dir = std::env::temp_dir().join(format!("dns_updater_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let acceptor = self_signed(&cert, &key, &[ip]).await.unwrap();
+        let pem = std::fs::read(&cert).unwrap();
+        // Kept for the next runs
+        self_signed(&cert, &key, &[ip]).await.unwrap();
+        assert_eq!(std::fs::read(&cert).unwrap(), pem);
+
+        let listener = TcpListener::bind((ip, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener = TlsListener::new(listener, acceptor).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await;
+                stream.write_all(b"hello").await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        // Trusted as its own root, so clients check it's valid for the names
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(&pem).unwrap())
+            .unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        for name in [
+            ServerName::from(ip),
+            ServerName::try_from("localhost").unwrap(),
+        ] {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = connector.connect(name, stream).await.unwrap();
+            let mut hello = String::new();
+            stream.read_to_string(&mut hello).await.unwrap();
+            assert_eq!(hello, "hello");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
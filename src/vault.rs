@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::secrets::{Secret, block_on, env_or_file, http_client};
+
+/// Settings of the Vault server secrets are read from, taken once from the
+/// environment.
+#[derive(Debug, Clone)]
+pub struct VaultConfig {
+    /// `VAULT_ADDR`, e.g. `https://vault.example.com:8200`
+    pub address: String,
+    pub auth: VaultAuth,
+    /// `VAULT_KV_MOUNT`, `secret` by default
+    pub mount: String,
+    /// `VAULT_KV_VERSION`, 1 or 2 (the default)
+    pub kv_version: u8,
+    /// `VAULT_NAMESPACE`, for Vault Enterprise
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum VaultAuth {
+    /// `VAULT_TOKEN`
+    Token(Secret),
+    /// `VAULT_ROLE_ID` and `VAULT_SECRET_ID`, logged in at `auth/approle`
+    AppRole { role_id: String, secret_id: Secret },
+}
+
+impl VaultConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let address = env_or_file("VAULT_ADDR")?.ok_or("VAULT_ADDR is not set")?;
+        let auth = match (
+            env_or_file("VAULT_TOKEN")?,
+            env_or_file("VAULT_ROLE_ID")?,
+            env_or_file("VAULT_SECRET_ID")?,
+        ) {
+            (Some(token), _, _) => VaultAuth::Token(token.into()),
+            (None, Some(role_id), Some(secret_id)) => VaultAuth::AppRole {
+                role_id,
+                secret_id: secret_id.into(),
+            },
+            _ => Err("Set VAULT_TOKEN, or VAULT_ROLE_ID and VAULT_SECRET_ID")?,
+        };
+        let kv_version = match env_or_file("VAULT_KV_VERSION")?.as_deref() {
+            None | Some("2") => 2,
+            Some("1") => 1,
+            Some(other) => Err(format!("Invalid VAULT_KV_VERSION {other}, expected 1 or 2"))?,
+        };
+        Ok(Self {
+            address: address.trim_end_matches('/').to_string(),
+            auth,
+            mount: env_or_file("VAULT_KV_MOUNT")?.unwrap_or("secret".to_string()),
+            kv_version,
+            namespace: env_or_file("VAULT_NAMESPACE")?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Leased<T> {
+    value: T,
+    expires: Option<Instant>,
+}
+
+impl<T> Leased<T> {
+    /// A lease duration of 0 means the value doesn't expire
+    fn new(value: T, lease_duration: u64) -> Self {
+        let expires =
+            (lease_duration > 0).then(|| Instant::now() + Duration::from_secs(lease_duration));
+        Self { value, expires }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.expires.is_none_or(|expires| Instant::now() < expires)
+    }
+}
+
+/// The client [`resolve`] reads secrets with, configured on first use
+static VAULT: OnceLock<Mutex<Option<Vault>>> = OnceLock::new();
+
+/// Vault KV client caching every secret read until its lease expires.
+#[derive(Debug)]
+pub struct Vault {
+    config: VaultConfig,
+    token: Option<Leased<Secret>>,
+    secrets: HashMap<String, Leased<HashMap<String, Secret>>>,
+}
+
+impl Vault {
+    pub fn new(config: VaultConfig) -> Self {
+        Self {
+            config,
+            token: None,
+            secrets: HashMap::new(),
+        }
+    }
+
+    /// Reads `key` of the secret at `path`, from the cache while its lease
+    /// is valid.
+    pub async fn get(&mut self, path: &str, key: &str) -> Result<String, String> {
+        if !self.secrets.get(path).is_some_and(Leased::is_valid) {
+            let secret = self.fetch(&http_client()?, path).await?;
+            self.secrets.insert(path.to_string(), secret);
+        }
+        self.secrets[path]
+            .value
+            .get(key)
+            .map(|secret| secret.expose().clone())
+            .ok_or(format!("Vault secret {path} has no key {key}"))
+    }
+
+    /// When the first lease of the cached secrets runs out, forgetting those
+    /// that already did
+    fn next_expiry(&mut self) -> Option<Instant> {
+        self.secrets.retain(|_, secret| secret.is_valid());
+        self.secrets
+            .values()
+            .filter_map(|secret| secret.expires)
+            .min()
+    }
+
+    async fn fetch(
+        &mut self,
+        client: &Client,
+        path: &str,
+    ) -> Result<Leased<HashMap<String, Secret>>, String> {
+        let token = self.token(client).await?;
+        let url = match self.config.kv_version {
+            1 => format!("{}/v1/{}/{path}", self.config.address, self.config.mount),
+            _ => format!(
+                "{}/v1/{}/data/{path}",
+                self.config.address, self.config.mount
+            ),
+        };
+        tracing::debug!(path, "Reading Vault secret");
+        let response = self
+            .request(client.get(url).header("X-Vault-Token", token.expose()))
+            .await?;
+
+        let data = match self.config.kv_version {
+            1 => &response["data"],
+            _ => &response["data"]["data"],
+        };
+        let data = data
+            .as_object()
+            .ok_or(format!("Vault secret {path} has no data"))?
+            .iter()
+            .map(|(k, v)| match v {
+                Value::String(s) => (k.clone(), s.as_str().into()),
+                other => (k.clone(), other.to_string().into()),
+            })
+            .collect();
+        Ok(Leased::new(
+            data,
+            response["lease_duration"].as_u64().unwrap_or(0),
+        ))
+    }
+
+    /// The client token, logging in again once an AppRole token expired
+    async fn token(&mut self, client: &Client) -> Result<Secret, String> {
+        if let Some(token) = self.token.as_ref().filter(|t| t.is_valid()) {
+            return Ok(token.value.clone());
+        }
+        let token = match &self.config.auth {
+            VaultAuth::Token(token) => Leased::new(token.clone(), 0),
+            VaultAuth::AppRole { role_id, secret_id } => {
+                let body =
+                    serde_json::json!({ "role_id": role_id, "secret_id": secret_id.expose() });
+                let url = format!("{}/v1/auth/approle/login", self.config.address);
+                let response = self
+                    .request(client.post(url).body(body.to_string()))
+                    .await?;
+                let auth = &response["auth"];
+                let token = auth["client_token"]
+                    .as_str()
+                    .ok_or("Vault AppRole login returned no token")?;
+                Leased::new(token.into(), auth["lease_duration"].as_u64().unwrap_or(0))
+            }
+        };
+        let value = token.value.clone();
+        self.token = Some(token);
+        Ok(value)
+    }
+
+    async fn request(&self, request: reqwest::RequestBuilder) -> Result<Value, String> {
+        let request = match &self.config.namespace {
+            Some(namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        };
+        let response = request.send().await.map_err(|e| format!("Vault: {e}"))?;
+        let status = response.status();
+        let body = response.text().await.map_err(|e| format!("Vault: {e}"))?;
+        if !status.is_success() {
+            return Err(format!("Vault returned {status}: {body}"));
+        }
+        serde_json::from_str(&body).map_err(|e| format!("Invalid Vault response: {e}"))
+    }
+}
+
+/// Resolves a `path#key` reference with the process wide Vault client,
+/// configured from the environment on first use.
+pub fn resolve(reference: &str) -> Result<String, String> {
+    let (path, key) = reference.rsplit_once('#').ok_or(format!(
+        "Invalid Vault reference {reference}, expected path#key"
+    ))?;
+    let mut vault = VAULT
+        .get_or_init(Default::default)
+        .lock()
+        .map_err(|_| "Vault client poisoned")?;
+    if vault.is_none() {
+        *vault = Some(Vault::new(VaultConfig::from_env()?));
+    }
+    block_on(vault.as_mut().expect("Just set").get(path, key))?
+}
+
+/// When the lease of a secret [`resolve`] read runs out, for the
+/// configuration to be read again then and the secret fetched anew. `None`
+/// when no secret expires.
+pub fn next_expiry() -> Option<Instant> {
+    let mut vault = VAULT.get()?.lock().ok()?;
+    vault.as_mut()?.next_expiry()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Instant};
+
+    use super::{Leased, Vault, VaultAuth, VaultConfig, resolve};
+
+    #[test]
+    fn test_lease() {
+        assert!(Leased::new("token", 0).is_valid());
+        assert!(Leased::new("token", 60).is_valid());
+        let mut expired = Leased::new("token", 60);
+        expired.expires = Some(std::time::Instant::now());
+        assert!(!expired.is_valid());
+    }
+
+    #[test]
+    fn test_next_expiry() {
+        let mut vault = Vault::new(VaultConfig {
+            address: "http://127.0.0.1:8200".to_string(),
+            auth: VaultAuth::Token("hunter2".into()),
+            mount: "secret".to_string(),
+            kv_version: 2,
+            namespace: None,
+        });
+        let secret =
+            |lease| Leased::new(HashMap::from([("key".to_string(), "s3cr3t".into())]), lease);
+        vault.secrets.insert("static".to_string(), secret(0));
+        assert_eq!(vault.next_expiry(), None);
+        let debug = format!("{vault:?}");
+        assert!(!debug.contains("hunter2") && !debug.contains("s3cr3t"));
+
+        let soon = secret(60);
+        let expires = soon.expires;
+        vault.secrets.insert("soon".to_string(), soon);
+        vault.secrets.insert("later".to_string(), secret(3600));
+        let mut expired = secret(60);
+        expired.expires = Some(Instant::now());
+        vault.secrets.insert("expired".to_string(), expired);
+        assert_eq!(vault.next_expiry(), expires);
+        // Expired secrets are fetched again when still referenced
+        assert!(!vault.secrets.contains_key("expired"));
+    }
+
+    #[test]
+    fn test_invalid_reference() {
+        assert!(resolve("secret/ddns").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::PathBuf,
+    process::ExitCode,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use dns_updater::{SimpleName, persistence, runner::Runner};
+use tokio::sync::watch;
+use tracing_subscriber::{filter::LevelFilter, fmt::writer::BoxMakeWriter};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::{cli::GlobalArgs, logfile::LogFile, logging, reload};
+
+pub const SERVICE_NAME: &str = "dns-updater";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// Arguments of `run --service`, for the service entry point called by the
+/// service manager
+static GLOBAL: OnceLock<GlobalArgs> = OnceLock::new();
+
+/// Where the service keeps its state files, history and log, since services
+/// start in `System32`: `%ProgramData%\dns-updater`
+fn state_dir() -> PathBuf {
+    env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from(r"C:\ProgramData"))
+        .join(SERVICE_NAME)
+}
+
+/// Hands the process over to the service control manager, returning once
+/// the service stopped.
+pub fn run(mut global: GlobalArgs) -> ExitCode {
+    // The default one would be the service account's
+    if global.state_dir.is_none() {
+        global.state_dir = Some(state_dir());
+    }
+    let dir = match global.enter_state_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Couldn't use the state directory: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // There's no console, log next to the state by default
+    let path = match &global.log_file {
+        Some(path) => path.clone(),
+        None => dir.join(format!("{SERVICE_NAME}.log")),
+    };
+    let writer = match LogFile::open(path, global.log_rotation()) {
+        Ok(log) => BoxMakeWriter::new(log),
+        Err(_) => BoxMakeWriter::new(std::io::stderr),
+    };
+    let _logging = match logging::init(&global, writer, false, LevelFilter::INFO) {
+        Ok(logging) => logging,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let _ = GLOBAL.set(global);
+    match service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!(
+                "Couldn't connect to the service manager, run without --service outside of a service: {e}"
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        tracing::error!(error = %e, "Service stopped");
+    }
+}
+
+fn run_service() -> Result<(), String> {
+    let (stop_tx, mut stop) = watch::channel(false);
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stop_tx.send_replace(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = service_control_handler::register(SERVICE_NAME, handler)
+        .map_err(|e| format!("Couldn't register the service: {e}"))?;
+    let set_state = |state, controls_accepted, exit_code| {
+        let status = status.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        });
+        if let Err(e) = status {
+            tracing::error!(error = %e, "Couldn't report the service status");
+        }
+    };
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::Win32(0),
+    );
+
+    let global = GLOBAL.get().expect("Set before starting the dispatcher");
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Couldn't start the runtime: {e}"))
+        .and_then(|runtime| {
+            runtime.block_on(async {
+                let config = global
+                    .load_config()
+                    .map_err(|e| format!("Invalid configuration: {e}"))?;
+                let dyn_dnss = config
+                    .dyn_dnss()
+                    .map_err(|e| format!("Invalid providers: {e}"))?;
+                let store = config
+                    .state
+                    .open()
+                    .map_err(|e| format!("Couldn't open the state: {e}"))?;
+                let legacy_dirs = global.legacy_state_dirs(&config.state);
+                for dd in &dyn_dnss {
+                    let dd = std::slice::from_ref(dd);
+                    persistence::migrate_legacy_state(store.as_ref(), dd, &legacy_dirs).await;
+                }
+                let client = config.client().map_err(|e| e.to_string())?;
+                let running = config.clone();
+                let runner = Runner::configured(config, client.clone())
+                    .and_then(|builder| builder.with_store(store).build())
+                    .map_err(|e| format!("Couldn't start: {e}"))?;
+                let reloads = reload::spawn(global.clone(), running, client).0;
+                tokio::select! {
+                    _ = runner.run_reloading(reloads) => {}
+                    _ = stop.wait_for(|stop| *stop) => tracing::info!("Stopping at the service manager's request"),
+                }
+                Ok(())
+            })
+        });
+
+    let exit_code = match result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    set_state(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    );
+    result
+}
+
+/// Registers a service starting at boot with the current configuration
+/// file, and starts it.
+pub fn install(global: &GlobalArgs) -> Result<(), String> {
+    let config = global.config.as_ref().ok_or(
+        "Installing the service needs a configuration file, see the migrate-config subcommand",
+    )?;
+    let config = std::path::absolute(config)
+        .map_err(|e| format!("Invalid configuration path {}: {e}", config.display()))?;
+    let mut launch_arguments = vec![OsString::from("--config"), config.into_os_string()];
+    if let Some(format) = global.config_format {
+        launch_arguments.extend(["--config-format".into(), format.simple_name().into()]);
+    }
+    if let Some(interface) = &global.interface {
+        launch_arguments.extend(["--interface".into(), interface.into()]);
+    }
+    if let Some(profile) = &global.profile {
+        launch_arguments.extend(["--profile".into(), profile.into()]);
+    }
+    if let Some(state_dir) = &global.state_dir {
+        let state_dir = std::path::absolute(state_dir)
+            .map_err(|e| format!("Invalid state directory {}: {e}", state_dir.display()))?;
+        launch_arguments.extend(["--state-dir".into(), state_dir.into()]);
+    }
+    launch_arguments.extend(["run".into(), "--service".into()]);
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| format!("Couldn't connect to the service manager (run as administrator): {e}"))?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "DNS Updater".into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe()
+            .map_err(|e| format!("Couldn't locate dns_updater: {e}"))?,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .map_err(|e| format!("Couldn't create the service: {e}"))?;
+    service
+        .set_description("Keeps dynamic DNS records pointing at this machine's current IP")
+        .map_err(|e| format!("Couldn't describe the service: {e}"))?;
+    service
+        .start(&[] as &[&OsStr])
+        .map_err(|e| format!("Service installed, but couldn't start it: {e}"))
+}
+
+/// Stops and removes the service.
+pub fn uninstall() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| {
+            format!("Couldn't connect to the service manager (run as administrator): {e}")
+        })?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|e| format!("Couldn't open the service: {e}"))?;
+    service
+        .delete()
+        .map_err(|e| format!("Couldn't remove the service: {e}"))?;
+    let state = service
+        .query_status()
+        .map_err(|e| format!("Couldn't query the service: {e}"))?
+        .current_state;
+    if state != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| format!("Service removed, but couldn't stop it: {e}"))?;
+    }
+    Ok(())
+}